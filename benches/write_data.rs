@@ -0,0 +1,125 @@
+//! Throughput of writing pty output to the console, run with `cargo bench`. A busy pty without
+//! any clients should cost little more than copying the output to the history.
+
+use cloud_console::{ConsoleMux, RingBuffer};
+use tokio::sync::mpsc;
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Counts the allocations, so the allocations per write can be reported.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+// SAFETY: all calls are forwarded to the system allocator.
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Same size as the history of the server.
+const HISTORY: usize = 80 / 2 * 2000;
+/// Size of a single read from the pty by the server.
+const CHUNK: usize = 320;
+/// Amount of output written per run.
+const TOTAL: usize = 256 << 20;
+
+/// Amount of writes per run.
+const WRITES: usize = TOTAL / CHUNK;
+
+/// Write `data` as often as needed for [`TOTAL`] bytes of output in chunks of [`CHUNK`] bytes,
+/// returning the time it took and the amount of allocations made.
+fn run(console: &mut ConsoleMux<RingBuffer<HISTORY>>, data: &[u8]) -> (Duration, u64) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..WRITES {
+        console.write_data(std::hint::black_box(data));
+    }
+    let elapsed = start.elapsed();
+    (elapsed, ALLOCATIONS.load(Ordering::Relaxed) - allocations)
+}
+
+/// Write `data` like [`run`] with `write`, while another thread keeps calling `attach`, returning
+/// the time the writes took and the amount of attaches made meanwhile.
+fn contended(write: impl Fn(&[u8]), attach: impl Fn() + Sync, data: &[u8]) -> (Duration, u64) {
+    let done = AtomicBool::new(false);
+    let attaches = AtomicU64::new(0);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                attach();
+                attaches.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let start = Instant::now();
+        for _ in 0..WRITES {
+            write(std::hint::black_box(data));
+        }
+        let elapsed = start.elapsed();
+        done.store(true, Ordering::Relaxed);
+        (elapsed, attaches.load(Ordering::Relaxed))
+    })
+}
+
+fn report_contended(name: &str, (elapsed, attaches): (Duration, u64)) {
+    let throughput = TOTAL as f64 / elapsed.as_secs_f64() / (1 << 20) as f64;
+    println!(
+        "{:<24} {:>8.1?} {:>10.1} MiB/s {:>8} attaches",
+        name, elapsed, throughput, attaches
+    );
+}
+
+fn report(name: &str, (elapsed, allocations): (Duration, u64)) {
+    let throughput = TOTAL as f64 / elapsed.as_secs_f64() / (1 << 20) as f64;
+    let per_write = allocations as f64 / WRITES as f64;
+    println!(
+        "{:<24} {:>8.1?} {:>10.1} MiB/s {:>6.2} allocations/write",
+        name, elapsed, throughput, per_write
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let data: Vec<u8> = (0..CHUNK).map(|i| b'a' + (i % 26) as u8).collect();
+    runtime.block_on(async {
+        let mut console = ConsoleMux::<RingBuffer<HISTORY>>::new();
+        report("no remotes", run(&mut console, &data));
+
+        // A remote which never reads, so all output after its buffer filled up is dropped.
+        let (tx, _rx) = mpsc::channel(1000);
+        console.attach_channel(tx).await;
+        report("one lagging remote", run(&mut console, &data));
+
+        // Empty writes don't change anything, and should take no time at all.
+        let mut console = ConsoleMux::<RingBuffer<HISTORY>>::new();
+        println!("{:<24} {:>8.1?}", "empty writes", run(&mut console, &[]).0);
+    });
+
+    // Remotes attaching all the time, which copies the history, and detaching right away. With
+    // the mux behind a mutex, every write waits for an attach in progress.
+    let console = Mutex::new(ConsoleMux::<RingBuffer<HISTORY>>::new());
+    let write = |data: &[u8]| console.lock().unwrap().write_data(data);
+    let attach = || {
+        let (tx, _rx) = mpsc::channel(1000);
+        futures::executor::block_on(console.lock().unwrap().attach_channel(tx));
+    };
+    report_contended("mutex, attaching", contended(write, attach, &data));
+}
@@ -10,21 +10,200 @@ const term = new Terminal({
 	minimumContractRatio: 7,
 });
 
-// Set up websocket, override binary data type as we don't want blobs
-const ws = new WebSocket("ws://" + window.location.host + "/ws");
-ws.binaryType = "arraybuffer";
+// Subprotocol to receive the newest history before the older history.
+const TAIL_FIRST_PROTOCOL = "cloud-console.tail-first";
+
+// Size of the frames a paste is sent in.
+const PASTE_CHUNK = 16 << 10;
 
 // Attach terminal
 term.open(document.getElementById('terminal'));
 
-ws.onmessage = msg => {
-	term.write(new Uint8Array(msg.data));
-};
+// The current connection to the server.
+let ws;
 
-// Use onData instead of onKey, this also fires when something is pasted
-// into the console.
-// onKey on the other hand fires when keys are pressed and seems to be
-// used more to override individual key functionality.
-term.onData(function(data, ev) {
-	ws.send(data);
-});
+// Pass on the token the page was opened with, if the server requires one.
+const token = new URLSearchParams(window.location.search).get("token");
+const query = token ? "?token=" + encodeURIComponent(token) : "";
+
+// Discover the features of the server before connecting. Servers without
+// the endpoint only support the raw terminal stream.
+fetch("/capabilities" + query)
+	.then(resp => resp.ok ? resp.json() : {})
+	.catch(() => ({}))
+	.then(caps => {
+		setup(caps);
+		setupEmbed(caps);
+		connect(caps, 0);
+	});
+
+// Send data to the server, if connected. Input while reconnecting is lost.
+function send(data) {
+	if (ws.readyState === WebSocket.OPEN) {
+		ws.send(data);
+	}
+}
+
+// Delay before the given reconnection attempt, as advised by the server.
+function reconnectDelay(reconnect, attempt) {
+	const delay = Math.min(reconnect.base_delay_ms * 2 ** attempt, reconnect.max_delay_ms);
+	const jitter = delay * reconnect.jitter_percent / 100;
+	return delay - jitter + Math.random() * 2 * jitter;
+}
+
+// Hook up the terminal, once for all connections.
+function setup(caps) {
+	// Report our size to the server, so it can resize the pty.
+	if (caps.resize) {
+		term.onResize(() => sendSize());
+	}
+
+	// Use onData instead of onKey, this also fires when something is pasted
+	// into the console.
+	// onKey on the other hand fires when keys are pressed and seems to be
+	// used more to override individual key functionality.
+	term.onData(function(data, ev) {
+		send(data);
+	});
+	// Mouse reports in the X10 encoding are not valid UTF-8, the terminal
+	// passes them as a string with one character per byte.
+	term.onBinary(function(data) {
+		send(Uint8Array.from(data, c => c.charCodeAt(0)));
+	});
+
+	// Send pastes in chunks marked as a paste, so the server can pace them
+	// and wrap them for bracketed paste mode. This runs before the paste
+	// handler of the terminal, which would send the paste as a single frame.
+	if (caps.paste) {
+		term.element.addEventListener("paste", ev => {
+			ev.preventDefault();
+			ev.stopPropagation();
+			// Like the terminal, submit lines with a carriage return.
+			const text = ev.clipboardData.getData("text/plain").replace(/\r?\n/g, "\r");
+			const data = new TextEncoder().encode(text);
+			send(JSON.stringify({ type: "paste_begin", bracketed: term.modes.bracketedPasteMode }));
+			for (let i = 0; i < data.length; i += PASTE_CHUNK) {
+				send(data.subarray(i, i + PASTE_CHUNK));
+			}
+			send(JSON.stringify({ type: "paste_end" }));
+		}, true);
+	}
+}
+
+// Let the pages embedding the console in an iframe control it with
+// postMessage, if the server allows their origin.
+function setupEmbed(caps) {
+	const origins = caps.embed_origins || [];
+	if (window.parent === window || origins.length === 0) {
+		return;
+	}
+	window.addEventListener("message", ev => {
+		if (!origins.includes(ev.origin)) {
+			return;
+		}
+		const msg = ev.data;
+		switch (msg.type) {
+			case "input":
+				send(msg.data);
+				break;
+			case "resize":
+				// The new size is reported to the server like any resize.
+				term.resize(msg.cols, msg.rows);
+				break;
+			case "buffer":
+				fetch("/buffer" + query)
+					.then(resp => resp.text())
+					.then(buffer => ev.source.postMessage({ type: "buffer", buffer }, ev.origin));
+				break;
+		}
+	});
+	// Messages to other origins than the one of the parent are discarded.
+	for (const origin of origins) {
+		window.parent.postMessage({ type: "ready" }, origin);
+	}
+}
+
+function sendSize() {
+	send(JSON.stringify({ type: "resize", cols: term.cols, rows: term.rows }));
+}
+
+function connect(caps, attempt) {
+	// Set up websocket, override binary data type as we don't want blobs
+	const protocols = caps.tail_first_replay ? [TAIL_FIRST_PROTOCOL] : [];
+	ws = new WebSocket("ws://" + window.location.host + "/ws" + query, protocols);
+	ws.binaryType = "arraybuffer";
+	let opened = false;
+
+	// With a tail first replay, the first frame is the newest history, which
+	// is shown right away, and the second frame the older history. Once that
+	// arrives, the terminal is rebuilt from the entire history, so it ends up
+	// in the same state as with a regular replay.
+	let backfill = 0;
+	let tail;
+	ws.addEventListener("open", () => {
+		// The replay of a new connection includes what the terminal already
+		// shows.
+		if (attempt > 0) {
+			term.reset();
+		}
+		opened = true;
+		if (ws.protocol === TAIL_FIRST_PROTOCOL) {
+			backfill = 2;
+		}
+		if (caps.resize) {
+			sendSize();
+		}
+	});
+
+	// Reconnect like the server advises, servers which don't advise it don't
+	// expect clients to reconnect.
+	ws.addEventListener("close", ev => {
+		// Tell why the server closed the connection, e.g. because the pty closed.
+		if (ev.reason) {
+			term.write("\r\n[" + ev.reason + "]\r\n");
+		}
+		// A normal close means the server ended the session, e.g. because the
+		// client was idle, reconnecting would only undo that.
+		if (caps.reconnect && ev.code !== 1000) {
+			const next = opened ? 0 : attempt;
+			setTimeout(() => connect(caps, next + 1), reconnectDelay(caps.reconnect, next));
+		}
+	});
+
+	// Binary messages are terminal data, text messages are control messages.
+	ws.onmessage = msg => {
+		if (typeof msg.data === "string") {
+			handleControl(JSON.parse(msg.data));
+			return;
+		}
+		const data = new Uint8Array(msg.data);
+		if (backfill === 2) {
+			tail = data;
+			backfill = 1;
+		} else if (backfill === 1) {
+			backfill = 0;
+			if (data.length > 0) {
+				term.reset();
+				term.write(data);
+				term.write(tail);
+			}
+			return;
+		}
+		term.write(data);
+	};
+}
+
+function handleControl(msg) {
+	switch (msg.type) {
+		case "winsize": {
+			// Another client uses a different size, our view might be clipped.
+			const clipped = msg.mismatch && (msg.cols < term.cols || msg.rows < term.rows);
+			document.getElementById('size-warning').hidden = !clipped;
+			break;
+		}
+		case "title": {
+			document.title = msg.title;
+			break;
+		}
+	}
+}
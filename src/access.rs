@@ -0,0 +1,291 @@
+use axum::http::{header, header::HeaderName, HeaderMap};
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// A range of IP addresses, denoted as `<address>/<prefix length>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Check if the address is part of this range. IPv4 mapped IPv6 addresses are considered to
+    /// be IPv4 addresses.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid address: {}", e))?
+            .to_canonical();
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|&prefix| prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid prefix length, must be at most {}", max_prefix))?,
+            None => max_prefix,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Determine the IP address of a client. If the peer is a trusted proxy, the address the proxy
+/// appended to the `X-Forwarded-For` header is used instead of the peer address.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[Cidr]) -> IpAddr {
+    if !trusted_proxies.iter().any(|proxy| proxy.contains(peer)) {
+        return peer;
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .and_then(|addr| addr.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+/// Check if a client with the given address may send input. If no ranges are configured, all
+/// clients can send input.
+pub fn input_allowed(client: IpAddr, allowed: &[Cidr]) -> bool {
+    allowed.is_empty() || allowed.iter().any(|range| range.contains(client))
+}
+
+/// Check if a token can be presented in a header, i.e. is non-empty printable ASCII without
+/// spaces.
+pub fn valid_token(token: &str) -> bool {
+    !token.is_empty() && token.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Parse a file with tokens, one per line. Empty lines and lines starting with `#` are ignored.
+pub fn parse_tokens(contents: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !valid_token(line) {
+            return Err(format!("invalid token on line {}", i + 1));
+        }
+        tokens.push(line.to_string());
+    }
+    if tokens.is_empty() {
+        return Err("no tokens".into());
+    }
+    Ok(tokens)
+}
+
+/// Check if a client presented one of the tokens, in an `Authorization: Bearer <token>` header or
+/// else in the `token` query parameter. If no tokens are configured, all clients are authorized.
+pub fn authorized(headers: &HeaderMap, query: Option<&str>, tokens: &[String]) -> bool {
+    if tokens.is_empty() {
+        return true;
+    }
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim());
+    let presented = match bearer.or(query) {
+        Some(presented) => presented,
+        None => return false,
+    };
+    // Compare with every token, so the time taken doesn't tell which one matched.
+    tokens.iter().fold(false, |found, token| {
+        found | constant_time_eq(presented, token)
+    })
+}
+
+/// Compare two strings in time which only depends on their length, so a token can't be guessed a
+/// byte at a time.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Maximum length of a correlation id supplied by a client.
+const MAX_CORRELATION_LEN: usize = 128;
+
+/// Find the correlation id supplied by a client, in the `header`, or else in the `cookie`. Ids
+/// which are empty, too long, or contain characters other than ASCII letters, digits, `.`, `_`
+/// and `-` are ignored, so they can't mess up logs, or add attributes to the cookie they are
+/// returned in.
+pub fn correlation_id(
+    headers: &HeaderMap,
+    header: &HeaderName,
+    cookie: Option<&str>,
+) -> Option<String> {
+    let from_header = headers.get(header).and_then(|value| value.to_str().ok());
+    let from_cookie = || {
+        let cookie = cookie?;
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == cookie)
+            .map(|(_, value)| value)
+    };
+    from_header
+        .or_else(from_cookie)
+        .map(str::trim)
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_CORRELATION_LEN
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+        })
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.20.30.40")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("1.2.3.4")));
+        assert!(cidr("192.168.1.5").contains(ip("192.168.1.5")));
+        assert!(!cidr("192.168.1.5").contains(ip("192.168.1.6")));
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+        assert!(cidr("fd00::/8").contains(ip("fd12::1")));
+        assert!(!cidr("fd00::/8").contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_cidr_parse_errors() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_client_ip_forwarded() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.1.1.1, 10.0.0.7".parse().unwrap());
+        let proxies = [cidr("127.0.0.1")];
+
+        assert_eq!(
+            client_ip(ip("127.0.0.1"), &headers, &proxies),
+            ip("10.0.0.7")
+        );
+        // Untrusted peers can't spoof their address.
+        assert_eq!(
+            client_ip(ip("127.0.0.2"), &headers, &proxies),
+            ip("127.0.0.2")
+        );
+        assert_eq!(
+            client_ip(ip("127.0.0.1"), &HeaderMap::new(), &proxies),
+            ip("127.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_authorized() {
+        let tokens = ["s3cret".to_string(), "other".to_string()];
+        let mut headers = HeaderMap::new();
+        assert!(authorized(&headers, None, &[]));
+        assert!(!authorized(&headers, None, &tokens));
+        assert!(authorized(&headers, Some("other"), &tokens));
+        assert!(!authorized(&headers, Some("s3cre"), &tokens));
+
+        headers.insert(header::AUTHORIZATION, "bearer s3cret".parse().unwrap());
+        assert!(authorized(&headers, None, &tokens));
+        // The header takes precedence over the query parameter.
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(!authorized(&headers, Some("s3cret"), &tokens));
+        headers.insert(header::AUTHORIZATION, "Basic s3cret".parse().unwrap());
+        assert!(authorized(&headers, Some("s3cret"), &tokens));
+        assert!(!authorized(&headers, None, &tokens));
+    }
+
+    #[test]
+    fn test_parse_tokens() {
+        assert_eq!(
+            parse_tokens("# deploy\ns3cret\n\n  other  \n"),
+            Ok(vec!["s3cret".into(), "other".into()])
+        );
+        assert!(parse_tokens("# nothing\n").is_err());
+        assert!(parse_tokens("s3cret\nwith space\n").is_err());
+    }
+
+    #[test]
+    fn test_correlation_id() {
+        let header = HeaderName::from_static("x-correlation-id");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            "theme=dark; cc-id=from-cookie".parse().unwrap(),
+        );
+        assert_eq!(
+            correlation_id(&headers, &header, Some("cc-id")),
+            Some("from-cookie".into())
+        );
+        assert_eq!(correlation_id(&headers, &header, None), None);
+        assert_eq!(correlation_id(&headers, &header, Some("theme=")), None);
+
+        // The header takes precedence over the cookie.
+        headers.insert(&header, "req-42".parse().unwrap());
+        assert_eq!(
+            correlation_id(&headers, &header, Some("cc-id")),
+            Some("req-42".into())
+        );
+        headers.insert(&header, "vm2.req_42".parse().unwrap());
+        assert_eq!(
+            correlation_id(&headers, &header, None),
+            Some("vm2.req_42".into())
+        );
+        let injected = "a;Domain=example.org;Max-Age=31536000";
+        for invalid in ["", "with space", &"x".repeat(200), injected, "a,b", "a=b"] {
+            headers.insert(&header, invalid.parse().unwrap());
+            assert_eq!(correlation_id(&headers, &header, None), None);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! Commands accepted on the local control socket, see `--control-socket`.
+
+use std::str::FromStr;
+
+/// A command sent on the control socket, one per line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// Discard the history, like `POST /clear`.
+    Clear,
+    /// Report the amount of connected clients.
+    Clients,
+    /// List the remotes attached to the console, see [`cloud_console::ConsoleMux::clients`].
+    Remotes,
+    /// Shut the server down, like Ctrl-C.
+    Shutdown,
+    /// Send the current contents of the history buffer, like `GET /buffer`.
+    Snapshot,
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "clear" => Ok(AdminCommand::Clear),
+            "clients" => Ok(AdminCommand::Clients),
+            "remotes" => Ok(AdminCommand::Remotes),
+            "shutdown" => Ok(AdminCommand::Shutdown),
+            "snapshot" => Ok(AdminCommand::Snapshot),
+            command => Err(format!("unknown command {}", command)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("clients\r\n".parse(), Ok(AdminCommand::Clients));
+        assert_eq!(" snapshot".parse(), Ok(AdminCommand::Snapshot));
+        assert_eq!("remotes".parse(), Ok(AdminCommand::Remotes));
+        assert_eq!(
+            "reboot".parse::<AdminCommand>(),
+            Err("unknown command reboot".into())
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::{error, warn};
+
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::clock::Clock;
+
+/// Amount of audit records which can be queued. Once the queue is full, recording waits, so no
+/// records are lost.
+const AUDIT_BACKLOG: usize = 256;
+/// Maximum length of a reconstructed command line, anything beyond it is discarded.
+const MAX_COMMAND_LEN: usize = 4096;
+/// Amount of bytes read from the end of an existing audit log to continue its hash chain.
+const TAIL_SIZE: u64 = 16 << 10;
+
+/// An event recorded in the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A client connected. Only `writable` clients can submit commands.
+    SessionStart { writable: bool },
+    /// A client disconnected.
+    SessionEnd,
+    /// A client submitted a command line.
+    Command { command: String },
+    /// A client submitted a line while the console prompted for a secret. The line itself is not
+    /// recorded.
+    Secret,
+}
+
+/// A single line of the audit log.
+#[derive(Debug, Serialize)]
+struct AuditRecord {
+    seq: u64,
+    /// RFC 3339 formatted time at which the event happened.
+    timestamp: String,
+    client: u64,
+    client_ip: IpAddr,
+    correlation_id: String,
+    #[serde(flatten)]
+    event: AuditEvent,
+    /// Hash of the previous hash and this record without the hash, if the hash chain is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+}
+
+/// The fields of an existing record needed to continue the log.
+#[derive(Debug, Default, Deserialize)]
+struct Tail {
+    seq: u64,
+    hash: Option<String>,
+}
+
+/// An append only audit log, recording client sessions and the commands they submit as JSON
+/// lines. Records are written in the background, in order.
+///
+/// With the hash chain enabled, every record has a `hash` field: the hex encoded SHA-256 of the
+/// previous hash (empty for the first record) followed by the record as written, without the
+/// `hash` field. Changing or removing a record breaks the chain from that record onwards.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    tx: mpsc::Sender<AuditRecord>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AuditLog {
+    /// Open the audit log at `path`, creating it if needed. Records are appended, and continue the
+    /// sequence numbers and hash chain of an existing log. An incomplete record at the end of the
+    /// log, e.g. after a crash, is ignored, and the next record starts on a new line.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub fn open(path: &Path, hash_chain: bool, clock: Arc<dyn Clock>) -> io::Result<AuditLog> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(len.saturating_sub(TAIL_SIZE)))?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail)?;
+        let complete = match tail.iter().rposition(|&b| b == b'\n') {
+            Some(end) => &tail[..end],
+            None => &[],
+        };
+        if tail.last().is_some_and(|&b| b != b'\n') {
+            warn!(
+                "Ignoring incomplete record at the end of audit log {}",
+                path.display()
+            );
+            file.write_all(b"\n")?;
+        }
+        let (next_seq, tail) = match complete.split(|&b| b == b'\n').rfind(|l| !l.is_empty()) {
+            Some(line) => {
+                let tail = serde_json::from_slice::<Tail>(line).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid audit log: {}", e),
+                    )
+                })?;
+                (tail.seq + 1, tail)
+            }
+            None => (0, Tail::default()),
+        };
+        let hash = hash_chain.then(|| tail.hash.unwrap_or_default());
+        Ok(AuditLog::spawn(
+            tokio::fs::File::from_std(file),
+            next_seq,
+            hash,
+            clock,
+        ))
+    }
+
+    /// Spawn a task writing records to `writer`, starting at sequence number `seq`. If `hash` is
+    /// set, records are chained starting from that hash.
+    fn spawn<W>(
+        mut writer: W,
+        mut seq: u64,
+        mut hash: Option<String>,
+        clock: Arc<dyn Clock>,
+    ) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel::<AuditRecord>(AUDIT_BACKLOG);
+        tokio::spawn(async move {
+            while let Some(mut record) = rx.recv().await {
+                record.seq = seq;
+                seq += 1;
+                // Serializing the record can't fail, there are no maps with non string keys.
+                let mut line = serde_json::to_string(&record).unwrap();
+                if let Some(prev) = &mut hash {
+                    let digest = Sha256::new()
+                        .chain_update(prev.as_bytes())
+                        .chain_update(line.as_bytes())
+                        .finalize();
+                    prev.clear();
+                    for b in digest {
+                        let _ = write!(prev, "{:02x}", b);
+                    }
+                    record.hash = Some(prev.clone());
+                    line = serde_json::to_string(&record).unwrap();
+                }
+                line.push('\n');
+                if let Err(e) = async {
+                    writer.write_all(line.as_bytes()).await?;
+                    writer.flush().await
+                }
+                .await
+                {
+                    error!("Could not write audit log {}", e);
+                }
+            }
+        });
+        AuditLog { tx, clock }
+    }
+
+    /// Record an event of the client with the given id, address and correlation id.
+    pub async fn record(
+        &self,
+        client: u64,
+        addr: SocketAddr,
+        correlation_id: &str,
+        event: AuditEvent,
+    ) {
+        let record = AuditRecord {
+            seq: 0,
+            timestamp: humantime::format_rfc3339_millis(self.clock.wall()).to_string(),
+            client,
+            client_ip: addr.ip(),
+            correlation_id: correlation_id.to_string(),
+            event,
+            hash: None,
+        };
+        if self.tx.send(record).await.is_err() {
+            warn!("Audit log writer stopped, dropping record");
+        }
+    }
+}
+
+/// Reconstructs the command lines submitted by a client from its raw input. Line editing with
+/// backspace, Ctrl-C and Ctrl-U is applied, escape sequences like cursor keys are ignored. Editing
+/// done by the program reading the input, like tab completion or history, can't be seen, so the
+/// result is an approximation of what was actually executed.
+#[derive(Debug, Default)]
+pub struct CommandLine {
+    line: Vec<u8>,
+    escape: Escape,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    #[default]
+    None,
+    /// An escape character was read.
+    Start,
+    /// The start of a control sequence was read, but no parameters yet.
+    Csi,
+    /// Inside a control sequence, which ends with a byte in the range `0x40..=0x7e`.
+    Sequence,
+    /// Inside an X10 mouse report, with the amount of raw bytes left to skip.
+    Mouse(u8),
+}
+
+impl CommandLine {
+    /// Create a new CommandLine with an empty line.
+    pub fn new() -> CommandLine {
+        CommandLine::default()
+    }
+
+    /// Feed input of the client, returning the lines which were submitted with it. Empty lines
+    /// are not returned.
+    pub fn feed(&mut self, input: &[u8]) -> Vec<String> {
+        let mut submitted = Vec::new();
+        for &b in input {
+            match (self.escape, b) {
+                (Escape::Start, b'[') => self.escape = Escape::Csi,
+                (Escape::Start, b'O') => self.escape = Escape::Sequence,
+                (Escape::Start, _) => self.escape = Escape::None,
+                (Escape::Csi, b'M') => self.escape = Escape::Mouse(3),
+                (Escape::Csi | Escape::Sequence, 0x40..=0x7e) => self.escape = Escape::None,
+                (Escape::Csi | Escape::Sequence, _) => self.escape = Escape::Sequence,
+                (Escape::Mouse(1), _) => self.escape = Escape::None,
+                (Escape::Mouse(left), _) => self.escape = Escape::Mouse(left - 1),
+                (Escape::None, 0x1b) => self.escape = Escape::Start,
+                (Escape::None, b'\r' | b'\n') => {
+                    if !self.line.is_empty() {
+                        submitted.push(String::from_utf8_lossy(&self.line).into_owned());
+                        self.line.clear();
+                    }
+                }
+                (Escape::None, 0x7f | 0x08) => {
+                    // Remove an entire character, not just its last byte.
+                    while let Some(b) = self.line.pop() {
+                        if b & 0xc0 != 0x80 {
+                            break;
+                        }
+                    }
+                }
+                // Ctrl-C and Ctrl-U discard the line.
+                (Escape::None, 0x03 | 0x15) => self.line.clear(),
+                (Escape::None, b'\t' | 0x20..) => {
+                    if self.line.len() < MAX_COMMAND_LEN {
+                        self.line.push(b);
+                    }
+                }
+                (Escape::None, _) => {}
+            }
+        }
+        submitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::io::AsyncBufReadExt;
+
+    use crate::clock::TokioClock;
+
+    fn feed(chunks: &[&[u8]]) -> Vec<String> {
+        let mut line = CommandLine::new();
+        chunks.iter().flat_map(|chunk| line.feed(chunk)).collect()
+    }
+
+    #[test]
+    fn test_command_line_editing() {
+        assert_eq!(feed(&[b"ls -", b"la\r"]), ["ls -la"]);
+        assert_eq!(feed(&[b"lss\x7f -l\r\n\r"]), ["ls -l"]);
+        assert_eq!(
+            feed(&[b"rm -rf /\x03", b"echo caf\xc3\xa9\x7f\x7fe\r"]),
+            ["echo cae"]
+        );
+        assert_eq!(
+            feed(&[b"top\x1b[A\x1bOB\x1b", b"[1;5Cx\x15uptime\r"]),
+            ["uptime"]
+        );
+        assert_eq!(feed(&[b"a\rb\nc"]), ["a", "b"]);
+        // Mouse reports, the X10 encoding contains raw bytes after the sequence.
+        assert_eq!(
+            feed(&[b"ls\x1b[<0;12;5M\x1b[<0;12;5m\x1b[M", b" +%\r"]),
+            ["ls"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hash_chain() {
+        let (writer, reader) = tokio::io::duplex(4096);
+        let audit = AuditLog::spawn(writer, 5, Some(String::new()), Arc::new(TokioClock));
+        let addr = "10.0.0.1:1234".parse().unwrap();
+        audit
+            .record(
+                1,
+                addr,
+                "req-1",
+                AuditEvent::SessionStart { writable: true },
+            )
+            .await;
+        audit
+            .record(
+                1,
+                addr,
+                "req-1",
+                AuditEvent::Command {
+                    command: "ls".into(),
+                },
+            )
+            .await;
+
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let mut prev = String::new();
+        for seq in 5..7 {
+            let line = lines.next_line().await.unwrap().unwrap();
+            let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(record["seq"], seq);
+            assert_eq!(record["client"], 1);
+            assert_eq!(record["client_ip"], "10.0.0.1");
+            assert_eq!(record["correlation_id"], "req-1");
+
+            // The hash covers the previous hash and the line without the hash.
+            let hash = record["hash"].as_str().unwrap();
+            let unhashed = line.replace(&format!(r#","hash":"{}""#, hash), "");
+            let digest = Sha256::new()
+                .chain_update(prev.as_bytes())
+                .chain_update(unhashed.as_bytes())
+                .finalize();
+            let expected: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+            assert_eq!(hash, expected);
+            prev = expected;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_truncated() {
+        let path = std::env::temp_dir().join(format!("cloud-console-audit-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            concat!(
+                r#"{"seq":3,"event":"session_end","hash":"abc"}"#,
+                "\n",
+                r#"{"seq":4,"timestamp":"2024-"#
+            ),
+        )
+        .unwrap();
+
+        let audit = AuditLog::open(&path, true, Arc::new(TokioClock)).unwrap();
+        let addr = "10.0.0.1:1234".parse().unwrap();
+        audit.record(1, addr, "req-1", AuditEvent::SessionEnd).await;
+        drop(audit);
+
+        // The incomplete record is left alone, the next one continues from the last complete record
+        // on a new line.
+        let line = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let contents = std::fs::read_to_string(&path).unwrap();
+                match contents.lines().nth(2) {
+                    Some(line) if contents.ends_with('\n') => break line.to_string(),
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["seq"], 4);
+        let hash = record["hash"].as_str().unwrap();
+        let unhashed = line.replace(&format!(r#","hash":"{}""#, hash), "");
+        let digest = Sha256::new()
+            .chain_update(b"abc")
+            .chain_update(unhashed.as_bytes())
+            .finalize();
+        let expected: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hash, expected);
+    }
+}
@@ -0,0 +1,41 @@
+use clap::Parser;
+use cloud_console::signature::{self, parse_verifying_key, VerifyingKey};
+
+use std::{path::PathBuf, process::ExitCode};
+
+/// Verify the signature of a log file written by cloud-console with `--log-signing-key`
+#[derive(Debug, Parser)]
+#[command(version)]
+struct VerifyConfig {
+    /// The public key of the signing key, as printed by cloud-console on startup.
+    #[arg(long, value_name = "HEX", value_parser = parse_key)]
+    public_key: VerifyingKey,
+    /// The signed files, their signature is read from the file with `.sig` appended to the name.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+}
+
+fn parse_key(key: &str) -> Result<VerifyingKey, String> {
+    parse_verifying_key(key).ok_or_else(|| "expected an Ed25519 public key of 64 hex digits".into())
+}
+
+fn main() -> ExitCode {
+    let config = VerifyConfig::parse();
+    let mut status = ExitCode::SUCCESS;
+    for file in &config.files {
+        match signature::verify(file, &config.public_key) {
+            Ok(verified) if verified.unsigned > 0 => println!(
+                "{}: OK, {} bytes signed, {} bytes appended after signing",
+                file.display(),
+                verified.signed,
+                verified.unsigned
+            ),
+            Ok(verified) => println!("{}: OK, {} bytes signed", file.display(), verified.signed),
+            Err(e) => {
+                println!("{}: FAILED, {}", file.display(), e);
+                status = ExitCode::FAILURE;
+            }
+        }
+    }
+    status
+}
@@ -0,0 +1,116 @@
+//! A budget for the history of all consoles served by the process, see `--history-budget`.
+
+use std::collections::HashMap;
+
+/// A console sharing the history budget.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetedConsole {
+    /// Identifies the console across rounds, e.g. the id of its session.
+    pub id: String,
+    /// The size of the history the console is configured with.
+    pub size: usize,
+    /// The total amount of output written to the console so far, which tells if it was active.
+    pub total_written: u64,
+}
+
+/// Divides a budget of history bytes over the consoles, in rounds. Every round, the most recently
+/// active consoles receive the history size they are configured with, until the budget runs out.
+/// The least recently active consoles are trimmed to what is left, possibly nothing.
+#[derive(Debug)]
+pub struct HistoryBudget {
+    budget: usize,
+    round: u64,
+    /// The total amount written to a console when it was last seen, and the round it was last
+    /// active in, by id.
+    activity: HashMap<String, (u64, u64)>,
+}
+
+impl HistoryBudget {
+    /// Create a new HistoryBudget of `budget` bytes, shared by all consoles.
+    pub fn new(budget: usize) -> HistoryBudget {
+        HistoryBudget {
+            budget,
+            round: 0,
+            activity: HashMap::new(),
+        }
+    }
+
+    /// Start a new round, returning the history size every console can keep, in the same order.
+    /// Consoles which wrote output since the last round, or which are new, count as active now.
+    /// Among consoles which were last active in the same round, the earlier ones are preferred.
+    pub fn allocate(&mut self, consoles: &[BudgetedConsole]) -> Vec<usize> {
+        self.round += 1;
+        let round = self.round;
+        for console in consoles {
+            let entry = self
+                .activity
+                .entry(console.id.clone())
+                .or_insert((console.total_written, round));
+            if entry.0 != console.total_written {
+                *entry = (console.total_written, round);
+            }
+        }
+        self.activity
+            .retain(|id, _| consoles.iter().any(|console| &console.id == id));
+
+        let mut order: Vec<usize> = (0..consoles.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(self.activity[&consoles[i].id].1));
+        let mut sizes = vec![0; consoles.len()];
+        let mut left = self.budget;
+        for i in order {
+            sizes[i] = consoles[i].size.min(left);
+            left -= sizes[i];
+        }
+        sizes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn console(id: &str, size: usize, total_written: u64) -> BudgetedConsole {
+        BudgetedConsole {
+            id: id.into(),
+            size,
+            total_written,
+        }
+    }
+
+    #[test]
+    fn test_history_budget() {
+        let mut budget = HistoryBudget::new(5000);
+        let consoles = [
+            console("", 2000, 0),
+            console("vm1", 2000, 0),
+            console("vm2", 2000, 0),
+        ];
+        // Everything is new, the earlier consoles are preferred.
+        assert_eq!(budget.allocate(&consoles), [2000, 2000, 1000]);
+        assert_eq!(budget.allocate(&consoles), [2000, 2000, 1000]);
+
+        // The least recently active console is trimmed.
+        let consoles = [
+            console("", 2000, 0),
+            console("vm1", 2000, 0),
+            console("vm2", 2000, 10),
+        ];
+        assert_eq!(budget.allocate(&consoles), [2000, 1000, 2000]);
+        let consoles = [
+            console("", 2000, 5),
+            console("vm1", 2000, 0),
+            console("vm2", 2000, 10),
+        ];
+        assert_eq!(budget.allocate(&consoles), [2000, 1000, 2000]);
+        let consoles = [
+            console("", 2000, 5),
+            console("vm1", 2000, 1),
+            console("vm2", 2000, 10),
+        ];
+        assert_eq!(budget.allocate(&consoles), [2000, 2000, 1000]);
+
+        // Consoles which are gone free their share.
+        let consoles = [console("", 2000, 5), console("vm2", 2000, 10)];
+        assert_eq!(budget.allocate(&consoles), [2000, 2000]);
+    }
+}
@@ -0,0 +1,92 @@
+use serde::Serialize;
+
+use crate::{
+    config::ServerConfig, control::PROTOCOL_VERSION, echo::LocalEcho, resize::ResizePolicy,
+};
+
+/// Maximum size of a single websocket frame accepted by the server. This is the default of the
+/// websocket implementation.
+const MAX_FRAME_SIZE: usize = 16 << 20;
+
+/// Description of the features supported by the server, so clients can configure themselves.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    /// Version of the websocket control message protocol.
+    pub protocol_version: u32,
+    pub resize: ResizeCapability,
+    /// Content encodings which can be used for HTTP responses.
+    pub compression: Vec<&'static str>,
+    /// Whether clients can connect in read only mode, on [`crate::replay::READ_ONLY_PATH`].
+    pub read_only: bool,
+    /// Maximum size of a websocket frame sent to the server, in bytes.
+    pub max_frame_size: usize,
+    /// Size of the history buffer replayed to new clients, in bytes.
+    pub buffer_size: usize,
+    pub local_echo: LocalEcho,
+    /// Whether title updates of the console are sent to clients.
+    pub title: bool,
+    /// Whether clients are advised to switch to the hexdump view when the output is binary.
+    pub binary_detection: bool,
+    /// Whether pastes can be marked with paste control messages.
+    pub paste: bool,
+    /// Whether clients can negotiate a replay of the newest history first.
+    pub tail_first_replay: bool,
+    /// Names of the macros clients with input access can trigger.
+    pub macros: Vec<String>,
+    pub reconnect: ReconnectCapability,
+    /// Origins of pages which can control the console with `postMessage` when embedding it.
+    pub embed_origins: Vec<String>,
+}
+
+/// Support for the resize control messages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResizeCapability {
+    pub policy: ResizePolicy,
+}
+
+/// How clients are advised to reconnect once their connection is lost.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReconnectCapability {
+    /// Delay before the first attempt in milliseconds, which doubles after every failed attempt.
+    pub base_delay_ms: u64,
+    /// Maximum delay between attempts in milliseconds.
+    pub max_delay_ms: u64,
+    /// Percentage of the delay which is randomized.
+    pub jitter_percent: u8,
+    /// Whether a client can resume its session after reconnecting. A reconnected client is a new
+    /// client, which receives the replay again.
+    pub resume: bool,
+}
+
+impl Capabilities {
+    /// Describe the capabilities of a server running with the given config and history buffer
+    /// size.
+    pub fn new(config: &ServerConfig, buffer_size: usize) -> Capabilities {
+        Capabilities {
+            protocol_version: PROTOCOL_VERSION,
+            resize: ResizeCapability {
+                policy: config.resize_policy,
+            },
+            compression: config
+                .compression()
+                .map(|c| c.encodings())
+                .unwrap_or_default(),
+            read_only: true,
+            max_frame_size: MAX_FRAME_SIZE,
+            buffer_size,
+            local_echo: config.local_echo,
+            title: config.title_updates,
+            binary_detection: config.detect_binary,
+            paste: true,
+            tail_first_replay: config.tail_first_replay.is_some(),
+            macros: config.macros.iter().map(|m| m.name.clone()).collect(),
+            reconnect: ReconnectCapability {
+                base_delay_ms: config.reconnect_delay,
+                max_delay_ms: config.reconnect_max_delay,
+                jitter_percent: config.reconnect_jitter,
+                resume: false,
+            },
+            embed_origins: config.embed_origin.clone(),
+        }
+    }
+}
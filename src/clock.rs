@@ -0,0 +1,101 @@
+use tokio::time::Instant;
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    time::{Duration, SystemTime},
+};
+
+/// A future which completes once a sleep finished.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Source of time for features which depend on it, so they can be tested deterministically.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current monotonic time.
+    fn now(&self) -> Instant;
+
+    /// The current wall clock time.
+    fn wall(&self) -> SystemTime;
+
+    /// Sleep until `deadline` is reached.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+
+    /// Sleep for the given duration.
+    fn sleep(&self, duration: Duration) -> Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+}
+
+/// The clock used in production, backed by tokio's timer and the system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+/// A clock for tests, which only advances when tokio's paused time is advanced. The wall clock
+/// starts at a fixed time and advances along with it.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy)]
+pub struct MockClock {
+    start: Instant,
+    wall_start: SystemTime,
+}
+
+#[cfg(test)]
+impl MockClock {
+    /// Create a MockClock with the wall clock set to `wall_start`. This pauses tokio's time, so
+    /// it must be called from a current thread runtime.
+    pub fn new(wall_start: SystemTime) -> MockClock {
+        tokio::time::pause();
+        MockClock {
+            start: Instant::now(),
+            wall_start,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.wall_start + (Instant::now() - self.start)
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    #[tokio::test]
+    async fn test_mock_clock() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1000));
+        let start = clock.now();
+        assert_eq!(clock.wall(), UNIX_EPOCH + Duration::from_secs(1000));
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(clock.now() - start, Duration::from_secs(5));
+        assert_eq!(clock.wall(), UNIX_EPOCH + Duration::from_secs(1005));
+    }
+}
@@ -0,0 +1,228 @@
+use std::{fmt, mem, str::FromStr};
+
+/// Longest line which is checked for repeats. Longer lines are passed on as is, so output without
+/// line breaks does not grow the state indefinitely.
+const MAX_LINE_LEN: usize = 4096;
+
+/// Which output of a [`ConsoleMux`](crate::ConsoleMux) repeated lines are collapsed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollapseScope {
+    /// The output sent to remotes, including the history buffer they receive when attaching.
+    Live,
+    /// The [`Recording`](crate::Recording) of the output.
+    History,
+    /// Both the live output and the recording.
+    All,
+}
+
+impl CollapseScope {
+    pub(crate) fn live(self) -> bool {
+        matches!(self, CollapseScope::Live | CollapseScope::All)
+    }
+
+    pub(crate) fn history(self) -> bool {
+        matches!(self, CollapseScope::History | CollapseScope::All)
+    }
+}
+
+impl FromStr for CollapseScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "live" => Ok(CollapseScope::Live),
+            "history" => Ok(CollapseScope::History),
+            "all" => Ok(CollapseScope::All),
+            _ => Err(format!(
+                "unknown collapse scope {}, expected live, history or all",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CollapseScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CollapseScope::Live => "live",
+            CollapseScope::History => "history",
+            CollapseScope::All => "all",
+        })
+    }
+}
+
+/// Collapses runs of identical lines in a stream of output. Once a line is repeated at least
+/// `threshold` times in a row, the run is written as the line once, followed by a
+/// `(repeated N times)` marker. Shorter runs are written unchanged.
+///
+/// Since repeats can only be counted once a run ends, repeated lines are held back until a
+/// different line arrives, or until [`RepeatCollapser::flush`] is called. A line which is the
+/// start of the previous line is held back as well, until it is clear whether it is a repeat.
+#[derive(Debug)]
+pub struct RepeatCollapser {
+    threshold: usize,
+    /// The last complete line, including the line feed.
+    last: Vec<u8>,
+    /// The line currently being written.
+    current: Vec<u8>,
+    /// The current line is held back, because it is the start of the last line.
+    held: bool,
+    /// The current line is too long to track.
+    untracked: bool,
+    /// Amount of held back repeats of the last line.
+    repeats: usize,
+}
+
+impl RepeatCollapser {
+    /// Create a new RepeatCollapser, which collapses runs of at least `threshold` identical lines.
+    pub fn new(threshold: usize) -> RepeatCollapser {
+        RepeatCollapser {
+            threshold,
+            last: Vec::new(),
+            current: Vec::new(),
+            held: true,
+            untracked: false,
+            repeats: 0,
+        }
+    }
+
+    /// Feed output, appending what can be written so far to `out`.
+    pub fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for &b in data {
+            if self.held {
+                self.current.push(b);
+                if self.last.starts_with(&self.current) {
+                    if self.current.len() == self.last.len() {
+                        self.repeats += 1;
+                        self.current.clear();
+                    }
+                    continue;
+                }
+                // This is a different line, so the run of repeats ended.
+                self.write_repeats(out);
+                out.extend_from_slice(&self.current);
+                self.held = false;
+            } else {
+                out.push(b);
+                if self.current.len() < MAX_LINE_LEN {
+                    self.current.push(b);
+                } else {
+                    self.untracked = true;
+                }
+            }
+
+            if b == b'\n' {
+                self.last = mem::take(&mut self.current);
+                if mem::take(&mut self.untracked) {
+                    self.last.clear();
+                }
+                self.held = true;
+            }
+        }
+    }
+
+    /// Write all held back output to `out`, ending the current run of repeats.
+    pub fn flush(&mut self, out: &mut Vec<u8>) {
+        self.write_repeats(out);
+        if self.held && !self.current.is_empty() {
+            out.extend_from_slice(&self.current);
+            self.held = false;
+        }
+    }
+
+    /// Whether there is held back output.
+    pub fn has_pending(&self) -> bool {
+        self.repeats > 0 || (self.held && !self.current.is_empty())
+    }
+
+    fn write_repeats(&mut self, out: &mut Vec<u8>) {
+        let repeats = mem::take(&mut self.repeats);
+        if repeats == 0 {
+            return;
+        }
+        // The first line of the run was already written.
+        let run = repeats + 1;
+        if run >= self.threshold {
+            out.extend_from_slice(format!("(repeated {} times)\r\n", run).as_bytes());
+        } else {
+            for _ in 0..repeats {
+                out.extend_from_slice(&self.last);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collapse(threshold: usize, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut collapser = RepeatCollapser::new(threshold);
+        let mut out = Vec::new();
+        for chunk in chunks {
+            collapser.feed(chunk, &mut out);
+        }
+        collapser.flush(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_collapse_repeated_lines() {
+        assert_eq!(
+            collapse(
+                3,
+                &[b"start\r\nerror\r\nerror\r\nerror\r\nerror\r\ndone\r\n"]
+            ),
+            b"start\r\nerror\r\n(repeated 4 times)\r\ndone\r\n"
+        );
+    }
+
+    #[test]
+    fn test_short_run_unchanged() {
+        assert_eq!(
+            collapse(3, &[b"a\nerror\nerror\nb\nb\n"]),
+            b"a\nerror\nerror\nb\nb\n"
+        );
+    }
+
+    #[test]
+    fn test_collapse_across_chunks() {
+        assert_eq!(
+            collapse(
+                2,
+                &[
+                    b"retry",
+                    b"ing\nretrying\nre",
+                    b"trying\n",
+                    b"retry",
+                    b"ok\n"
+                ]
+            ),
+            b"retrying\n(repeated 3 times)\r\nretryok\n"
+        );
+    }
+
+    #[test]
+    fn test_run_resets() {
+        assert_eq!(
+            collapse(2, &[b"x\nx\ny\nx\nx\n"]),
+            b"x\n(repeated 2 times)\r\ny\nx\n(repeated 2 times)\r\n"
+        );
+    }
+
+    #[test]
+    fn test_flush_partial_line() {
+        let mut collapser = RepeatCollapser::new(2);
+        let mut out = Vec::new();
+        collapser.feed(b"$ ls\n$ ", &mut out);
+        // The prompt might still become a repeat of the previous line.
+        assert_eq!(out, b"$ ls\n");
+        assert!(collapser.has_pending());
+
+        collapser.flush(&mut out);
+        assert_eq!(out, b"$ ls\n$ ");
+        assert!(!collapser.has_pending());
+        collapser.feed(b"ls\n", &mut out);
+        assert_eq!(out, b"$ ls\n$ ls\n");
+    }
+}
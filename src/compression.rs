@@ -0,0 +1,170 @@
+//! Compression settings, shared by everything the server compresses.
+
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+use clap::ValueEnum;
+use tokio::io::AsyncWrite;
+use tower_http::{compression::CompressionLayer, CompressionLevel};
+
+use std::{fmt, ops::RangeInclusive, str::FromStr};
+
+/// A compression algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Algorithm {
+    Gzip,
+    Br,
+    Zstd,
+}
+
+impl Algorithm {
+    /// Name of the algorithm as content encoding.
+    pub fn encoding(self) -> &'static str {
+        match self {
+            Algorithm::Gzip => "gzip",
+            Algorithm::Br => "br",
+            Algorithm::Zstd => "zstd",
+        }
+    }
+
+    /// The levels supported by the algorithm.
+    pub fn levels(self) -> RangeInclusive<u32> {
+        match self {
+            Algorithm::Gzip => 1..=9,
+            Algorithm::Br => 0..=11,
+            Algorithm::Zstd => 1..=22,
+        }
+    }
+}
+
+/// A compression format for the log file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogCompression {
+    Gzip,
+    Zstd,
+}
+
+impl LogCompression {
+    /// Wrap `writer` in a streaming compressor. The compressed stream is only complete once the
+    /// returned writer is shut down.
+    pub fn writer<W>(self, writer: W) -> Box<dyn AsyncWrite + Unpin + Send>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        match self {
+            LogCompression::Gzip => Box::new(GzipEncoder::new(writer)),
+            LogCompression::Zstd => Box::new(ZstdEncoder::new(writer)),
+        }
+    }
+}
+
+/// A compression level, trading CPU time for compression ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Fastest,
+    /// The default level of the algorithm, which is a good balance.
+    Default,
+    Best,
+    /// A level specific to the algorithm.
+    Precise(u32),
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fastest" => Ok(Level::Fastest),
+            "default" => Ok(Level::Default),
+            "best" => Ok(Level::Best),
+            s => s.parse().map(Level::Precise).map_err(|_| {
+                format!(
+                    "unknown compression level {}, expected fastest, default, best or a number",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Fastest => f.write_str("fastest"),
+            Level::Default => f.write_str("default"),
+            Level::Best => f.write_str("best"),
+            Level::Precise(level) => write!(f, "{}", level),
+        }
+    }
+}
+
+/// The compression algorithms which can be used, and the level to compress at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compression {
+    algorithms: Vec<Algorithm>,
+    level: Level,
+}
+
+impl Compression {
+    /// Create new compression settings. A precise level must be supported by all algorithms.
+    pub fn new(algorithms: &[Algorithm], level: Level) -> Result<Compression, String> {
+        if let Level::Precise(level) = level {
+            for algorithm in algorithms {
+                let levels = algorithm.levels();
+                if !levels.contains(&level) {
+                    return Err(format!(
+                        "compression level {} is not supported by {}, which supports levels {} to {}",
+                        level,
+                        algorithm.encoding(),
+                        levels.start(),
+                        levels.end()
+                    ));
+                }
+            }
+        }
+        Ok(Compression {
+            algorithms: algorithms.to_vec(),
+            level,
+        })
+    }
+
+    /// The content encodings which can be used.
+    pub fn encodings(&self) -> Vec<&'static str> {
+        self.algorithms.iter().map(|a| a.encoding()).collect()
+    }
+
+    /// A layer compressing HTTP responses with these settings.
+    pub fn layer(&self) -> CompressionLayer {
+        CompressionLayer::new()
+            .gzip(self.algorithms.contains(&Algorithm::Gzip))
+            .br(self.algorithms.contains(&Algorithm::Br))
+            .zstd(self.algorithms.contains(&Algorithm::Zstd))
+            .no_deflate()
+            .quality(match self.level {
+                Level::Fastest => CompressionLevel::Fastest,
+                Level::Default => CompressionLevel::Default,
+                Level::Best => CompressionLevel::Best,
+                Level::Precise(level) => CompressionLevel::Precise(level),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_validation() {
+        assert!(Compression::new(&[Algorithm::Gzip, Algorithm::Br], Level::Precise(9)).is_ok());
+        assert!(Compression::new(&[Algorithm::Gzip, Algorithm::Br], Level::Precise(11)).is_err());
+        assert!(Compression::new(&[Algorithm::Br], Level::Precise(11)).is_ok());
+        assert!(Compression::new(&[Algorithm::Zstd], Level::Precise(0)).is_err());
+        assert!(Compression::new(&[Algorithm::Gzip], Level::Best).is_ok());
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!("fastest".parse::<Level>(), Ok(Level::Fastest));
+        assert_eq!("6".parse::<Level>(), Ok(Level::Precise(6)));
+        assert!("-1".parse::<Level>().is_err());
+        assert!("fast".parse::<Level>().is_err());
+    }
+}
@@ -0,0 +1,515 @@
+use axum::http::{header::HeaderName, Uri};
+use clap::{ArgGroup, Parser};
+use cloud_console::{Backpressure, CollapseScope, LineEnding, CONNECTION_BUFFER};
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    access::{self, Cidr},
+    compression::{Algorithm, Compression, Level, LogCompression},
+    echo::LocalEcho,
+    history::HistoryMode,
+    macros::Macro,
+    output::NulBytes,
+    pty::{HangupPolicy, PtyReader},
+    replay::ReplayRoute,
+    resize::ResizePolicy,
+    schedule::HourRange,
+    session::SessionPty,
+    webhook::LifecycleEvent,
+};
+
+/// 80 columns, 2000 rows. Technically the Mux does not track rows but just a byte array. This is
+///    a sane default as such: a single column can contain up to 4 bytes (since it is unicode),
+///    however not all rows will be completely filled. Most will indeed only be partially used.
+///    As such, 40 bytes per line on average should be rather sufficient.
+pub const CONSOLE_BUFFER: usize = 80 / 2 * 2000;
+/// Smallest history buffer which can be configured with `--buffer-size`.
+const MIN_BUFFER_SIZE: usize = 1024;
+
+/// Cloud console - An interactive web based terminal connected to a pty
+#[derive(Debug, Clone, Parser)]
+#[command(
+    version,
+    group(ArgGroup::new("pty_path").required(true)),
+    group(ArgGroup::new("bind_addr").required(true)),
+    group(ArgGroup::new("log_output")),
+)]
+pub struct ServerConfig {
+    /// The path to the pty device file to connect to.
+    #[arg(long = "pty", value_name = "PATH", group = "pty_path")]
+    pty: Option<PathBuf>,
+    /// The address and port to bind the server to, e.g. `127.0.0.1:8080` or `[::1]:8080`.
+    #[arg(long, value_name = "ADDR:PORT", group = "bind_addr")]
+    bind: Option<SocketAddr>,
+    /// Optional file which receives all data sent by the pty. The file is created if needed, and
+    /// data is appended to it.
+    #[arg(long, value_name = "PATH", group = "log_output")]
+    log_file: Option<PathBuf>,
+    /// Deprecated, use `--pty`.
+    #[arg(value_name = "PTY", group = "pty_path")]
+    pty_arg: Option<PathBuf>,
+    /// Deprecated, use `--bind`.
+    #[arg(value_name = "BIND_IP", group = "bind_addr", requires = "bind_port")]
+    bind_ip: Option<IpAddr>,
+    /// Deprecated, use `--bind`.
+    #[arg(value_name = "BIND_PORT")]
+    bind_port: Option<u16>,
+    /// Deprecated, use `--log-file`.
+    #[arg(value_name = "LOG_FILE", group = "log_output")]
+    log_file_arg: Option<PathBuf>,
+    /// Normalize line endings in the log file to either `lf` or `crlf`. The output sent to clients
+    /// is not affected. By default the output is logged as is.
+    #[arg(long, value_name = "lf|crlf")]
+    pub log_line_endings: Option<LineEnding>,
+    /// Prefix every line in the log file with the time it started, as an RFC 3339 timestamp. The
+    /// output sent to clients is not affected.
+    #[arg(long, requires = "log_output")]
+    pub log_timestamps: bool,
+    /// Compress the log file as a `gzip` or `zstd` stream. Output reaches the file in compressed
+    /// blocks, and the stream is finished when the server shuts down. Appending to an existing
+    /// log file adds another stream, which both formats allow. By default the log file is not
+    /// compressed.
+    #[arg(long, value_enum, value_name = "gzip|zstd", requires = "log_output")]
+    pub log_compress: Option<LogCompression>,
+    /// Rotate the log file once it holds this many bytes: it is renamed to `<log_file>.1`, earlier
+    /// rotated files move up by one, and a new log file is started. Output is split at the limit.
+    /// Every file is signed before it is rotated, if the log file is signed. Can't be combined with
+    /// compressing the log file.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parse_nonzero,
+        requires = "log_output",
+        conflicts_with = "log_compress"
+    )]
+    pub log_max_size: Option<usize>,
+    /// Amount of rotated log files to keep, see `--log-max-size`. The oldest is deleted once
+    /// another file is rotated.
+    #[arg(long, value_name = "N", default_value_t = 5, value_parser = parse_nonzero, requires = "log_max_size")]
+    pub log_max_files: usize,
+    /// How the pty is read: with async file I/O, with blocking reads on a dedicated `thread`,
+    /// which can be more reliable for some devices, or by reading whenever the pty is readable
+    /// with `poll`, which doesn't keep a read pending.
+    #[arg(long, value_enum, default_value_t = PtyReader::Async)]
+    pub pty_reader: PtyReader,
+    /// Read up to this many bytes of output from the pty at once, between 64 bytes and 1 MiB.
+    /// Every read is sent to clients as is, so small reads keep the latency low, while large
+    /// reads cost fewer syscalls and messages for consoles with a lot of output.
+    #[arg(long, value_name = "BYTES", default_value_t = 320, value_parser = clap::value_parser!(u32).range(64..=1 << 20))]
+    pub read_buffer: u32,
+    /// Whether NUL bytes in the pty output, which some devices send as padding or keepalive, are
+    /// passed on to clients or stripped.
+    #[arg(long, value_enum, default_value_t = NulBytes::Pass)]
+    pub nul_bytes: NulBytes,
+    /// Amount of writes buffered for the log file while it is slow to write to.
+    #[arg(long, value_name = "WRITES", default_value_t = CONNECTION_BUFFER, value_parser = parse_nonzero)]
+    pub log_buffer: usize,
+    /// What happens once the buffer of the log file is full: `drop` output for the log file, or
+    /// `block` reading from the pty until the log file caught up, so no output is lost. Clients
+    /// are not affected either way.
+    #[arg(long, value_name = "drop|block", default_value_t = Backpressure::Drop)]
+    pub log_backpressure: Backpressure,
+    /// Wait up to this many milliseconds for clients which can't keep up with the output, before
+    /// dropping output for them. This pauses reading from the pty, and so slows down the output
+    /// to all clients, while one client is lagging. Set to 0 to drop output right away.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 0)]
+    pub send_wait: u64,
+    /// Sign the log file with the Ed25519 key in this file, given as 64 hex digits. The signature
+    /// is written next to the log file, with `.sig` appended to its name, and can be checked with
+    /// `verify-recording`. With `--log-max-size`, every rotated file keeps its own signature.
+    #[arg(long, value_name = "PATH", requires = "log_output")]
+    pub log_signing_key: Option<PathBuf>,
+    /// Interval in seconds at which the log file is signed again. The log file is also signed when
+    /// the server shuts down.
+    #[arg(long, value_name = "SECS", default_value_t = 60, value_parser = parse_nonzero)]
+    pub log_sign_interval: usize,
+    /// Serve the console on an extra websocket route, which replays at most the given amount of
+    /// bytes of history to connecting clients, denoted as `<path>=<bytes>` with a path below
+    /// `/ws/`, e.g. `/ws/lite=4096` for light clients. Can be repeated.
+    #[arg(long, value_name = "PATH=BYTES")]
+    pub replay_route: Vec<ReplayRoute>,
+    /// Serve another pty as a separate session on `/ws/session/<id>`, denoted as
+    /// `<id>=<path>[,<bytes>]`, e.g. `vm2=/dev/pts/4,1048576`. Sessions have their own history and
+    /// clients, of `--buffer-size` bytes unless a size is given. Can be repeated.
+    #[arg(long, value_name = "ID=PATH[,BYTES]")]
+    pub session: Vec<SessionPty>,
+    /// Mirror all console output to a second pty or device at this path, for tools which can only
+    /// read a tty. Output is dropped for the mirror while the device is not read.
+    #[arg(long, value_name = "PATH")]
+    pub mirror_pty: Option<PathBuf>,
+    /// Forward all console output to a TCP collector at `<host>:<port>`, e.g. for central log
+    /// aggregation. The connection is reestablished if it is lost.
+    #[arg(long, value_name = "HOST:PORT")]
+    pub forward_tcp: Option<String>,
+    /// Also serve the console on a plain TCP socket at this address, e.g. for `nc`. Connections
+    /// receive the history and all output, and what they send is written to the pty. They can't
+    /// be authenticated, so this can't be combined with `--auth-token`. Only `--allow-input-from`
+    /// applies to them.
+    #[arg(long, value_name = "ADDR:PORT", conflicts_with_all = ["auth_token", "auth_token_file"])]
+    pub tcp_bind: Option<SocketAddr>,
+    /// Accept commands to control the server on a Unix socket at this path, one per line:
+    /// `clear`, `clients`, `shutdown` or `snapshot`. Only the user running the server can connect.
+    #[arg(long, value_name = "PATH")]
+    pub control_socket: Option<PathBuf>,
+    /// Amount of bytes of output buffered while the TCP collector is unreachable. Once the buffer
+    /// is full, the oldest output is dropped.
+    #[arg(long, value_name = "BYTES", default_value_t = 1 << 20, value_parser = parse_nonzero)]
+    pub forward_buffer: usize,
+    /// Record client sessions and the command lines they submit in an append only audit log at
+    /// this path, separate from the log file.
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+    /// Chain the records of the audit log with SHA-256 hashes, so changes to the log can be
+    /// detected.
+    #[arg(long)]
+    pub audit_hash_chain: bool,
+    /// Send titles set by the console with OSC 0 or OSC 2 sequences to clients, which show them
+    /// as title of the browser tab.
+    #[arg(long)]
+    pub title_updates: bool,
+    /// Detect output which is mostly binary data, and advise clients to switch to the hexdump
+    /// view served on `/ws/hex` while it lasts.
+    #[arg(long)]
+    pub detect_binary: bool,
+    /// Draw a status line with the name of the console and whether the client is read only on the
+    /// bottom row of the terminal of clients, while no full-screen program is running.
+    #[arg(long)]
+    pub status_line: bool,
+    /// Compression algorithms which can be used for HTTP responses, in case the client supports
+    /// them.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [Algorithm::Gzip, Algorithm::Br]
+    )]
+    pub compression: Vec<Algorithm>,
+    /// Compression level: `fastest`, `default`, `best`, or a level supported by all configured
+    /// compression algorithms.
+    #[arg(long, default_value_t = Level::Default)]
+    pub compression_level: Level,
+    /// Detach clients and the log file if they have output queued, but did not accept any of it
+    /// for this many seconds. Set to 0 to never detach them.
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    pub stuck_timeout: u64,
+    /// Drop clients and the log file if the history can't be sent to them within this many
+    /// seconds after connecting, e.g. because of a congested link. Set to 0 to wait indefinitely.
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub attach_timeout: u64,
+    /// Disconnect clients which didn't send any input for this many seconds. Output, resizes and
+    /// other control messages don't count as input. Set to 0 to never disconnect them.
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    pub idle_timeout: u64,
+    /// Disconnect clients which send a websocket message of input larger than this many bytes,
+    /// rather than writing it to the pty.
+    #[arg(long, value_name = "BYTES", default_value_t = 64 * 1024, value_parser = parse_nonzero)]
+    pub max_input_frame: usize,
+    /// Replay the history to at most N clients at the same time. Other clients wait for their
+    /// turn, which smooths out a storm of clients reconnecting after a restart. By default there
+    /// is no limit.
+    #[arg(long, value_name = "N", value_parser = parse_nonzero)]
+    pub attach_concurrency: Option<usize>,
+    /// Amount of writes buffered for every client while it is slow to receive them. A larger
+    /// buffer lets clients lag behind for longer before output is dropped for them, but costs more
+    /// memory per lagging client. At least 2, since the history is queued as two writes.
+    #[arg(long, value_name = "WRITES", default_value_t = CONNECTION_BUFFER, value_parser = parse_connection_buffer)]
+    pub connection_buffer: usize,
+    /// Collect the output for a client for up to this many milliseconds before sending it, so
+    /// bursts of small writes are sent as one websocket message. Set to 0 to send every write
+    /// right away.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 0)]
+    pub coalesce_interval: u64,
+    /// How the history is kept: the raw output as `bytes`, only complete `lines` of output, so the
+    /// replay never starts halfway a line, or a model of the `screen`, of which new clients receive
+    /// a reconstruction instead of the raw history. Only basic terminal features are modeled.
+    #[arg(long, value_enum, default_value_t = HistoryMode::Bytes)]
+    pub history_mode: HistoryMode,
+    /// Size of the history buffer in bytes, at least 1024. The history is replayed to clients
+    /// when they connect, a larger buffer gives more scrollback at the cost of memory and a longer
+    /// replay. The buffer is allocated as output arrives.
+    #[arg(long, value_name = "BYTES", default_value_t = CONSOLE_BUFFER, value_parser = parse_buffer_size)]
+    pub buffer_size: usize,
+    /// Maximum amount of history in bytes kept by the console and all sessions together. Once
+    /// their buffers add up to more, the least recently active consoles are trimmed, down to
+    /// nothing if needed, and get their size back once they are active again. Can't be combined
+    /// with `--history-lines`, which isn't bounded by a size.
+    #[arg(long, value_name = "BYTES", value_parser = parse_buffer_size, conflicts_with = "history_lines")]
+    pub history_budget: Option<usize>,
+    /// With `--history-mode lines`, keep the last N complete lines of output, rather than as many
+    /// lines as fit in the history buffer.
+    #[arg(long, value_name = "N", value_parser = parse_nonzero)]
+    pub history_lines: Option<usize>,
+    /// Maximum length of a line kept with `--history-lines`, longer lines are truncated.
+    #[arg(long, value_name = "BYTES", default_value_t = 1024, value_parser = parse_nonzero)]
+    pub history_line_max: usize,
+    /// Same as `--history-mode screen`.
+    #[arg(long)]
+    pub replay_screen: bool,
+    /// Only replay the last N complete lines of the history to new clients, followed by the current
+    /// incomplete line. By default the entire history is replayed.
+    #[arg(long, value_name = "N")]
+    pub replay_lines: Option<usize>,
+    /// Offer clients to replay the last BYTES of the history first, before the older history,
+    /// so the latest output is shown right away. Clients opt in with the `cloud-console.tail-first`
+    /// websocket subprotocol.
+    #[arg(long, value_name = "BYTES")]
+    pub tail_first_replay: Option<usize>,
+    /// Limit the rate at which output is sent to clients to this many bytes per second, so bursts
+    /// of output don't scroll by too fast to read. Output beyond the rate is held back, up to the
+    /// size of the history.
+    #[arg(long, value_name = "BYTES")]
+    pub rate_limit: Option<u64>,
+    /// Only limit the rate of the output during these hours, in UTC, e.g. `22-6`. By default the
+    /// rate is always limited.
+    #[arg(long, value_name = "START-END", requires = "rate_limit")]
+    pub rate_limit_hours: Option<HourRange>,
+    /// Decides which of the sizes reported by the connected clients is applied to the pty.
+    #[arg(long, value_enum, default_value_t = ResizePolicy::Smallest)]
+    pub resize_policy: ResizePolicy,
+    /// Milliseconds clients are advised to wait before reconnecting once their connection is lost.
+    /// The delay doubles after every failed attempt, up to `--reconnect-max-delay`.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    pub reconnect_delay: u64,
+    /// Maximum delay in milliseconds between reconnection attempts advised to clients.
+    #[arg(long, value_name = "MS", default_value_t = 30000)]
+    pub reconnect_max_delay: u64,
+    /// Percentage of the reconnection delay clients are advised to randomize, so they don't all
+    /// reconnect at the same time after the server restarts.
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 20,
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    pub reconnect_jitter: u8,
+    /// Seconds after opening the pty after which the console is reported as ready, even if the
+    /// pty did not produce any output yet.
+    #[arg(long, default_value_t = 10)]
+    pub ready_timeout: u64,
+    /// Echo client input on the server, for consoles which don't echo input themselves. Input is
+    /// not echoed while the console prompts for a password.
+    #[arg(long, value_enum, default_value_t = LocalEcho::Off)]
+    pub local_echo: LocalEcho,
+    /// Maximum rate in bytes per second at which input pasted by clients is written to the pty,
+    /// so large pastes don't overwhelm the console. By default pastes are written as fast as the
+    /// pty accepts them.
+    #[arg(long, value_name = "BYTES")]
+    pub paste_rate: Option<u64>,
+    /// A macro clients with input access can trigger with a macro control message, denoted as
+    /// `<name>=<input>`. The input is written to the pty as if the client typed it, and can contain
+    /// the escapes `\r`, `\n`, `\t`, `\e` and `\\`. Can be repeated.
+    #[arg(long = "macro", value_name = "NAME=INPUT")]
+    pub macros: Vec<Macro>,
+    /// Keep an in-memory recording of up to this many bytes of console output, which can be
+    /// downloaded from `/log`. The recording is independent of the history buffer and the log
+    /// file. Set to 0 to disable the recording.
+    #[arg(long, default_value_t = 0)]
+    pub recording_size: usize,
+    /// Collapse runs of at least this many identical lines of output into the line followed by a
+    /// `(repeated N times)` marker. Repeated lines are held back until a different line arrives
+    /// or the console is idle. Not suitable for full screen programs. Disabled by default.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(2..))]
+    pub collapse_repeats: Option<u32>,
+    /// Where repeated lines are collapsed: in the `live` output sent to clients and the log file,
+    /// in the `history` recording, or in `all` of them.
+    #[arg(long, value_name = "live|history|all", default_value_t = CollapseScope::All)]
+    pub collapse_scope: CollapseScope,
+    /// Store the history and the recording as plain text, without escape sequences. Clients and the
+    /// log file still receive the output as is, but the history replayed to new clients and served
+    /// from `/buffer` is plain text.
+    #[arg(long)]
+    pub plain_history: bool,
+    /// Name of the console, used to identify it to external systems. Defaults to the path of the
+    /// pty.
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Webhook which is called with a JSON payload on client lifecycle events. Only http urls are
+    /// supported.
+    #[arg(long, value_parser = parse_http_url)]
+    pub webhook_url: Option<Uri>,
+    /// The lifecycle events which are sent to the webhook.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [LifecycleEvent::Connect, LifecycleEvent::Disconnect, LifecycleEvent::Dropped]
+    )]
+    pub webhook_events: Vec<LifecycleEvent>,
+    /// Base url of an OpenTelemetry collector receiving OTLP over HTTP, e.g.
+    /// `http://localhost:4318`. Metrics are exported every 10 seconds, and a span for every client
+    /// session once it ended. Only http urls are supported.
+    #[cfg(feature = "otlp")]
+    #[arg(long, value_parser = parse_http_url)]
+    pub otlp_endpoint: Option<Uri>,
+    /// Only clients with an address in one of these ranges can send input, other clients are
+    /// read only. Can be repeated. By default all clients can send input.
+    #[arg(long, value_name = "CIDR")]
+    pub allow_input_from: Vec<Cidr>,
+    /// Address ranges of reverse proxies in front of the server. For connections from these
+    /// ranges, the client address is taken from the `X-Forwarded-For` header. Can be repeated.
+    #[arg(long, value_name = "CIDR")]
+    pub trusted_proxy: Vec<Cidr>,
+    /// Token clients must present to connect to the console, in an `Authorization: Bearer
+    /// <token>` header or a `token` query parameter. Can be repeated, any of the tokens is
+    /// accepted. By default no token is needed.
+    #[arg(long, value_name = "TOKEN", value_parser = parse_token)]
+    pub auth_token: Vec<String>,
+    /// File with tokens clients can present to connect, one per line, in addition to the ones
+    /// given with `--auth-token`. Empty lines and lines starting with `#` are ignored.
+    #[arg(long, value_name = "PATH")]
+    pub auth_token_file: Option<PathBuf>,
+    /// Origin of a page which can embed the console in an iframe and control it with
+    /// `postMessage`, e.g. `https://dashboard.example.com`. Can be repeated. Once set, only these
+    /// origins can embed the console.
+    #[arg(long, value_name = "ORIGIN", value_parser = parse_origin)]
+    pub embed_origin: Vec<String>,
+    /// Header from which the correlation id of a connection is taken, which identifies the
+    /// connection in logs, audit records and webhook calls. If the client doesn't supply one, a
+    /// UUID is generated. The id is returned to the client in the same header.
+    #[arg(long, value_name = "NAME", default_value = "x-correlation-id")]
+    pub correlation_header: HeaderName,
+    /// Cookie from which the correlation id is taken if the header is not set. The id is returned
+    /// to the client in this cookie as well.
+    #[arg(long, value_name = "NAME")]
+    pub correlation_cookie: Option<String>,
+    /// Maximum amount of seconds to wait for existing sessions to end while draining, before
+    /// shutting down.
+    #[arg(long, default_value_t = 300)]
+    pub drain_timeout: u64,
+    /// If the pty can be opened for reading but not for writing, e.g. because of its permissions,
+    /// serve the console read only instead of exiting. Input of all clients is discarded.
+    #[arg(long)]
+    pub read_only_fallback: bool,
+    /// Try to reopen the pty up to this many times once reading from or writing to it fails,
+    /// waiting longer after every attempt, before exiting. Clients stay connected and keep their
+    /// history meanwhile. Set to 0 to exit right away.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub pty_reopen_attempts: u32,
+    /// When the foreground process group of the pty receives SIGHUP: `never`, when the server
+    /// shuts down, or also when the last client disconnects. Background jobs which should survive
+    /// the console being left alone need `never`.
+    #[arg(long, value_enum, default_value_t = HangupPolicy::Never)]
+    pub hangup: HangupPolicy,
+    /// Keep trying to open the pty for up to this many seconds on startup, e.g. while the VM it
+    /// belongs to is still starting. The server is already serving meanwhile, but reports it is
+    /// not ready. By default the server exits if the pty can't be opened right away.
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    pub pty_wait: u64,
+    /// Close the pty once the console had no clients and no output for this many seconds, and
+    /// open it again when a client connects. Output of the console is not read while the pty is
+    /// closed. Requires the `poll` pty reader, the other readers can't stop a pending read.
+    #[arg(long, value_name = "SECS")]
+    pub idle_release: Option<u64>,
+}
+
+impl ServerConfig {
+    /// The path to the pty device file to connect to.
+    pub fn pty(&self) -> &Path {
+        self.pty
+            .as_deref()
+            .or(self.pty_arg.as_deref())
+            .expect("the pty is a required argument")
+    }
+
+    /// Connect to another pty, e.g. for a session.
+    pub fn set_pty(&mut self, path: PathBuf) {
+        self.pty = Some(path);
+        self.pty_arg = None;
+    }
+
+    /// The address to bind the server to.
+    pub fn bind(&self) -> SocketAddr {
+        match (self.bind, self.bind_ip, self.bind_port) {
+            (Some(addr), _, _) => addr,
+            (None, Some(ip), Some(port)) => SocketAddr::new(ip, port),
+            _ => unreachable!("the bind address is a required argument"),
+        }
+    }
+
+    /// The file which receives all data sent by the pty, if any.
+    pub fn log_file(&self) -> Option<&Path> {
+        self.log_file.as_deref().or(self.log_file_arg.as_deref())
+    }
+
+    /// Stop writing the output to a log file.
+    pub fn disable_log_file(&mut self) {
+        self.log_file = None;
+        self.log_file_arg = None;
+    }
+
+    /// Whether any of the deprecated positional arguments was used instead of `--pty`, `--bind`
+    /// and `--log-file`.
+    pub fn positional_args(&self) -> bool {
+        self.pty_arg.is_some() || self.bind_ip.is_some() || self.log_file_arg.is_some()
+    }
+
+    /// The compression settings, or an error if the configured level is not supported.
+    pub fn compression(&self) -> Result<Compression, String> {
+        Compression::new(&self.compression, self.compression_level)
+    }
+
+    /// The name of the console.
+    pub fn console_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self.pty().display().to_string(),
+        }
+    }
+}
+
+fn parse_nonzero(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>().map_err(|e| format!("{}", e))? {
+        0 => Err("must be at least 1".into()),
+        value => Ok(value),
+    }
+}
+
+fn parse_connection_buffer(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>().map_err(|e| format!("{}", e))? {
+        // The replay is queued before the client is served, it must fit in the buffer.
+        0 | 1 => Err("must be at least 2".into()),
+        value => Ok(value),
+    }
+}
+
+pub(crate) fn parse_buffer_size(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>().map_err(|e| format!("{}", e))? {
+        size if size < MIN_BUFFER_SIZE => Err(format!("must be at least {}", MIN_BUFFER_SIZE)),
+        size => Ok(size),
+    }
+}
+
+fn parse_token(token: &str) -> Result<String, String> {
+    if !access::valid_token(token) {
+        return Err("a token must be non-empty printable ASCII without spaces".into());
+    }
+    Ok(token.to_string())
+}
+
+/// Parse a web origin, normalized to `<scheme>://<host>[:<port>]`.
+fn parse_origin(origin: &str) -> Result<String, String> {
+    let uri: Uri = origin.parse().map_err(|e| format!("{}", e))?;
+    let scheme = match uri.scheme_str() {
+        Some(scheme @ ("http" | "https")) => scheme,
+        _ => return Err("expected an http or https origin".into()),
+    };
+    let authority = uri
+        .authority()
+        .ok_or_else(|| "the origin has no host".to_string())?;
+    if !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+        return Err("an origin can't have a path".into());
+    }
+    Ok(format!("{}://{}", scheme, authority))
+}
+
+fn parse_http_url(url: &str) -> Result<Uri, String> {
+    let url: Uri = url.parse().map_err(|e| format!("{}", e))?;
+    if url.scheme_str() != Some("http") {
+        return Err("only http urls are supported".into());
+    }
+    Ok(url)
+}
@@ -0,0 +1,245 @@
+//! Control messages exchanged over the websocket, next to the raw terminal data.
+//!
+//! Terminal data is always sent as binary frames by the server. Text frames sent by the server
+//! are JSON encoded [`ServerMessage`]s. Text frames sent by a client which decode to a
+//! [`ClientMessage`] are handled by the server, any other frame is forwarded to the pty as input.
+
+use serde::{Deserialize, Serialize};
+
+use crate::resize::WinSize;
+
+/// Version of the control message protocol, to be increased on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The `type` of every [`ClientMessage`] which can be sent by a client.
+const CLIENT_MESSAGE_TYPES: [&str; 7] = [
+    "resize",
+    "eof",
+    "paste_begin",
+    "paste_end",
+    "macro",
+    "marker",
+    "capture",
+];
+
+/// A control message sent by a client.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// The terminal of the client has been resized.
+    Resize { cols: u16, rows: u16 },
+    /// Signal end of file to the program reading the pty, by sending the EOF character of the pty.
+    /// Like pressing Ctrl-D, this only works at the start of a line, and might end the session.
+    Eof,
+    /// The frames following this message, up to [`ClientMessage::PasteEnd`], are pasted input.
+    /// Pasted input is paced when written to the pty. If `bracketed` is set, because the program
+    /// enabled bracketed paste mode, the paste is wrapped in bracketed paste markers.
+    PasteBegin {
+        #[serde(default)]
+        bracketed: bool,
+    },
+    /// The paste started with [`ClientMessage::PasteBegin`] is complete.
+    PasteEnd,
+    /// Write the input of the macro with the given name, configured on the server, to the pty.
+    Macro { name: String },
+    /// Mark the current position in the output, to later capture the output from there on.
+    Marker,
+    /// Request the output since the last [`ClientMessage::Marker`], which is sent back in a
+    /// [`ServerMessage::Capture`].
+    Capture,
+    /// A frame with the `type` of a control message, which can't be decoded as that message, e.g.
+    /// a resize without a valid size. Such frames are ignored rather than forwarded as input.
+    #[serde(skip)]
+    Malformed { error: String },
+}
+
+/// A control message sent by the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// The size applied to the pty changed. If `mismatch` is set, not all clients reported the
+    /// same size, and clients with a bigger terminal might see a clipped view.
+    Winsize {
+        cols: u16,
+        rows: u16,
+        mismatch: bool,
+    },
+    /// The console set its title.
+    Title { title: String },
+    /// The output since the marker of the client, converted to UTF-8 lossily. If `truncated` is set,
+    /// the start of the output is no longer retained in the history.
+    Capture { output: String, truncated: bool },
+    /// The console output switched to or from mostly binary data. While `binary` is set, clients
+    /// can switch to the hexdump view, served on a separate websocket route.
+    BinaryOutput { binary: bool },
+}
+
+impl ClientMessage {
+    /// Try to decode a text frame as a control message. `None` is returned if the frame is not a
+    /// control message, in which case it should be treated as regular input.
+    pub fn parse(frame: &str) -> Option<ClientMessage> {
+        // Avoid running the decoder on every keystroke.
+        if !frame.starts_with('{') {
+            return None;
+        }
+        let error = match serde_json::from_str(frame) {
+            Ok(msg) => return Some(msg),
+            Err(e) => e,
+        };
+        #[derive(Deserialize)]
+        struct Tagged {
+            r#type: String,
+        }
+        let tagged: Tagged = serde_json::from_str(frame).ok()?;
+        CLIENT_MESSAGE_TYPES
+            .contains(&tagged.r#type.as_str())
+            .then(|| ClientMessage::Malformed {
+                error: error.to_string(),
+            })
+    }
+
+    /// Whether the message writes input to the pty, rather than only affecting the connection or
+    /// the size of the terminal.
+    pub fn is_input(&self) -> bool {
+        matches!(
+            self,
+            ClientMessage::Eof
+                | ClientMessage::PasteBegin { .. }
+                | ClientMessage::PasteEnd
+                | ClientMessage::Macro { .. }
+        )
+    }
+}
+
+impl ServerMessage {
+    /// Encode the message for transmission in a text frame.
+    pub fn to_json(&self) -> String {
+        // Serializing these types can't fail, there are no maps with non string keys.
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl From<(WinSize, bool)> for ServerMessage {
+    fn from((size, mismatch): (WinSize, bool)) -> Self {
+        ServerMessage::Winsize {
+            cols: size.cols,
+            rows: size.rows,
+            mismatch,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_message_types() {
+        // Serde lists the types of all variants which can be decoded when it meets an unknown one.
+        // Every one of them must be listed, so frames with a type but invalid fields are ignored.
+        let error = serde_json::from_str::<ClientMessage>(r#"{"type":""}"#)
+            .unwrap_err()
+            .to_string();
+        let (_, expected) = error.split_once("expected one of ").unwrap();
+        let types: Vec<_> = expected.split('`').skip(1).step_by(2).collect();
+        assert_eq!(types, CLIENT_MESSAGE_TYPES);
+    }
+
+    #[test]
+    fn test_parse_resize() {
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"resize","cols":120,"rows":40}"#),
+            Some(ClientMessage::Resize {
+                cols: 120,
+                rows: 40
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_resize() {
+        for frame in [
+            r#"{"type":"resize","cols":120}"#,
+            r#"{"type":"resize","cols":-1,"rows":40}"#,
+            r#"{"type":"resize","cols":"120","rows":40}"#,
+        ] {
+            assert!(
+                matches!(
+                    ClientMessage::parse(frame),
+                    Some(ClientMessage::Malformed { .. })
+                ),
+                "{}",
+                frame
+            );
+        }
+        let msg = ClientMessage::parse(r#"{"type":"resize","cols":80}"#);
+        assert_eq!(
+            msg,
+            Some(ClientMessage::Malformed {
+                error: "missing field `rows`".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_eof() {
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"eof"}"#),
+            Some(ClientMessage::Eof)
+        );
+    }
+
+    #[test]
+    fn test_parse_paste() {
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"paste_begin","bracketed":true}"#),
+            Some(ClientMessage::PasteBegin { bracketed: true })
+        );
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"paste_begin"}"#),
+            Some(ClientMessage::PasteBegin { bracketed: false })
+        );
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"paste_end"}"#),
+            Some(ClientMessage::PasteEnd)
+        );
+    }
+
+    #[test]
+    fn test_parse_macro() {
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"macro","name":"restart"}"#),
+            Some(ClientMessage::Macro {
+                name: "restart".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_capture() {
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"marker"}"#),
+            Some(ClientMessage::Marker)
+        );
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"capture"}"#),
+            Some(ClientMessage::Capture)
+        );
+    }
+
+    #[test]
+    fn test_parse_regular_input() {
+        assert_eq!(ClientMessage::parse("ls -la\r"), None);
+        assert_eq!(ClientMessage::parse("{"), None);
+        assert_eq!(ClientMessage::parse(r#"{"type":"unknown"}"#), None);
+    }
+
+    #[test]
+    fn test_encode_winsize() {
+        let msg = ServerMessage::from((WinSize { cols: 80, rows: 25 }, true));
+        assert_eq!(
+            msg.to_json(),
+            r#"{"type":"winsize","cols":80,"rows":25,"mismatch":true}"#
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use tokio::sync::Notify;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Tracks client sessions, so the server can be drained before shutting down: once draining
+/// starts, no new sessions are accepted, and existing sessions can finish.
+#[derive(Debug, Default)]
+pub struct Drain {
+    draining: AtomicBool,
+    sessions: AtomicUsize,
+    changed: Notify,
+}
+
+/// A session which keeps the server from shutting down while draining, until it is dropped.
+#[derive(Debug)]
+pub struct Session(Arc<Drain>);
+
+impl Drain {
+    /// Create a new Drain which is not draining and has no sessions.
+    pub fn new() -> Drain {
+        Drain::default()
+    }
+
+    /// Start draining. Returns false if the server was already draining.
+    pub fn start(&self) -> bool {
+        let started = !self.draining.swap(true, Ordering::SeqCst);
+        self.changed.notify_waiters();
+        started
+    }
+
+    /// Whether the server is draining.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// The amount of active sessions.
+    pub fn sessions(&self) -> usize {
+        self.sessions.load(Ordering::SeqCst)
+    }
+
+    /// Start a new session, unless the server is draining.
+    pub fn session(self: &Arc<Self>) -> Option<Session> {
+        self.sessions.fetch_add(1, Ordering::SeqCst);
+        // Checked after registering the session, so we can't race with a concurrent call to
+        // `finished` which just saw 0 sessions.
+        if self.is_draining() {
+            self.end_session();
+            return None;
+        }
+        Some(Session(self.clone()))
+    }
+
+    /// Completes once draining started, and then either all sessions ended or `deadline` passed.
+    pub async fn finished(&self, deadline: Duration) {
+        self.wait_for(|| self.is_draining()).await;
+        let _ = tokio::time::timeout(deadline, self.wait_for(|| self.sessions() == 0)).await;
+    }
+
+    async fn wait_for(&self, condition: impl Fn() -> bool) {
+        loop {
+            let changed = self.changed.notified();
+            if condition() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    fn end_session(&self) {
+        self.sessions.fetch_sub(1, Ordering::SeqCst);
+        self.changed.notify_waiters();
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.0.end_session();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_sessions() {
+        let drain = Arc::new(Drain::new());
+        let session = drain.session().unwrap();
+
+        let finished = tokio::spawn({
+            let drain = drain.clone();
+            async move { drain.finished(Duration::from_secs(60)).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(drain.start());
+        assert!(!drain.start());
+        assert!(drain.session().is_none());
+        assert_eq!(drain.sessions(), 1);
+
+        tokio::task::yield_now().await;
+        assert!(!finished.is_finished());
+        drop(session);
+        tokio::time::timeout(Duration::from_secs(5), finished)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_deadline() {
+        let drain = Arc::new(Drain::new());
+        let _session = drain.session().unwrap();
+        drain.start();
+
+        // The session never ends, so this only completes because of the deadline.
+        drain.finished(Duration::from_secs(60)).await;
+        assert_eq!(drain.sessions(), 1);
+    }
+}
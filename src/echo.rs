@@ -0,0 +1,112 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// Amount of bytes of the last output line to keep for prompt detection.
+const PROMPT_TAIL: usize = 64;
+
+/// Server side echo of client input, for consoles which don't echo input themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalEcho {
+    /// Don't echo input, the console is expected to echo it.
+    Off,
+    /// Echo input back to the client which sent it.
+    Sender,
+    /// Echo input to the shared view, i.e. all connected clients.
+    All,
+}
+
+/// Watches the console output for password prompts, during which input must not be echoed.
+#[derive(Debug, Default)]
+pub struct PromptDetector {
+    /// The last line of output, up to [`PROMPT_TAIL`] bytes.
+    tail: Vec<u8>,
+}
+
+impl PromptDetector {
+    /// Create a new PromptDetector which has not seen any output.
+    pub fn new() -> PromptDetector {
+        PromptDetector::default()
+    }
+
+    /// Feed console output to the detector. Returns true if the output ends in a prompt for a
+    /// secret, such as a password.
+    pub fn feed(&mut self, data: &[u8]) -> bool {
+        match data.iter().rposition(|&b| b == b'\n' || b == b'\r') {
+            Some(pos) => {
+                self.tail.clear();
+                self.tail.extend_from_slice(&data[pos + 1..]);
+            }
+            None => self.tail.extend_from_slice(data),
+        }
+        if self.tail.len() > PROMPT_TAIL {
+            self.tail.drain(..self.tail.len() - PROMPT_TAIL);
+        }
+
+        let line = String::from_utf8_lossy(&self.tail).to_lowercase();
+        let line = line.trim_end();
+        line.ends_with(':') && (line.contains("password") || line.contains("passphrase"))
+    }
+}
+
+/// Convert client input to the bytes which would be echoed by a terminal. Line endings are
+/// expanded, erase characters erase the previous character, and other control characters and
+/// escape sequences (e.g. arrow keys and mouse reports) are not echoed.
+pub fn echo_bytes(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut iter = input.iter().copied().peekable();
+    while let Some(b) = iter.next() {
+        match b {
+            b'\r' | b'\n' => out.extend_from_slice(b"\r\n"),
+            0x08 | 0x7f => out.extend_from_slice(b"\x08 \x08"),
+            0x1b => match iter.next() {
+                // X10 mouse report, three raw bytes follow which can be any printable character.
+                Some(b'[') if iter.peek() == Some(&b'M') => {
+                    iter.by_ref().take(4).for_each(drop);
+                }
+                // CSI, skip parameters up to and including the final byte.
+                Some(b'[') => {
+                    for b in iter.by_ref() {
+                        if (0x40..=0x7e).contains(&b) {
+                            break;
+                        }
+                    }
+                }
+                // SS3, a single byte follows.
+                Some(b'O') => {
+                    iter.next();
+                }
+                _ => {}
+            },
+            b if b < 0x20 => {}
+            b => out.push(b),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo_bytes() {
+        assert_eq!(echo_bytes(b"ls -l\r"), b"ls -l\r\n");
+        assert_eq!(echo_bytes(b"a\x7f"), b"a\x08 \x08");
+        assert_eq!(echo_bytes(b"\x1b[A\x1bOPx\x03"), b"x");
+        assert_eq!(echo_bytes(b"\x1b[<0;10;5M\x1b[M !!y"), b"y");
+        assert_eq!(echo_bytes("é".as_bytes()), "é".as_bytes());
+    }
+
+    #[test]
+    fn test_detect_password_prompt() {
+        let mut pd = PromptDetector::new();
+
+        assert!(!pd.feed(b"Welcome\r\nlogin: "));
+        assert!(!pd.feed(b"root\r\n"));
+        // Prompt split over multiple reads.
+        assert!(!pd.feed(b"Pass"));
+        assert!(pd.feed(b"word: "));
+        assert!(!pd.feed(b"\r\nroot@vm:~# "));
+    }
+}
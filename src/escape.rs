@@ -0,0 +1,145 @@
+//! Helpers to reason about ANSI escape sequences in console output.
+
+use vte::{Parser, Perform};
+
+/// Longest escape sequence which is held back while incomplete. Longer sequences (e.g. a huge OSC
+/// string) are passed on as is, so a misbehaving program can't stall the output indefinitely.
+pub const MAX_ESCAPE_LEN: usize = 256;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Returns the length of the incomplete escape sequence at the end of `data`, or 0 if `data` does
+/// not end in the middle of an escape sequence. Sequences longer than [`MAX_ESCAPE_LEN`] are
+/// considered complete.
+pub fn incomplete_escape_len(data: &[u8]) -> usize {
+    let window = &data[data.len().saturating_sub(MAX_ESCAPE_LEN)..];
+    let start = match window.iter().rposition(|&b| b == ESC) {
+        Some(start) => start,
+        None => return 0,
+    };
+    if is_complete(&window[start..]) {
+        0
+    } else {
+        window.len() - start
+    }
+}
+
+/// Check if the escape sequence in `seq`, which starts with an ESC byte and contains no other ESC
+/// bytes, is complete.
+fn is_complete(seq: &[u8]) -> bool {
+    let rest = &seq[1..];
+    match rest.first() {
+        None => false,
+        // CSI: parameter and intermediate bytes, terminated by a final byte.
+        Some(b'[') => rest[1..].iter().any(|b| (0x40..=0x7e).contains(b)),
+        // OSC: terminated by BEL, or by ST (ESC \) which starts a new sequence.
+        Some(b']') => rest[1..].contains(&BEL),
+        // DCS, SOS, PM and APC strings are only terminated by ST.
+        Some(b'P' | b'X' | b'^' | b'_') => false,
+        // SS2 and SS3 select a single character.
+        Some(b'N' | b'O') => rest.len() > 1,
+        // Intermediate bytes, followed by a final byte.
+        Some(0x20..=0x2f) => rest[1..].iter().any(|b| (0x30..=0x7e).contains(b)),
+        // Any other byte terminates a two byte sequence.
+        Some(_) => true,
+    }
+}
+
+/// Strips escape sequences from console output, leaving plain text. Of the control characters,
+/// only newlines, carriage returns and tabs are kept. Sequences and UTF-8 characters can be split
+/// over multiple writes.
+pub struct AnsiStripper {
+    parser: Parser,
+}
+
+/// Collects the plain text seen by the parser.
+struct PlainText<'a>(&'a mut Vec<u8>);
+
+impl AnsiStripper {
+    pub fn new() -> AnsiStripper {
+        AnsiStripper {
+            parser: Parser::new(),
+        }
+    }
+
+    /// Feed output, appending the plain text in it to `out`.
+    pub fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        let mut text = PlainText(out);
+        for &b in data {
+            self.parser.advance(&mut text, b);
+        }
+    }
+}
+
+impl Default for AnsiStripper {
+    fn default() -> Self {
+        AnsiStripper::new()
+    }
+}
+
+impl Perform for PlainText<'_> {
+    fn print(&mut self, ch: char) {
+        let mut buf = [0; 4];
+        self.0
+            .extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+    }
+
+    fn execute(&mut self, byte: u8) {
+        if matches!(byte, b'\n' | b'\r' | b'\t') {
+            self.0.push(byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_output() {
+        assert_eq!(incomplete_escape_len(b""), 0);
+        assert_eq!(incomplete_escape_len(b"plain text"), 0);
+        assert_eq!(incomplete_escape_len(b"\x1b[31mred\x1b[0m"), 0);
+        assert_eq!(incomplete_escape_len(b"\x1b]0;title\x07"), 0);
+        assert_eq!(incomplete_escape_len(b"\x1bPdata\x1b\\"), 0);
+        assert_eq!(incomplete_escape_len(b"\x1bOP"), 0);
+        assert_eq!(incomplete_escape_len(b"\x1b(B"), 0);
+        assert_eq!(incomplete_escape_len(b"\x1b7"), 0);
+    }
+
+    #[test]
+    fn test_incomplete_output() {
+        assert_eq!(incomplete_escape_len(b"text\x1b"), 1);
+        assert_eq!(incomplete_escape_len(b"text\x1b[3"), 3);
+        assert_eq!(incomplete_escape_len(b"text\x1b[1;3"), 5);
+        assert_eq!(incomplete_escape_len(b"\x1b]0;tit"), 7);
+        assert_eq!(incomplete_escape_len(b"\x1bPdata"), 6);
+        assert_eq!(incomplete_escape_len(b"\x1bO"), 2);
+        assert_eq!(incomplete_escape_len(b"\x1b("), 2);
+    }
+
+    #[test]
+    fn test_strip_escapes() {
+        let mut stripper = AnsiStripper::new();
+        let mut out = Vec::new();
+        stripper.feed(
+            b"\x1b]0;title\x07\x1b[1;31mred\x1b[0m\x07\r\n\x1b[3",
+            &mut out,
+        );
+        stripper.feed("2mgr\u{fc}n\tok\n".as_bytes(), &mut out);
+        assert_eq!(out, "red\r\ngr\u{fc}n\tok\n".as_bytes());
+        // UTF-8 characters can be split as well.
+        out.clear();
+        stripper.feed(&"\u{fc}".as_bytes()[..1], &mut out);
+        stripper.feed(&"\u{fc}".as_bytes()[1..], &mut out);
+        assert_eq!(out, "\u{fc}".as_bytes());
+    }
+
+    #[test]
+    fn test_oversized_sequence() {
+        let mut data = b"\x1b]0;".to_vec();
+        data.extend_from_slice(&[b'a'; MAX_ESCAPE_LEN]);
+        assert_eq!(incomplete_escape_len(&data), 0);
+    }
+}
@@ -0,0 +1,256 @@
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::Notify,
+    task::JoinHandle,
+};
+use tracing::warn;
+
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Delay before the first attempt to reconnect to the collector. The delay doubles after every
+/// failed attempt, up to [`RECONNECT_MAX`].
+const RECONNECT_MIN: Duration = Duration::from_millis(100);
+/// Maximum delay between attempts to reconnect to the collector.
+const RECONNECT_MAX: Duration = Duration::from_secs(10);
+/// Maximum amount of bytes written to the collector at once.
+const WRITE_CHUNK: usize = 8192;
+
+/// Output which is not yet sent to the collector. Once the backlog is full, the oldest output is
+/// dropped to make room for new output.
+#[derive(Debug)]
+struct Backlog {
+    data: VecDeque<u8>,
+    capacity: usize,
+    /// Position in the output stream of the first byte in `data`.
+    start: u64,
+    /// Amount of bytes dropped since the last time the collector was connected.
+    dropped: u64,
+}
+
+impl Backlog {
+    fn push(&mut self, data: &[u8]) {
+        let excess = (self.data.len() + data.len()).saturating_sub(self.capacity);
+        let buffered = excess.min(self.data.len());
+        self.data.drain(..buffered);
+        self.data.extend(&data[excess - buffered..]);
+        self.start += excess as u64;
+        self.dropped += excess as u64;
+    }
+
+    /// Copy the oldest output, returning it together with its position in the output stream.
+    fn peek(&self, max: usize) -> (u64, Vec<u8>) {
+        (self.start, self.data.iter().take(max).copied().collect())
+    }
+
+    /// Remove all output up to `end`, which was sent to the collector. Output which was dropped in
+    /// the meantime is not removed again.
+    fn consume(&mut self, end: u64) {
+        let n = end.saturating_sub(self.start) as usize;
+        self.data.drain(..n.min(self.data.len()));
+        self.start = self.start.max(end);
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    backlog: Mutex<Backlog>,
+    changed: Notify,
+}
+
+/// An [`AsyncWrite`] which forwards all data to a TCP collector in the background. The connection
+/// is reestablished if it is lost, while the collector is unreachable up to `capacity` bytes are
+/// buffered. Writes always complete immediately, so an unreachable collector never holds up the
+/// console.
+#[derive(Debug)]
+pub struct TcpForwarder {
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+impl TcpForwarder {
+    /// Create a new forwarder which connects to `addr` (`<host>:<port>`), buffering up to
+    /// `capacity` bytes while the collector can't keep up or is unreachable.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub fn spawn(addr: String, capacity: usize) -> TcpForwarder {
+        let shared = Arc::new(Shared {
+            backlog: Mutex::new(Backlog {
+                data: VecDeque::new(),
+                capacity,
+                start: 0,
+                dropped: 0,
+            }),
+            changed: Notify::new(),
+        });
+        let task = tokio::spawn(run(addr, shared.clone()));
+        TcpForwarder { shared, task }
+    }
+}
+
+impl Drop for TcpForwarder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl AsyncWrite for TcpForwarder {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.shared.backlog.lock().unwrap().push(data);
+        self.shared.changed.notify_one();
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Keep a connection to the collector open, and send it the backlog.
+async fn run(addr: String, shared: Arc<Shared>) {
+    let mut delay = RECONNECT_MIN;
+    loop {
+        match TcpStream::connect(&addr).await {
+            Ok(mut stream) => {
+                delay = RECONNECT_MIN;
+                let dropped = std::mem::take(&mut shared.backlog.lock().unwrap().dropped);
+                if dropped > 0 {
+                    warn!("Dropped {} bytes of output for collector {}", dropped, addr);
+                }
+                let (mut reader, mut writer) = stream.split();
+                if let Err(e) = forward(&mut reader, &mut writer, &shared).await {
+                    warn!("Lost connection to collector {}: {}", addr, e);
+                }
+            }
+            Err(e) => warn!("Could not connect to collector {}: {}", addr, e),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX);
+    }
+}
+
+/// Send the backlog to the collector until the connection fails. The collector is not expected to
+/// send anything, but the connection is read from so a closed connection is noticed right away,
+/// instead of after output was written to it.
+async fn forward<R, W>(reader: &mut R, writer: &mut W, shared: &Shared) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut discard = [0; 64];
+    loop {
+        let (start, chunk) = shared.backlog.lock().unwrap().peek(WRITE_CHUNK);
+        tokio::select! {
+            read = reader.read(&mut discard) => {
+                if read? == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed by collector",
+                    ));
+                }
+            }
+            _ = shared.changed.notified(), if chunk.is_empty() => {}
+            written = writer.write(&chunk), if !chunk.is_empty() => {
+                let written = written?;
+                shared.backlog.lock().unwrap().consume(start + written as u64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    async fn read_exact(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        buf
+    }
+
+    async fn accept(listener: &TcpListener) -> TcpStream {
+        tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn test_backlog_drops_oldest() {
+        let mut backlog = Backlog {
+            data: VecDeque::new(),
+            capacity: 8,
+            start: 0,
+            dropped: 0,
+        };
+        backlog.push(b"hello");
+        let (start, chunk) = backlog.peek(3);
+        assert_eq!((start, chunk.as_slice()), (0, &b"hel"[..]));
+        backlog.push(b" world");
+        assert_eq!(backlog.dropped, 3);
+        // Part of the peeked output was dropped while it was sent.
+        backlog.consume(start + 3);
+        assert_eq!(backlog.peek(100), (3, b"lo world".to_vec()));
+        backlog.consume(5);
+        assert_eq!(backlog.peek(100), (5, b" world".to_vec()));
+        backlog.push(b"0123456789");
+        assert_eq!(backlog.peek(100), (13, b"23456789".to_vec()));
+        assert_eq!(backlog.dropped, 11);
+    }
+
+    #[tokio::test]
+    async fn test_forward_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut forwarder = TcpForwarder::spawn(listener.local_addr().unwrap().to_string(), 1024);
+
+        let mut conn = accept(&listener).await;
+        forwarder.write_all(b"first").await.unwrap();
+        assert_eq!(read_exact(&mut conn, 5).await, b"first");
+
+        // The collector goes away, output is buffered until the forwarder reconnected.
+        drop(conn);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        forwarder.write_all(b"second").await.unwrap();
+        let mut conn = accept(&listener).await;
+        assert_eq!(read_exact(&mut conn, 6).await, b"second");
+        assert_eq!(forwarder.shared.backlog.lock().unwrap().dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn test_forward_collector_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let mut forwarder = TcpForwarder::spawn(addr.to_string(), 1024);
+        forwarder.write_all(b"buffered").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let mut conn = accept(&listener).await;
+        assert_eq!(read_exact(&mut conn, 8).await, b"buffered");
+    }
+}
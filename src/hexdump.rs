@@ -0,0 +1,148 @@
+//! Detect binary console output, and render output as a hexdump for clients which can't show it.
+
+use std::fmt::Write;
+
+/// Amount of bytes per line of a hexdump.
+const LINE_LEN: usize = 16;
+/// Amount of recent output the ratio of binary bytes is computed over. Older output counts less
+/// and less.
+const WINDOW: u32 = 1024;
+/// Amount of output counted at once.
+const BLOCK_LEN: usize = WINDOW as usize / 4;
+/// Minimum amount of output before it can be considered binary.
+const MIN_SAMPLE: u32 = 128;
+/// Percentage of binary bytes above which the output is considered binary.
+const BINARY_PERCENT: u32 = 30;
+/// Percentage of binary bytes below which the output is considered text again, lower than
+/// [`BINARY_PERCENT`] so the state doesn't flap on output which is in between.
+const TEXT_PERCENT: u32 = 10;
+
+/// Detects whether recent console output is mostly binary data rather than text, from the ratio
+/// of control characters which are not used by terminals, and bytes which are not valid UTF-8.
+#[derive(Debug, Default)]
+pub struct BinaryDetector {
+    /// Amount of bytes counted.
+    total: u32,
+    /// Amount of the counted bytes which are binary.
+    binary: u32,
+    /// Whether the output is currently considered binary.
+    is_binary: bool,
+}
+
+impl BinaryDetector {
+    pub fn new() -> BinaryDetector {
+        BinaryDetector::default()
+    }
+
+    /// Feed a chunk of console output. Returns whether the output is binary if that changed.
+    pub fn feed(&mut self, data: &[u8]) -> Option<bool> {
+        // Count in blocks, so older output in a big chunk also counts less.
+        for block in data.chunks(BLOCK_LEN) {
+            for chunk in block.utf8_chunks() {
+                let controls = chunk.valid().bytes().filter(|&b| is_binary_control(b));
+                self.binary += controls.count() as u32;
+                // An invalid sequence at the end can be a character split over two blocks, the
+                // difference is negligible.
+                self.binary += chunk.invalid().len() as u32;
+                self.total += (chunk.valid().len() + chunk.invalid().len()) as u32;
+            }
+            while self.total > WINDOW {
+                self.total /= 2;
+                self.binary /= 2;
+            }
+        }
+        if self.total < MIN_SAMPLE {
+            return None;
+        }
+        let percent = self.binary * 100 / self.total;
+        let is_binary = match self.is_binary {
+            false => percent > BINARY_PERCENT,
+            true => percent >= TEXT_PERCENT,
+        };
+        if is_binary == self.is_binary {
+            return None;
+        }
+        self.is_binary = is_binary;
+        Some(is_binary)
+    }
+}
+
+/// Whether a byte is a control character which terminals don't use in text output.
+fn is_binary_control(b: u8) -> bool {
+    matches!(b, 0x00..=0x06 | 0x0e..=0x1a | 0x1c..=0x1f | 0x7f)
+}
+
+/// Renders output as a hexdump in the canonical format of `hexdump -C`: the offset, 16 bytes in
+/// hex, and those bytes as ASCII. Every chunk starts a new line, so output is never held back
+/// waiting for a full line.
+#[derive(Debug, Default)]
+pub struct HexDump {
+    /// Offset of the next byte in the output.
+    offset: u64,
+}
+
+impl HexDump {
+    pub fn new() -> HexDump {
+        HexDump::default()
+    }
+
+    /// Render a chunk of output, appending the lines to `out`.
+    pub fn feed(&mut self, data: &[u8], out: &mut String) {
+        for line in data.chunks(LINE_LEN) {
+            let _ = write!(out, "{:08x} ", self.offset);
+            for i in 0..LINE_LEN {
+                if i % 8 == 0 {
+                    out.push(' ');
+                }
+                match line.get(i) {
+                    Some(b) => {
+                        let _ = write!(out, "{:02x} ", b);
+                    }
+                    None => out.push_str("   "),
+                }
+            }
+            out.push_str(" |");
+            out.extend(line.iter().map(|&b| match b {
+                0x20..=0x7e => b as char,
+                _ => '.',
+            }));
+            out.push_str("|\r\n");
+            self.offset += line.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_binary() {
+        let mut detector = BinaryDetector::new();
+        let text = "ls -l\r\n\x1b[1;34mdir\x1b[0m  file.txt  \u{e9}t\u{e9}\r\n".repeat(20);
+        assert_eq!(detector.feed(text.as_bytes()), None);
+
+        // The recent text output still weighs in.
+        let binary: Vec<u8> = (0..=255).cycle().take(600).collect();
+        assert_eq!(detector.feed(&binary), None);
+        assert_eq!(detector.feed(&binary), Some(true));
+        assert_eq!(detector.feed(&binary), None);
+
+        // Text output takes over again.
+        assert_eq!(detector.feed(text.repeat(4).as_bytes()), Some(false));
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let mut dump = HexDump::new();
+        let mut out = String::new();
+        dump.feed(b"Hello, world!\r\n\x00\xff", &mut out);
+        dump.feed(b"ok", &mut out);
+        assert_eq!(
+            out,
+            "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0d 0a 00  |Hello, world!...|\r\n\
+             00000010  ff                                                |.|\r\n\
+             00000011  6f 6b                                             |ok|\r\n"
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use clap::ValueEnum;
+use cloud_console::{DynLineRing, DynRingBuffer, HistoryStore, LineBuffer};
+
+/// How the history of the console is kept and replayed to new clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HistoryMode {
+    /// Keep the raw output, for the most faithful replay.
+    Bytes,
+    /// Keep complete lines of output, so the replay never starts halfway a line.
+    Lines,
+    /// Keep the raw output, but replay a reconstruction of the current screen, so new clients
+    /// immediately see the correct screen.
+    Screen,
+}
+
+/// The store keeping the history of the console, depending on the [`HistoryMode`].
+#[derive(Debug, Clone)]
+pub enum History {
+    Bytes(DynRingBuffer),
+    Lines(DynLineRing),
+    LastLines(LineBuffer),
+}
+
+impl History {
+    /// Create a new, empty store for the given mode, retaining up to `size` bytes. The screen is
+    /// modeled by the console itself, which keeps the raw output as history next to it.
+    pub fn new(mode: HistoryMode, size: usize) -> History {
+        match mode {
+            HistoryMode::Bytes | HistoryMode::Screen => History::Bytes(DynRingBuffer::new(size)),
+            HistoryMode::Lines => History::Lines(DynLineRing::new(size)),
+        }
+    }
+
+    /// Create a new, empty store keeping the last `max_lines` complete lines, truncated to
+    /// `max_line_len` bytes, rather than as many lines as fit in the buffer size.
+    pub fn last_lines(max_lines: usize, max_line_len: usize) -> History {
+        History::LastLines(LineBuffer::new(max_lines, max_line_len))
+    }
+
+    /// Change the amount of bytes retained, evicting the oldest history which no longer fits. The
+    /// last lines are bounded by their number rather than a size, so they are not resized.
+    pub fn resize(&mut self, capacity: usize) {
+        match self {
+            History::Bytes(store) => store.resize(capacity),
+            History::Lines(store) => store.resize(capacity),
+            History::LastLines(_) => {}
+        }
+    }
+}
+
+impl HistoryStore for History {
+    fn append(&mut self, data: &[u8]) {
+        match self {
+            History::Bytes(store) => store.append(data),
+            History::Lines(store) => store.append(data),
+            History::LastLines(store) => store.append(data),
+        }
+    }
+
+    fn snapshot(&self) -> (&[u8], &[u8]) {
+        match self {
+            History::Bytes(store) => store.snapshot(),
+            History::Lines(store) => store.snapshot(),
+            History::LastLines(store) => store.snapshot(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            History::Bytes(store) => store.len(),
+            History::Lines(store) => store.len(),
+            History::LastLines(store) => store.len(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            History::Bytes(store) => store.capacity(),
+            History::Lines(store) => store.capacity(),
+            History::LastLines(store) => store.capacity(),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            History::Bytes(store) => store.clear(),
+            History::Lines(store) => store.clear(),
+            History::LastLines(store) => store.clear(),
+        }
+    }
+}
@@ -1,112 +1,635 @@
-use std::sync::Arc;
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    fmt, io,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use tokio::{
-    io::{AsyncWrite, AsyncWriteExt},
-    sync::mpsc,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+    time::Instant,
 };
+use tracing::Instrument;
+
+pub use collapse::{CollapseScope, RepeatCollapser};
+pub use newline::{LineEnding, NewlineWriter};
+pub use rate::TokenBucket;
+pub use recording::Recording;
+pub use screen::Screen;
+pub use store::{DynLineRing, DynRingBuffer, HistoryStore, LineBuffer, LineRing, RingBuffer};
+
+use escape::AnsiStripper;
+use logging::log_error;
+use rate::Pacer;
+
+mod collapse;
+pub mod escape;
+pub mod logging;
+mod newline;
+mod rate;
+mod recording;
+mod screen;
+pub mod signature;
+mod store;
 
-const CONNECTION_BUFFER: usize = 1000;
+/// Amount of writes buffered for a remote by default.
+pub const CONNECTION_BUFFER: usize = 1000;
 
-/// An internal console buffer, multiplexing to multiple outputs. The size of the buffer is a
-/// constant parameter.
+/// Sequence sent to remotes when the history is cleared, see [`ConsoleMux::clear`]. It moves the
+/// cursor home, and clears the screen and the scrollback.
+pub const CLEAR_SEQUENCE: &[u8] = b"\x1b[H\x1b[2J\x1b[3J";
+
+/// An internal console buffer, multiplexing to multiple outputs. The history is kept in a
+/// [`HistoryStore`], by default a [`RingBuffer`] of which the size is a constant parameter.
 /// The internal buffer is intentionally extremely dumb. In other words, it won't store a certain
 /// amount of lines, but rather just an amount of data. It is up to the user to guestimate how much
-/// buffer space is needed to keep the required history.
-pub struct ConsoleMux<const H: usize> {
-    data: [u8; H],
-    head: usize,
-    remotes: Vec<mpsc::Sender<Arc<Vec<u8>>>>,
+/// buffer space is needed to keep the required history, or to use a [`LineBuffer`] to keep a
+/// certain amount of lines instead.
+///
+/// # Ordering
+///
+/// Attaching a remote and writing data both need exclusive access to the mux, so a remote is
+/// registered in the same step as the history it receives is taken. Output written after an
+/// `attach_*` function returned is delivered to that remote after the replay, with no gap and no
+/// duplication. Together, a remote receives a contiguous suffix of the output sent to remotes,
+/// starting at the oldest retained history. This no longer holds for a remote once messages are
+/// dropped for it because it lags (see [`Backpressure::Drop`]), and the reconstructed screen
+/// replayed with [`ConsoleMux::enable_screen`] is not part of the output.
+pub struct ConsoleMux<S> {
+    store: S,
+    remotes: Vec<Remote>,
+    /// Id to assign to the next attached remote.
+    next_remote_id: u64,
+    recording: Option<Recording>,
+    /// Trailing incomplete escape sequence, which has been written to the buffer but is held
+    /// back from remotes until it is completed.
+    pending: Vec<u8>,
+    /// Total amount of bytes written to the console.
+    total_written: u64,
+    /// Total amount of bytes appended to the history store.
+    total_stored: u64,
+    /// Incremented whenever the history replayed to new remotes changes.
+    generation: u64,
+    /// Total amount of messages dropped for remotes which were lagging.
+    dropped_messages: u64,
+    /// Model of the current screen, which is sent to new remotes instead of the history, if
+    /// enabled.
+    screen: Option<Screen>,
+    /// Amount of complete lines of history replayed to new remotes, if limited.
+    replay_lines: Option<usize>,
+    /// Holds back output sent to remotes to limit its rate, if enabled.
+    pacer: Option<Pacer>,
+    /// Collapses repeated lines in the output sent to remotes, if enabled.
+    live_collapser: Option<RepeatCollapser>,
+    /// Collapses repeated lines in the recording, if enabled.
+    recording_collapser: Option<RepeatCollapser>,
+    /// Strips escape sequences from the output stored in the history, if enabled.
+    history_stripper: Option<AnsiStripper>,
+    /// Strips escape sequences from the output stored in the recording, if enabled.
+    recording_stripper: Option<AnsiStripper>,
+    /// Maximum time to send the history to a new remote, if limited.
+    attach_timeout: Option<Duration>,
+    /// Maximum time to wait for lagging remotes which can lose data, before dropping their
+    /// output. If `None`, their output is dropped right away.
+    send_wait: Option<Duration>,
 }
 
-impl<const H: usize> ConsoleMux<H> {
+impl<const H: usize> ConsoleMux<RingBuffer<H>> {
     /// Create a new ConsoleMux with no data.
-    // TODO: implement a cleanup loop for dropped receivers, this now only happens when data is
-    // send and can cause a theoretical buildup.
-    pub fn new() -> ConsoleMux<H> {
+    pub fn new() -> ConsoleMux<RingBuffer<H>> {
+        ConsoleMux::with_store(RingBuffer::new())
+    }
+}
+
+/// A [`ConsoleMux`] of which the size of the history is chosen at runtime, and can be changed
+/// while it is running.
+pub type DynConsoleMux = ConsoleMux<DynRingBuffer>;
+
+impl ConsoleMux<DynRingBuffer> {
+    /// Create a new ConsoleMux with no data, retaining up to `capacity` bytes of history.
+    pub fn with_capacity(capacity: usize) -> DynConsoleMux {
+        ConsoleMux::with_store(DynRingBuffer::new(capacity))
+    }
+
+    /// Change the amount of history retained. When shrinking, the most recent history which fits
+    /// is kept. Remotes which are attached are not affected.
+    pub fn resize(&mut self, capacity: usize) {
+        self.store.resize(capacity);
+        self.generation += 1;
+    }
+}
+
+impl<S: HistoryStore> ConsoleMux<S> {
+    /// Create a new ConsoleMux keeping its history in the given store.
+    pub fn with_store(store: S) -> ConsoleMux<S> {
         ConsoleMux {
-            data: [0; H],
-            head: 0,
+            store,
             remotes: Vec::new(),
+            next_remote_id: 0,
+            recording: None,
+            pending: Vec::new(),
+            total_written: 0,
+            total_stored: 0,
+            generation: 0,
+            dropped_messages: 0,
+            screen: None,
+            replay_lines: None,
+            pacer: None,
+            live_collapser: None,
+            recording_collapser: None,
+            history_stripper: None,
+            recording_stripper: None,
+            attach_timeout: None,
+            send_wait: None,
+        }
+    }
+
+    /// The store keeping the history.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Change the store keeping the history, e.g. to resize it. The history replayed to new
+    /// remotes is considered changed, see [`ConsoleMux::generation`].
+    pub fn update_store<R>(&mut self, f: impl FnOnce(&mut S) -> R) -> R {
+        self.generation += 1;
+        f(&mut self.store)
+    }
+
+    /// Capture all data written to the console in a separate [`Recording`], which retains up to
+    /// `max_size` bytes. This is independent of the history buffer, so it can be much larger
+    /// without slowing down the replay to new clients. Enabling the recording again discards the
+    /// existing recording.
+    pub fn enable_recording(&mut self, max_size: usize) {
+        self.recording = Some(Recording::new(max_size));
+    }
+
+    /// The recording of the console output, if it is enabled.
+    pub fn recording(&self) -> Option<&Recording> {
+        self.recording.as_ref()
+    }
+
+    /// Maintain a model of the screen of the given size, see [`Screen`]. New remotes receive a
+    /// reconstruction of the current screen instead of the raw history, so they don't need to
+    /// replay output which might not make sense on its own. The model starts out empty.
+    pub fn enable_screen(&mut self, cols: u16, rows: u16) {
+        self.screen = Some(Screen::new(cols, rows));
+    }
+
+    /// Resize the screen model, if it is enabled.
+    pub fn resize_screen(&mut self, cols: u16, rows: u16) {
+        if let Some(screen) = &mut self.screen {
+            screen.resize(cols, rows);
+        }
+    }
+
+    /// The model of the screen, if it is enabled.
+    pub fn screen(&self) -> Option<&Screen> {
+        self.screen.as_ref()
+    }
+
+    /// Only replay the last `lines` complete lines of the history to new remotes, followed by the
+    /// current incomplete line. If the history holds fewer lines, all complete lines which are
+    /// retained are replayed. This has no effect if the screen model is enabled.
+    pub fn limit_replay(&mut self, lines: usize) {
+        self.replay_lines = Some(lines);
+    }
+
+    /// Give up attaching a remote if the history can't be sent to it within `timeout`, e.g.
+    /// because it is on a congested link, or never reads at all. Such a remote is not attached,
+    /// so it doesn't tie up a task and a queue indefinitely. Passing `None` waits as long as it
+    /// takes, which is the default.
+    pub fn limit_attach(&mut self, timeout: Option<Duration>) {
+        self.attach_timeout = timeout;
+    }
+
+    /// Wait up to `timeout` for remotes which are lagging, rather than dropping their output as
+    /// soon as their channel is full, so remotes which are only slow for a moment don't lose
+    /// output. Output for such a remote is queued until it catches up, or didn't consume any
+    /// output for `timeout`, after which the queued output is dropped. At most as many messages
+    /// are queued as its channel holds. The writer of the console is expected to pause meanwhile,
+    /// by writing with [`ConsoleMux::write_data_async`]. Beware that a single slow remote then
+    /// slows down the output to all remotes. Remotes with [`Backpressure::Block`] are not
+    /// affected. Passing `None` drops output right away, which is the default.
+    pub fn limit_send_wait(&mut self, timeout: Option<Duration>) {
+        self.send_wait = timeout;
+    }
+
+    /// Limit the rate at which output is sent to remotes with the given [`TokenBucket`], e.g. so
+    /// a burst of output doesn't scroll by too fast to read. Output beyond the rate is held back,
+    /// and sent once [`ConsoleMux::pace_output`] is called after the bucket refilled. If more
+    /// output is held back than the history can retain, the oldest output is dropped, and replaced
+    /// by a marker. Passing `None` removes the limit, and sends all output held back right away.
+    pub fn limit_rate(&mut self, bucket: Option<TokenBucket>) {
+        match (bucket, &mut self.pacer) {
+            (Some(bucket), Some(pacer)) => pacer.bucket = bucket,
+            (Some(bucket), None) => self.pacer = Some(Pacer::new(bucket, self.store.capacity())),
+            (None, _) => {
+                if let Some(out) = self.pacer.take().and_then(|mut pacer| pacer.take_all()) {
+                    self.broadcast(&out);
+                }
+            }
+        }
+    }
+
+    /// Send output held back because of the rate limit, as far as the rate allows. This needs to
+    /// be called regularly while the rate is limited.
+    pub fn pace_output(&mut self) {
+        if let Some(out) = self.pacer.as_mut().and_then(|pacer| pacer.take()) {
+            self.broadcast(&out);
+        }
+    }
+
+    /// Collapse runs of at least `threshold` identical lines in the given output, see
+    /// [`RepeatCollapser`]. Held back repeats are written once a different line is written, or
+    /// when [`ConsoleMux::flush_collapsed`] is called.
+    pub fn enable_collapse(&mut self, threshold: usize, scope: CollapseScope) {
+        if scope.live() {
+            self.live_collapser = Some(RepeatCollapser::new(threshold));
+        }
+        if scope.history() {
+            self.recording_collapser = Some(RepeatCollapser::new(threshold));
+        }
+    }
+
+    /// Store the output in the history and the recording as plain text, see [`AnsiStripper`].
+    /// Remotes still receive the output as is, but new remotes receive the plain history. Output
+    /// is stored in the history once it is sent to remotes, so held back output is not part of
+    /// the history yet. The screen model, if enabled, still receives the output as is.
+    pub fn enable_plain_history(&mut self) {
+        self.history_stripper = Some(AnsiStripper::new());
+        self.recording_stripper = Some(AnsiStripper::new());
+    }
+
+    /// Whether output is held back to collapse repeated lines.
+    pub fn has_collapsed(&self) -> bool {
+        let pending = |c: &Option<RepeatCollapser>| c.as_ref().is_some_and(|c| c.has_pending());
+        pending(&self.live_collapser) || pending(&self.recording_collapser)
+    }
+
+    /// Write output which is held back to collapse repeated lines. This should be called once the
+    /// console has been idle for a while, so held back output is not delayed indefinitely.
+    pub fn flush_collapsed(&mut self) {
+        if let Some(collapser) = &mut self.recording_collapser {
+            let mut out = Vec::new();
+            collapser.flush(&mut out);
+            self.record(&out);
+        }
+        if let Some(collapser) = &mut self.live_collapser {
+            let mut out = Vec::new();
+            collapser.flush(&mut out);
+            self.write_live(&out);
+        }
+    }
+
+    /// Discard the retained history, e.g. so remotes attaching later don't see output which is
+    /// sensitive. Remotes which are attached are sent [`CLEAR_SEQUENCE`], so their screen and
+    /// scrollback match what new remotes receive. Output held back because of the rate limit is
+    /// discarded as well. The recording is not affected.
+    pub fn clear(&mut self) {
+        self.store.clear();
+        self.generation += 1;
+        if let Some(screen) = &mut self.screen {
+            screen.reset();
         }
+        self.pending.clear();
+        if let Some(pacer) = &mut self.pacer {
+            pacer.take_all();
+        }
+        self.broadcast(CLEAR_SEQUENCE);
     }
 
-    /// Writes data to the console. The last H bytes of data are retained in the internal buffer
-    /// and will be served to new clients when they connect
+    /// Writes data to the console. The most recent data is retained in the history store and will
+    /// be served to new clients when they connect. If the data ends with an incomplete
+    /// escape sequence, that sequence is only sent to remotes once it is completed by a later
+    /// write.
     pub fn write_data(&mut self, data: &[u8]) {
-        // Only keep the data we can actually write to the buffer.
-        let sized_data = if data.len() > H {
-            &data[data.len() - H..]
-        } else {
+        if data.is_empty() {
+            return;
+        }
+        self.total_written += data.len() as u64;
+
+        if self.recording.is_some() {
+            match &mut self.recording_collapser {
+                Some(collapser) => {
+                    let mut out = Vec::new();
+                    collapser.feed(data, &mut out);
+                    self.record(&out);
+                }
+                None => self.record(data),
+            }
+        }
+
+        match &mut self.live_collapser {
+            Some(collapser) => {
+                let mut out = Vec::new();
+                collapser.feed(data, &mut out);
+                self.write_live(&out);
+            }
+            None => self.write_live(data),
+        }
+    }
+
+    /// Write data to the recording, if it is enabled.
+    fn record(&mut self, data: &[u8]) {
+        if let Some(recording) = &mut self.recording {
+            match &mut self.recording_stripper {
+                Some(stripper) => {
+                    let mut out = Vec::new();
+                    stripper.feed(data, &mut out);
+                    recording.push(&out);
+                }
+                None => recording.push(data),
+            }
+        }
+    }
+
+    /// Write data to the history store and the remotes.
+    fn write_live(&mut self, data: &[u8]) {
+        // Nothing changes, so there is no need to check the remotes either.
+        if data.is_empty() {
+            return;
+        }
+        // Plain history is stored once it is sent, since sequences are stripped as a whole.
+        if self.history_stripper.is_none() {
+            self.append_history(data);
+        }
+        // The parser keeps incomplete escape sequences to itself, so the screen matches the data
+        // sent to remotes.
+        if let Some(screen) = &mut self.screen {
+            screen.feed(data);
+        }
+
+        // Hold back an incomplete escape sequence at the end of the data until it is completed.
+        // Otherwise a remote attaching now receives the start of the sequence as part of the
+        // history, followed by output which doesn't continue it.
+        let joined;
+        let data = if self.pending.is_empty() {
             data
+        } else {
+            joined = [self.pending.as_slice(), data].concat();
+            &joined
         };
+        let (data, pending) = data.split_at(data.len() - escape::incomplete_escape_len(data));
+        self.pending.clear();
+        self.pending.extend_from_slice(pending);
 
-        // Write data to buffer from head -> end of buffer
-        let remainder = H - self.head;
-        let to_write = usize::min(remainder, sized_data.len());
-        self.data[self.head..self.head + to_write].copy_from_slice(&sized_data[..to_write]);
-        // Now write remainder of data to start of buffer -> head
-        if sized_data.len() > remainder {
-            self.data[..sized_data.len() - remainder].copy_from_slice(&sized_data[to_write..]);
+        match &mut self.pacer {
+            Some(pacer) => {
+                pacer.push(data);
+                self.pace_output();
+            }
+            None => self.broadcast(data),
         }
+    }
 
-        // Update head
-        self.head = (self.head + sized_data.len()) % H;
+    /// Append data to the history store.
+    fn append_history(&mut self, data: &[u8]) {
+        self.store.append(data);
+        self.total_stored += data.len() as u64;
+        self.generation += 1;
+    }
 
+    /// Send data to all remotes, and store it in the plain history if enabled.
+    fn broadcast(&mut self, data: &[u8]) {
+        // Held back output which is sent becomes part of the replayed history.
+        if !data.is_empty() {
+            self.generation += 1;
+        }
+        if let Some(stripper) = &mut self.history_stripper {
+            let mut out = Vec::new();
+            stripper.feed(data, &mut out);
+            self.append_history(&out);
+        }
         // Write data to connected endpoints, but check if there are any first. This avoids a heap
         // allocation if it is not needed.
-        if self.remotes.is_empty() {
+        if self.remotes.is_empty() || data.is_empty() {
             return;
         }
 
-        let msg = Arc::new(Vec::from(data));
+        let msg: Arc<[u8]> = Arc::from(data);
+        let wait = self.send_wait;
 
         // Importantly we do a try send here to avoid blocking. If the channel is full, the remote
-        // is lagging and we drop the message. This will likely cause a disconnect and reconnect
-        // later. If the remote is disconnected it means it is gone entirely.
-        self.remotes.retain(|remote| {
-            !matches!(
-                remote.try_send(msg.clone()),
-                Err(mpsc::error::TrySendError::Closed(_))
-            )
-        });
+        // is lagging and we drop the message, unless the remote can't lose data. This will likely
+        // cause a disconnect and reconnect later. If the remote is disconnected it means it is
+        // gone entirely.
+        self.remotes
+            .retain_mut(|remote| remote.send(msg.clone(), wait, &mut self.dropped_messages));
+    }
+
+    /// Wait until all remotes which can't lose data have caught up, see
+    /// [`Backpressure::Block`], as well as lagging remotes which are waited for, see
+    /// [`ConsoleMux::limit_send_wait`]. The mux is only locked while checking the remotes, so
+    /// other remotes are unaffected while waiting. Other remotes which drop data are never waited
+    /// for.
+    pub async fn wait_for_remotes(console: &Mutex<Self>) {
+        loop {
+            let (tx, deadline) = {
+                let mut console = console.lock().await;
+                let console = &mut *console;
+                let wait = console.send_wait;
+                let dropped = &mut console.dropped_messages;
+                console.remotes.retain_mut(|remote| {
+                    let connected = remote.flush();
+                    remote.expire(wait, dropped);
+                    connected
+                });
+                match console.remotes.iter().find(|r| !r.overflow.is_empty()) {
+                    Some(remote) => (remote.tx.clone(), remote.deadline(wait)),
+                    None => return,
+                }
+            };
+            // An error means the remote is gone, which is handled when flushing again. Once the
+            // deadline passed, the output of the remote is dropped when checking again.
+            match deadline {
+                Some(deadline) => {
+                    let _ = tokio::time::timeout_at(deadline, tx.reserve()).await;
+                }
+                None => {
+                    let _ = tx.reserve().await;
+                }
+            }
+        }
+    }
+
+    /// Write data to the console like [`ConsoleMux::write_data`], and wait for the remotes which
+    /// are lagging behind, see [`ConsoleMux::wait_for_remotes`].
+    pub async fn write_data_async(console: &Mutex<Self>, data: &[u8]) {
+        console.lock().await.write_data(data);
+        ConsoleMux::wait_for_remotes(console).await;
     }
 
     /// Attach a new remote, which will receive data every time a write happens on console mux.
     /// The future returned by this function completes as soon as the existing buffer is sent to
-    /// the remote. Errors encountered during writing at any point will not be propagated, instead
-    /// they will simply stop the processing of data. After an error writing to the remote, no new
-    /// data will be written to the remote.
+    /// the remote. If sending the buffer fails or times out (see [`ConsoleMux::limit_attach`]),
+    /// the remote is not attached and the error is returned. Errors encountered while writing
+    /// later output will not be propagated, instead they will simply stop the processing of data.
+    /// After an error writing to the remote, no new data will be written to the remote. The
+    /// returned [`RemoteHandle`] can be used to detach the remote later on, dropping it leaves the
+    /// remote attached.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub async fn attach_remote<R>(&mut self, remote: R) -> io::Result<RemoteHandle>
+    where
+        R: AsyncWrite + Unpin + Send + 'static,
+    {
+        self.attach_remote_with_capacity(remote, CONNECTION_BUFFER)
+            .await
+    }
+
+    /// Attach a new remote like [`ConsoleMux::attach_remote`], buffering up to `capacity` writes
+    /// for the remote instead of [`CONNECTION_BUFFER`]. A larger capacity tolerates the remote
+    /// lagging for longer before output is dropped for it, at the cost of more memory for every
+    /// remote which lags.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub async fn attach_remote_with_capacity<R>(
+        &mut self,
+        remote: R,
+        capacity: usize,
+    ) -> io::Result<RemoteHandle>
+    where
+        R: AsyncWrite + Unpin + Send + 'static,
+    {
+        self.attach_sink(remote, capacity, Backpressure::Drop).await
+    }
+
+    /// Attach a new remote like [`ConsoleMux::attach_remote`], buffering up to `capacity` writes
+    /// for the remote. `backpressure` decides what happens once the buffer is full. The policy
+    /// only applies to this remote, a slow remote never holds up other remotes.
     ///
     /// # Panics
     ///
     /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
-    pub async fn attach_remote<R>(&mut self, mut remote: R)
+    pub async fn attach_sink<R>(
+        &mut self,
+        mut remote: R,
+        capacity: usize,
+        backpressure: Backpressure,
+    ) -> io::Result<RemoteHandle>
     where
         R: AsyncWrite + Unpin + Send + 'static,
     {
-        let (tx, mut rx) = mpsc::channel(CONNECTION_BUFFER);
-        self.remotes.push(tx);
+        let (tx, rx) = mpsc::channel(capacity);
+        let id = self.next_remote_id;
+        let handle = RemoteHandle { id };
+        self.add_remote(tx, backpressure, 0);
 
         // Write the contents of the existing buffer
-        if let Err(e) = remote.write_all(&self.data[self.head..]).await {
-            eprintln!("Error writing first half of data buffer to remote {}", e);
-            return;
+        let (first, second) = self.replay(usize::MAX);
+        let replayed = (first.len() + second.len()) as u64;
+        let replay = async {
+            remote.write_all(&first).await?;
+            remote.write_all(&second).await
+        };
+        let result = within(self.attach_timeout, replay)
+            .await
+            .unwrap_or_else(|| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out writing data buffer to remote",
+                ))
+            });
+        if let Err(e) = result {
+            self.detach(id);
+            return Err(e);
         }
-        if let Err(e) = remote.write_all(&self.data[..self.head]).await {
-            eprintln!("Error writing second half of data buffer to remote {}", e);
-            return;
+        if let Some(remote) = self.remotes.last_mut() {
+            remote.bytes_sent += replayed;
+        }
+
+        self.forward(remote, rx);
+        Ok(handle)
+    }
+
+    /// Attach a new remote like [`ConsoleMux::attach_remote`], without sending it the history
+    /// first. The remote only receives output written after it is attached, e.g. for a sink
+    /// forwarding the output to a log which would duplicate entries on a replay.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub fn attach_remote_live<R>(&mut self, remote: R) -> RemoteHandle
+    where
+        R: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(CONNECTION_BUFFER);
+        let handle = RemoteHandle {
+            id: self.next_remote_id,
+        };
+        self.add_remote(tx, Backpressure::Drop, 0);
+        self.forward(remote, rx);
+        handle
+    }
+
+    /// Attach a new remote like [`ConsoleMux::attach_remote`], queueing the history for it rather
+    /// than writing it before returning. The history is written by the forwarding task like later
+    /// output, so a remote which is slow to receive it, e.g. an untrusted network connection,
+    /// never holds up the console. A remote which fails to receive the history is detached once
+    /// the next output is sent.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub fn attach_remote_queued<R>(&mut self, remote: R) -> RemoteHandle
+    where
+        R: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(CONNECTION_BUFFER);
+        let (first, second) = self.replay(usize::MAX);
+        let replayed = (first.len() + second.len()) as u64;
+        for part in [first, second] {
+            // The channel is new and holds more than the two parts, so they always fit.
+            if !part.is_empty() {
+                let _ = tx.try_send(Arc::from(part.as_ref()));
+            }
         }
+        let handle = RemoteHandle {
+            id: self.next_remote_id,
+        };
+        self.add_remote(tx, Backpressure::Drop, replayed);
+        self.forward(remote, rx);
+        handle
+    }
 
-        // Spawn data forwarding loop.
-        tokio::spawn(async move {
+    /// Spawn the loop writing the data sent to the last added remote to `remote`. Errors are logged
+    /// in the span the remote was attached in, e.g. the span of its client.
+    fn forward<R>(&mut self, mut remote: R, mut rx: mpsc::Receiver<Arc<[u8]>>)
+    where
+        R: AsyncWrite + Unpin + Send + 'static,
+    {
+        let forward = async move {
             while let Some(data) = rx.recv().await {
                 // If we encounter an error writing to the remote, treat it as fatal. Also, use
                 // write_all as a convenience here.
                 if let Err(e) = remote.write_all(&data).await {
-                    eprintln!("Error writing to remote {}", e);
-                    break;
+                    log_error(
+                        "remote write",
+                        format_args!("Error writing to remote {}", e),
+                    );
+                    return;
                 }
             }
-        });
+            // The channel is closed, so no more output follows.
+            if let Err(e) = remote.shutdown().await {
+                log_error(
+                    "remote write",
+                    format_args!("Error shutting down remote {}", e),
+                );
+            }
+        };
+        let task = tokio::spawn(forward.instrument(tracing::Span::current()));
+        if let Some(remote) = self.remotes.last_mut() {
+            remote.task = Some(task);
+        }
     }
 
     /// Attach a new channel sender to the console, which will be used to notify the receiver of
@@ -120,82 +643,1778 @@ impl<const H: usize> ConsoleMux<H> {
     /// # Panics
     ///
     /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
-    pub async fn attach_channel(&mut self, tx: mpsc::Sender<Arc<Vec<u8>>>) {
+    pub async fn attach_channel(&mut self, tx: mpsc::Sender<Arc<[u8]>>) {
+        self.attach_channel_limited(tx, usize::MAX).await;
+    }
+
+    /// Attach a new channel sender like [`ConsoleMux::attach_channel`], without sending the
+    /// history first. The receiver is only notified of data written after the sender is attached.
+    pub fn attach_channel_live(&mut self, tx: mpsc::Sender<Arc<[u8]>>) {
+        self.add_remote(tx, Backpressure::Drop, 0);
+    }
+
+    /// Attach a new channel sender like [`ConsoleMux::attach_channel`], replaying at most the
+    /// last `max_replay` bytes of the history. A history which is cut starts at a line. The
+    /// reconstructed screen is always replayed entirely. Returns the amount of bytes replayed.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub async fn attach_channel_limited(
+        &mut self,
+        tx: mpsc::Sender<Arc<[u8]>>,
+        max_replay: usize,
+    ) -> usize {
         // Write the contents of the existing buffer
-        if let Err(e) = tx.send(Arc::new(Vec::from(&self.data[self.head..]))).await {
-            eprintln!("Error writing first half of data buffer to channel {}", e);
-            return;
+        let (first, second) = self.replay(max_replay);
+        let replayed = first.len() + second.len();
+        let replay = async {
+            if let Err(e) = tx.send(Arc::from(first)).await {
+                log_error(
+                    "channel replay",
+                    format_args!("Error writing first half of data buffer to channel {}", e),
+                );
+                return false;
+            }
+            if let Err(e) = tx.send(Arc::from(second)).await {
+                log_error(
+                    "channel replay",
+                    format_args!("Error writing second half of data buffer to channel {}", e),
+                );
+                return false;
+            }
+            true
+        };
+        match within(self.attach_timeout, replay).await {
+            Some(true) => {}
+            Some(false) => return 0,
+            None => {
+                log_error("channel replay", "Timed out writing data buffer to channel");
+                return 0;
+            }
         }
-        if let Err(e) = tx.send(Arc::new(Vec::from(&self.data[..self.head]))).await {
-            eprintln!("Error writing second half of data buffer to channel {}", e);
-            return;
+
+        self.add_remote(tx, Backpressure::Drop, replayed as u64);
+        replayed
+    }
+
+    /// Attach a new channel sender like [`ConsoleMux::attach_channel_limited`], sending the
+    /// newest part of the replay first, so a client can show the latest output right away. The
+    /// replay is sent as exactly two messages: first the last `tail` bytes, starting at a line,
+    /// then the older part, which can be empty. The older part followed by the tail forms the
+    /// regular replay. The reconstructed screen is always sent as tail. Returns the amount of
+    /// bytes replayed.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub async fn attach_channel_tail_first(
+        &mut self,
+        tx: mpsc::Sender<Arc<[u8]>>,
+        max_replay: usize,
+        tail: usize,
+    ) -> usize {
+        let (first, second) = self.replay(max_replay);
+        let replay = [first.as_ref(), second.as_ref()].concat();
+        let start = match &self.screen {
+            Some(_) => 0,
+            None => line_start(&replay, replay.len().saturating_sub(tail)),
+        };
+        let replayed = replay.len();
+        let older = replay[..start].to_vec();
+        let mut newest = replay;
+        newest.drain(..start);
+        let replay = async {
+            if let Err(e) = tx.send(Arc::from(newest)).await {
+                log_error(
+                    "channel replay",
+                    format_args!("Error writing newest history to channel {}", e),
+                );
+                return false;
+            }
+            if let Err(e) = tx.send(Arc::from(older)).await {
+                log_error(
+                    "channel replay",
+                    format_args!("Error writing older history to channel {}", e),
+                );
+                return false;
+            }
+            true
+        };
+        match within(self.attach_timeout, replay).await {
+            Some(true) => {}
+            Some(false) => return 0,
+            None => {
+                log_error("channel replay", "Timed out writing history to channel");
+                return 0;
+            }
         }
 
-        self.remotes.push(tx);
+        self.add_remote(tx, Backpressure::Drop, replayed as u64);
+        replayed
     }
-}
 
-impl<const H: usize> Default for ConsoleMux<H> {
-    fn default() -> Self {
-        Self::new()
+    /// Serve the console over a bidirectional stream, e.g. a channel of an SSH server or a custom
+    /// tunnel which already handles authentication. The stream receives the history and all
+    /// output like a remote attached with [`ConsoleMux::attach_remote_queued`], and everything
+    /// read from the stream is sent as input on `input`. Completes once the stream reached end of
+    /// file or failed to read, after which it no longer receives output.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub async fn serve_stream<T>(console: &Mutex<Self>, stream: T, input: mpsc::Sender<Vec<u8>>)
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, writer) = tokio::io::split(stream);
+        let handle = console.lock().await.attach_remote_queued(writer);
+
+        let mut buf = vec![0; 4096];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if input.send(buf[..n].to_vec()).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    log_error("stream", format_args!("Error reading from stream {}", e));
+                    break;
+                }
+            }
+        }
+        handle.detach(&mut *console.lock().await);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Detach the remote with the given id, if it is still attached.
+    fn detach(&mut self, id: u64) {
+        self.remotes.retain(|remote| {
+            if remote.id != id {
+                return true;
+            }
+            if let Some(task) = &remote.task {
+                task.abort();
+            }
+            false
+        });
+    }
 
-    #[test]
-    fn test_mux_write_no_rotation() {
-        let mut cm = ConsoleMux::<200>::new();
+    /// Detach all remotes once the output buffered for them is delivered, e.g. before the process
+    /// exits. Output held back to collapse repeated lines is written first. The channel of every
+    /// remote is closed after the output queued on it, so its receiver sees the end of the output,
+    /// and this waits until the forwarding tasks of remotes attached with
+    /// [`ConsoleMux::attach_remote`] wrote all output and shut down the remote, e.g. so a
+    /// compressed stream is finished. A remote which doesn't keep up delays the shutdown, so the
+    /// caller might want to limit the time it takes.
+    pub async fn shutdown(&mut self) {
+        self.flush_collapsed();
+        let mut tasks = Vec::new();
+        for mut remote in std::mem::take(&mut self.remotes) {
+            // Output which did not fit in the channel of a remote which can't lose data.
+            for msg in remote.overflow.drain(..) {
+                if remote.tx.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            tasks.extend(remote.task);
+        }
+        // The channels are closed now, so the tasks stop once they forwarded everything.
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
 
-        let data = vec![1; 150];
-        cm.write_data(&data);
+    /// Remove all remotes which are gone, because the receiver of their channel was dropped or
+    /// their forwarding task stopped. This otherwise only happens when output is written, so this
+    /// should be called regularly to clean up after remotes of a console which is idle. Returns
+    /// the amount of remotes removed.
+    pub fn prune_closed(&mut self) -> usize {
+        let before = self.remotes.len();
+        self.remotes.retain(|remote| !remote.tx.is_closed());
+        before - self.remotes.len()
+    }
 
-        assert_eq!(cm.head, 150);
-        assert_eq!(cm.data[149], 1);
-        assert_eq!(cm.data[150], 0);
+    /// The amount of attached remotes, including remotes which are gone but not yet removed, see
+    /// [`ConsoleMux::prune_closed`].
+    pub fn remote_count(&self) -> usize {
+        self.remotes.len()
     }
 
-    #[test]
-    fn test_mux_write_with_rotation() {
-        let mut cm = ConsoleMux::<200>::new();
+    /// The fill level of the queue of every attached remote, which shows how close a remote is to
+    /// having messages dropped because it is lagging.
+    pub fn queue_fill(&self) -> Vec<QueueFill> {
+        self.remotes
+            .iter()
+            .filter(|remote| !remote.tx.is_closed())
+            .map(|remote| QueueFill {
+                remote: remote.id,
+                queued: remote.tx.max_capacity() - remote.tx.capacity(),
+                capacity: remote.tx.max_capacity(),
+            })
+            .collect()
+    }
 
-        let data = vec![1; 150];
-        cm.write_data(&data);
+    /// The attached remotes, in the order they were attached, e.g. to show who is connected.
+    /// Remotes which are gone are left out.
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.remotes
+            .iter()
+            .filter(|remote| !remote.tx.is_closed())
+            .map(|remote| ClientInfo {
+                id: remote.id,
+                attached: remote.attached,
+                bytes_sent: remote.bytes_sent,
+            })
+            .collect()
+    }
 
-        let data = vec![2; 90];
-        cm.write_data(&data);
+    /// Detach remotes which have data queued, but did not consume any of it for at least
+    /// `max_stall`. The forwarding task of a remote attached with [`ConsoleMux::attach_remote`]
+    /// is aborted, a channel is closed. This is a safety net for remotes which hang
+    /// indefinitely. Returns the ids of the detached remotes.
+    pub fn detach_stuck(&mut self, max_stall: Duration) -> Vec<u64> {
+        let now = Instant::now();
+        let mut stuck = Vec::new();
+        self.remotes.retain_mut(|remote| {
+            if !remote.is_stuck(now, max_stall) {
+                return true;
+            }
+            if let Some(task) = &remote.task {
+                task.abort();
+            }
+            stuck.push(remote.id);
+            false
+        });
+        stuck
+    }
 
-        assert_eq!(cm.head, 40);
-        assert_eq!(cm.data[39], 2);
-        assert_eq!(cm.data[40], 1);
-        assert_eq!(cm.data[149], 1);
-        assert_eq!(cm.data[150], 2);
+    /// The total amount of bytes written to the console since it was created, including data
+    /// which is no longer retained in the buffer.
+    pub fn total_written(&self) -> u64 {
+        self.total_written
     }
 
-    #[test]
-    fn test_mux_write_large_buffer_no_rotation() {
-        let mut cm = ConsoleMux::<100>::new();
+    /// A counter which changes whenever the history replayed to new remotes changes, because
+    /// output was written or released, or the history was cleared or resized. Unlike
+    /// [`ConsoleMux::total_written`], it can be used to tell if a [`ConsoleMux::snapshot`] is
+    /// still current.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
 
-        let data = vec![1; 150];
-        cm.write_data(&data);
+    /// The amount of history retained, in bytes. This does not count the padding of a buffer
+    /// which is not yet filled, so it stays below the capacity of the store until it wraps.
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
 
-        assert_eq!(cm.head, 0);
-        assert_eq!(&cm.data, vec![1; 100].as_slice());
+    /// Whether no history is retained, because nothing was written yet or the history was cleared.
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
     }
 
-    #[test]
-    fn test_mux_write_large_buffer_with_rotation() {
-        let mut cm = ConsoleMux::<100>::new();
+    /// The total amount of messages dropped for remotes because their queue was full, see
+    /// [`Backpressure::Drop`].
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped_messages
+    }
 
-        let data = vec![1; 50];
-        cm.write_data(&data);
+    /// The position in the output sent to remotes, which increases with every byte sent. Unlike
+    /// [`ConsoleMux::total_written`], this only counts the output after collapsing repeated lines,
+    /// and excludes output which is held back.
+    pub fn position(&self) -> u64 {
+        self.total_stored - self.held_back() as u64
+    }
 
-        let data = vec![2; 125];
-        cm.write_data(&data);
+    /// Copy the output sent to remotes since `position`, as returned by
+    /// [`ConsoleMux::position`]. The output is bounded by the history, the second element is set if
+    /// part of the output since `position` is no longer retained.
+    pub fn output_since(&self, position: u64) -> (Vec<u8>, bool) {
+        let (first, second) = self.history();
+        let retained = (first.len() + second.len()) as u64;
+        let wanted = self.position().saturating_sub(position);
+        let (first, second) = split_from(first, second, retained.saturating_sub(wanted) as usize);
+        ([first, second].concat(), wanted > retained)
+    }
+
+    /// Copy the retained history, oldest data first. This is the same data as a new remote
+    /// receives when attaching, without the padding of a buffer which is not yet filled.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let (first, second) = self.history();
+        [first, second].concat()
+    }
+
+    /// Add a remote, to which `replayed` bytes of history were sent already.
+    fn add_remote(
+        &mut self,
+        tx: mpsc::Sender<Arc<[u8]>>,
+        backpressure: Backpressure,
+        replayed: u64,
+    ) {
+        // The history might already be queued.
+        let queued = (tx.max_capacity() - tx.capacity()) as u64;
+        self.remotes.push(Remote {
+            id: self.next_remote_id,
+            attached: SystemTime::now(),
+            bytes_sent: replayed,
+            tx,
+            backpressure,
+            overflow: VecDeque::new(),
+            lagging_since: None,
+            sent: queued,
+            consumed: 0,
+            last_progress: Instant::now(),
+            task: None,
+        });
+        self.next_remote_id += 1;
+    }
+
+    /// The data to send to a new remote before it receives new data, as two parts which need to be
+    /// sent in order. This is either the reconstructed screen if the screen model is enabled, or
+    /// the history, of which at most the last `max` bytes are replayed.
+    fn replay(&self, max: usize) -> (Cow<'_, [u8]>, Cow<'_, [u8]>) {
+        match &self.screen {
+            Some(screen) => (Cow::Owned(screen.render()), Cow::Borrowed(&[])),
+            None => {
+                let (first, second) = match self.replay_lines {
+                    Some(lines) => self.history_lines(lines),
+                    None => self.history(),
+                };
+                let (first, second) = match first.len() + second.len() > max {
+                    true => self.history_tail(first, second, max),
+                    false => (first, second),
+                };
+                (Cow::Borrowed(first), Cow::Borrowed(second))
+            }
+        }
+    }
+
+    /// Cap the history to the last `max` bytes. If the history is cut, it starts
+    /// after the first newline in the remaining part, if any, so it doesn't start halfway a line.
+    fn history_tail<'a>(
+        &self,
+        first: &'a [u8],
+        second: &'a [u8],
+        max: usize,
+    ) -> (&'a [u8], &'a [u8]) {
+        let total = first.len() + second.len();
+        if total <= max {
+            return (first, second);
+        }
+        // Include the byte before the cut, to know if the cut is at the start of a line.
+        let (first, second) = split_from(first, second, total - max - 1);
+        let start = (0..max)
+            .find(|&i| byte_at(first, second, i) == b'\n')
+            .map_or(1, |i| i + 1);
+        split_from(first, second, start)
+    }
+
+    /// The last `lines` complete lines of the history and the current incomplete line, see
+    /// [`ConsoleMux::limit_replay`].
+    fn history_lines(&self, lines: usize) -> (&[u8], &[u8]) {
+        let (first, second) = self.history();
+
+        let total = first.len() + second.len();
+        let is_newline = |&i: &usize| byte_at(first, second, i) == b'\n';
+        let start = match (0..total).rev().filter(is_newline).nth(lines) {
+            Some(i) => i + 1,
+            // The oldest data might have been overwritten, so the first line can be incomplete.
+            None if self.store.len() == self.store.capacity() => {
+                (0..total).find(is_newline).map_or(total, |i| i + 1)
+            }
+            None => 0,
+        };
+        split_from(first, second, start)
+    }
+
+    /// The amount of data at the end of the history which has not been sent to remotes yet: a
+    /// pending incomplete escape sequence, and output held back because of the rate limit. Plain
+    /// history is only stored once sent, so nothing is held back in it.
+    fn held_back(&self) -> usize {
+        if self.history_stripper.is_some() {
+            return 0;
+        }
+        self.pending.len() + self.pacer.as_ref().map_or(0, |pacer| pacer.len())
+    }
+
+    /// The history to send to a new remote, as two slices which need to be sent in order. This
+    /// excludes the padding of a store which is not yet filled, so a new remote only receives
+    /// output which was actually written. It also excludes a pending incomplete escape sequence
+    /// and output held back because of the rate limit, which the remote will receive once they
+    /// are sent.
+    fn history(&self) -> (&[u8], &[u8]) {
+        let (first, second) = self.store.snapshot();
+        let padding = (first.len() + second.len()).saturating_sub(self.store.len());
+        let (first, second) = split_from(first, second, padding);
+        let pending = usize::min(self.held_back(), first.len() + second.len());
+        if pending <= second.len() {
+            (first, &second[..second.len() - pending])
+        } else {
+            (&first[..first.len() - (pending - second.len())], &[])
+        }
+    }
+}
+
+/// Run `fut` to completion, unless it takes longer than `timeout`, in which case `None` is
+/// returned.
+async fn within<F: std::future::Future>(timeout: Option<Duration>, fut: F) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+/// The byte at position `i` of the concatenation of `first` and `second`.
+fn byte_at(first: &[u8], second: &[u8], i: usize) -> u8 {
+    match i.checked_sub(first.len()) {
+        Some(i) => second[i],
+        None => first[i],
+    }
+}
+
+/// The start of the first line in `data` which starts at or after `from`, or `from` if there is
+/// none.
+fn line_start(data: &[u8], from: usize) -> usize {
+    if from == 0 {
+        return 0;
+    }
+    data[from - 1..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(from, |i| from + i)
+}
+
+/// Skip the first `start` bytes of the concatenation of `first` and `second`.
+fn split_from<'a>(first: &'a [u8], second: &'a [u8], start: usize) -> (&'a [u8], &'a [u8]) {
+    match start.checked_sub(first.len()) {
+        Some(start) => (&[], &second[start..]),
+        None => (&first[start..], second),
+    }
+}
+
+/// What happens with data for a remote which is lagging, and has a full buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Drop the data for this remote.
+    Drop,
+    /// Keep the data in memory, until the remote caught up. The writer of the console is expected
+    /// to pause, by waiting for [`ConsoleMux::wait_for_remotes`] after every write.
+    Block,
+}
+
+impl FromStr for Backpressure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Backpressure::Drop),
+            "block" => Ok(Backpressure::Block),
+            _ => Err(format!(
+                "unknown backpressure {}, expected drop or block",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backpressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backpressure::Drop => "drop",
+            Backpressure::Block => "block",
+        })
+    }
+}
+
+/// A remote attached to a [`ConsoleMux`].
+struct Remote {
+    /// Unique id of the remote within the mux.
+    id: u64,
+    /// When the remote was attached.
+    attached: SystemTime,
+    /// Amount of bytes sent to the remote, including the replay.
+    bytes_sent: u64,
+    tx: mpsc::Sender<Arc<[u8]>>,
+    backpressure: Backpressure,
+    /// Messages which did not fit in the channel of a remote which can't lose data, or which is
+    /// waited for.
+    overflow: VecDeque<Arc<[u8]>>,
+    /// When a remote which can lose data, but is waited for, last made progress while it had
+    /// overflow.
+    lagging_since: Option<Instant>,
+    /// Amount of messages sent on the channel.
+    sent: u64,
+    /// Amount of messages consumed from the channel, when last checked.
+    consumed: u64,
+    /// Last time the remote was seen consuming messages, or had nothing to consume.
+    last_progress: Instant,
+    /// The forwarding task, if the mux spawned it.
+    task: Option<JoinHandle<()>>,
+}
+
+impl Remote {
+    /// Send a message to the remote, without blocking. Returns false if the remote is gone. If the
+    /// message is dropped because the remote is lagging, `dropped` is incremented. If the remote
+    /// can lose data, but `wait` is set, the message is queued for up to `wait`, see
+    /// [`ConsoleMux::limit_send_wait`].
+    fn send(&mut self, msg: Arc<[u8]>, wait: Option<Duration>, dropped: &mut u64) -> bool {
+        if !self.flush() {
+            return false;
+        }
+        self.expire(wait, dropped);
+        // Keep messages in order behind earlier overflow.
+        if !self.overflow.is_empty() {
+            match self.backpressure {
+                Backpressure::Drop if self.overflow.len() >= self.tx.max_capacity() => {
+                    *dropped += 1
+                }
+                _ => self.overflow.push_back(msg),
+            }
+            return true;
+        }
+        let len = msg.len() as u64;
+        match self.tx.try_send(msg) {
+            Ok(()) => {
+                self.sent += 1;
+                self.bytes_sent += len;
+                true
+            }
+            Err(mpsc::error::TrySendError::Full(msg)) => {
+                match (self.backpressure, wait) {
+                    (Backpressure::Block, _) => self.overflow.push_back(msg),
+                    (Backpressure::Drop, Some(_)) => {
+                        self.overflow.push_back(msg);
+                        self.lagging_since = Some(Instant::now());
+                    }
+                    (Backpressure::Drop, None) => *dropped += 1,
+                }
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// When the overflow of a remote which can lose data is dropped, if it doesn't make progress.
+    fn deadline(&self, wait: Option<Duration>) -> Option<Instant> {
+        match self.backpressure {
+            Backpressure::Block => None,
+            // Without a wait, overflow left from before the wait was disabled is still delivered.
+            Backpressure::Drop => Some(self.lagging_since? + wait?),
+        }
+    }
+
+    /// Drop the overflow of a remote which can lose data, once it didn't make progress for
+    /// `wait`, adding the amount of messages dropped to `dropped`.
+    fn expire(&mut self, wait: Option<Duration>, dropped: &mut u64) {
+        if self.overflow.is_empty() {
+            self.lagging_since = None;
+            return;
+        }
+        if self
+            .deadline(wait)
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            *dropped += self.overflow.len() as u64;
+            self.overflow.clear();
+            self.lagging_since = None;
+        }
+    }
+
+    /// Check if the remote has pending messages, and did not consume any for at least
+    /// `max_stall`.
+    fn is_stuck(&mut self, now: Instant, max_stall: Duration) -> bool {
+        let queued = (self.tx.max_capacity() - self.tx.capacity()) as u64;
+        let consumed = self.sent - queued;
+        if consumed != self.consumed || (queued == 0 && self.overflow.is_empty()) {
+            self.consumed = consumed;
+            self.last_progress = now;
+            return false;
+        }
+        now.duration_since(self.last_progress) >= max_stall
+    }
+
+    /// Move as much overflow as possible to the channel. Returns false if the remote is gone.
+    fn flush(&mut self) -> bool {
+        while let Some(msg) = self.overflow.pop_front() {
+            let len = msg.len() as u64;
+            match self.tx.try_send(msg) {
+                Ok(()) => {
+                    self.sent += 1;
+                    self.bytes_sent += len;
+                    // The remote is making progress, so keep waiting for it.
+                    if self.lagging_since.is_some() {
+                        self.lagging_since = Some(Instant::now());
+                    }
+                }
+                Err(mpsc::error::TrySendError::Full(msg)) => {
+                    self.overflow.push_front(msg);
+                    return true;
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Handle to a remote attached with [`ConsoleMux::attach_remote`], to detach it explicitly, e.g.
+/// when the access of a client is revoked. Dropping the handle leaves the remote attached.
+#[derive(Debug)]
+pub struct RemoteHandle {
+    id: u64,
+}
+
+impl RemoteHandle {
+    /// Id of the remote, unique within the [`ConsoleMux`], as used in [`QueueFill`].
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Detach the remote from the `console` it was attached to. Its forwarding task is aborted,
+    /// so it doesn't receive any further output, not even output which is already queued. Does
+    /// nothing if the remote is already gone.
+    pub fn detach<S: HistoryStore>(self, console: &mut ConsoleMux<S>) {
+        console.detach(self.id);
+    }
+}
+
+/// The fill level of the queue of messages waiting to be delivered to a remote. Once the queue is
+/// full, new messages for the remote are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFill {
+    /// Id of the remote, unique within the [`ConsoleMux`].
+    pub remote: u64,
+    /// Amount of queued messages.
+    pub queued: usize,
+    /// Maximum amount of messages which can be queued.
+    pub capacity: usize,
+}
+
+/// A remote attached to a [`ConsoleMux`], as listed by [`ConsoleMux::clients`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// Id of the remote, unique within the [`ConsoleMux`], as returned by [`RemoteHandle::id`].
+    pub id: u64,
+    /// When the remote was attached.
+    pub attached: SystemTime,
+    /// Amount of bytes of output handed to the remote, including the replay of the history.
+    /// Output dropped because the remote was lagging is not counted.
+    pub bytes_sent: u64,
+}
+
+impl<const H: usize> Default for ConsoleMux<RingBuffer<H>> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reports the size of the history and the amount of remotes, not the history itself, which can be
+/// large and contain secrets typed on the console.
+impl<S: HistoryStore> fmt::Debug for ConsoleMux<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConsoleMux")
+            .field("len", &self.store.len())
+            .field("capacity", &self.store.capacity())
+            .field("remotes", &self.remotes.len())
+            .field("total_written", &self.total_written)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io,
+        pin::Pin,
+        sync::atomic::{AtomicBool, Ordering},
+        task::{Context, Poll},
+    };
+
+    #[test]
+    fn test_mux_write_no_rotation() {
+        let mut cm = ConsoleMux::<RingBuffer<200>>::new();
+
+        let data = vec![1; 150];
+        cm.write_data(&data);
+
+        assert_eq!(cm.store.head, 150);
+        assert_eq!(cm.store.data[149], 1);
+        assert_eq!(cm.store.data[150], 0);
+    }
+
+    #[test]
+    fn test_mux_write_with_rotation() {
+        let mut cm = ConsoleMux::<RingBuffer<200>>::new();
+
+        let data = vec![1; 150];
+        cm.write_data(&data);
+
+        let data = vec![2; 90];
+        cm.write_data(&data);
+
+        assert_eq!(cm.store.head, 40);
+        assert_eq!(cm.store.data[39], 2);
+        assert_eq!(cm.store.data[40], 1);
+        assert_eq!(cm.store.data[149], 1);
+        assert_eq!(cm.store.data[150], 2);
+    }
+
+    #[test]
+    fn test_mux_write_large_buffer_no_rotation() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+
+        let data = vec![1; 150];
+        cm.write_data(&data);
+
+        assert_eq!(cm.store.head, 0);
+        assert_eq!(&cm.store.data, vec![1; 100].as_slice());
+    }
+
+    #[test]
+    fn test_mux_write_large_buffer_with_rotation() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+
+        let data = vec![1; 50];
+        cm.write_data(&data);
+
+        let data = vec![2; 125];
+        cm.write_data(&data);
+
+        assert_eq!(cm.store.head, 50);
+        assert_eq!(&cm.store.data, vec![2; 100].as_slice());
+    }
+
+    #[test]
+    fn test_mux_snapshot_no_rotation() {
+        let mut cm = ConsoleMux::<RingBuffer<200>>::new();
+        assert!(cm.snapshot().is_empty());
+
+        cm.write_data(&[1; 150]);
+
+        // The padding of the part which is not written yet is not included.
+        assert_eq!(cm.snapshot(), vec![1; 150]);
+    }
+
+    #[test]
+    fn test_mux_snapshot_exactly_full() {
+        let mut cm = ConsoleMux::<RingBuffer<200>>::new();
+
+        cm.write_data(&[1; 150]);
+        cm.write_data(&[2; 50]);
+
+        assert_eq!(cm.store.head, 0);
+        assert_eq!(cm.snapshot(), [vec![1; 150], vec![2; 50]].concat());
+    }
+
+    #[test]
+    fn test_mux_snapshot_with_rotation() {
+        let mut cm = ConsoleMux::<RingBuffer<200>>::new();
+
+        cm.write_data(&[1; 150]);
+        cm.write_data(&[2; 90]);
+
+        // Oldest data first, from the head to the end, then from the start to the head.
+        assert_eq!(cm.snapshot(), [vec![1; 110], vec![2; 90]].concat());
+
+        cm.write_data(&[3; 250]);
+        assert_eq!(cm.snapshot(), vec![3; 200]);
+    }
+
+    #[tokio::test]
+    async fn test_mux_attach_empty() {
+        let mut cm = ConsoleMux::<RingBuffer<200>>::new();
+        let (remote, mut remote_rx) = tokio::io::duplex(1024);
+        cm.attach_remote(remote).await.unwrap();
+        assert!(replayed(&mut cm).await.is_empty());
+
+        // The remote only receives the new output, no padding.
+        cm.write_data(b"output");
+        let mut buf = vec![0; 6];
+        remote_rx.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"output");
+    }
+
+    #[tokio::test]
+    async fn test_mux_attach_partially_filled() {
+        let mut cm = ConsoleMux::<RingBuffer<200>>::new();
+        cm.write_data(&[1; 150]);
+        assert_eq!(replayed(&mut cm).await, vec![1; 150]);
+
+        let (remote, mut remote_rx) = tokio::io::duplex(1024);
+        cm.attach_remote(remote).await.unwrap();
+        cm.write_data(b"!");
+        let mut buf = vec![0; 151];
+        remote_rx.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [vec![1; 150], b"!".to_vec()].concat());
+    }
+
+    #[tokio::test]
+    async fn test_mux_replay_holds_back_incomplete_escape() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        let (tx, mut early) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+
+        cm.write_data(b"hello \x1b[3");
+        let (tx, mut late) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        cm.write_data(b"1mred");
+
+        let mut early_stream = Vec::new();
+        while let Ok(data) = early.try_recv() {
+            early_stream.extend_from_slice(&data);
+        }
+        let mut late_stream = Vec::new();
+        while let Ok(data) = late.try_recv() {
+            late_stream.extend_from_slice(&data);
+        }
+        assert!(early_stream.ends_with(b"hello \x1b[31mred"));
+        assert!(late_stream.ends_with(b"hello \x1b[31mred"));
+        assert_eq!(cm.pending, b"");
+    }
+
+    /// A store keeping the history in a plain vector, without padding.
+    struct VecStore {
+        data: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl HistoryStore for VecStore {
+        fn append(&mut self, data: &[u8]) {
+            self.data.extend_from_slice(data);
+            let excess = self.data.len().saturating_sub(self.capacity);
+            self.data.drain(..excess);
+        }
+
+        fn snapshot(&self) -> (&[u8], &[u8]) {
+            (&self.data, &[])
+        }
+
+        fn len(&self) -> usize {
+            self.data.len()
+        }
+
+        fn capacity(&self) -> usize {
+            self.capacity
+        }
+
+        fn clear(&mut self) {
+            self.data.clear();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mux_custom_store() {
+        let mut cm = ConsoleMux::with_store(VecStore {
+            data: Vec::new(),
+            capacity: 10,
+        });
+        cm.write_data(b"some history");
+        cm.write_data(b" and \x1b[3");
+        assert_eq!(cm.snapshot(), b"ry and ");
+
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        cm.write_data(b"1mred");
+        let mut stream = Vec::new();
+        while let Ok(data) = rx.try_recv() {
+            stream.extend_from_slice(&data);
+        }
+        assert_eq!(stream, b"ry and \x1b[31mred");
+        assert_eq!(cm.snapshot(), b"d \x1b[31mred");
+    }
+
+    #[tokio::test]
+    async fn test_mux_detach_handle() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        let (kicked, mut kicked_rx) = tokio::io::duplex(1024);
+        let handle = cm.attach_remote(kicked).await.unwrap();
+        let (kept, mut kept_rx) = tokio::io::duplex(1024);
+        // Dropping the handle leaves the remote attached.
+        cm.attach_remote(kept).await.unwrap();
+        assert_eq!(handle.id(), 0);
+
+        handle.detach(&mut cm);
+        assert_eq!(cm.remote_count(), 1);
+        cm.write_data(b"output");
+        let mut buf = vec![0; 6];
+        kept_rx.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"output");
+        // The forwarding task is gone, so the remote is closed without receiving the output.
+        let mut rest = Vec::new();
+        kicked_rx.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mux_remote_capacity() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        let (small, mut small_rx) = tokio::io::duplex(1024);
+        cm.attach_remote_with_capacity(small, 1).await.unwrap();
+        let (large, mut large_rx) = tokio::io::duplex(1024);
+        cm.attach_remote_with_capacity(large, 100).await.unwrap();
+
+        // The forwarding tasks don't run in between, so the writes queue up.
+        cm.write_data(b"first ");
+        cm.write_data(b"second");
+
+        let mut buf = vec![0; 12];
+        large_rx.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"first second");
+        drop(cm);
+        let mut rest = Vec::new();
+        small_rx.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"first ");
+    }
+
+    #[tokio::test]
+    async fn test_mux_dropped_messages() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        let (tx, _rx) = mpsc::channel(3);
+        // The history is sent as 2 messages.
+        cm.attach_channel(tx).await;
+        cm.write_data(b"fills the queue");
+        assert_eq!(cm.dropped_messages(), 0);
+        cm.write_data(b"dropped");
+        cm.write_data(b"dropped too");
+        assert_eq!(cm.dropped_messages(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mux_clear() {
+        let mut cm = ConsoleMux::<RingBuffer<16>>::new();
+        cm.write_data(b"password: hunter2\r\n");
+        let (tx, mut attached) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        let replay = [
+            &attached.try_recv().unwrap()[..],
+            &attached.try_recv().unwrap(),
+        ]
+        .concat();
+        assert_eq!(replay, b"sword: hunter2\r\n");
+
+        let generation = cm.generation();
+        cm.clear();
+        assert_eq!(&attached.try_recv().unwrap()[..], CLEAR_SEQUENCE);
+        assert_eq!(cm.snapshot(), b"");
+        assert_ne!(cm.generation(), generation);
+        // A remote attaching after the clear receives no history at all.
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        let replay = [&rx.try_recv().unwrap()[..], &rx.try_recv().unwrap()].concat();
+        assert_eq!(replay, b"");
+
+        cm.write_data(b"$ ");
+        assert_eq!(&attached.try_recv().unwrap()[..], b"$ ");
+        assert_eq!(&rx.try_recv().unwrap()[..], b"$ ");
+        assert_eq!(cm.snapshot(), b"$ ");
+    }
+
+    #[tokio::test]
+    async fn test_mux_default_debug() {
+        let mut cm: ConsoleMux<RingBuffer<100>> = Default::default();
+        cm.write_data(b"password\r\n");
+        let (tx, _rx) = mpsc::channel(10);
+        cm.attach_channel_live(tx);
+        assert_eq!(
+            format!("{:?}", cm),
+            "ConsoleMux { len: 10, capacity: 100, remotes: 1, total_written: 10, .. }"
+        );
+    }
+
+    #[test]
+    fn test_mux_len() {
+        let mut cm = ConsoleMux::<RingBuffer<8>>::new();
+        assert_eq!(cm.len(), 0);
+        assert!(cm.is_empty());
+        cm.write_data(b"abc");
+        assert_eq!(cm.len(), 3);
+        assert!(!cm.is_empty());
+        // Once wrapped, the buffer is full.
+        cm.write_data(b"defghij");
+        assert_eq!(cm.len(), 8);
+        assert_eq!(cm.snapshot(), b"cdefghij");
+        cm.clear();
+        assert!(cm.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mux_attach_live() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        cm.write_data(b"old\r\n");
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel_live(tx);
+        let (remote, mut remote_rx) = tokio::io::duplex(1024);
+        cm.attach_remote_live(remote);
+        assert!(rx.try_recv().is_err());
+
+        cm.write_data(b"new\r\n");
+        assert_eq!(&rx.try_recv().unwrap()[..], b"new\r\n");
+        assert!(rx.try_recv().is_err());
+        let mut buf = [0; 5];
+        remote_rx.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"new\r\n");
+        // The history is still replayed to remotes attached as usual.
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        let replay = [rx.try_recv().unwrap(), rx.try_recv().unwrap()].concat();
+        assert_eq!(replay, b"old\r\nnew\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_mux_attach_queued() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        cm.write_data(b"history");
+        // A remote which never receives the history doesn't hold up attaching or writing.
+        let dropped = Arc::new(AtomicBool::new(false));
+        let stuck = StuckWriter {
+            budget: 2,
+            dropped: dropped.clone(),
+        };
+        let handle = cm.attach_remote_queued(stuck);
+        cm.write_data(b"output");
+        assert_eq!(cm.clients()[0].bytes_sent, 13);
+
+        let (remote, mut remote_rx) = tokio::io::duplex(1024);
+        cm.attach_remote_queued(remote);
+        cm.write_data(b"!");
+        let mut buf = [0; 14];
+        remote_rx.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"historyoutput!");
+
+        handle.detach(&mut cm);
+        tokio::task::yield_now().await;
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_mux_fan_out() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        let mut receivers = Vec::new();
+        for _ in 0..3 {
+            let (tx, mut rx) = mpsc::channel(10);
+            cm.attach_channel(tx).await;
+            // Skip the empty history.
+            rx.try_recv().unwrap();
+            rx.try_recv().unwrap();
+            receivers.push(rx);
+        }
+        cm.write_data(b"\x1b[1mbold\x1b[0m\r\n");
+        cm.write_data(b"\x00\xff");
+
+        let first: Vec<_> = receivers
+            .iter_mut()
+            .map(|rx| rx.try_recv().unwrap())
+            .collect();
+        let second: Vec<_> = receivers
+            .iter_mut()
+            .map(|rx| rx.try_recv().unwrap())
+            .collect();
+        for msg in &first {
+            assert_eq!(&msg[..], b"\x1b[1mbold\x1b[0m\r\n");
+            // All remotes share the same allocation.
+            assert!(Arc::ptr_eq(msg, &first[0]));
+        }
+        for msg in &second {
+            assert_eq!(&msg[..], b"\x00\xff");
+        }
+        assert!(receivers.iter_mut().all(|rx| rx.try_recv().is_err()));
+    }
+
+    #[tokio::test]
+    async fn test_mux_attach_error() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        cm.write_data(b"history");
+        let (remote, reader) = tokio::io::duplex(1024);
+        drop(reader);
+        let err = cm.attach_remote(remote).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(cm.remote_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mux_prune_closed() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        cm.write_data(b"history");
+        let mut receivers = Vec::new();
+        for _ in 0..3 {
+            let (tx, rx) = mpsc::channel(10);
+            cm.attach_channel(tx).await;
+            receivers.push(rx);
+        }
+        assert_eq!(cm.remote_count(), 3);
+        assert_eq!(cm.prune_closed(), 0);
+
+        // Without any output, the remotes are only removed by pruning.
+        drop(receivers);
+        assert_eq!(cm.remote_count(), 3);
+        assert_eq!(cm.prune_closed(), 3);
+        assert_eq!(cm.remote_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mux_line_ring() {
+        test_line_store(LineRing::<16>::new()).await;
+        test_line_store(DynLineRing::new(16)).await;
+    }
+
+    async fn test_line_store<S: HistoryStore>(store: S) {
+        let mut cm = ConsoleMux::with_store(store);
+        cm.write_data(b"one\ntwo\n");
+        assert_eq!(cm.snapshot(), b"one\ntwo\n");
+        // The start of "one" is evicted, so the rest of the line is not retained either.
+        cm.write_data(b"three\nfour");
+        assert_eq!(cm.snapshot(), b"two\nthree\nfour");
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        let replay = [&rx.try_recv().unwrap()[..], &rx.try_recv().unwrap()].concat();
+        assert_eq!(replay, b"two\nthree\nfour");
+
+        // A line which does not fit is retained partially.
+        cm.write_data(b"0123456789abcdefgh");
+        assert_eq!(cm.snapshot(), b"23456789abcdefgh");
+    }
+
+    #[tokio::test]
+    async fn test_mux_attach_ordering() {
+        const WRITERS: usize = 3;
+        const WRITES: usize = 100;
+        let record = |writer: usize, i: usize| format!("{}:{};", writer, i).into_bytes();
+        // The writers take turns, like they would locking a shared console.
+        let records: Vec<_> = (0..WRITES)
+            .flat_map(|i| (0..WRITERS).map(move |writer| record(writer, i)))
+            .collect();
+        let total: usize = records.iter().map(Vec::len).sum();
+
+        // Attach a channel and a remote at every point in the output. The history is smaller than
+        // the output, so later replays start halfway.
+        for attach in 0..=records.len() {
+            for remote in [false, true] {
+                let mut cm = ConsoleMux::<RingBuffer<512>>::new();
+                // All output, in the order the mux received it.
+                let mut written = Vec::new();
+                for data in &records[..attach] {
+                    cm.write_data(data);
+                    written.extend_from_slice(data);
+                }
+                let (tx, mut rx) = mpsc::channel(records.len() + 2);
+                let (mut client, server) = tokio::io::duplex(total * 2);
+                let replayed = match remote {
+                    false => cm.attach_channel_limited(tx, usize::MAX).await,
+                    true => {
+                        let (first, second) = cm.replay(usize::MAX);
+                        let replayed = first.len() + second.len();
+                        cm.attach_remote(server).await.unwrap();
+                        replayed
+                    }
+                };
+                let start = written.len() - replayed;
+                for data in &records[attach..] {
+                    cm.write_data(data);
+                    written.extend_from_slice(data);
+                    // Let the remote forward some of the output while the rest is written.
+                    tokio::task::yield_now().await;
+                }
+
+                let expected = &written[start..];
+                let mut received = vec![0; expected.len()];
+                let receive = async {
+                    match remote {
+                        false => {
+                            let mut pos = 0;
+                            while pos < received.len() {
+                                let data = rx.recv().await.unwrap();
+                                received[pos..pos + data.len()].copy_from_slice(&data);
+                                pos += data.len();
+                            }
+                        }
+                        true => {
+                            client.read_exact(&mut received).await.unwrap();
+                        }
+                    }
+                };
+                tokio::time::timeout(Duration::from_secs(5), receive)
+                    .await
+                    .unwrap();
+                assert!(rx.try_recv().is_err());
+                assert!(
+                    received == expected,
+                    "{} attached after {} writes did not receive a contiguous suffix",
+                    if remote { "remote" } else { "channel" },
+                    attach
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mux_shutdown() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        cm.write_data(b"history ");
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        let (remote, mut remote_rx) = tokio::io::duplex(1024);
+        cm.attach_remote(remote).await.unwrap();
+        // Output which overflows the channel of a remote which can't lose data.
+        let (slow, mut slow_rx) = tokio::io::duplex(1024);
+        cm.attach_sink(slow, 1, Backpressure::Block).await.unwrap();
+        for chunk in [b"one ".as_slice(), b"two ", b"three"] {
+            cm.write_data(chunk);
+        }
+
+        cm.shutdown().await;
+        assert_eq!(cm.remote_count(), 0);
+        let mut received = Vec::new();
+        while let Some(data) = rx.recv().await {
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(received, b"history one two three");
+        for reader in [&mut remote_rx, &mut slow_rx] {
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"history one two three");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mux_resize() {
+        let mut cm = DynConsoleMux::with_capacity(8);
+        cm.write_data(b"0123456789");
+        assert_eq!(cm.snapshot(), b"23456789");
+
+        // Growing keeps everything, and leaves room for more.
+        cm.resize(12);
+        assert_eq!(cm.snapshot(), b"23456789");
+        cm.write_data(b"abcdef");
+        assert_eq!(cm.snapshot(), b"456789abcdef");
+        assert_eq!(cm.store().capacity(), 12);
+
+        // Shrinking keeps the most recent history.
+        cm.resize(4);
+        assert_eq!(cm.snapshot(), b"cdef");
+        cm.write_data(b"gh");
+        assert_eq!(cm.snapshot(), b"efgh");
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        let replay = [&rx.try_recv().unwrap()[..], &rx.try_recv().unwrap()].concat();
+        assert_eq!(replay, b"efgh");
+    }
+
+    #[tokio::test]
+    async fn test_mux_line_buffer() {
+        let mut cm = ConsoleMux::with_store(LineBuffer::new(3, 8));
+        cm.write_data(b"one\ntwo\nthr");
+        assert_eq!(cm.snapshot(), b"one\ntwo\nthr");
+        // The incomplete line is not counted until it is complete.
+        cm.write_data(b"ee\nfour\nfi");
+        assert_eq!(cm.snapshot(), b"two\nthree\nfour\nfi");
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        let replay = [&rx.try_recv().unwrap()[..], &rx.try_recv().unwrap()].concat();
+        assert_eq!(replay, b"two\nthree\nfour\nfi");
+
+        // A line which is too long is truncated, also if it is written in parts.
+        cm.write_data(b"ve\n0123");
+        cm.write_data(b"456789abcdef\n");
+        assert_eq!(cm.snapshot(), b"four\nfive\n01234567\n");
+        cm.write_data(&[b'x'; 100]);
+        assert_eq!(cm.snapshot(), b"four\nfive\n01234567\nxxxxxxxx");
+        assert_eq!(cm.store().len(), 27);
+    }
+
+    #[test]
+    fn test_mux_output_since() {
+        let mut cm = ConsoleMux::<RingBuffer<16>>::new();
+        cm.write_data(b"$ ");
+        let marker = cm.position();
+        assert_eq!(cm.output_since(marker), (Vec::new(), false));
+        // The incomplete escape sequence is not sent yet.
+        cm.write_data(b"ls\r\nfile\x1b[");
+        assert_eq!(cm.output_since(marker), (b"ls\r\nfile".to_vec(), false));
+        cm.write_data(b"0m\r\n");
+        assert_eq!(
+            cm.output_since(marker),
+            (b"ls\r\nfile\x1b[0m\r\n".to_vec(), false)
+        );
+        // Output which is no longer retained is cut off.
+        cm.write_data(b"$ ls");
+        assert_eq!(
+            cm.output_since(marker),
+            (b"\r\nfile\x1b[0m\r\n$ ls".to_vec(), true)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mux_plain_history() {
+        let mut cm = ConsoleMux::<RingBuffer<1000>>::new();
+        cm.enable_recording(1000);
+        cm.enable_plain_history();
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        cm.write_data(b"\x1b[1;32mok\x1b[0m\r\n\x1b[");
+        cm.write_data(b"2K$ ");
+
+        // Remotes receive the output as is, with the incomplete sequence once it is completed.
+        let mut live = Vec::new();
+        while let Ok(data) = rx.try_recv() {
+            live.extend_from_slice(&data);
+        }
+        assert!(live.ends_with(b"\x1b[1;32mok\x1b[0m\r\n\x1b[2K$ "));
+        assert_eq!(cm.snapshot(), b"ok\r\n$ ");
+        assert_eq!(cm.recording().unwrap().to_vec(), b"ok\r\n$ ");
+        assert_eq!(replayed(&mut cm).await, b"ok\r\n$ ");
+    }
+
+    #[tokio::test]
+    async fn test_mux_replay_screen() {
+        let mut cm = ConsoleMux::<RingBuffer<1000>>::new();
+        cm.enable_screen(20, 3);
+        cm.write_data(b"one\r\ntwo\r\nthree\r\nfour\x1b[1");
+
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        cm.write_data(b"mbold");
+        let mut stream = Vec::new();
+        while let Ok(data) = rx.try_recv() {
+            stream.extend_from_slice(&data);
+        }
+
+        // The first line scrolled off, and is not part of the reconstruction.
+        let mut client = Screen::new(20, 3);
+        client.feed(&stream);
+        assert_eq!(client.lines(), ["two", "three", "fourbold"]);
+        assert_eq!(client.lines(), cm.screen().unwrap().lines());
+        assert!(!stream.windows(3).any(|w| w == b"one"));
+    }
+
+    async fn replayed<const H: usize>(cm: &mut ConsoleMux<RingBuffer<H>>) -> Vec<u8> {
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        let mut replayed = Vec::new();
+        while let Ok(data) = rx.try_recv() {
+            replayed.extend_from_slice(&data);
+        }
+        replayed
+    }
+
+    #[tokio::test]
+    async fn test_mux_serve_stream() {
+        let console = Arc::new(Mutex::new(ConsoleMux::<RingBuffer<100>>::new()));
+        console.lock().await.write_data(b"login: ");
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (input, mut input_rx) = mpsc::channel(10);
+        let serve = tokio::spawn({
+            let console = console.clone();
+            async move { ConsoleMux::serve_stream(&console, server, input).await }
+        });
+
+        let mut buf = vec![0; 7];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"login: ");
+        client.write_all(b"root\r").await.unwrap();
+        assert_eq!(input_rx.recv().await.unwrap(), b"root\r");
+        console.lock().await.write_data(b"root\r\n# ");
+        let mut buf = vec![0; 8];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, b"root\r\n# ");
+
+        // Once the client is done, the stream is detached.
+        client.shutdown().await.unwrap();
+        serve.await.unwrap();
+        assert!(console.lock().await.queue_fill().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mux_rate_limit() {
+        let mut cm = ConsoleMux::<RingBuffer<10000>>::new();
+        cm.limit_rate(Some(TokenBucket::new(1000, 100)));
+        let (tx, mut rx) = mpsc::channel(1000);
+        cm.attach_channel(tx).await;
+        let recv = |rx: &mut mpsc::Receiver<Arc<[u8]>>| {
+            let mut data = Vec::new();
+            while let Ok(d) = rx.try_recv() {
+                data.extend_from_slice(&d);
+            }
+            data.into_iter().filter(|&b| b != 0).count()
+        };
+        assert_eq!(recv(&mut rx), 0);
+
+        cm.write_data(&[b'x'; 3000]);
+        let mut received = recv(&mut rx);
+        assert_eq!(received, 100);
+        for step in 1..=10 {
+            tokio::time::advance(Duration::from_millis(100)).await;
+            cm.pace_output();
+            received += recv(&mut rx);
+            assert!(
+                received <= 100 + step * 100,
+                "{} bytes after {}",
+                received,
+                step
+            );
+        }
+        assert_eq!(received, 1100);
+
+        // Remotes attaching now receive the held back output once it is sent, not as history.
+        let (tx, mut late) = mpsc::channel(1000);
+        cm.attach_channel(tx).await;
+        assert_eq!(recv(&mut late), received);
+        cm.limit_rate(None);
+        assert_eq!(received + recv(&mut rx), 3000);
+        assert_eq!(recv(&mut late), 1900);
+    }
+
+    #[tokio::test]
+    async fn test_mux_replay_limited() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        cm.write_data(b"first line\nsecond line\nthird");
+
+        let (tx, mut rx) = mpsc::channel(10);
+        assert_eq!(cm.attach_channel_limited(tx, 15).await, 5);
+        let replay = [&rx.try_recv().unwrap()[..], &rx.try_recv().unwrap()].concat();
+        assert_eq!(replay, b"third");
+        let (tx, mut rx) = mpsc::channel(10);
+        assert_eq!(cm.attach_channel_limited(tx, 17).await, 17);
+        let replay = [&rx.try_recv().unwrap()[..], &rx.try_recv().unwrap()].concat();
+        assert_eq!(replay, b"second line\nthird");
+
+        // The entire history fits.
+        let (tx, mut rx) = mpsc::channel(10);
+        assert_eq!(cm.attach_channel_limited(tx, 40).await, 28);
+        let replay = [&rx.try_recv().unwrap()[..], &rx.try_recv().unwrap()].concat();
+        assert_eq!(replay, b"first line\nsecond line\nthird");
+        cm.write_data(b"!");
+        assert_eq!(&rx.try_recv().unwrap()[..], b"!");
+    }
+
+    #[tokio::test]
+    async fn test_mux_replay_tail_first() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        cm.write_data(b"first line\nsecond line\nthird");
+
+        let (tx, mut rx) = mpsc::channel(10);
+        assert_eq!(cm.attach_channel_tail_first(tx, usize::MAX, 14).await, 28);
+        assert_eq!(&rx.try_recv().unwrap()[..], b"third");
+        assert_eq!(&rx.try_recv().unwrap()[..], b"first line\nsecond line\n");
+
+        // A tail which starts at a line, or covers the entire replay.
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel_tail_first(tx, 40, 17).await;
+        assert_eq!(&rx.try_recv().unwrap()[..], b"second line\nthird");
+        assert_eq!(&rx.try_recv().unwrap()[..], b"first line\n");
+        let (tx, mut rx) = mpsc::channel(10);
+        cm.attach_channel_tail_first(tx, 40, 1000).await;
+        assert_eq!(
+            &rx.try_recv().unwrap()[..],
+            b"first line\nsecond line\nthird"
+        );
+        assert_eq!(&rx.try_recv().unwrap()[..], b"");
+        cm.write_data(b"!");
+        assert_eq!(&rx.try_recv().unwrap()[..], b"!");
+    }
+
+    #[tokio::test]
+    async fn test_mux_replay_lines() {
+        let mut cm = ConsoleMux::<RingBuffer<1000>>::new();
+        cm.limit_replay(2);
+        cm.write_data(b"line 1\r\nline 2\r\nline 3\r\nline 4\r\n> ");
+        assert_eq!(replayed(&mut cm).await, b"line 3\r\nline 4\r\n> ");
+        cm.write_data(b"ls\r\n");
+        assert_eq!(replayed(&mut cm).await, b"line 4\r\n> ls\r\n");
+
+        // Without enough lines, the entire history is replayed, but not the padding.
+        cm.limit_replay(10);
+        assert_eq!(
+            replayed(&mut cm).await,
+            b"line 1\r\nline 2\r\nline 3\r\nline 4\r\n> ls\r\n"
+        );
+
+        // If the start of the history was overwritten, the incomplete first line is skipped.
+        let mut cm = ConsoleMux::<RingBuffer<16>>::new();
+        cm.limit_replay(10);
+        cm.write_data(b"aaaa\nbbbbbbbb\ncccc\n");
+        assert_eq!(replayed(&mut cm).await, b"bbbbbbbb\ncccc\n");
+    }
+
+    #[test]
+    fn test_mux_total_written() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        assert_eq!(cm.total_written(), 0);
+
+        cm.write_data(&[1; 60]);
+        cm.write_data(&[]);
+        assert_eq!(cm.total_written(), 60);
+        // Data which doesn't fit in the buffer is still counted.
+        cm.write_data(&[2; 250]);
+        assert_eq!(cm.total_written(), 310);
+        assert_eq!(cm.snapshot(), vec![2; 100]);
+    }
+
+    #[tokio::test]
+    async fn test_mux_queue_fill() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        let (tx, mut lagging) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        let (tx, mut consuming) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+
+        for _ in 0..5 {
+            cm.write_data(b"data");
+            while consuming.try_recv().is_ok() {}
+        }
+
+        // The lagging remote also has the 2 messages with the history queued.
+        assert_eq!(
+            cm.queue_fill(),
+            vec![
+                QueueFill {
+                    remote: 0,
+                    queued: 7,
+                    capacity: 10
+                },
+                QueueFill {
+                    remote: 1,
+                    queued: 0,
+                    capacity: 10
+                }
+            ]
+        );
+
+        lagging.recv().await;
+        assert_eq!(cm.queue_fill()[0].queued, 6);
+        drop(lagging);
+        assert_eq!(cm.queue_fill().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mux_clients() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        cm.write_data(b"history");
+        let (tx, first) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+        let (tx, _second) = mpsc::channel(10);
+        cm.attach_channel_live(tx);
+
+        let clients = cm.clients();
+        assert_eq!(clients.len(), 2);
+        assert_ne!(clients[0].id, clients[1].id);
+        assert!(clients[0].attached <= clients[1].attached);
+        // Only the first remote received the history.
+        assert_eq!(clients[0].bytes_sent, 7);
+        assert_eq!(clients[1].bytes_sent, 0);
+
+        cm.write_data(b"data");
+        let sent: Vec<_> = cm.clients().iter().map(|c| c.bytes_sent).collect();
+        assert_eq!(sent, vec![11, 4]);
+        cm.write_data(b"more");
+        let sent: Vec<_> = cm.clients().iter().map(|c| c.bytes_sent).collect();
+        assert_eq!(sent, vec![15, 8]);
+
+        drop(first);
+        assert_eq!(cm.clients()[0].id, clients[1].id);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mux_send_wait() {
+        let full = |wait| async move {
+            let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+            cm.limit_send_wait(wait);
+            let (tx, rx) = mpsc::channel(3);
+            // The history is sent as 2 messages, so this fills the channel.
+            cm.attach_channel(tx).await;
+            cm.write_data(b"first");
+            (Mutex::new(cm), rx)
+        };
+        let drain = |mut rx: mpsc::Receiver<Arc<[u8]>>| {
+            let mut received = Vec::new();
+            while let Ok(msg) = rx.try_recv() {
+                received.extend_from_slice(&msg);
+            }
+            received
+        };
+
+        // By default, output for a remote with a full channel is dropped right away.
+        let (console, rx) = full(None).await;
+        let start = Instant::now();
+        ConsoleMux::write_data_async(&console, b" second").await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(console.lock().await.dropped_messages(), 1);
+        assert_eq!(drain(rx), b"first");
+
+        // When waiting, a remote which catches up in time doesn't lose output.
+        let (console, mut rx) = full(Some(Duration::from_secs(1))).await;
+        let reader = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            // Consume the history.
+            rx.recv().await.unwrap();
+            rx.recv().await.unwrap();
+            rx
+        });
+        let start = Instant::now();
+        ConsoleMux::write_data_async(&console, b" second").await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+        console.lock().await.write_data(b" third");
+        assert_eq!(console.lock().await.dropped_messages(), 0);
+        assert_eq!(drain(reader.await.unwrap()), b"first second third");
+
+        // A remote which doesn't catch up loses the output queued for it after the wait.
+        let (console, rx) = full(Some(Duration::from_secs(1))).await;
+        let start = Instant::now();
+        ConsoleMux::write_data_async(&console, b" second").await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(console.lock().await.dropped_messages(), 1);
+        assert_eq!(drain(rx), b"first");
+    }
+
+    #[tokio::test]
+    async fn test_mux_slow_sink_isolated() {
+        use tokio::io::AsyncReadExt;
+
+        let mut cm = ConsoleMux::<RingBuffer<4>>::new();
+        let (fast, mut fast_rx) = tokio::io::duplex(4096);
+        cm.attach_sink(fast, 2, Backpressure::Drop).await.unwrap();
+        // Nothing reads from the slow sink for now, so it stalls once a few bytes are buffered.
+        let (slow, mut slow_rx) = tokio::io::duplex(16);
+        cm.attach_sink(slow, 2, Backpressure::Block).await.unwrap();
+        let console = Mutex::new(cm);
+
+        let mut expected = Vec::new();
+        for i in 0..10 {
+            let line = format!("line {}\n", i);
+            console.lock().await.write_data(line.as_bytes());
+            expected.extend_from_slice(line.as_bytes());
+
+            // The fast sink receives everything right away.
+            let mut buf = vec![0; line.len()];
+            fast_rx.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, line.as_bytes());
+        }
+
+        // The slow sink did not lose any data, so the writer has to wait for it.
+        let slow_data = {
+            let wait = ConsoleMux::wait_for_remotes(&console);
+            tokio::pin!(wait);
+            assert!(
+                tokio::time::timeout(std::time::Duration::from_millis(50), &mut wait)
+                    .await
+                    .is_err()
+            );
+            let slow_data = tokio::spawn(async move {
+                let mut data = Vec::new();
+                slow_rx.read_to_end(&mut data).await.unwrap();
+                data
+            });
+            wait.await;
+            slow_data
+        };
+        drop(console);
+        assert_eq!(slow_data.await.unwrap(), expected);
+    }
+
+    /// A writer which accepts `budget` bytes, and then hangs forever.
+    struct StuckWriter {
+        budget: usize,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl AsyncWrite for StuckWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.budget == 0 {
+                return Poll::Pending;
+            }
+            let n = usize::min(self.budget, buf.len());
+            self.budget -= n;
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Drop for StuckWriter {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mux_detach_stuck_remote() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let mut cm = ConsoleMux::<RingBuffer<4>>::new();
+        // Accepts the first write, then hangs in the middle of the second.
+        let stuck = StuckWriter {
+            budget: 6 + 2,
+            dropped: dropped.clone(),
+        };
+        cm.attach_remote(stuck).await.unwrap();
+        let (tx, mut healthy) = mpsc::channel(10);
+        cm.attach_channel(tx).await;
+
+        for _ in 0..3 {
+            cm.write_data(b"output");
+            tokio::task::yield_now().await;
+            while healthy.try_recv().is_ok() {}
+        }
+        assert!(cm.detach_stuck(Duration::from_secs(30)).is_empty());
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        // The healthy remote had nothing queued, so it is not affected.
+        assert_eq!(cm.detach_stuck(Duration::from_secs(30)), vec![0]);
+        tokio::task::yield_now().await;
+        assert!(dropped.load(Ordering::SeqCst));
+        assert_eq!(cm.queue_fill().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mux_attach_timeout() {
+        let mut cm = ConsoleMux::<RingBuffer<4>>::new();
+        cm.write_data(b"history");
+        cm.limit_attach(Some(Duration::from_secs(10)));
+
+        // Accepts part of the history, and then never reads again.
+        let start = Instant::now();
+        let stuck = StuckWriter {
+            budget: 2,
+            dropped: Arc::new(AtomicBool::new(false)),
+        };
+        let err = cm.attach_remote(stuck).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        assert!(cm.queue_fill().is_empty());
+
+        // A channel which is full is not attached either.
+        let (tx, _rx) = mpsc::channel(1);
+        tx.send(Arc::from(&[][..])).await.unwrap();
+        assert_eq!(cm.attach_channel_limited(tx.clone(), 2).await, 0);
+        assert!(cm.queue_fill().is_empty());
+        assert_eq!(cm.attach_channel_tail_first(tx, 4, 2).await, 0);
+        assert!(cm.queue_fill().is_empty());
+    }
+
+    #[test]
+    fn test_mux_recording_exceeds_buffer() {
+        let mut cm = ConsoleMux::<RingBuffer<100>>::new();
+        cm.enable_recording(250);
+
+        cm.write_data(&[1; 150]);
+        cm.write_data(&[2; 150]);
 
-        assert_eq!(cm.head, 50);
-        assert_eq!(&cm.data, vec![2; 100].as_slice());
+        let recording = cm.recording().unwrap().to_vec();
+        assert_eq!(recording.len(), 250);
+        assert_eq!(&recording[..100], &[1; 100]);
+        assert_eq!(&recording[100..], &[2; 150]);
+        assert_eq!(&cm.store.data, vec![2; 100].as_slice());
     }
 }
@@ -0,0 +1,481 @@
+//! Logging of the server, as [`tracing`] events written to stderr by a [`LogSubscriber`], which
+//! is filtered like `RUST_LOG` is for other programs. Errors which can repeat rapidly, e.g. while a
+//! client is failing in a reconnect loop, are logged with [`log_error`], which collapses repeated
+//! errors of the same kind, so they don't flood the log.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt::{self, Write},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span, Event, Metadata, Subscriber,
+};
+use tracing_core::span::Current;
+
+/// Time after logging an error during which further errors of the same kind are suppressed.
+pub const SUPPRESS_WINDOW: Duration = Duration::from_secs(10);
+
+/// Collapses repeated errors of the same kind. After an error of a kind is logged, errors of that
+/// kind are only counted for a while. The next error logged afterwards reports how many were
+/// suppressed.
+#[derive(Debug)]
+pub struct ErrorDedup {
+    window: Duration,
+    kinds: HashMap<&'static str, Suppressed>,
+}
+
+#[derive(Debug)]
+struct Suppressed {
+    /// When the last error of the kind was logged.
+    logged: Instant,
+    /// Amount of errors of the kind suppressed since.
+    count: u64,
+}
+
+impl ErrorDedup {
+    /// Create a new ErrorDedup, suppressing errors of a kind for `window` after one is logged.
+    pub fn new(window: Duration) -> ErrorDedup {
+        ErrorDedup {
+            window,
+            kinds: HashMap::new(),
+        }
+    }
+
+    /// Report an error of `kind` which happened at `now`. Returns the lines to log, which are
+    /// empty if the error is suppressed.
+    pub fn report(
+        &mut self,
+        kind: &'static str,
+        error: impl fmt::Display,
+        now: Instant,
+    ) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(suppressed) = self.kinds.get_mut(kind) {
+            if now.duration_since(suppressed.logged) < self.window {
+                suppressed.count += 1;
+                return lines;
+            }
+            if suppressed.count > 0 {
+                lines.push(format!(
+                    "{} similar errors suppressed ({})",
+                    suppressed.count, kind
+                ));
+            }
+        }
+        lines.push(error.to_string());
+        self.kinds.insert(
+            kind,
+            Suppressed {
+                logged: now,
+                count: 0,
+            },
+        );
+        lines
+    }
+}
+
+/// Log an error, suppressing repeated errors of the same `kind` for [`SUPPRESS_WINDOW`].
+pub fn log_error(kind: &'static str, error: impl fmt::Display) {
+    static ERRORS: OnceLock<Mutex<ErrorDedup>> = OnceLock::new();
+    let errors = ERRORS.get_or_init(|| Mutex::new(ErrorDedup::new(SUPPRESS_WINDOW)));
+    let lines = errors.lock().unwrap().report(kind, error, Instant::now());
+    for line in lines {
+        tracing::error!("{}", line);
+    }
+}
+
+/// The filter used if `RUST_LOG` is not set: events of the server down to info, and only warnings
+/// of the libraries it uses.
+pub const DEFAULT_FILTER: &str = "warn,cloud_console=info";
+
+/// Decides which events are logged, from a comma separated list of levels like `RUST_LOG`, each
+/// optionally prefixed with a target and `=`, e.g. `warn,cloud_console=debug`. The directive with
+/// the longest matching target applies, events no directive matches are not logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    /// The levels by target, longest target first. An empty target matches all events.
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut directives = Vec::new();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let (target, level) = directive.split_once('=').unwrap_or(("", directive));
+            let level = level
+                .parse()
+                .map_err(|_| format!("invalid level {}", level))?;
+            directives.push((target.to_string(), level));
+        }
+        directives.sort_by_key(|(target, _)| Reverse(target.len()));
+        Ok(LogFilter { directives })
+    }
+}
+
+impl LogFilter {
+    /// Whether events with `metadata` are logged.
+    pub fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let target = metadata.target();
+        self.directives
+            .iter()
+            .find(|(prefix, _)| {
+                prefix.is_empty()
+                    || target == prefix
+                    || target
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .is_some_and(|(_, level)| level >= metadata.level())
+    }
+
+    /// The most verbose level any event is logged at.
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .max()
+            .unwrap_or(LevelFilter::OFF)
+    }
+}
+
+/// A span which is open.
+#[derive(Debug)]
+struct SpanData {
+    metadata: &'static Metadata<'static>,
+    /// The recorded fields, formatted as ` name=value` each.
+    fields: String,
+    parent: Option<u64>,
+    /// Amount of handles to the span.
+    refs: usize,
+}
+
+/// A [`Subscriber`] writing every event which passes its [`LogFilter`] as a line, prefixed with the
+/// level and the spans the event happened in, e.g. `WARN client{id=3}: Could not send output`.
+pub struct LogSubscriber {
+    filter: LogFilter,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    /// The spans entered on every thread, innermost last.
+    entered: Mutex<HashMap<ThreadId, Vec<u64>>>,
+    write: Box<dyn Fn(&str) + Send + Sync>,
+}
+
+impl fmt::Debug for LogSubscriber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogSubscriber")
+            .field("filter", &self.filter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LogSubscriber {
+    /// Create a new LogSubscriber, passing every line to log to `write`.
+    pub fn new(filter: LogFilter, write: impl Fn(&str) + Send + Sync + 'static) -> LogSubscriber {
+        LogSubscriber {
+            filter,
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+            entered: Mutex::new(HashMap::new()),
+            write: Box::new(write),
+        }
+    }
+
+    /// The innermost span entered on the current thread.
+    fn current(&self) -> Option<u64> {
+        let entered = self.entered.lock().unwrap();
+        entered.get(&thread::current().id())?.last().copied()
+    }
+}
+
+impl Subscriber for LogSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.filter.max_level())
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let parent = match attrs.is_contextual() {
+            true => self.current(),
+            false => attrs.parent().map(span::Id::into_u64),
+        };
+        let mut fields = FieldWriter::default();
+        attrs.record(&mut fields);
+        let span = SpanData {
+            metadata: attrs.metadata(),
+            fields: fields.fields,
+            parent,
+            refs: 1,
+        };
+        self.spans.lock().unwrap().insert(id, span);
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            let mut fields = FieldWriter {
+                fields: std::mem::take(&mut span.fields),
+                ..FieldWriter::default()
+            };
+            values.record(&mut fields);
+            span.fields = fields.fields;
+        }
+    }
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn current_span(&self) -> Current {
+        let current = self.current();
+        let spans = self.spans.lock().unwrap();
+        match current.and_then(|id| Some((id, spans.get(&id)?))) {
+            Some((id, span)) => Current::new(span::Id::from_u64(id), span.metadata),
+            None => Current::none(),
+        }
+    }
+
+    fn event(&self, event: &Event<'_>) {
+        let parent = match event.is_contextual() {
+            true => self.current(),
+            false => event.parent().map(span::Id::into_u64),
+        };
+        let mut line = format!("{} ", event.metadata().level());
+        {
+            let spans = self.spans.lock().unwrap();
+            let mut chain = Vec::new();
+            let mut next = parent;
+            while let Some(span) = next.and_then(|id| spans.get(&id)) {
+                chain.push(span);
+                next = span.parent;
+            }
+            for (i, span) in chain.iter().rev().enumerate() {
+                if i > 0 {
+                    line.push(':');
+                }
+                line.push_str(span.metadata.name());
+                if !span.fields.is_empty() {
+                    let _ = write!(line, "{{{}}}", span.fields.trim_start());
+                }
+            }
+            if !chain.is_empty() {
+                line.push_str(": ");
+            }
+        }
+        let mut fields = FieldWriter::default();
+        event.record(&mut fields);
+        line.push_str(&fields.message);
+        line.push_str(&fields.fields);
+        (self.write)(&line);
+    }
+
+    fn enter(&self, span: &span::Id) {
+        let mut entered = self.entered.lock().unwrap();
+        let stack = entered.entry(thread::current().id()).or_default();
+        stack.push(span.into_u64());
+    }
+
+    fn exit(&self, span: &span::Id) {
+        let mut entered = self.entered.lock().unwrap();
+        let thread = thread::current().id();
+        if let Some(stack) = entered.get_mut(&thread) {
+            if let Some(pos) = stack.iter().rposition(|&id| id == span.into_u64()) {
+                stack.remove(pos);
+            }
+            if stack.is_empty() {
+                entered.remove(&thread);
+            }
+        }
+    }
+
+    fn clone_span(&self, span: &span::Id) -> span::Id {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            span.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: span::Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let id = span.into_u64();
+        match spans.get_mut(&id) {
+            Some(span) if span.refs > 1 => {
+                span.refs -= 1;
+                false
+            }
+            Some(_) => {
+                spans.remove(&id);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Formats the fields of a span or event.
+#[derive(Debug, Default)]
+struct FieldWriter {
+    message: String,
+    /// The fields other than the message, formatted as ` name=value` each.
+    fields: String,
+}
+
+impl Visit for FieldWriter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => {
+                let _ = write!(self.message, "{:?}", value);
+            }
+            name => {
+                let _ = write!(self.fields, " {}={:?}", name, value);
+            }
+        }
+    }
+}
+
+/// Log to stderr, with the filter in `RUST_LOG` or else [`DEFAULT_FILTER`]. Does nothing if a
+/// subscriber is already set.
+pub fn init() {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(filter) => filter.parse().unwrap_or_else(|e| {
+            eprintln!("Ignoring RUST_LOG: {}", e);
+            DEFAULT_FILTER.parse().unwrap()
+        }),
+        Err(_) => DEFAULT_FILTER.parse().unwrap(),
+    };
+    let subscriber = LogSubscriber::new(filter, |line| eprintln!("{}", line));
+    let _ = tracing::subscriber::set_global_default(subscriber);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tracing::Instrument;
+    #[test]
+    fn test_collapse_repeated_errors() {
+        let mut errors = ErrorDedup::new(Duration::from_secs(10));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let error = "Error writing to remote Broken pipe (os error 32)";
+
+        assert_eq!(errors.report("remote write", error, at(0)), [error]);
+        for secs in 1..10 {
+            assert!(errors.report("remote write", error, at(secs)).is_empty());
+        }
+        // Other kinds of errors are not affected.
+        assert_eq!(
+            errors.report("stream read", "Error reading from stream", at(5)),
+            ["Error reading from stream"]
+        );
+
+        assert_eq!(
+            errors.report("remote write", error, at(10)),
+            ["9 similar errors suppressed (remote write)", error]
+        );
+        // Nothing was suppressed since.
+        assert_eq!(errors.report("remote write", error, at(20)), [error]);
+    }
+
+    /// A LogSubscriber with `filter`, collecting the lines it logs.
+    fn capture(filter: &str) -> (LogSubscriber, std::sync::Arc<Mutex<Vec<String>>>) {
+        let lines = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let subscriber = LogSubscriber::new(filter.parse().unwrap(), {
+            let lines = lines.clone();
+            move |line| lines.lock().unwrap().push(line.to_string())
+        });
+        (subscriber, lines)
+    }
+
+    #[test]
+    fn test_log_filter() {
+        let filter = "warn, cloud_console=debug,cloud_console::pty=off";
+        assert_eq!(
+            filter.parse::<LogFilter>().unwrap().max_level(),
+            LevelFilter::DEBUG
+        );
+        let (subscriber, lines) = capture(filter);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!(target: "cloud_console", "console");
+            tracing::debug!(target: "cloud_console::logging", "module");
+            tracing::error!(target: "cloud_console::pty", "disabled module");
+            tracing::warn!(target: "cloud_consoles", "other crate");
+            tracing::info!(target: "hyper::proto", "library");
+        });
+        assert_eq!(
+            *lines.lock().unwrap(),
+            ["DEBUG console", "DEBUG module", "WARN other crate"]
+        );
+        assert!("cloud_console=loud".parse::<LogFilter>().is_err());
+    }
+
+    #[test]
+    fn test_log_subscriber() {
+        let (subscriber, lines) = capture("info");
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("started");
+            let client = tracing::info_span!("client", id = 3, addr = "10.0.0.1:4000");
+            let _entered = client.enter();
+            tracing::warn!(bytes = 12, "could not send {}", "output");
+            tracing::debug!("hidden");
+            tracing::info_span!("replay").in_scope(|| tracing::error!("timed out"));
+        });
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                "INFO started",
+                "WARN client{id=3 addr=10.0.0.1:4000}: could not send output bytes=12",
+                "ERROR client{id=3 addr=10.0.0.1:4000}:replay: timed out",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remote_write_error() {
+        let (subscriber, lines) = capture("error");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            runtime.block_on(async {
+                let mut cm = crate::ConsoleMux::<crate::RingBuffer<100>>::new();
+                let (remote, reader) = tokio::io::duplex(1024);
+                let client = tracing::error_span!("client", id = 7);
+                cm.attach_remote(remote).instrument(client).await.unwrap();
+
+                // The client goes away, so the next write to it fails.
+                drop(reader);
+                cm.write_data(b"output");
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    while lines.lock().unwrap().is_empty() {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                })
+                .await
+                .unwrap();
+            })
+        });
+        assert_eq!(
+            *lines.lock().unwrap(),
+            ["ERROR client{id=7}: Error writing to remote broken pipe"]
+        );
+    }
+}
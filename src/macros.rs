@@ -0,0 +1,75 @@
+use std::str::FromStr;
+
+/// A named piece of input, written to the pty when a client triggers it. Denoted as
+/// `<name>=<input>`, where the input can contain the escapes `\r`, `\n`, `\t`, `\e` (escape) and
+/// `\\`, e.g. `uptime=uptime\r`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macro {
+    pub name: String,
+    pub input: Vec<u8>,
+}
+
+impl Macro {
+    /// Find the macro with the given name.
+    pub fn find<'a>(macros: &'a [Macro], name: &str) -> Option<&'a Macro> {
+        macros.iter().find(|m| m.name == name)
+    }
+}
+
+impl FromStr for Macro {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, text) = s
+            .split_once('=')
+            .ok_or_else(|| "expected <name>=<input>".to_string())?;
+        if name.is_empty() {
+            return Err("the name of a macro can't be empty".into());
+        }
+        let mut input = Vec::with_capacity(text.len());
+        let mut bytes = text.bytes();
+        while let Some(b) = bytes.next() {
+            if b != b'\\' {
+                input.push(b);
+                continue;
+            }
+            input.push(match bytes.next() {
+                Some(b'r') => b'\r',
+                Some(b'n') => b'\n',
+                Some(b't') => b'\t',
+                Some(b'e') => 0x1b,
+                Some(b'\\') => b'\\',
+                Some(other) => return Err(format!("unknown escape \\{}", other as char)),
+                None => return Err("input ends with an incomplete escape".into()),
+            });
+        }
+        Ok(Macro {
+            name: name.to_string(),
+            input,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_macro() {
+        assert_eq!(
+            "restart=systemctl restart app\\r".parse(),
+            Ok(Macro {
+                name: "restart".into(),
+                input: b"systemctl restart app\r".to_vec(),
+            })
+        );
+        assert_eq!(
+            "menu=\\e[A\\\\=".parse::<Macro>().unwrap().input,
+            b"\x1b[A\\="
+        );
+        assert!("no-input".parse::<Macro>().is_err());
+        assert!("=ls".parse::<Macro>().is_err());
+        assert!("bad=\\x".parse::<Macro>().is_err());
+        assert!("bad=ls\\".parse::<Macro>().is_err());
+    }
+}
@@ -1,31 +1,162 @@
 use axum::{
-    body::{boxed, Full},
-    extract::ws::{WebSocket, WebSocketUpgrade},
-    http::{header, StatusCode, Uri},
-    response::{IntoResponse, Response},
-    routing::get,
-    Extension, Router,
+    body::{boxed, Bytes, Full},
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Query,
+    },
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use clap::{CommandFactory, Parser};
+use cloud_console::{
+    logging::{self, log_error},
+    signature::{format_verifying_key, parse_signing_key, FileSigner},
+    Backpressure, ConsoleMux, HistoryStore, NewlineWriter, TokenBucket, CONNECTION_BUFFER,
 };
-use cloud_console::ConsoleMux;
 use futures::{sink::SinkExt, stream::StreamExt};
 use rust_embed::RustEmbed;
+use serde::{Deserialize, Serialize};
 use tokio::{
     fs::OpenOptions,
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::{mpsc, Mutex},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, mpsc, Mutex, Notify, Semaphore},
+    task::JoinHandle,
+};
+use tracing::{debug, error, info, warn, Instrument};
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, UNIX_EPOCH},
 };
-use tower_http::compression::CompressionLayer;
 
-use std::{net::SocketAddr, sync::Arc};
+use admin::AdminCommand;
+use audit::{AuditEvent, AuditLog, CommandLine};
+use budget::{BudgetedConsole, HistoryBudget};
+use capabilities::Capabilities;
+use clock::{Clock, TokioClock};
+use config::ServerConfig;
+use control::{ClientMessage, ServerMessage};
+use drain::{Drain, Session};
+use echo::{LocalEcho, PromptDetector};
+use forward::TcpForwarder;
+use hexdump::{BinaryDetector, HexDump};
+use history::{History, HistoryMode};
+use macros::Macro;
+use metrics::Metrics;
+#[cfg(feature = "otlp")]
+use otlp::Otlp;
+use pty::{HangupPolicy, PollReader, PtyReader, ThreadReader};
+#[cfg(doc)]
+use replay::ReplayRoute;
+use replay::{HEX_PATH, READ_ONLY_PATH};
+use resize::{SizeTracker, WinSize};
+use rotate::RotatingFile;
+use session::{SessionPty, SESSION_PATH};
+use status::StatusLine;
+use timestamp::TimestampingWriter;
+use title::TitleParser;
+use uuid::Uuid;
+use webhook::{LifecycleEvent, Webhook};
+
+mod access;
+mod admin;
+mod audit;
+mod budget;
+mod capabilities;
+mod clock;
+mod compression;
+mod config;
+mod control;
+mod drain;
+mod echo;
+mod forward;
+mod hexdump;
+mod history;
+mod macros;
+mod metrics;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod output;
+mod pty;
+mod replay;
+mod resize;
+mod rotate;
+mod schedule;
+mod session;
+mod status;
+mod timestamp;
+mod title;
+mod webhook;
 
-/// 80 columns, 2000 rows. Technically the Mux does not track rows but just a byte array. This is
-///    a sane default as such: a single column can contain up to 4 bytes (since it is unicode),
-///    however not all rows will be completely filled. Most will indeed only be partially used.
-///    As such, 40 bytes per line on average should be rather sufficient.
-const CONSOLE_BUFFER: usize = 80 / 2 * 2000;
 /// Amount of data fragments from remotes to buffer while forwarding to the pty. If there are more
 /// than this amount queued, new writes from remotes will block. Not sure if this is even needed.
 const WRITE_BACKLOG: usize = 100;
+/// Amount of control messages which can be queued for a client before it starts missing them.
+const EVENT_BACKLOG: usize = 16;
+/// Size of the screen model until a client reports its size.
+const DEFAULT_COLS: u16 = 80;
+const DEFAULT_ROWS: u16 = 24;
+/// Longest time the replay of the history to a client which advertised its bandwidth may take.
+/// The history is capped to what can be sent in this time.
+const REPLAY_TIME: Duration = Duration::from_secs(2);
+/// Interval at which chunks of the history are sent to a client which advertised its bandwidth.
+const REPLAY_INTERVAL: Duration = Duration::from_millis(100);
+/// Interval at which output held back because of the rate limit is sent.
+const PACE_INTERVAL: Duration = Duration::from_millis(50);
+/// Interval at which opening the pty is retried while waiting for it on startup, and the first
+/// interval after which a pty which failed is reopened.
+const PTY_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+/// Maximum interval between attempts to reopen a pty which failed.
+const PTY_REOPEN_MAX_INTERVAL: Duration = Duration::from_secs(10);
+/// Page served instead of the console while waiting for the pty, which reloads until the console
+/// is available.
+const WAITING_PAGE: &str = "<!DOCTYPE html><html><head><meta http-equiv=\"refresh\" content=\"2\">\
+<title>Waiting for console</title></head><body><p>Waiting for the console to become available\
+&hellip;</p></body></html>";
+/// Interval at which chunks of pasted input are written to the pty, if the paste rate is limited.
+const PASTE_INTERVAL: Duration = Duration::from_millis(10);
+/// Websocket subprotocol with which clients opt in to a replay of the newest history first.
+const TAIL_FIRST_PROTOCOL: &str = "cloud-console.tail-first";
+/// Reason given to clients of which the connection is closed because the pty can't be written to.
+const PTY_UNAVAILABLE: &str = "pty unavailable";
+/// Reason the connection of a client is closed when the console no longer sends it output.
+const CONSOLE_DETACHED: &str = "console detached";
+/// Reason the connections of the clients are closed when the pty can't be read anymore.
+const PTY_CLOSED: &str = "pty closed";
+/// Reason a client is dropped when the history can't be sent to it in time.
+const ATTACH_TIMED_OUT: &str = "attach timed out";
+/// Reason the connection of a client is closed when it didn't send input for too long.
+const IDLE_TIMEOUT: &str = "idle timeout";
+/// Reason of the close frame sent to clients which sent a message larger than `--max-input-frame`.
+const INPUT_TOO_LARGE: &str = "input frame too large";
+/// Maximum time to wait for a client to acknowledge the close of its connection, before dropping
+/// the connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Markers around a paste in bracketed paste mode.
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+/// Interval at which remotes which are gone are removed from the console.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+/// Interval at which metrics are exported to the OpenTelemetry collector.
+#[cfg(feature = "otlp")]
+const OTLP_INTERVAL: Duration = Duration::from_secs(10);
+/// Amount of output for a client after which coalesced output is sent without waiting further.
+const COALESCE_LIMIT: usize = 16 * 1024;
+/// Time the pty has to be idle before output held back to collapse repeated lines is written.
+const COLLAPSE_IDLE: Duration = Duration::from_millis(250);
+/// Interval at which the history budget is divided over the consoles again, see
+/// [`apply_history_budget`].
+const BUDGET_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(RustEmbed)]
 #[folder = "frontend/dist"]
@@ -34,229 +165,5283 @@ struct Assets;
 /// Application shared state between handlers.
 #[derive(Clone)]
 struct State {
-    inner: Arc<Mutex<ConsoleMux<CONSOLE_BUFFER>>>,
+    inner: Arc<Mutex<ConsoleMux<History>>>,
     data_sender: mpsc::Sender<Vec<u8>>,
+    /// Handle to the pty used for ioctls, if any. Not set while an idle pty is released.
+    pty: Arc<RwLock<Option<Arc<std::fs::File>>>>,
+    /// The loops forwarding data from and to the pty, if the state manages the pty.
+    pty_loops: Option<Arc<PtyLoops>>,
+    /// Terminal sizes reported by the connected clients.
+    sizes: Arc<Mutex<SizeTracker>>,
+    /// Control messages to be delivered to all connected clients.
+    events: broadcast::Sender<ServerMessage>,
+    next_client_id: Arc<AtomicU64>,
+    /// Set once the pty is confirmed to be functional.
+    ready: Arc<AtomicBool>,
+    /// Set while the console output shows a prompt for a secret, e.g. a password.
+    secret_prompt: Arc<AtomicBool>,
+    /// The last title set by the console, if title updates are enabled.
+    title: Arc<Mutex<Option<String>>>,
+    /// Set while the console output is mostly binary data, if binary detection is enabled.
+    binary: Arc<AtomicBool>,
+    config: Arc<ServerConfig>,
+    webhook: Option<Webhook>,
+    /// The audit log of `--audit-log`, opened on startup so a failure stops the server.
+    audit: Option<AuditLog>,
+    #[cfg(feature = "otlp")]
+    otlp: Option<Otlp>,
+    /// Sessions of connected clients, to drain the server before shutdown.
+    drain: Arc<Drain>,
+    /// Whether the pty accepts input. If not, the input of all clients is discarded.
+    pty_writable: Arc<AtomicBool>,
+    /// Set while waiting for the pty to appear on startup.
+    pty_waiting: Arc<AtomicBool>,
+    /// Limits the amount of clients the history is replayed to at the same time, if configured.
+    attach_gate: Option<Arc<Semaphore>>,
+    /// Source of time for timing dependent features.
+    clock: Arc<dyn Clock>,
+    /// Consoles of the other ptys served by the server, by session id.
+    sessions: Arc<RwLock<HashMap<String, State>>>,
+    /// Close frame sent to clients once the console detaches them, set when shutting down.
+    close_frame: Arc<RwLock<Option<CloseFrame<'static>>>>,
+    /// Total amount of bytes of input forwarded to the pty.
+    input_bytes: Arc<AtomicU64>,
+    /// Identifies this instance of the server, so entity tags of the buffer differ between
+    /// restarts.
+    instance: u64,
 }
 
 impl State {
     /// Create a new State with a default iniitalized ConsoleMux and the given channel write half
-    /// to forward data to the pty.
-    pub fn new(data_sender: mpsc::Sender<Vec<u8>>) -> State {
+    /// to forward data to the pty. `pty` is used to control the pty, e.g. to set the window size.
+    pub fn new(
+        data_sender: mpsc::Sender<Vec<u8>>,
+        pty: Option<std::fs::File>,
+        config: &ServerConfig,
+    ) -> State {
+        State::with_clock(data_sender, pty, config, Arc::new(TokioClock))
+    }
+
+    /// Create a new State like [`State::new`], using the given clock as source of time.
+    pub fn with_clock(
+        data_sender: mpsc::Sender<Vec<u8>>,
+        pty: Option<std::fs::File>,
+        config: &ServerConfig,
+        clock: Arc<dyn Clock>,
+    ) -> State {
+        let history = match (config.history_mode, config.history_lines) {
+            (HistoryMode::Lines, Some(lines)) => {
+                History::last_lines(lines, config.history_line_max)
+            }
+            (mode, _) => History::new(mode, config.buffer_size),
+        };
+        let mut console = ConsoleMux::with_store(history);
+        if config.recording_size > 0 {
+            console.enable_recording(config.recording_size);
+        }
+        if config.replay_screen || config.history_mode == HistoryMode::Screen {
+            console.enable_screen(DEFAULT_COLS, DEFAULT_ROWS);
+        }
+        if let Some(lines) = config.replay_lines {
+            console.limit_replay(lines);
+        }
+        if let Some(threshold) = config.collapse_repeats {
+            console.enable_collapse(threshold as usize, config.collapse_scope);
+        }
+        if config.plain_history {
+            console.enable_plain_history();
+        }
+        if config.attach_timeout > 0 {
+            console.limit_attach(Some(Duration::from_secs(config.attach_timeout)));
+        }
+        if config.send_wait > 0 {
+            console.limit_send_wait(Some(Duration::from_millis(config.send_wait)));
+        }
         State {
-            inner: Arc::new(Mutex::new(ConsoleMux::new())),
+            inner: Arc::new(Mutex::new(console)),
             data_sender,
+            pty: Arc::new(RwLock::new(pty.map(Arc::new))),
+            pty_loops: None,
+            sizes: Arc::new(Mutex::new(SizeTracker::new(config.resize_policy))),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            next_client_id: Arc::new(AtomicU64::new(0)),
+            ready: Arc::new(AtomicBool::new(false)),
+            secret_prompt: Arc::new(AtomicBool::new(false)),
+            title: Arc::new(Mutex::new(None)),
+            binary: Arc::new(AtomicBool::new(false)),
+            config: Arc::new(config.clone()),
+            webhook: config.webhook_url.clone().map(|url| {
+                let name = config.console_name();
+                Webhook::spawn(url, &config.webhook_events, &name, clock.clone())
+            }),
+            audit: None,
+            #[cfg(feature = "otlp")]
+            otlp: config
+                .otlp_endpoint
+                .as_ref()
+                .map(|endpoint| Otlp::spawn(endpoint, &config.console_name(), clock.clone())),
+            drain: Arc::new(Drain::new()),
+            pty_writable: Arc::new(AtomicBool::new(true)),
+            pty_waiting: Arc::new(AtomicBool::new(false)),
+            attach_gate: config
+                .attach_concurrency
+                .map(|permits| Arc::new(Semaphore::new(permits))),
+            instance: clock
+                .wall()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default(),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            close_frame: Arc::new(RwLock::new(None)),
+            input_bytes: Arc::new(AtomicU64::new(0)),
+            clock,
         }
     }
 
     /// Retrieve a reference to the ConsoleMux.
-    pub fn console(&self) -> Arc<Mutex<ConsoleMux<CONSOLE_BUFFER>>> {
+    pub fn console(&self) -> Arc<Mutex<ConsoleMux<History>>> {
         self.inner.clone()
     }
+
+    /// Collect the current metrics of the server.
+    async fn metrics(&self) -> Metrics {
+        let (total, dropped, fill, history) = {
+            let console = self.inner.lock().await;
+            let dropped = console.dropped_messages();
+            (
+                console.total_written(),
+                dropped,
+                console.queue_fill(),
+                console.len(),
+            )
+        };
+        let mut metrics = Metrics::new();
+        metrics
+            .bytes_written(total)
+            .bytes_forwarded(self.input_bytes.load(Ordering::Relaxed))
+            .dropped_messages(dropped)
+            .history_bytes(history)
+            .connections(self.drain.sessions())
+            .queue_fill(&fill);
+        metrics
+    }
+
+    /// Notify interested parties of a lifecycle event of a client.
+    fn notify(
+        &self,
+        event: LifecycleEvent,
+        client: SocketAddr,
+        correlation_id: &str,
+        reason: Option<String>,
+    ) {
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(event, client, correlation_id, reason);
+        }
+    }
+
+    /// Record an event of a client in the audit log, if enabled.
+    async fn audit(&self, client: u64, addr: SocketAddr, correlation_id: &str, event: AuditEvent) {
+        if let Some(audit) = &self.audit {
+            audit.record(client, addr, correlation_id, event).await;
+        }
+    }
+
+    /// Record the command lines submitted with input of a client in the audit log, if enabled.
+    /// Lines submitted while the console prompts for a secret are not recorded.
+    async fn audit_input(
+        &self,
+        client: u64,
+        addr: SocketAddr,
+        correlation_id: &str,
+        line: &std::sync::Mutex<CommandLine>,
+        input: &[u8],
+    ) {
+        if self.audit.is_none() {
+            return;
+        }
+        let secret = self.secret_prompt.load(Ordering::Relaxed);
+        let submitted = line.lock().unwrap().feed(input);
+        for command in submitted {
+            let event = match secret {
+                true => AuditEvent::Secret,
+                false => AuditEvent::Command { command },
+            };
+            self.audit(client, addr, correlation_id, event).await;
+        }
+    }
+
+    /// Forward input of a client to the pty. `client_tx` is the output channel of the client, used
+    /// for local echo.
+    async fn forward_input(
+        &self,
+        input: Vec<u8>,
+        client_tx: &mpsc::WeakSender<Arc<[u8]>>,
+    ) -> Result<(), PtyUnavailable> {
+        let echo = self.local_echo(&input);
+        self.write_pty(input).await?;
+        if echo.is_empty() {
+            return Ok(());
+        }
+        match self.config.local_echo {
+            LocalEcho::Off => {}
+            // Like regular console output, echo is dropped if the client is lagging.
+            LocalEcho::Sender => {
+                if let Some(client_tx) = client_tx.upgrade() {
+                    let _ = client_tx.try_send(Arc::from(echo));
+                }
+            }
+            LocalEcho::All => self.inner.lock().await.write_data(&echo),
+        }
+        Ok(())
+    }
+
+    /// Forward input of a client to the pty like [`State::forward_input`]. If `paste` is set, the
+    /// input is part of a paste, and `paste` tells whether the paste is bracketed. Pasted input is
+    /// paced to the paste rate, and bracketed pastes have escape characters removed, so the paste
+    /// can't end the bracketed paste early.
+    async fn forward_client_input(
+        &self,
+        mut input: Vec<u8>,
+        paste: Option<bool>,
+        client_tx: &mpsc::WeakSender<Arc<[u8]>>,
+    ) -> Result<(), PtyUnavailable> {
+        if paste == Some(true) {
+            input.retain(|&b| b != 0x1b);
+        }
+        let rate = match (paste, self.config.paste_rate) {
+            (Some(_), Some(rate)) => rate,
+            _ => return self.forward_input(input, client_tx).await,
+        };
+        let chunk = (rate as u128 * PASTE_INTERVAL.as_millis() / 1000).max(1) as usize;
+        for chunk in input.chunks(chunk) {
+            self.forward_input(chunk.to_vec(), client_tx).await?;
+            self.clock.sleep(PASTE_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    /// Shut down gracefully: refuse new clients, and close the connections of connected clients
+    /// once the output buffered for them is delivered, with the given close frame. The log file
+    /// receives its buffered output as well.
+    async fn shutdown(&self, frame: CloseFrame<'static>) {
+        self.drain.start();
+        let sessions: Vec<_> = self.sessions.read().unwrap().values().cloned().collect();
+        for state in std::iter::once(self).chain(&sessions) {
+            *state.close_frame.write().unwrap() = Some(frame.clone());
+            state.inner.lock().await.shutdown().await;
+        }
+    }
+
+    /// Serve the console of another pty as session `id`. The session is drained along with this
+    /// console.
+    fn register_session(&self, id: String, mut session: State) {
+        session.drain = self.drain.clone();
+        self.sessions.write().unwrap().insert(id, session);
+    }
+
+    /// The console of the session with the given id, if any.
+    fn session(&self, id: &str) -> Option<State> {
+        self.sessions.read().unwrap().get(id).cloned()
+    }
+
+    /// Check if a client presented a valid token, logging the attempt if not.
+    fn authorized(&self, headers: &HeaderMap, token: Option<&str>, client: SocketAddr) -> bool {
+        let authorized = access::authorized(headers, token, &self.config.auth_token);
+        if !authorized {
+            log_error(
+                "unauthorized",
+                format!("Rejected client {} without a valid token", client),
+            );
+        }
+        authorized
+    }
+
+    /// Check the token of a request to an HTTP endpoint, see [`State::authorized`], returning the
+    /// response to reject it with if it is not authorized.
+    fn check_token(
+        &self,
+        peer: SocketAddr,
+        headers: &HeaderMap,
+        token: Option<&str>,
+    ) -> Option<Response> {
+        let ip = access::client_ip(peer.ip(), headers, &self.config.trusted_proxy);
+        match self.authorized(headers, token, SocketAddr::new(ip, peer.port())) {
+            true => None,
+            false => Some(unauthorized()),
+        }
+    }
+
+    /// The handle to the pty used for ioctls, if any.
+    fn pty(&self) -> Option<Arc<std::fs::File>> {
+        self.pty.read().unwrap().clone()
+    }
+
+    /// Open the pty, and start forwarding its output to the console and input to it, unless the
+    /// pty is already open or not managed by this state.
+    async fn acquire_pty(&self) -> std::io::Result<()> {
+        let loops = match &self.pty_loops {
+            Some(loops) => loops,
+            None => return Ok(()),
+        };
+        let mut tasks = loops.tasks.lock().await;
+        if !tasks.is_empty() {
+            return Ok(());
+        }
+        let (reader, writer) = self.open_pty().await?;
+        let input = loops.input.clone();
+        tasks.push(tokio::spawn(serve_pty(reader, writer, input, self.clone())));
+        Ok(())
+    }
+
+    /// Open the pty, keeping a handle for ioctls. Returns the handles to read from and, unless it
+    /// is served read only, write to the pty.
+    async fn open_pty(&self) -> std::io::Result<(PtyOutput, Option<tokio::fs::File>)> {
+        let (reader, writer) = open_pty(self.config.pty(), self.config.read_only_fallback).await?;
+        // Duplicate the read handle for ioctls, the other handles are moved into their loops.
+        let control = reader.try_clone().await?.into_std().await;
+        let reader: PtyOutput = match self.config.pty_reader {
+            PtyReader::Async => Box::new(reader),
+            PtyReader::Thread => Box::new(ThreadReader::spawn(
+                reader.into_std().await,
+                self.config.read_buffer as usize,
+            )),
+            PtyReader::Poll => Box::new(PollReader::new(reader.into_std().await)?),
+        };
+        *self.pty.write().unwrap() = Some(Arc::new(control));
+        self.pty_writable.store(writer.is_some(), Ordering::Relaxed);
+        Ok((reader, writer))
+    }
+
+    /// Close the pty and stop the loops forwarding data from and to it, unless clients are
+    /// connected. Returns whether the pty was released.
+    async fn release_pty(&self) -> bool {
+        let loops = match &self.pty_loops {
+            Some(loops) => loops,
+            None => return false,
+        };
+        // Clients acquire the pty after their session started, so this can't race with a client
+        // which is connecting.
+        let mut tasks = loops.tasks.lock().await;
+        if tasks.is_empty() || self.drain.sessions() > 0 {
+            return false;
+        }
+        // The handles to the pty are closed once the loops owning them are dropped.
+        for task in tasks.drain(..) {
+            task.abort();
+        }
+        *self.pty.write().unwrap() = None;
+        true
+    }
+
+    /// Send the EOF character of the pty, so the program reading it sees the end of its input.
+    async fn send_eof(&self) -> Result<(), PtyUnavailable> {
+        let eof = match &self.pty() {
+            Some(pty) => pty::eof_char(&**pty).unwrap_or_else(|e| {
+                warn!("Could not get the EOF character of the pty {}", e);
+                pty::DEFAULT_EOF
+            }),
+            None => pty::DEFAULT_EOF,
+        };
+        self.write_pty(vec![eof]).await
+    }
+
+    /// Send a hangup to the foreground process group of the pty, if the pty is open.
+    fn hangup(&self) {
+        if let Some(pty) = &self.pty() {
+            if let Err(e) = pty::hangup(&**pty) {
+                warn!("Could not send hangup to the pty {}", e);
+            }
+        }
+    }
+
+    /// Write data to the pty as is, without echo.
+    async fn write_pty(&self, data: Vec<u8>) -> Result<(), PtyUnavailable> {
+        let len = data.len() as u64;
+        self.data_sender.send(data).await.map_err(|e| {
+            error!("Could not send data to pty forwarder {}", e);
+            PtyUnavailable
+        })?;
+        self.input_bytes.fetch_add(len, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Get the local echo for client input, which is empty if local echo is disabled. Input is
+    /// not echoed while the console prompts for a secret. Since the secret is submitted with a
+    /// newline, a newline in the input ends the prompt again.
+    fn local_echo(&self, input: &[u8]) -> Vec<u8> {
+        if self.config.local_echo == LocalEcho::Off {
+            return Vec::new();
+        }
+        if !self.secret_prompt.load(Ordering::Relaxed) {
+            return echo::echo_bytes(input);
+        }
+        if input.iter().any(|&b| b == b'\r' || b == b'\n') {
+            self.secret_prompt.store(false, Ordering::Relaxed);
+            return b"\r\n".to_vec();
+        }
+        Vec::new()
+    }
+
+    /// Record the terminal size reported by a client, and update the pty if needed.
+    async fn client_resized(&self, client: u64, size: WinSize) {
+        let mut sizes = self.sizes.lock().await;
+        let change = sizes.update(client, size);
+        self.apply_winsize(change).await;
+    }
+
+    /// Forget the terminal size of a client which disconnected, and update the pty if needed.
+    async fn client_left(&self, client: u64) {
+        let mut sizes = self.sizes.lock().await;
+        let change = sizes.remove(client);
+        self.apply_winsize(change).await;
+    }
+
+    /// Apply a change reported by the [`SizeTracker`] to the pty, and notify all clients. This
+    /// must be called with the size tracker locked, so changes are applied in order.
+    async fn apply_winsize(&self, change: Option<(WinSize, bool)>) {
+        let (size, mismatch) = match change {
+            Some(change) => change,
+            None => return,
+        };
+        if let Some(pty) = &self.pty() {
+            if let Err(e) = resize::set_winsize(&**pty, size) {
+                warn!("Could not set pty window size {}", e);
+            }
+        }
+        self.inner.lock().await.resize_screen(size.cols, size.rows);
+        // An error only means there are no clients connected at the moment.
+        let _ = self.events.send(ServerMessage::from((size, mismatch)));
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let mut args = std::env::args();
-    // Binary name - ignore
-    let _ = args.next();
-    let pty = if let Some(pty) = args.next() {
-        pty
-    } else {
-        print_usage_and_exit()
-    };
-    let bind_ip = if let Some(bind_ip) = args.next() {
-        bind_ip
-    } else {
-        print_usage_and_exit()
-    };
-    let bind_port = if let Some(bind_port) = args.next() {
-        bind_port
-    } else {
-        print_usage_and_exit()
-    };
-    // Optional log file
-    let log_file = args.next();
-    let addr = SocketAddr::new(bind_ip.parse().unwrap(), bind_port.parse().unwrap());
+    let mut config = ServerConfig::parse();
+    logging::init();
+    if config.positional_args() {
+        warn!("Positional arguments are deprecated, use --pty, --bind and --log-file instead");
+    }
+    if let Err(e) = config.compression() {
+        ServerConfig::command()
+            .error(clap::error::ErrorKind::ValueValidation, e)
+            .exit();
+    }
+    if config.idle_release.is_some() && config.pty_reader != PtyReader::Poll {
+        ServerConfig::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--idle-release requires --pty-reader poll",
+            )
+            .exit();
+    }
+    if config.history_lines.is_some() && config.history_mode != HistoryMode::Lines {
+        ServerConfig::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--history-lines requires --history-mode lines",
+            )
+            .exit();
+    }
+    if config.reconnect_max_delay < config.reconnect_delay {
+        ServerConfig::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--reconnect-max-delay can't be shorter than --reconnect-delay",
+            )
+            .exit();
+    }
+    for (i, route) in config.replay_route.iter().enumerate() {
+        if config.replay_route[..i]
+            .iter()
+            .any(|r| r.path == route.path)
+        {
+            ServerConfig::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("--replay-route {} is given more than once", route.path),
+                )
+                .exit();
+        }
+    }
+    for (i, session) in config.session.iter().enumerate() {
+        if config.session[..i].iter().any(|s| s.id == session.id) {
+            ServerConfig::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("--session {} is given more than once", session.id),
+                )
+                .exit();
+        }
+    }
+    if let Some(path) = &config.auth_token_file {
+        let tokens = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| access::parse_tokens(&contents))
+            .unwrap_or_else(|e| {
+                error!("Could not load auth tokens {}: {}", path.display(), e);
+                std::process::exit(1);
+            });
+        config.auth_token.extend(tokens);
+    }
+    let audit = config.audit_log.as_ref().map(|path| {
+        AuditLog::open(path, config.audit_hash_chain, Arc::new(TokioClock)).unwrap_or_else(|e| {
+            error!("Could not open audit log {}: {}", path.display(), e);
+            std::process::exit(1);
+        })
+    });
+    let addr = config.bind();
 
-    // Open the pty file handle twice, one for reading and one for writing. Opening it in both read
-    // + write, then calling `.split()` on it seems to resuld in a deadlock somehow.
-    let mut reader = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .create(false)
-        .truncate(false)
-        .open(&pty)
-        .await
-        .unwrap();
-    let mut writer = OpenOptions::new()
-        .read(false)
-        .write(true)
-        .create(false)
-        .truncate(false)
-        .open(pty)
-        .await
-        .unwrap();
+    let (tx, rx) = mpsc::channel::<Vec<u8>>(WRITE_BACKLOG);
+    let mut state = State::new(tx, None, &config);
+    state.pty_loops = Some(Arc::new(PtyLoops::new(rx)));
+    state.audit = audit;
+    // While waiting for the pty, the server is already started so it can report its status.
+    state.pty_waiting.store(true, Ordering::Relaxed);
+    tokio::spawn({
+        let state = state.clone();
+        let wait = Duration::from_secs(config.pty_wait);
+        async move {
+            if let Err(e) = wait_for_pty(&state, wait).await {
+                error!("Could not open pty {}: {}", state.config.pty().display(), e);
+                std::process::exit(1);
+            }
+        }
+    });
 
-    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(WRITE_BACKLOG);
+    if let Some(idle) = config.idle_release {
+        tokio::spawn(release_idle_pty(state.clone(), Duration::from_secs(idle)));
+    }
 
-    // Loop to forward console data to pty.
-    tokio::spawn(async move {
-        while let Some(data) = rx.recv().await {
-            if let Err(e) = writer.write_all(&data).await {
-                // Consider this to be fatal
-                eprintln!("Could not forward data to pty {}", e);
-                std::process::exit(2);
+    for session in &config.session {
+        if let Err(e) = spawn_session(&state, session).await {
+            error!(
+                "Could not open pty {} of session {}: {}",
+                session.path.display(),
+                session.id,
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(budget) = config.history_budget {
+        tokio::spawn({
+            let state = state.clone();
+            let mut budget = HistoryBudget::new(budget);
+            async move {
+                loop {
+                    apply_history_budget(&state, &mut budget).await;
+                    state.clock.sleep(BUDGET_INTERVAL).await;
+                }
+            }
+        });
+    }
+
+    // Safety net for remotes which hang, e.g. because of a deadlock.
+    if config.stuck_timeout > 0 {
+        tokio::spawn({
+            let state = state.clone();
+            let max_stall = Duration::from_secs(config.stuck_timeout);
+            async move {
+                loop {
+                    state.clock.sleep(max_stall / 2).await;
+                    for remote in state.inner.lock().await.detach_stuck(max_stall) {
+                        warn!("Detached remote {} which stopped accepting output", remote);
+                    }
+                }
+            }
+        });
+    }
+
+    // Clean up after clients which left while the console is idle.
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            loop {
+                state.clock.sleep(PRUNE_INTERVAL).await;
+                state.inner.lock().await.prune_closed();
             }
         }
     });
 
-    let state = State::new(tx);
-    let console = state.console();
-    // Loop to forward pty data to console mux
-    tokio::spawn(async move {
-        // TODO: good buffer size?
-        let mut buffer = [0; 320];
-        loop {
-            let n = match reader.read(&mut buffer).await {
-                Ok(n) => n,
-                Err(e) => {
-                    // This cleanup is not ideal but sufficient for our usecase
-                    eprintln!("Could not read from pty {}", e);
-                    std::process::exit(2);
+    // Periodically export the metrics to the OpenTelemetry collector.
+    #[cfg(feature = "otlp")]
+    if let Some(otlp) = state.otlp.clone() {
+        tokio::spawn({
+            let state = state.clone();
+            async move {
+                loop {
+                    state.clock.sleep(OTLP_INTERVAL).await;
+                    otlp.export_metrics(&state.metrics().await);
                 }
-            };
-            // Forward data to console mux.
-            console.lock().await.write_data(&buffer[..n]);
-        }
+            }
+        });
+    }
+
+    // Pace the output sent to clients while the rate is limited.
+    if let Some(rate) = config.rate_limit {
+        tokio::spawn({
+            let state = state.clone();
+            let hours = config.rate_limit_hours;
+            async move {
+                let mut limited = false;
+                loop {
+                    let limit = hours.is_none_or(|hours| hours.contains(state.clock.wall()));
+                    let mut console = state.inner.lock().await;
+                    if limit != limited {
+                        console.limit_rate(limit.then(|| TokenBucket::new(rate, rate)));
+                        limited = limit;
+                    }
+                    console.pace_output();
+                    drop(console);
+                    state.clock.sleep(PACE_INTERVAL).await;
+                }
+            }
+        });
+    }
+
+    // Sign the log file, so it can be shown it wasn't changed afterwards.
+    let signer = config.log_signing_key.as_ref().map(|path| {
+        let key = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|key| parse_signing_key(&key).ok_or_else(|| "invalid key".to_string()))
+            .unwrap_or_else(|e| {
+                error!("Could not load signing key {}: {}", path.display(), e);
+                std::process::exit(1);
+            });
+        info!(
+            "Signing log file with public key {}",
+            format_verifying_key(&key.verifying_key())
+        );
+        // The key requires a log file.
+        let log_file = config.log_file().unwrap();
+        Arc::new(std::sync::Mutex::new(FileSigner::new(key, log_file)))
     });
 
     // If there is a log file, attach it to the mux to receive the console output as well.
-    if let Some(log_file) = log_file {
-        let file = OpenOptions::new()
-            .read(false)
-            .create(true)
-            .truncate(false)
-            .append(true)
-            .open(log_file)
-            .await
-            .unwrap();
-        state.inner.lock().await.attach_remote(file).await;
+    if let Some(log_file) = config.log_file() {
+        if let Err(e) = attach_log_file(&state, log_file, signer.clone()).await {
+            error!("Could not write to log file {}: {}", log_file.display(), e);
+            std::process::exit(1);
+        }
     };
 
-    let app = Router::new()
-        .route("/", get(index))
-        .route("/ws", get(handler))
-        .fallback(get(static_handler))
-        .layer(CompressionLayer::new())
-        .layer(Extension(state));
+    // Periodically sign the log file as well.
+    if let Some(signer) = signer.clone() {
+        let state = state.clone();
+        let interval = Duration::from_secs(config.log_sign_interval as u64);
+        tokio::spawn(async move {
+            loop {
+                state.clock.sleep(interval).await;
+                sign_log_file(signer.clone()).await;
+            }
+        });
+    }
+
+    if let Some(path) = &config.mirror_pty {
+        attach_mirror(&state, path).await;
+    }
+
+    if let Some(addr) = &config.forward_tcp {
+        let forwarder = TcpForwarder::spawn(addr.clone(), config.forward_buffer);
+        if let Err(e) = state.inner.lock().await.attach_remote(forwarder).await {
+            warn!("Could not forward the history to {}: {}", addr, e);
+        }
+    }
+
+    if let Some(addr) = config.tcp_bind {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                tokio::spawn(serve_tcp(listener, state.clone()));
+            }
+            Err(e) => {
+                error!("Could not listen for TCP connections on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &config.control_socket {
+        match bind_control_socket(path) {
+            Ok(listener) => {
+                tokio::spawn(serve_control_socket(listener, state.clone()));
+            }
+            Err(e) => {
+                error!(
+                    "Could not listen on control socket {}: {}",
+                    path.display(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Shut down on Ctrl-C, without losing output which is on its way to clients.
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                let frame = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: CONSOLE_DETACHED.into(),
+                };
+                state.shutdown(frame).await;
+            }
+        }
+    });
+    // Drain the server on SIGTERM, so orchestrators can stop it without interrupting sessions.
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    tokio::spawn({
+        let drain = state.drain.clone();
+        async move {
+            sigterm.recv().await;
+            drain.start();
+        }
+    });
+    let drain = state.drain.clone();
+    let drain_timeout = Duration::from_secs(config.drain_timeout);
 
     //tokio::task::spawn(async move {
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app(state.clone()).into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move { drain.finished(drain_timeout).await })
         .await
         .unwrap();
-}
 
-async fn handler(ws: WebSocketUpgrade, Extension(state): Extension<State>) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    if config.hangup != HangupPolicy::Never {
+        state.hangup();
+    }
+
+    // Write the remaining output to the log file and finish it, before it is signed.
+    state.inner.lock().await.shutdown().await;
+    if let Some(signer) = signer {
+        sign_log_file(signer).await;
+    }
 }
 
-async fn handle_socket(socket: WebSocket, state: State) {
-    // Split socket in a tx and rx pair.
-    let (mut sender, receiver) = socket.split();
-    // Attach tx pair to console.
-    // Since SplitSink only implements futures-sink::Sink, we need a converter. Do in-memory for
-    // now.
-    // TODO: good channel capacity;
-    let (tx, mut rx) = mpsc::channel::<Arc<Vec<u8>>>(1000);
+/// The pty can't be written to anymore, because the loop forwarding input to it stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PtyUnavailable;
 
-    tokio::spawn(async move {
-        while let Some(buf) = rx.recv().await {
-            if let Err(e) = sender
-                .send(axum::extract::ws::Message::Binary(buf.to_vec()))
-                .await
-            {
-                eprintln!("Could not send buffer to websocket {}", e);
-                // Try to close the socket so the other half is also closed for automatic cleanup.
-                // We don't care about errors here
-                let _ = sender.close().await;
-                return;
-            };
+/// Reads the output of the pty, with the reader selected by `--pty-reader`.
+type PtyOutput = Box<dyn AsyncRead + Unpin + Send>;
+
+/// The loops forwarding data from and to the pty, which are stopped while an idle pty is released.
+#[derive(Debug)]
+struct PtyLoops {
+    /// Input for the pty, shared so the writer loop can be started again.
+    input: Arc<Mutex<mpsc::Receiver<Vec<u8>>>>,
+    /// The running loops, empty while the pty is released.
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl PtyLoops {
+    fn new(input: mpsc::Receiver<Vec<u8>>) -> PtyLoops {
+        PtyLoops {
+            input: Arc::new(Mutex::new(input)),
+            tasks: Mutex::new(Vec::new()),
         }
-    });
-    state.inner.lock().await.attach_channel(tx).await;
+    }
+}
 
-    tokio::spawn({
-        async move {
-            receiver
-                .for_each(|msg| async {
-                    if let Ok(msg) = msg {
-                        match msg {
-                            axum::extract::ws::Message::Binary(d) => {
-                                if let Err(e) = state.data_sender.send(d).await {
-                                    eprintln!("Could not send data to pty forwarder {}", e);
-                                }
-                            }
-                            axum::extract::ws::Message::Text(t) => {
-                                if let Err(e) = state.data_sender.send(t.into_bytes()).await {
-                                    eprintln!("Could not send data to pty forwarder {}", e);
-                                }
-                            }
-                            m => {
-                                eprintln!("Unsupported websocket message {:?}", m);
-                            }
-                        };
-                    };
-                })
-                .await;
+/// Sign the current contents of the log file.
+async fn sign_log_file(signer: Arc<std::sync::Mutex<FileSigner>>) {
+    let signed = tokio::task::spawn_blocking(move || signer.lock().unwrap().sign()).await;
+    if let Ok(Err(e)) = signed {
+        error!("Could not sign log file {}", e);
+    }
+}
+
+/// Open the pty, retrying for up to `wait` while it can't be opened. Once it is opened, the console
+/// is considered ready after the ready timeout, even if it stays silent.
+async fn wait_for_pty(state: &State, wait: Duration) -> std::io::Result<()> {
+    let deadline = state.clock.now() + wait;
+    loop {
+        match state.acquire_pty().await {
+            Ok(()) => break,
+            Err(e) if state.clock.now() >= deadline => return Err(e),
+            Err(_) => state.clock.sleep(PTY_RETRY_INTERVAL).await,
         }
+    }
+    state.pty_waiting.store(false, Ordering::Relaxed);
+    let timeout = state
+        .clock
+        .sleep(Duration::from_secs(state.config.ready_timeout));
+    let ready = state.ready.clone();
+    tokio::spawn(async move {
+        timeout.await;
+        ready.store(true, Ordering::Relaxed);
     });
+    Ok(())
 }
 
-/// Prints usage instructions to stdandard error, and exists the process with an error code.
-fn print_usage_and_exit() -> ! {
-    eprintln!(
-        r#"Cloud console - An interactive web based terminal connected to a pty
-    Usage:
-        cloud-console <path_to_pty> <bind_ip> <bind_port> [<log_file>]"#
-    );
-    std::process::exit(1);
+/// Open the pty of a session, and serve it next to the console of `state`. Only the console itself
+/// is served for a session: the log file, audit log and webhook only cover the main pty, and the
+/// output of a session is not paced, checked for stuck clients or exported.
+async fn spawn_session(state: &State, session: &SessionPty) -> std::io::Result<()> {
+    let mut config = (*state.config).clone();
+    config.set_pty(session.path.clone());
+    config.name = Some(session.id.clone());
+    if let Some(size) = session.buffer_size {
+        config.buffer_size = size;
+    }
+    config.disable_log_file();
+    config.webhook_url = None;
+    #[cfg(feature = "otlp")]
+    {
+        config.otlp_endpoint = None;
+    }
+    let (tx, rx) = mpsc::channel(WRITE_BACKLOG);
+    let mut console = State::with_clock(tx, None, &config, state.clock.clone());
+    console.pty_loops = Some(Arc::new(PtyLoops::new(rx)));
+    console.acquire_pty().await?;
+    console.ready.store(true, Ordering::Relaxed);
+    state.register_session(session.id.clone(), console);
+    Ok(())
 }
 
-/// Handle index
-async fn index() -> impl IntoResponse {
-    static_handler("/index.html".parse::<Uri>().unwrap()).await
+/// Release the pty once the console had no clients and no output for `idle`. The pty is acquired
+/// again when a client connects.
+async fn release_idle_pty(state: State, idle: Duration) {
+    let mut written = state.inner.lock().await.total_written();
+    let mut active = state.clock.now();
+    loop {
+        state.clock.sleep(idle / 2).await;
+        let total = state.inner.lock().await.total_written();
+        if total != written || state.drain.sessions() > 0 {
+            written = total;
+            active = state.clock.now();
+        } else if state.clock.now() - active >= idle && state.release_pty().await {
+            info!("Released pty after {:?} without clients or output", idle);
+        }
+    }
 }
 
-/// Handle static files
-async fn static_handler(uri: Uri) -> impl IntoResponse {
-    let path = uri.path().trim_start_matches('/').to_string();
-
-    StaticFile(path)
+/// Resize the history of the console and its sessions to their share of the history budget. The
+/// consoles which need to shrink are resized first, so the total stays within the budget.
+async fn apply_history_budget(state: &State, budget: &mut HistoryBudget) {
+    let mut sessions: Vec<_> = (state.sessions.read().unwrap().iter())
+        .map(|(id, session)| (id.clone(), session.clone()))
+        .collect();
+    // In a stable order, so the budget prefers the same consoles among equally active ones.
+    sessions.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut states = vec![(String::new(), state.clone())];
+    states.extend(sessions);
+    let mut consoles = Vec::with_capacity(states.len());
+    let mut capacities = Vec::with_capacity(states.len());
+    for (id, state) in &states {
+        let console = state.inner.lock().await;
+        consoles.push(BudgetedConsole {
+            id: id.clone(),
+            size: state.config.buffer_size,
+            total_written: console.total_written(),
+        });
+        capacities.push(console.store().capacity());
+    }
+    let sizes = budget.allocate(&consoles);
+    let (shrink, grow): (Vec<_>, Vec<_>) = states
+        .iter()
+        .zip(sizes)
+        .zip(capacities)
+        .filter(|((_, size), capacity)| size != capacity)
+        .partition(|((_, size), capacity)| size < capacity);
+    for (((_, state), size), _) in shrink.into_iter().chain(grow) {
+        state
+            .inner
+            .lock()
+            .await
+            .update_store(|store| store.resize(size));
+    }
 }
 
-pub struct StaticFile<T>(pub T);
+/// Write input to the pty, until writing fails.
+async fn forward_pty_input(
+    mut writer: tokio::fs::File,
+    input: &Mutex<mpsc::Receiver<Vec<u8>>>,
+) -> std::io::Error {
+    let mut input = input.lock().await;
+    while let Some(data) = input.recv().await {
+        if let Err(e) = writer.write_all(&data).await {
+            return e;
+        }
+    }
+    // The input is only closed along with the console.
+    std::future::pending().await
+}
 
-impl<T> IntoResponse for StaticFile<T>
-where
-    T: Into<String>,
-{
-    fn into_response(self) -> Response {
-        let path = self.0.into();
+/// Open the pty for reading and writing. The pty is opened twice, one for reading and one for
+/// writing. Opening it in both read + write, then calling `.split()` on it seems to resuld in a
+/// deadlock somehow. If the pty can't be opened for writing and `read_only_fallback` is set, only
+/// the read handle is returned.
+async fn open_pty(
+    path: &Path,
+    read_only_fallback: bool,
+) -> std::io::Result<(tokio::fs::File, Option<tokio::fs::File>)> {
+    let reader = OpenOptions::new()
+        .read(true)
+        .write(false)
+        .create(false)
+        .truncate(false)
+        .open(path)
+        .await?;
+    let writer = OpenOptions::new()
+        .read(false)
+        .write(true)
+        .create(false)
+        .truncate(false)
+        .open(path)
+        .await;
+    match writer {
+        Ok(writer) => Ok((reader, Some(writer))),
+        Err(e) if read_only_fallback => {
+            warn!(
+                "Could not open pty {} for writing, serving it read only: {}",
+                path.display(),
+                e
+            );
+            Ok((reader, None))
+        }
+        Err(e) => Err(e),
+    }
+}
 
-        match Assets::get(path.as_str()) {
-            Some(content) => {
-                let body = boxed(Full::from(content.data));
-                let mime = mime_guess::from_path(path).first_or_octet_stream();
+/// Open the log file at `path`, and attach it to the console with the rotation, compression,
+/// timestamps, line endings and buffering from the config. A rotated file is signed by `signer`
+/// before it is rotated, if given. Fails if the file can't be opened or the history can't be
+/// written to it.
+async fn attach_log_file(
+    state: &State,
+    path: &Path,
+    signer: Option<Arc<std::sync::Mutex<FileSigner>>>,
+) -> std::io::Result<()> {
+    let config = &state.config;
+    let file: Box<dyn AsyncWrite + Unpin + Send> = match config.log_max_size {
+        Some(max_size) => {
+            let file = RotatingFile::open(path, max_size as u64, config.log_max_files).await?;
+            match signer {
+                Some(signer) => Box::new(file.signed_by(signer)),
+                None => Box::new(file),
+            }
+        }
+        None => {
+            let file = OpenOptions::new()
+                .read(false)
+                .create(true)
+                .truncate(false)
+                .append(true)
+                .open(path)
+                .await?;
+            match config.log_compress {
+                Some(compression) => compression.writer(file),
+                None => Box::new(file),
+            }
+        }
+    };
+    let file: Box<dyn AsyncWrite + Unpin + Send> = match config.log_timestamps {
+        true => Box::new(TimestampingWriter::new(file, state.clock.clone())),
+        false => file,
+    };
+    let mut console = state.inner.lock().await;
+    let (buffer, backpressure) = (config.log_buffer, config.log_backpressure);
+    match config.log_line_endings {
+        Some(ending) => {
+            let file = NewlineWriter::new(file, ending);
+            console.attach_sink(file, buffer, backpressure).await?;
+        }
+        None => {
+            console.attach_sink(file, buffer, backpressure).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Attach the device at `path` to the console, so it receives all output. The console is served
+/// without the mirror if the device can't be opened for writing.
+async fn attach_mirror(state: &State, path: &Path) {
+    let mirror = OpenOptions::new()
+        .read(false)
+        .write(true)
+        .create(false)
+        .truncate(false)
+        .open(path)
+        .await;
+    let mut mirror = match mirror {
+        Ok(mirror) => mirror,
+        Err(e) => {
+            warn!(
+                "Could not open mirror {}, not mirroring: {}",
+                path.display(),
+                e
+            );
+            return;
+        }
+    };
+    // Writes to a device block while it is not read, so the history is queued on a channel
+    // rather than written while the console is locked.
+    let (tx, mut rx) = mpsc::channel::<Arc<[u8]>>(CONNECTION_BUFFER);
+    state.inner.lock().await.attach_channel(tx).await;
+    tokio::spawn(async move {
+        while let Some(data) = rx.recv().await {
+            if let Err(e) = mirror.write_all(&data).await {
+                warn!("Could not write to mirror, not mirroring anymore: {}", e);
+                return;
+            }
+        }
+    });
+}
+
+/// Forward the output of the pty to the console mux and the input of the console to the pty. Once
+/// the pty fails, it is reopened up to `--pty-reopen-attempts` times, after which the process
+/// exits.
+async fn serve_pty(
+    mut reader: PtyOutput,
+    mut writer: Option<tokio::fs::File>,
+    input: Arc<Mutex<mpsc::Receiver<Vec<u8>>>>,
+    state: State,
+) {
+    loop {
+        let input = async {
+            match writer {
+                Some(writer) => forward_pty_input(writer, &input).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            e = forward_pty_output(reader, state.clone()) => {
+                error!("Could not read from pty {}", e);
+            }
+            e = input => error!("Could not forward data to pty {}", e),
+        }
+        (reader, writer) = match reopen_pty(&state).await {
+            Some(pty) => pty,
+            None => {
+                pty_closed(&state).await;
+                std::process::exit(2);
+            }
+        };
+    }
+}
+
+/// Reopen the pty after it failed, waiting longer after every failed attempt. Clients stay
+/// connected with their history meanwhile. Returns `None` if the pty couldn't be reopened within
+/// `--pty-reopen-attempts` attempts.
+async fn reopen_pty(state: &State) -> Option<(PtyOutput, Option<tokio::fs::File>)> {
+    *state.pty.write().unwrap() = None;
+    let attempts = state.config.pty_reopen_attempts;
+    let mut delay = PTY_RETRY_INTERVAL;
+    for attempt in 1..=attempts {
+        state.clock.sleep(delay).await;
+        match state.open_pty().await {
+            Ok(pty) => {
+                info!("Reopened pty {}", state.config.pty().display());
+                return Some(pty);
+            }
+            Err(e) => warn!(
+                "Could not reopen pty {} (attempt {}/{}): {}",
+                state.config.pty().display(),
+                attempt,
+                attempts,
+                e
+            ),
+        }
+        delay = (delay * 2).min(PTY_REOPEN_MAX_INTERVAL);
+    }
+    None
+}
+
+/// Close the connections of all clients because the pty failed, telling them why.
+async fn pty_closed(state: &State) {
+    let frame = CloseFrame {
+        code: close_code::ERROR,
+        reason: PTY_CLOSED.into(),
+    };
+    // Deliver the output read so far before exiting.
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, state.shutdown(frame)).await;
+    state.drain.finished(CLOSE_TIMEOUT).await;
+}
+
+/// Read data from the pty and forward it to the console mux, until reading fails. The console is
+/// marked as ready once the first data has been read.
+async fn forward_pty_output<R>(mut reader: R, state: State) -> std::io::Error
+where
+    R: AsyncRead + Unpin,
+{
+    let console = state.console();
+    let mut prompts = PromptDetector::new();
+    let mut titles = TitleParser::new();
+    let mut detector = BinaryDetector::new();
+    let mut buffer = vec![0; state.config.read_buffer as usize];
+    loop {
+        // Don't hold back output which might still be collapsed forever if the pty goes quiet.
+        let read = reader.read(&mut buffer);
+        let read = if console.lock().await.has_collapsed() {
+            tokio::select! {
+                read = read => read,
+                _ = state.clock.sleep(COLLAPSE_IDLE) => {
+                    console.lock().await.flush_collapsed();
+                    continue;
+                }
+            }
+        } else {
+            read.await
+        };
+        let n = match read {
+            Ok(n) => n,
+            Err(e) => return e,
+        };
+        if n > 0 {
+            state.ready.store(true, Ordering::Relaxed);
+        }
+        // Padding in the history buffer is never part of the output, so this only affects NUL
+        // bytes sent by the device.
+        let data = state.config.nul_bytes.apply(&buffer[..n]);
+        if state.config.local_echo != LocalEcho::Off {
+            let secret = prompts.feed(&data);
+            state.secret_prompt.store(secret, Ordering::Relaxed);
+        }
+        if state.config.title_updates {
+            if let Some(title) = titles.feed(&data) {
+                *state.title.lock().await = Some(title.clone());
+                // An error only means there are no clients connected at the moment.
+                let _ = state.events.send(ServerMessage::Title { title });
+            }
+        }
+        if state.config.detect_binary {
+            if let Some(binary) = detector.feed(&data) {
+                state.binary.store(binary, Ordering::Relaxed);
+                let _ = state.events.send(ServerMessage::BinaryOutput { binary });
+            }
+        }
+        // Forward data to console mux, and stop reading while a remote which can't lose data, or
+        // is waited for, is lagging.
+        if state.config.log_backpressure == Backpressure::Block || state.config.send_wait > 0 {
+            ConsoleMux::write_data_async(&console, &data).await;
+        } else {
+            console.lock().await.write_data(&data);
+        }
+    }
+}
+
+/// Serve the console to plain TCP connections, see `--tcp-bind`.
+async fn serve_tcp(listener: TcpListener, state: State) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let span = tracing::info_span!("tcp_client", %addr);
+                tokio::spawn(serve_tcp_client(stream, addr, state.clone()).instrument(span));
+            }
+            Err(e) => log_error(
+                "tcp accept",
+                format_args!("Could not accept TCP connection {}", e),
+            ),
+        }
+    }
+}
+
+/// Serve the console to a TCP connection like to a websocket client: the connection receives the
+/// history and all output, and what it sends is written to the pty, if the client can send input.
+async fn serve_tcp_client(stream: TcpStream, addr: SocketAddr, state: State) {
+    if state.pty_waiting.load(Ordering::Relaxed) {
+        return;
+    }
+    let session = match state.drain.session() {
+        Some(session) => session,
+        None => return,
+    };
+    if let Err(e) = state.acquire_pty().await {
+        error!("Could not open idle pty {}", e);
+        return;
+    }
+    let writable = state.pty_writable.load(Ordering::Relaxed)
+        && access::input_allowed(addr.ip(), &state.config.allow_input_from);
+    let (mut reader, writer) = stream.into_split();
+    // The history is queued, so a client which doesn't read can't hold up the console.
+    let handle = state.inner.lock().await.attach_remote_queued(writer);
+    info!("TCP client connected");
+    let mut buf = vec![0; 4096];
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) => break,
+            // Read only clients can't influence the pty in any way.
+            Ok(_) if !writable => {}
+            Ok(n) => {
+                if state.write_pty(buf[..n].to_vec()).await.is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                log_error(
+                    "tcp read",
+                    format_args!("Could not read from TCP client {}: {}", addr, e),
+                );
+                break;
+            }
+        }
+    }
+    handle.detach(&mut *state.inner.lock().await);
+    info!("TCP client disconnected");
+    drop(session);
+    if state.config.hangup == HangupPolicy::Disconnect && state.drain.sessions() == 0 {
+        state.hangup();
+    }
+}
+
+/// Listen on the Unix socket at `path`, replacing a socket left behind by a previous run. Only the
+/// user running the server can connect.
+fn bind_control_socket(path: &Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Serve the commands sent to the control socket, see `--control-socket`.
+async fn serve_control_socket(listener: UnixListener, state: State) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve_admin(stream, state.clone()));
+            }
+            Err(e) => log_error(
+                "control accept",
+                format_args!("Could not accept control connection {}", e),
+            ),
+        }
+    }
+}
+
+/// Answer the commands sent on a connection to the control socket, every command with a line of
+/// text. A snapshot has no end marker, so the connection is closed after it.
+async fn serve_admin(stream: UnixStream, state: State) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match line.parse() {
+            Ok(AdminCommand::Clear) => {
+                state.inner.lock().await.clear();
+                info!("Cleared the history on request of the control socket");
+                "ok\n".to_string()
+            }
+            Ok(AdminCommand::Clients) => format!("{}\n", state.drain.sessions()),
+            Ok(AdminCommand::Remotes) => {
+                let mut response = String::new();
+                for client in state.inner.lock().await.clients() {
+                    let attached = client
+                        .attached
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    response.push_str(&format!(
+                        "{} {} {}\n",
+                        client.id,
+                        attached.as_secs(),
+                        client.bytes_sent
+                    ));
+                }
+                // An empty line ends the list.
+                response.push('\n');
+                response
+            }
+            Ok(AdminCommand::Snapshot) => {
+                let snapshot = state.inner.lock().await.snapshot();
+                let _ = writer.write_all(&snapshot).await;
+                return;
+            }
+            Ok(AdminCommand::Shutdown) => {
+                let _ = writer.write_all(b"ok\n").await;
+                info!("Shutting down on request of the control socket");
+                let frame = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: CONSOLE_DETACHED.into(),
+                };
+                state.shutdown(frame).await;
+                return;
+            }
+            Err(e) => format!("{}\n", e),
+        };
+        if writer.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Build the router serving the frontend and the websocket endpoint.
+fn app(state: State) -> Router {
+    let compression = state
+        .config
+        .compression()
+        .expect("compression settings are validated on startup");
+    let mut router = Router::new()
+        .route("/", get(index))
+        .route("/ws", get(handler))
+        .route(HEX_PATH, get(handler).layer(Extension(View::Hexdump)))
+        .route(
+            READ_ONLY_PATH,
+            get(handler).layer(Extension(View::ReadOnly)),
+        )
+        .route(&format!("{}:id", SESSION_PATH), get(session_handler));
+    // The same console, with a different replay.
+    for route in &state.config.replay_route {
+        let cap = ReplayCap(route.max);
+        router = router.route(&route.path, get(handler).layer(Extension(cap)));
+    }
+    router
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/capabilities", get(capabilities))
+        .route("/log", get(log))
+        .route("/buffer", get(buffer))
+        .route("/drain", post(start_drain))
+        .route("/input", post(input))
+        .route("/clear", post(clear))
+        .route("/metrics", get(metrics))
+        .route("/pty", get(pty_info))
+        .fallback(get(static_handler))
+        .layer(compression.layer())
+        .layer(Extension(state))
+}
+
+/// Query parameters of a websocket connection.
+#[derive(Debug, Default, Deserialize)]
+struct ConnectParams {
+    /// Bandwidth of the client in bytes per second. If set, the history replayed to the client is
+    /// capped and paced to this bandwidth.
+    bandwidth: Option<u64>,
+    /// Token the client is authorized with, for clients which can't set the `Authorization`
+    /// header, like browsers.
+    token: Option<String>,
+}
+
+/// Query parameters of the HTTP endpoints.
+#[derive(Debug, Deserialize)]
+struct InputParams {
+    /// Token the client is authorized with, see [`ConnectParams`].
+    token: Option<String>,
+}
+
+/// Maximum amount of history replayed to clients connecting to a route, see [`ReplayRoute`].
+#[derive(Debug, Clone, Copy)]
+struct ReplayCap(usize);
+
+/// Marks a route serving the console differently than `/ws`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum View {
+    /// The output is rendered as a hexdump.
+    Hexdump,
+    /// The input of clients is discarded.
+    ReadOnly,
+}
+
+/// How output is sent to a client.
+#[derive(Debug, Clone, Copy)]
+struct Output {
+    /// Bandwidth of the client in bytes per second, to which the replay is paced.
+    bandwidth: Option<u64>,
+    /// Maximum amount of bytes replayed.
+    max_replay: usize,
+    /// Whether the output is rendered as a hexdump.
+    hexdump: bool,
+}
+
+async fn handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<ConnectParams>,
+    cap: Option<Extension<ReplayCap>>,
+    view: Option<Extension<View>>,
+    Extension(state): Extension<State>,
+) -> Response {
+    let ip = access::client_ip(peer.ip(), &headers, &state.config.trusted_proxy);
+    let addr = SocketAddr::new(ip, peer.port());
+    if !state.authorized(&headers, params.token.as_deref(), addr) {
+        return unauthorized();
+    }
+    let view = view.map(|Extension(view)| view);
+    if state.pty_waiting.load(Ordering::Relaxed) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "waiting for pty").into_response();
+    }
+    let session = match state.drain.session() {
+        Some(session) => session,
+        None => return (StatusCode::SERVICE_UNAVAILABLE, "server is draining").into_response(),
+    };
+    if let Err(e) = state.acquire_pty().await {
+        error!("Could not open idle pty {}", e);
+        return (StatusCode::SERVICE_UNAVAILABLE, "could not open pty").into_response();
+    }
+    // Reopening an idle pty can fall back to read only, so this is only known once it is open.
+    let writable = state.pty_writable.load(Ordering::Relaxed)
+        && view != Some(View::ReadOnly)
+        && access::input_allowed(ip, &state.config.allow_input_from);
+    // A bandwidth of 0 can't be paced, treat it as the lowest possible bandwidth instead.
+    let bandwidth = params.bandwidth.map(|bandwidth| bandwidth.max(1));
+    let max = bandwidth.map_or(usize::MAX, |bandwidth| {
+        bandwidth.saturating_mul(REPLAY_TIME.as_secs()) as usize
+    });
+    let output = Output {
+        bandwidth,
+        max_replay: cap.map_or(max, |Extension(ReplayCap(cap))| max.min(cap)),
+        hexdump: view == Some(View::Hexdump),
+    };
+    // The offsets in a hexdump only make sense if the output is in order.
+    let ws = match state.config.tail_first_replay {
+        Some(_) if !output.hexdump => ws.protocols([TAIL_FIRST_PROTOCOL]),
+        _ => ws,
+    };
+    let correlation_id = access::correlation_id(
+        &headers,
+        &state.config.correlation_header,
+        state.config.correlation_cookie.as_deref(),
+    )
+    .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let correlation: Arc<str> = correlation_id.as_str().into();
+    let cookie = state
+        .config
+        .correlation_cookie
+        .as_ref()
+        .map(|name| format!("{}={}; Path=/; SameSite=Strict", name, correlation_id));
+    let header = state.config.correlation_header.clone();
+    let mut response = ws.on_upgrade(move |socket| {
+        handle_socket(socket, addr, correlation, writable, output, session, state)
+    });
+    // The id only contains printable ASCII, so it is a valid header value.
+    let headers = response.headers_mut();
+    headers.insert(header, correlation_id.parse().unwrap());
+    if let Some(cookie) = cookie {
+        headers.insert(header::SET_COOKIE, cookie.parse().unwrap());
+    }
+    response
+}
+
+/// Connect a websocket to the console. If the client is not `writable`, its input is discarded.
+/// The history replayed to the client is capped to the maximum of the `output` settings, and
+/// paced if the client advertised its bandwidth. If the client negotiated a tail first replay, the
+/// newest history is replayed first, which is never paced. The `session` is held until the client
+/// disconnects. The `correlation_id` identifies the connection in logs and records.
+async fn handle_socket(
+    socket: WebSocket,
+    addr: SocketAddr,
+    correlation_id: Arc<str>,
+    writable: bool,
+    output: Output,
+    session: Session,
+    state: State,
+) {
+    let id = state.next_client_id.fetch_add(1, Ordering::Relaxed);
+    // Tells the logs of the clients apart.
+    let span = tracing::info_span!("client", id, %addr, %correlation_id);
+    let tail_first = match socket.protocol() {
+        Some(protocol) if protocol == TAIL_FIRST_PROTOCOL => state.config.tail_first_replay,
+        _ => None,
+    };
+    #[cfg(feature = "otlp")]
+    let connected = state.clock.wall();
+    state.notify(LifecycleEvent::Connect, addr, &correlation_id, None);
+    state
+        .audit(
+            id,
+            addr,
+            &correlation_id,
+            AuditEvent::SessionStart { writable },
+        )
+        .await;
+    // Connections end either because the client leaves, or because we drop it. Only report
+    // whichever happens first.
+    let ended = Arc::new(AtomicBool::new(false));
+    // Notified to stop reading from the client, which closes the connection once the writer is
+    // gone too.
+    let stop = Arc::new(Notify::new());
+    // Notified once the reader stopped, which happens when the client acknowledged a close.
+    let closed = Arc::new(Notify::new());
+    // Split socket in a tx and rx pair.
+    let (mut sender, receiver) = socket.split();
+    // Attach tx pair to console.
+    // Since SplitSink only implements futures-sink::Sink, we need a converter. Do in-memory for
+    // now.
+    let (tx, mut rx) = mpsc::channel::<Arc<[u8]>>(state.config.connection_buffer);
+    // Control messages for this client only.
+    let (control_tx, mut control_rx) = mpsc::channel::<Message>(EVENT_BACKLOG);
+    let mut events = state.events.subscribe();
+    // Doesn't keep the channel open, so the writer sees when the console detaches the client.
+    let echo_tx = tx.downgrade();
+    // When the client last sent input, to disconnect clients which left.
+    let last_input = Arc::new(std::sync::Mutex::new(state.clock.now()));
+    // Wait for our turn, the turn lasts until the history is sent.
+    let permit = match &state.attach_gate {
+        // The semaphore is never closed.
+        Some(gate) => Some(gate.clone().acquire_owned().await.unwrap()),
+        None => None,
+    };
+    // The history is queued on the channel before the writer starts, so the writer knows how much
+    // of the output to pace.
+    let Output {
+        bandwidth,
+        max_replay,
+        hexdump,
+    } = output;
+    let paced = match tail_first {
+        // Pacing would split the replay in more than the two messages of a tail first replay.
+        Some(tail) => {
+            let mut console = state.inner.lock().await;
+            console
+                .attach_channel_tail_first(tx, max_replay, tail)
+                .await;
+            0
+        }
+        None if max_replay < usize::MAX => {
+            let mut console = state.inner.lock().await;
+            console.attach_channel_limited(tx, max_replay).await
+        }
+        None => {
+            state.inner.lock().await.attach_channel(tx).await;
+            0
+        }
+    };
+
+    tokio::spawn({
+        let state = state.clone();
+        let ended = ended.clone();
+        let stop = stop.clone();
+        let closed = closed.clone();
+        let correlation_id = correlation_id.clone();
+        let last_input = last_input.clone();
+        async move {
+            // Clients which connect later still need to know the current title.
+            let title = state.title.lock().await.clone();
+            let binary = state.binary.load(Ordering::Relaxed);
+            let mut dump = hexdump.then(HexDump::new);
+            // A hexdump has no bottom row to draw on.
+            let mut status = (state.config.status_line && !hexdump)
+                .then(|| StatusLine::new(state.config.console_name()));
+            let mut render = |buf: &[u8]| match &mut dump {
+                Some(dump) => {
+                    let mut out = String::new();
+                    dump.feed(buf, &mut out);
+                    out.into_bytes()
+                }
+                None => buf.to_vec(),
+            };
+            // The history is sent first, which has to complete within the attach timeout, so a
+            // client which can't even receive it doesn't tie up the connection indefinitely.
+            let replay = async {
+                if let Some(title) = title {
+                    let _ = sender
+                        .send(Message::Text(ServerMessage::Title { title }.to_json()))
+                        .await;
+                }
+                if binary {
+                    let msg = ServerMessage::BinaryOutput { binary };
+                    let _ = sender.send(Message::Text(msg.to_json())).await;
+                }
+                // The replay is queued as two messages.
+                for _ in 0..2 {
+                    let buf = match rx.recv().await {
+                        Some(buf) => buf,
+                        None => return Ok(false),
+                    };
+                    let buf = render(&buf);
+                    match bandwidth {
+                        Some(bandwidth) if paced > 0 => {
+                            send_paced(&mut sender, &buf, bandwidth, &*state.clock).await?
+                        }
+                        _ => sender.send(Message::Binary(buf)).await?,
+                    }
+                }
+                Ok::<_, axum::Error>(true)
+            };
+            let replayed = match state.config.attach_timeout {
+                0 => Ok(replay.await),
+                secs => tokio::time::timeout(Duration::from_secs(secs), replay).await,
+            };
+            drop(permit);
+            let reason = match replayed {
+                Ok(Ok(true)) => None,
+                Ok(Ok(false)) => return,
+                Ok(Err(e)) => Some(e.to_string()),
+                Err(_) => Some(ATTACH_TIMED_OUT.to_string()),
+            };
+            if let Some(reason) = reason {
+                log_error(
+                    "websocket send",
+                    format_args!(
+                        "Could not send history to websocket {}: {}",
+                        correlation_id, reason
+                    ),
+                );
+                if !ended.swap(true, Ordering::Relaxed) {
+                    state.notify(LifecycleEvent::Dropped, addr, &correlation_id, Some(reason));
+                }
+                // The client might not read at all, so drop the connection rather than waiting
+                // for a close frame to be sent.
+                stop.notify_one();
+                return;
+            }
+            if let Some(status) = &mut status {
+                let read_only = !writable || !state.pty_writable.load(Ordering::Relaxed);
+                status.set_read_only(read_only);
+                status.set_binary(binary);
+                let size = state.sizes.lock().await.effective();
+                if let Some(draw) = size.and_then(|size| status.set_size(size)) {
+                    let _ = sender.send(Message::Binary(draw)).await;
+                }
+            }
+            let idle_timeout = Duration::from_secs(state.config.idle_timeout);
+            let coalesce = Duration::from_millis(state.config.coalesce_interval);
+            loop {
+                let idle_deadline = *last_input.lock().unwrap() + idle_timeout;
+                let sent = tokio::select! {
+                    buf = rx.recv() => match buf {
+                        Some(buf) => {
+                            let mut out = render(&buf);
+                            if let Some(draw) = status.as_mut().and_then(|s| s.feed(&buf)) {
+                                out.extend(draw);
+                            }
+                            if !coalesce.is_zero() {
+                                let deadline = state.clock.now() + coalesce;
+                                while out.len() < COALESCE_LIMIT {
+                                    let buf = tokio::select! {
+                                        // A detached client is closed on the next iteration.
+                                        Some(buf) = rx.recv() => buf,
+                                        _ = state.clock.sleep_until(deadline) => break,
+                                    };
+                                    out.extend(render(&buf));
+                                    if let Some(draw) = status.as_mut().and_then(|s| s.feed(&buf)) {
+                                        out.extend(draw);
+                                    }
+                                }
+                            }
+                            sender.send(Message::Binary(out)).await
+                        }
+                        // The console detached the client, e.g. because it is shutting down.
+                        None => {
+                            let frame = state.close_frame.read().unwrap().clone();
+                            let frame = frame.unwrap_or(CloseFrame {
+                                code: close_code::AWAY,
+                                reason: CONSOLE_DETACHED.into(),
+                            });
+                            close_connection(&mut sender, Some(frame), &closed, &stop).await;
+                            return;
+                        }
+                    },
+                    Some(msg) = control_rx.recv() => match msg {
+                        // The session ends once the close frame is sent.
+                        Message::Close(frame) => {
+                            close_connection(&mut sender, frame, &closed, &stop).await;
+                            return;
+                        }
+                        msg => sender.send(msg).await,
+                    },
+                    event = events.recv() => match event {
+                        Ok(event) => {
+                            let draw = status.as_mut().and_then(|s| s.update(&event));
+                            match sender.send(Message::Text(event.to_json())).await {
+                                Ok(()) => match draw {
+                                    Some(draw) => sender.send(Message::Binary(draw)).await,
+                                    None => Ok(()),
+                                },
+                                Err(e) => Err(e),
+                            }
+                        }
+                        // Control messages are informational, missing some is not an issue.
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    },
+                    _ = state.clock.sleep_until(idle_deadline), if !idle_timeout.is_zero() => {
+                        // Input might have arrived meanwhile.
+                        if state.clock.now() < *last_input.lock().unwrap() + idle_timeout {
+                            continue;
+                        }
+                        info!("Disconnecting client without input for {:?}", idle_timeout);
+                        if !ended.swap(true, Ordering::Relaxed) {
+                            let reason = Some(IDLE_TIMEOUT.to_string());
+                            state.notify(LifecycleEvent::Dropped, addr, &correlation_id, reason);
+                        }
+                        let frame = CloseFrame {
+                            code: close_code::NORMAL,
+                            reason: IDLE_TIMEOUT.into(),
+                        };
+                        close_connection(&mut sender, Some(frame), &closed, &stop).await;
+                        return;
+                    }
+                };
+                if let Err(e) = sent {
+                    log_error(
+                        "websocket send",
+                        format_args!(
+                            "Could not send buffer to websocket {}: {}",
+                            correlation_id, e
+                        ),
+                    );
+                    if !ended.swap(true, Ordering::Relaxed) {
+                        let reason = Some(e.to_string());
+                        state.notify(LifecycleEvent::Dropped, addr, &correlation_id, reason);
+                    }
+                    // Try to close the socket so the other half is also closed for automatic
+                    // cleanup. We don't care about errors here
+                    close_connection(&mut sender, None, &closed, &stop).await;
+                    return;
+                };
+            }
+        }
+        .instrument(span.clone())
+    });
+
+    tokio::spawn({
+        async move {
+            let line = std::sync::Mutex::new(CommandLine::new());
+            // Set while the client is pasting, to whether the paste is bracketed.
+            let paste = std::sync::Mutex::new(None);
+            // Set once a notice was logged that the input of the client is discarded because the
+            // pty is read only.
+            let dropping = AtomicBool::new(false);
+            // Position in the output of the last marker set by the client.
+            let marker = std::sync::Mutex::new(None);
+            // Set once the connection is closed because the pty can't be written to.
+            let closing = AtomicBool::new(false);
+            // Set once the connection is closed because the client sent too much input at once.
+            let rejected = AtomicBool::new(false);
+            receiver
+                .take_until(stop.notified())
+                .for_each(|msg| async {
+                    if closing.load(Ordering::Relaxed) || rejected.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    if let Ok(msg) = msg {
+                        let len = match &msg {
+                            Message::Binary(d) => d.len(),
+                            Message::Text(t) => t.len(),
+                            _ => 0,
+                        };
+                        if len > state.config.max_input_frame {
+                            rejected.store(true, Ordering::Relaxed);
+                            warn!("Disconnecting client which sent a message of {} bytes", len);
+                            if !ended.swap(true, Ordering::Relaxed) {
+                                let reason = Some(INPUT_TOO_LARGE.to_string());
+                                state.notify(
+                                    LifecycleEvent::Dropped,
+                                    addr,
+                                    &correlation_id,
+                                    reason,
+                                );
+                            }
+                            let frame = CloseFrame {
+                                code: close_code::SIZE,
+                                reason: INPUT_TOO_LARGE.into(),
+                            };
+                            let _ = control_tx.send(Message::Close(Some(frame))).await;
+                            return;
+                        }
+                        let input = match &msg {
+                            Message::Binary(_) => true,
+                            Message::Text(t) => {
+                                ClientMessage::parse(t).is_none_or(|m| m.is_input())
+                            }
+                            _ => false,
+                        };
+                        if input {
+                            *last_input.lock().unwrap() = state.clock.now();
+                        }
+                        // Markers only affect the client itself, so read only clients can set
+                        // them too.
+                        if let Message::Text(t) = &msg {
+                            match ClientMessage::parse(t) {
+                                Some(ClientMessage::Marker) => {
+                                    let position = state.inner.lock().await.position();
+                                    *marker.lock().unwrap() = Some(position);
+                                    return;
+                                }
+                                Some(ClientMessage::Capture) => {
+                                    let position = *marker.lock().unwrap();
+                                    match position {
+                                        Some(position) => {
+                                            let console = state.inner.lock().await;
+                                            let (output, truncated) =
+                                                console.output_since(position);
+                                            drop(console);
+                                            let output =
+                                                String::from_utf8_lossy(&output).into_owned();
+                                            let msg = ServerMessage::Capture { output, truncated };
+                                            let msg = Message::Text(msg.to_json());
+                                            let _ = control_tx.send(msg).await;
+                                        }
+                                        None => {
+                                            debug!("Client captured without a marker")
+                                        }
+                                    }
+                                    return;
+                                }
+                                _ => {}
+                            }
+                        }
+                        // Read only clients can't influence the pty in any way.
+                        if !writable && matches!(msg, Message::Binary(_) | Message::Text(_)) {
+                            let pty_writable = state.pty_writable.load(Ordering::Relaxed);
+                            if !pty_writable && !dropping.swap(true, Ordering::Relaxed) {
+                                info!("Discarding input of client, the pty is read only");
+                            }
+                            return;
+                        }
+                        let pasting = *paste.lock().unwrap();
+                        let written = match msg {
+                            Message::Binary(d) => {
+                                state
+                                    .audit_input(id, addr, &correlation_id, &line, &d)
+                                    .await;
+                                state.forward_client_input(d, pasting, &echo_tx).await
+                            }
+                            Message::Text(t) => match ClientMessage::parse(&t) {
+                                Some(ClientMessage::Resize { cols, rows }) => {
+                                    state.client_resized(id, WinSize { cols, rows }).await;
+                                    Ok(())
+                                }
+                                Some(ClientMessage::Eof) => state.send_eof().await,
+                                // A paste can't be nested in another paste.
+                                Some(ClientMessage::PasteBegin { .. }) if pasting.is_some() => {
+                                    Ok(())
+                                }
+                                Some(ClientMessage::PasteBegin { bracketed }) => {
+                                    *paste.lock().unwrap() = Some(bracketed);
+                                    match bracketed {
+                                        true => state.write_pty(PASTE_START.to_vec()).await,
+                                        false => Ok(()),
+                                    }
+                                }
+                                Some(ClientMessage::Macro { name }) => {
+                                    match Macro::find(&state.config.macros, &name) {
+                                        Some(m) => {
+                                            state
+                                                .audit_input(
+                                                    id,
+                                                    addr,
+                                                    &correlation_id,
+                                                    &line,
+                                                    &m.input,
+                                                )
+                                                .await;
+                                            state.forward_input(m.input.clone(), &echo_tx).await
+                                        }
+                                        None => {
+                                            warn!("Client triggered unknown macro {}", name);
+                                            Ok(())
+                                        }
+                                    }
+                                }
+                                // Handled before the input access is checked.
+                                Some(ClientMessage::Marker | ClientMessage::Capture) => Ok(()),
+                                Some(ClientMessage::Malformed { error }) => {
+                                    warn!("Ignoring malformed control message: {}", error);
+                                    Ok(())
+                                }
+                                Some(ClientMessage::PasteEnd) => {
+                                    let bracketed = paste.lock().unwrap().take() == Some(true);
+                                    match bracketed {
+                                        true => state.write_pty(PASTE_END.to_vec()).await,
+                                        false => Ok(()),
+                                    }
+                                }
+                                None => {
+                                    state
+                                        .audit_input(id, addr, &correlation_id, &line, t.as_bytes())
+                                        .await;
+                                    let input = t.into_bytes();
+                                    state.forward_client_input(input, pasting, &echo_tx).await
+                                }
+                            },
+                            // The websocket acknowledges a close of the client itself.
+                            Message::Close(_) => Ok(()),
+                            m => {
+                                debug!("Unsupported websocket message {:?}", m);
+                                Ok(())
+                            }
+                        };
+                        // Rather than leaving the client with a session which only shows output,
+                        // close the connection entirely.
+                        if written.is_err() && !closing.swap(true, Ordering::Relaxed) {
+                            if !ended.swap(true, Ordering::Relaxed) {
+                                let reason = Some(PTY_UNAVAILABLE.to_string());
+                                state.notify(
+                                    LifecycleEvent::Dropped,
+                                    addr,
+                                    &correlation_id,
+                                    reason,
+                                );
+                            }
+                            let frame = CloseFrame {
+                                code: close_code::ERROR,
+                                reason: PTY_UNAVAILABLE.into(),
+                            };
+                            let _ = control_tx.send(Message::Close(Some(frame))).await;
+                        }
+                    };
+                })
+                .await;
+            closed.notify_one();
+            // Don't leave the program waiting for the end of a paste which never arrives.
+            if paste.into_inner().unwrap() == Some(true) && !closing.into_inner() {
+                let _ = state.write_pty(PASTE_END.to_vec()).await;
+            }
+            state.client_left(id).await;
+            if !ended.swap(true, Ordering::Relaxed) {
+                state.notify(LifecycleEvent::Disconnect, addr, &correlation_id, None);
+            }
+            state
+                .audit(id, addr, &correlation_id, AuditEvent::SessionEnd)
+                .await;
+            #[cfg(feature = "otlp")]
+            if let Some(otlp) = &state.otlp {
+                otlp.export_session(id, addr, &correlation_id, writable, connected);
+            }
+            drop(session);
+            // Like a terminal which is disconnected, once nobody is left on the console.
+            if state.config.hangup == HangupPolicy::Disconnect && state.drain.sessions() == 0 {
+                state.hangup();
+            }
+        }
+        .instrument(span)
+    });
+}
+
+/// Send a close frame to the client, and wait until it acknowledged the close, which ends the
+/// reader of the connection and notifies `closed`. A client which doesn't receive or acknowledge
+/// the close within [`CLOSE_TIMEOUT`] is dropped by notifying `stop`.
+async fn close_connection<S>(
+    sender: &mut S,
+    frame: Option<CloseFrame<'static>>,
+    closed: &Notify,
+    stop: &Notify,
+) where
+    S: futures::Sink<Message> + Unpin,
+{
+    let close = async {
+        sender.send(Message::Close(frame)).await.ok()?;
+        closed.notified().await;
+        Some(())
+    };
+    if !matches!(
+        tokio::time::timeout(CLOSE_TIMEOUT, close).await,
+        Ok(Some(()))
+    ) {
+        stop.notify_one();
+    }
+}
+
+/// Send data to a websocket in chunks, at about `bandwidth` bytes per second.
+async fn send_paced<S>(
+    sender: &mut S,
+    data: &[u8],
+    bandwidth: u64,
+    clock: &dyn Clock,
+) -> Result<(), S::Error>
+where
+    S: futures::Sink<Message> + Unpin,
+{
+    let chunk = (bandwidth as u128 * REPLAY_INTERVAL.as_millis() / 1000).max(1) as usize;
+    for chunk in data.chunks(chunk) {
+        sender.send(Message::Binary(chunk.to_vec())).await?;
+        clock.sleep(REPLAY_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// Liveness probe, the process is up if it can respond. Waiting for the pty and draining are
+/// only reported by the readiness probe, so the process is not restarted meanwhile.
+async fn healthz() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
+}
+
+/// Readiness probe, the console is ready once the pty produced data, or stayed silent for the
+/// configured ready timeout. The console is not ready while draining.
+async fn readyz(Extension(state): Extension<State>) -> impl IntoResponse {
+    if state.drain.is_draining() {
+        (StatusCode::SERVICE_UNAVAILABLE, "draining")
+    } else if state.pty_waiting.load(Ordering::Relaxed) {
+        (StatusCode::SERVICE_UNAVAILABLE, "waiting for pty")
+    } else if state.ready.load(Ordering::Relaxed) {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+/// Describe the features supported by the server.
+async fn capabilities(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<InputParams>,
+    Extension(state): Extension<State>,
+) -> Response {
+    if let Some(resp) = state.check_token(peer, &headers, params.token.as_deref()) {
+        return resp;
+    }
+    Json(Capabilities::new(&state.config, state.config.buffer_size)).into_response()
+}
+
+/// Information about the pty the console is connected to.
+#[derive(Debug, Serialize)]
+struct PtyInfo {
+    path: PathBuf,
+    /// Total amount of bytes of output written by the pty since the server started, including
+    /// output which is no longer retained in the history buffer.
+    total_written: u64,
+    /// The window size applied to the pty, if any client reported its size.
+    winsize: Option<WinSize>,
+    /// The last title set by the console, if title updates are enabled.
+    title: Option<String>,
+}
+
+/// Describe the pty the console is connected to.
+async fn pty_info(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<InputParams>,
+    Extension(state): Extension<State>,
+) -> Response {
+    if let Some(resp) = state.check_token(peer, &headers, params.token.as_deref()) {
+        return resp;
+    }
+    let total_written = state.inner.lock().await.total_written();
+    let winsize = state.sizes.lock().await.effective();
+    let title = state.title.lock().await.clone();
+    Json(PtyInfo {
+        path: state.config.pty().to_path_buf(),
+        total_written,
+        winsize,
+        title,
+    })
+    .into_response()
+}
+
+/// Expose metrics in the Prometheus text format.
+async fn metrics(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<InputParams>,
+    Extension(state): Extension<State>,
+) -> Response {
+    if let Some(resp) = state.check_token(peer, &headers, params.token.as_deref()) {
+        return resp;
+    }
+    (
+        [(header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        state.metrics().await.render(),
+    )
+        .into_response()
+}
+
+/// Start draining the server: new connections are refused, and the server shuts down once all
+/// existing sessions ended, or the drain timeout passed.
+async fn start_drain(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<InputParams>,
+    Extension(state): Extension<State>,
+) -> Response {
+    if let Some(resp) = state.check_token(peer, &headers, params.token.as_deref()) {
+        return resp;
+    }
+    if state.drain.start() {
+        (StatusCode::ACCEPTED, "draining").into_response()
+    } else {
+        (StatusCode::OK, "already draining").into_response()
+    }
+}
+
+/// Connect a websocket to the console of a session, like `/ws` does for the main console.
+async fn session_handler(
+    axum::extract::Path(id): axum::extract::Path<String>,
+    ws: WebSocketUpgrade,
+    peer: ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    params: Query<ConnectParams>,
+    Extension(state): Extension<State>,
+) -> Response {
+    // Check the token first, so clients without it can't probe which sessions exist.
+    let ip = access::client_ip(peer.0.ip(), &headers, &state.config.trusted_proxy);
+    let addr = SocketAddr::new(ip, peer.0.port());
+    if !state.authorized(&headers, params.token.as_deref(), addr) {
+        return unauthorized();
+    }
+    match state.session(&id) {
+        Some(session) => handler(ws, peer, headers, params, None, None, Extension(session)).await,
+        None => (StatusCode::NOT_FOUND, "unknown session").into_response(),
+    }
+}
+
+/// Write the body of the request to the pty as input, for clients which only send input and don't
+/// need the output. Only clients which could send input over a websocket can use it. The input is
+/// recorded in the audit log like the input of a websocket client.
+async fn input(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<InputParams>,
+    Extension(state): Extension<State>,
+    body: Bytes,
+) -> Response {
+    let ip = access::client_ip(peer.ip(), &headers, &state.config.trusted_proxy);
+    let addr = SocketAddr::new(ip, peer.port());
+    if !state.authorized(&headers, params.token.as_deref(), addr) {
+        return unauthorized();
+    }
+    if !access::input_allowed(ip, &state.config.allow_input_from) {
+        return (StatusCode::FORBIDDEN, "input not allowed").into_response();
+    }
+    if !state.pty_writable.load(Ordering::Relaxed) {
+        return (StatusCode::FORBIDDEN, "pty is read only").into_response();
+    }
+    if state.pty_waiting.load(Ordering::Relaxed) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "waiting for pty").into_response();
+    }
+    if let Err(e) = state.acquire_pty().await {
+        error!("Could not open idle pty {}", e);
+        return (StatusCode::SERVICE_UNAVAILABLE, "could not open pty").into_response();
+    }
+    if state.audit.is_some() {
+        let id = state.next_client_id.fetch_add(1, Ordering::Relaxed);
+        let correlation_id = access::correlation_id(
+            &headers,
+            &state.config.correlation_header,
+            state.config.correlation_cookie.as_deref(),
+        )
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+        // Only lines submitted within the request are recorded.
+        let line = std::sync::Mutex::new(CommandLine::new());
+        state
+            .audit_input(id, addr, &correlation_id, &line, &body)
+            .await;
+    }
+    match state.write_pty(body.to_vec()).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(PtyUnavailable) => (StatusCode::SERVICE_UNAVAILABLE, PTY_UNAVAILABLE).into_response(),
+    }
+}
+
+/// Clear the history, so clients connecting later don't see the output so far. Connected clients
+/// are told to clear their terminal. Only clients which can send input can clear the history.
+async fn clear(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<InputParams>,
+    Extension(state): Extension<State>,
+) -> Response {
+    let ip = access::client_ip(peer.ip(), &headers, &state.config.trusted_proxy);
+    let addr = SocketAddr::new(ip, peer.port());
+    if !state.authorized(&headers, params.token.as_deref(), addr) {
+        return unauthorized();
+    }
+    if !access::input_allowed(ip, &state.config.allow_input_from) {
+        return (StatusCode::FORBIDDEN, "input not allowed").into_response();
+    }
+    state.inner.lock().await.clear();
+    info!("Cleared the history on request of {}", addr);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Response to a client which didn't present a valid token.
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        "invalid or missing token",
+    )
+        .into_response()
+}
+
+/// Get a snapshot of the history buffer. The generation of the history is used as entity tag, so
+/// polling clients only receive the buffer again once it changed, including when it is cleared.
+async fn buffer(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<InputParams>,
+    Extension(state): Extension<State>,
+) -> Response {
+    if let Some(resp) = state.check_token(peer, &headers, params.token.as_deref()) {
+        return resp;
+    }
+    let console = state.inner.lock().await;
+    let etag = format!("\"{:x}-{}\"", state.instance, console.generation());
+    let unchanged = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| etag_matches(v, &etag));
+
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, "no-cache");
+    if unchanged {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(boxed(Full::default()))
+            .unwrap();
+    }
+    response
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .body(boxed(Full::from(console.snapshot())))
+        .unwrap()
+}
+
+/// Check if an If-None-Match header value matches an entity tag, using weak comparison.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Download the in-memory recording of the console output.
+async fn log(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<InputParams>,
+    Extension(state): Extension<State>,
+) -> Response {
+    if let Some(resp) = state.check_token(peer, &headers, params.token.as_deref()) {
+        return resp;
+    }
+    let recording = match state.inner.lock().await.recording() {
+        Some(recording) => recording.to_vec(),
+        None => return (StatusCode::NOT_FOUND, "console recording is not enabled").into_response(),
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"console.log\"",
+        )
+        .body(boxed(Full::from(recording)))
+        .unwrap()
+}
+
+/// Handle index
+async fn index(Extension(state): Extension<State>) -> Response {
+    if state.pty_waiting.load(Ordering::Relaxed) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "2")],
+            Html(WAITING_PAGE),
+        )
+            .into_response();
+    }
+    let mut response = static_handler("/index.html".parse::<Uri>().unwrap())
+        .await
+        .into_response();
+    let origins = &state.config.embed_origin;
+    if !origins.is_empty() {
+        // Origins are validated when parsing the config, so this is a valid header value.
+        let policy = format!("frame-ancestors 'self' {}", origins.join(" "));
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_SECURITY_POLICY, policy.parse().unwrap());
+    }
+    response
+}
+
+/// Handle static files
+async fn static_handler(uri: Uri) -> impl IntoResponse {
+    let path = uri.path().trim_start_matches('/').to_string();
+
+    StaticFile(path)
+}
+
+pub struct StaticFile<T>(pub T);
+
+impl<T> IntoResponse for StaticFile<T>
+where
+    T: Into<String>,
+{
+    fn into_response(self) -> Response {
+        let path = self.0.into();
+
+        match Assets::get(path.as_str()) {
+            Some(content) => {
+                let body = boxed(Full::from(content.data));
+                let mime = mime_guess::from_path(path).first_or_octet_stream();
                 Response::builder()
                     .header(header::CONTENT_TYPE, mime.as_ref())
                     .body(body)
                     .unwrap()
             }
-            None => Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(boxed(Full::from("404")))
-                .unwrap(),
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(boxed(Full::from("404")))
+                .unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::CONSOLE_BUFFER;
+    use axum::{body::Body, http::Request};
+    use tokio_tungstenite::tungstenite;
+    use tower::ServiceExt;
+
+    use std::os::unix::io::FromRawFd;
+
+    /// Serve the app on a random local port.
+    fn serve(state: State) -> SocketAddr {
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(app(state).into_make_service_with_connect_info::<SocketAddr>());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// The app as it is served to a local client, for requests which don't need a connection.
+    fn local_app(state: State) -> Router {
+        let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+        app(state).layer(Extension(ConnectInfo(peer)))
+    }
+
+    /// Open a new pty pair, returning the master and slave side.
+    fn openpty() -> (std::fs::File, std::fs::File) {
+        let (mut master, mut slave) = (0, 0);
+        // SAFETY: all pointers are valid for the duration of the call, and on success the
+        // returned fds are owned by us.
+        unsafe {
+            assert_eq!(
+                libc::openpty(
+                    &mut master,
+                    &mut slave,
+                    std::ptr::null_mut(),
+                    std::ptr::null(),
+                    std::ptr::null()
+                ),
+                0
+            );
+            (
+                std::fs::File::from_raw_fd(master),
+                std::fs::File::from_raw_fd(slave),
+            )
+        }
+    }
+
+    fn get_winsize(fd: &std::fs::File) -> WinSize {
+        use std::os::unix::io::AsRawFd;
+
+        let mut ws = libc::winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: TIOCGWINSZ writes to the winsize struct, which outlives the call.
+        assert_eq!(
+            unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCGWINSZ, &mut ws) },
+            0
+        );
+        WinSize {
+            cols: ws.ws_col,
+            rows: ws.ws_row,
+        }
+    }
+
+    fn test_config(args: &[&str]) -> ServerConfig {
+        ServerConfig::parse_from(
+            ["cloud-console", "/dev/null", "127.0.0.1", "0"]
+                .iter()
+                .chain(args),
+        )
+    }
+
+    /// Wait for the next text frame on a websocket.
+    async fn next_text<S>(ws: &mut S) -> String
+    where
+        S: futures::Stream<Item = Result<tungstenite::Message, tungstenite::Error>> + Unpin,
+    {
+        loop {
+            match tokio::time::timeout(Duration::from_secs(5), ws.next()).await {
+                Ok(Some(Ok(tungstenite::Message::Text(t)))) => return t,
+                Ok(Some(Ok(_))) => continue,
+                r => panic!("websocket did not produce a text frame: {:?}", r),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resize_smallest_wins() {
+        let (master, slave) = openpty();
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, Some(slave), &test_config(&[]));
+        let addr = serve(state);
+        let url = format!("ws://{}/ws", addr);
+
+        let (mut c1, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        c1.send(tungstenite::Message::Text(
+            r#"{"type":"resize","cols":120,"rows":40}"#.into(),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            next_text(&mut c1).await,
+            r#"{"type":"winsize","cols":120,"rows":40,"mismatch":false}"#
+        );
+
+        let (mut c2, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        c2.send(tungstenite::Message::Text(
+            r#"{"type":"resize","cols":100,"rows":50}"#.into(),
+        ))
+        .await
+        .unwrap();
+        let expected = r#"{"type":"winsize","cols":100,"rows":40,"mismatch":true}"#;
+        assert_eq!(next_text(&mut c1).await, expected);
+        assert_eq!(next_text(&mut c2).await, expected);
+        assert_eq!(
+            get_winsize(&master),
+            WinSize {
+                cols: 100,
+                rows: 40
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resize_controller_wins() {
+        let (master, slave) = openpty();
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(
+            tx,
+            Some(slave),
+            &test_config(&["--resize-policy", "controller"]),
+        );
+        let addr = serve(state);
+        let url = format!("ws://{}/ws", addr);
+
+        let (mut c1, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        c1.send(tungstenite::Message::Text(
+            r#"{"type":"resize","cols":120,"rows":40}"#.into(),
+        ))
+        .await
+        .unwrap();
+        next_text(&mut c1).await;
+
+        let (mut c2, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        c2.send(tungstenite::Message::Text(
+            r#"{"type":"resize","cols":100,"rows":50}"#.into(),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            next_text(&mut c2).await,
+            r#"{"type":"winsize","cols":120,"rows":40,"mismatch":true}"#
+        );
+        assert_eq!(
+            get_winsize(&master),
+            WinSize {
+                cols: 120,
+                rows: 40
+            }
+        );
+    }
+
+    async fn get_status(state: &State, uri: &str) -> StatusCode {
+        local_app(state.clone())
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_healthz() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+
+        assert_eq!(get_status(&state, "/healthz").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_after_pty_output() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        let (mut pty, reader) = tokio::io::duplex(64);
+        tokio::spawn(forward_pty_output(reader, state.clone()));
+
+        tokio::task::yield_now().await;
+        assert_eq!(
+            get_status(&state, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        pty.write_all(b"login: ").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while get_status(&state, "/readyz").await != StatusCode::OK {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_pty() {
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+
+        let (mut master, slave) = openpty();
+        let pts = std::fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd())).unwrap();
+        let path = std::env::temp_dir().join(format!("cloud-console-pty-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = ServerConfig::parse_from([
+            "cloud-console",
+            path.to_str().unwrap(),
+            "127.0.0.1",
+            "0",
+            "--pty-wait",
+            "10",
+        ]);
+        let (tx, rx) = mpsc::channel(WRITE_BACKLOG);
+        let mut state = State::new(tx, None, &config);
+        state.pty_loops = Some(Arc::new(PtyLoops::new(rx)));
+        state.pty_waiting.store(true, Ordering::Relaxed);
+        let waiting = tokio::spawn({
+            let state = state.clone();
+            async move { wait_for_pty(&state, Duration::from_secs(10)).await }
+        });
+
+        // The server reports it is waiting, instead of serving the console, but it is alive.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for path in ["/readyz", "/"] {
+            assert_eq!(
+                get_status(&state, path).await,
+                StatusCode::SERVICE_UNAVAILABLE
+            );
+        }
+        assert_eq!(get_status(&state, "/healthz").await, StatusCode::OK);
+        // Once the pty appears, it is opened and the console becomes ready with its output.
+        std::os::unix::fs::symlink(&pts, &path).unwrap();
+        waiting.await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(get_status(&state, "/healthz").await, StatusCode::OK);
+        assert_eq!(get_status(&state, "/").await, StatusCode::OK);
+        master.write_all(b"login\n").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while get_status(&state, "/readyz").await != StatusCode::OK {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_log_write_error() {
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let subscriber = logging::LogSubscriber::new("info".parse().unwrap(), {
+            let lines = lines.clone();
+            move |line| lines.lock().unwrap().push(line.to_string())
+        });
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+
+        // Writes to /dev/full fail. A file reports the failure of a write with the next one.
+        attach_mirror(&state, Path::new("/dev/full")).await;
+        state.console().lock().await.write_data(b"output");
+        state.console().lock().await.write_data(b"more output");
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while lines.lock().unwrap().is_empty() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "write error was not logged"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let line = lines.lock().unwrap()[0].clone();
+        assert!(
+            line.starts_with("WARN Could not write to mirror, not mirroring anymore: "),
+            "{}",
+            line
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mirror_pty() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        // A mirror which can't be written to is skipped.
+        attach_mirror(&state, &std::env::temp_dir()).await;
+
+        let (mut master, slave) = openpty();
+        let path = std::fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd())).unwrap();
+        attach_mirror(&state, &path).await;
+        state.console().lock().await.write_data(b"mirrored output");
+        let mirrored = tokio::task::spawn_blocking(move || {
+            let mut mirrored = Vec::new();
+            let mut buf = [0; 4096];
+            while !mirrored.ends_with(b"mirrored output") {
+                let n = master.read(&mut buf).unwrap();
+                mirrored.extend_from_slice(&buf[..n]);
+            }
+            mirrored
+        });
+        let mirrored = tokio::time::timeout(Duration::from_secs(5), mirrored)
+            .await
+            .unwrap()
+            .unwrap();
+        // The history was empty, so only the output is mirrored.
+        assert_eq!(mirrored, b"mirrored output");
+    }
+
+    #[tokio::test]
+    async fn test_log_compress() {
+        use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+
+        for compression in ["gzip", "zstd"] {
+            let path = std::env::temp_dir().join(format!(
+                "cloud-console-log-{}-{}",
+                compression,
+                std::process::id()
+            ));
+            let log_file = path.to_str().unwrap();
+            let config = test_config(&[log_file, "--log-compress", compression]);
+            let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+            let state = State::new(tx, None, &config);
+            state.console().lock().await.write_data(b"history\r\n");
+            attach_log_file(&state, &path, None).await.unwrap();
+            state.console().lock().await.write_data(b"$ ls\r\n");
+            // The stream is only finished on shutdown.
+            state.console().lock().await.shutdown().await;
+
+            let compressed = std::fs::read(&path).unwrap();
+            let mut decompressed = Vec::new();
+            match compression {
+                "gzip" => GzipDecoder::new(compressed.as_slice())
+                    .read_to_end(&mut decompressed)
+                    .await
+                    .unwrap(),
+                _ => ZstdDecoder::new(compressed.as_slice())
+                    .read_to_end(&mut decompressed)
+                    .await
+                    .unwrap(),
+            };
+            assert_eq!(decompressed, b"history\r\n$ ls\r\n");
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_log_timestamps() {
+        let path = std::env::temp_dir().join(format!("cloud-console-ts-{}", std::process::id()));
+        let log_file = path.to_str().unwrap();
+        let config = test_config(&[log_file, "--log-timestamps"]);
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &config);
+        attach_log_file(&state, &path, None).await.unwrap();
+        let (tx, mut client) = mpsc::channel(WRITE_BACKLOG);
+        state.console().lock().await.attach_channel_live(tx);
+        state.console().lock().await.write_data(b"$ ls\r\nfile");
+        state.console().lock().await.write_data(b".txt\r\n");
+        state.console().lock().await.shutdown().await;
+
+        // Other remotes receive the output as is.
+        assert_eq!(&*client.recv().await.unwrap(), b"$ ls\r\nfile");
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (line, text) in lines.iter().zip(["$ ls", "file.txt"]) {
+            let (timestamp, rest) = line.split_once(' ').unwrap();
+            assert!(humantime::parse_rfc3339(timestamp).is_ok(), "{}", line);
+            assert_eq!(rest, text);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_when_pty_unavailable() {
+        let (tx, rx) = mpsc::channel(WRITE_BACKLOG);
+        let addr = serve(State::new(tx, None, &test_config(&[])));
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        // The loop forwarding input to the pty stopped.
+        drop(rx);
+        ws.send(tungstenite::Message::Text("ls\r".into()))
+            .await
+            .unwrap();
+        let frame = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match ws.next().await {
+                    Some(Ok(tungstenite::Message::Close(frame))) => return frame,
+                    Some(Ok(_)) => continue,
+                    r => panic!("websocket was not closed: {:?}", r),
+                }
+            }
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            frame.code,
+            tungstenite::protocol::frame::coding::CloseCode::Error
+        );
+        assert_eq!(frame.reason, PTY_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_close_handshake() {
+        let (tx, rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        let addr = serve(state.clone());
+        let url = format!("ws://{}/ws", addr);
+        let (mut acked, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut silent, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        drop(rx);
+        let start = std::time::Instant::now();
+        for ws in [&mut acked, &mut silent] {
+            ws.send(tungstenite::Message::Text("ls\r".into()))
+                .await
+                .unwrap();
+            loop {
+                match tokio::time::timeout(Duration::from_secs(5), ws.next()).await {
+                    Ok(Some(Ok(tungstenite::Message::Close(_)))) => break,
+                    Ok(Some(Ok(_))) => continue,
+                    r => panic!("websocket was not closed: {:?}", r),
+                }
+            }
+        }
+
+        // Reading on acknowledges the close, after which the server ends the connection cleanly.
+        let end = tokio::time::timeout(Duration::from_secs(5), acked.next()).await;
+        assert!(matches!(end, Ok(None)), "{:?}", end);
+        let sessions_ended = |remaining| {
+            let drain = state.drain.clone();
+            async move {
+                while drain.sessions() > remaining {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), sessions_ended(1))
+            .await
+            .unwrap();
+        assert!(start.elapsed() < CLOSE_TIMEOUT);
+
+        // A client which doesn't acknowledge the close is dropped eventually.
+        tokio::time::timeout(CLOSE_TIMEOUT * 2, sessions_ended(0))
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= CLOSE_TIMEOUT);
+        drop(silent);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        state.console().lock().await.write_data(b"$ ");
+        let addr = serve(state.clone());
+        let url = format!("ws://{}/ws", addr);
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            assert_eq!(next_binary(&mut ws).await, b"$ ");
+            clients.push(ws);
+        }
+
+        let output: Vec<u8> = (0..200)
+            .flat_map(|i| format!("line {}\r\n", i).into_bytes())
+            .collect();
+        for chunk in output.chunks(64) {
+            state.console().lock().await.write_data(chunk);
+        }
+        state
+            .shutdown(CloseFrame {
+                code: close_code::AWAY,
+                reason: CONSOLE_DETACHED.into(),
+            })
+            .await;
+
+        // Every client receives all output before the console closes its connection.
+        for mut ws in clients {
+            let mut received = Vec::new();
+            let frame = loop {
+                match tokio::time::timeout(Duration::from_secs(5), ws.next()).await {
+                    Ok(Some(Ok(tungstenite::Message::Binary(data)))) => received.extend(data),
+                    Ok(Some(Ok(tungstenite::Message::Close(frame)))) => break frame.unwrap(),
+                    Ok(Some(Ok(_))) => continue,
+                    r => panic!("websocket was not closed: {:?}", r),
+                }
+            };
+            assert_eq!(received, output);
+            assert_eq!(
+                frame.code,
+                tungstenite::protocol::frame::coding::CloseCode::Away
+            );
+            assert_eq!(frame.reason, CONSOLE_DETACHED);
+        }
+        // New clients are refused.
+        let refused = tokio_tungstenite::connect_async(&url).await;
+        assert!(refused.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_close_on_pty_error() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        let addr = serve(state.clone());
+        let (master, mut slave) = openpty();
+        tokio::spawn({
+            let state = state.clone();
+            async move {
+                let reader = tokio::fs::File::from_std(master);
+                forward_pty_output(reader, state.clone()).await;
+                pty_closed(&state).await;
+            }
+        });
+        std::io::Write::write_all(&mut slave, b"$ ").unwrap();
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        assert_eq!(next_binary(&mut ws).await, b"$ ");
+
+        // Reading the pty fails once the other side is closed.
+        std::io::Write::write_all(&mut slave, b"bye").unwrap();
+        drop(slave);
+        let mut received = Vec::new();
+        let frame = loop {
+            match tokio::time::timeout(Duration::from_secs(5), ws.next()).await {
+                Ok(Some(Ok(tungstenite::Message::Binary(data)))) => received.extend(data),
+                Ok(Some(Ok(tungstenite::Message::Close(frame)))) => break frame.unwrap(),
+                Ok(Some(Ok(_))) => continue,
+                r => panic!("websocket was not closed: {:?}", r),
+            }
+        };
+        assert_eq!(received, b"bye");
+        assert_eq!(
+            frame.code,
+            tungstenite::protocol::frame::coding::CloseCode::Error
+        );
+        assert_eq!(frame.reason, PTY_CLOSED);
+    }
+
+    #[tokio::test]
+    async fn test_attach_timeout() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--attach-timeout", "1"]));
+        state
+            .console()
+            .lock()
+            .await
+            .write_data(&[b'x'; CONSOLE_BUFFER]);
+        // Small socket buffers, so the history doesn't fit in them.
+        let listener = tokio::net::TcpSocket::new_v4().unwrap();
+        listener.set_send_buffer_size(4096).unwrap();
+        listener.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = listener.listen(16).unwrap().into_std().unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app(state.clone()).into_make_service_with_connect_info::<SocketAddr>());
+        tokio::spawn(server);
+
+        // A client on a congested link, which can't receive the history and never reads.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(1024).unwrap();
+        let stream = socket.connect(addr).await.unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}/ws", addr), stream)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(state.drain.sessions(), 1);
+
+        // The connection is dropped once the timeout passed.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(state.drain.sessions(), 0);
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(_)) = ws.next().await {}
+        });
+        closed.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tcp_client() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        state.console().lock().await.write_data(b"login: ");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_tcp(listener, state.clone()));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 7];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"login: ");
+        stream.write_all(b"root\r").await.unwrap();
+        let input = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(input, b"root\r");
+        state.console().lock().await.write_data(b"# ");
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"# ");
+
+        // The client is detached once it disconnects.
+        assert_eq!(state.drain.sessions(), 1);
+        drop(stream);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while state.drain.sessions() > 0 {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "client was not detached"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state.console().lock().await.remote_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_control_socket() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        state.console().lock().await.write_data(b"secret\r\n");
+        let addr = serve(state.clone());
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        assert_eq!(next_binary(&mut ws).await, b"secret\r\n");
+        let path = std::env::temp_dir().join(format!("cloud-console-ctl-{}", std::process::id()));
+        let listener = bind_control_socket(&path).unwrap();
+        tokio::spawn(serve_control_socket(listener, state.clone()));
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        for (command, response) in [
+            ("clients", "1"),
+            ("reboot", "unknown command reboot"),
+            ("clear", "ok"),
+        ] {
+            writer
+                .write_all(format!("{}\n", command).as_bytes())
+                .await
+                .unwrap();
+            assert_eq!(lines.next_line().await.unwrap().unwrap(), response);
+        }
+        assert_eq!(next_binary(&mut ws).await, cloud_console::CLEAR_SEQUENCE);
+
+        // The client received the history and the clear sequence.
+        writer.write_all(b"remotes\n").await.unwrap();
+        let remote = lines.next_line().await.unwrap().unwrap();
+        let sent = (b"secret\r\n".len() + cloud_console::CLEAR_SEQUENCE.len()).to_string();
+        assert_eq!(remote.split(' ').nth(2), Some(sent.as_str()));
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "");
+
+        state.console().lock().await.write_data(b"$ ");
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream.write_all(b"snapshot\n").await.unwrap();
+        let mut snapshot = Vec::new();
+        stream.read_to_end(&mut snapshot).await.unwrap();
+        assert_eq!(snapshot, b"$ ");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_output() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--coalesce-interval", "200"]));
+        state.console().lock().await.write_data(b"$ ");
+        let addr = serve(state.clone());
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        assert_eq!(next_binary(&mut ws).await, b"$ ");
+
+        // Rapid writes are sent as one frame.
+        for chunk in [&b"l"[..], b"s", b"\r\n", b"file.txt\r\n"] {
+            state.console().lock().await.write_data(chunk);
+        }
+        assert_eq!(next_binary(&mut ws).await, b"ls\r\nfile.txt\r\n");
+        // A write after the interval is sent in a frame of its own.
+        state.console().lock().await.write_data(b"$ ");
+        assert_eq!(next_binary(&mut ws).await, b"$ ");
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--idle-timeout", "1"]));
+        state.console().lock().await.write_data(b"$ ");
+        let addr = serve(state.clone());
+        let url = format!("ws://{}/ws", addr);
+        let (mut silent, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut active, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert_eq!(next_binary(&mut silent).await, b"$ ");
+        assert_eq!(next_binary(&mut active).await, b"$ ");
+
+        // Only input keeps a client connected, output and resizes don't.
+        let resize = r#"{"type":"resize","cols":80,"rows":24}"#;
+        silent
+            .send(tungstenite::Message::Text(resize.into()))
+            .await
+            .unwrap();
+        for _ in 0..6 {
+            state.console().lock().await.write_data(b".");
+            active
+                .send(tungstenite::Message::Binary(b"x".to_vec()))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+        let frame = loop {
+            match tokio::time::timeout(Duration::from_secs(5), silent.next()).await {
+                Ok(Some(Ok(tungstenite::Message::Close(frame)))) => break frame.unwrap(),
+                Ok(Some(Ok(_))) => continue,
+                r => panic!("idle client was not disconnected: {:?}", r),
+            }
+        };
+        assert_eq!(
+            frame.code,
+            tungstenite::protocol::frame::coding::CloseCode::Normal
+        );
+        assert_eq!(frame.reason, IDLE_TIMEOUT);
+        // The active client is still served.
+        state.console().lock().await.write_data(b"!");
+        loop {
+            match next_binary(&mut active).await.as_slice() {
+                b"!" => break,
+                _ => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_paced_bracketed_paste() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--paste-rate", "10000"]));
+        let addr = serve(state);
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+
+        // The paste tries to end bracketed paste mode early.
+        let chunks: Vec<Vec<u8>> = (0..4)
+            .map(|i| {
+                let mut chunk = format!("{:0999}\n", i).into_bytes();
+                if i == 1 {
+                    chunk.extend_from_slice(b"\x1b[201~rm -rf /\r");
+                }
+                chunk
+            })
+            .collect();
+        let start = std::time::Instant::now();
+        ws.send(tungstenite::Message::Text(
+            r#"{"type":"paste_begin","bracketed":true}"#.into(),
+        ))
+        .await
+        .unwrap();
+        for chunk in &chunks {
+            ws.send(tungstenite::Message::Binary(chunk.clone()))
+                .await
+                .unwrap();
+        }
+        ws.send(tungstenite::Message::Text(r#"{"type":"paste_end"}"#.into()))
+            .await
+            .unwrap();
+        ws.send(tungstenite::Message::Text("\r".into()))
+            .await
+            .unwrap();
+
+        let mut expected = PASTE_START.to_vec();
+        for chunk in &chunks {
+            expected.extend(chunk.iter().filter(|&&b| b != 0x1b));
+        }
+        expected.extend_from_slice(PASTE_END);
+        expected.push(b'\r');
+
+        let mut written = Vec::new();
+        while written.len() < expected.len() {
+            let data = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            // 10000 bytes per second, in chunks of 10ms.
+            assert!(data.len() <= 100);
+            written.extend(data);
+        }
+        assert!(start.elapsed() >= PASTE_INTERVAL * 40);
+        assert_eq!(written, expected);
+    }
+
+    #[tokio::test]
+    async fn test_session_buffer_size() {
+        use std::os::unix::io::AsRawFd;
+
+        let (_master, slave) = openpty();
+        let path = std::fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd())).unwrap();
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--buffer-size", "4096"]));
+        // Sessions keep --buffer-size bytes of history, unless they have their own size.
+        for (id, suffix, size) in [("vm1", "", 4096), ("vm2", ",1024", 1024)] {
+            let session = format!("{}={}{}", id, path.display(), suffix);
+            spawn_session(&state, &session.parse().unwrap())
+                .await
+                .unwrap();
+            let console = state.session(id).unwrap().console();
+            console.lock().await.write_data(&[b'x'; 8192]);
+            assert_eq!(console.lock().await.len(), size, "{}", id);
+        }
+    }
+
+    /// Many consoles together keep no more history than the budget, the idle ones are trimmed.
+    #[tokio::test]
+    async fn test_history_budget() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&["--buffer-size", "4096"]);
+        let state = State::new(tx, None, &config);
+        for i in 0..10 {
+            let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+            state.register_session(format!("vm{}", i), State::new(tx, None, &config));
+        }
+        let consoles = || {
+            let sessions = state.sessions.read().unwrap();
+            let mut consoles = vec![state.console()];
+            consoles.extend((0..10).map(|i| sessions[&format!("vm{}", i)].console()));
+            consoles
+        };
+        for console in consoles() {
+            console.lock().await.write_data(&[b'x'; 4096]);
+        }
+        let mut budget = HistoryBudget::new(3 * 4096);
+        apply_history_budget(&state, &mut budget).await;
+        let capacities = || async {
+            let mut capacities = Vec::new();
+            for console in consoles() {
+                let console = console.lock().await;
+                assert!(console.len() <= console.store().capacity());
+                capacities.push(console.store().capacity());
+            }
+            capacities
+        };
+        // Equally active, so the main console and the first sessions keep their history.
+        let mut expected = vec![0; 11];
+        expected[..3].fill(4096);
+        assert_eq!(capacities().await, expected);
+        assert_eq!(state.console().lock().await.len(), 4096);
+        let vm2 = state.session("vm2").unwrap().console();
+        assert!(vm2.lock().await.is_empty());
+
+        // Only vm3 and vm7 are active since, they get the budget of the idle vm0 and vm1.
+        for i in [3, 7] {
+            let session = state.session(&format!("vm{}", i)).unwrap();
+            session.console().lock().await.write_data(b"$ ");
+        }
+        apply_history_budget(&state, &mut budget).await;
+        let mut expected = vec![0; 11];
+        for i in [0, 4, 8] {
+            expected[i] = 4096;
+        }
+        assert_eq!(capacities().await, expected);
+        let vm0 = state.session("vm0").unwrap().console();
+        assert!(vm0.lock().await.is_empty());
+        let vm3 = state.session("vm3").unwrap().console();
+        vm3.lock().await.write_data(b"uptime\r\n");
+        assert_eq!(vm3.lock().await.snapshot(), b"uptime\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        let mut inputs = Vec::new();
+        for id in ["vm1", "vm2"] {
+            let (tx, rx) = mpsc::channel(WRITE_BACKLOG);
+            let session = State::new(tx, None, &test_config(&[]));
+            let prompt = format!("{}$ ", id);
+            session.console().lock().await.write_data(prompt.as_bytes());
+            state.register_session(id.into(), session);
+            inputs.push(rx);
+        }
+        let addr = serve(state.clone());
+        let mut clients = Vec::new();
+        for id in ["vm1", "vm2"] {
+            let url = format!("ws://{}{}{}", addr, SESSION_PATH, id);
+            let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            assert_eq!(next_binary(&mut ws).await, format!("{}$ ", id).as_bytes());
+            clients.push(ws);
+        }
+        // Sessions are drained along with the main console.
+        assert_eq!(state.drain.sessions(), 2);
+
+        // Output and input stay within their session.
+        for id in ["vm1", "vm2"] {
+            let console = state.session(id).unwrap().console();
+            let output = format!("output of {}\r\n", id);
+            console.lock().await.write_data(output.as_bytes());
+        }
+        for ((id, ws), input) in ["vm1", "vm2"].iter().zip(&mut clients).zip(&mut inputs) {
+            let output = format!("output of {}\r\n", id);
+            assert_eq!(next_binary(ws).await, output.as_bytes());
+            let typed = format!("typed in {}\r", id);
+            ws.send(tungstenite::Message::Text(typed.clone()))
+                .await
+                .unwrap();
+            let received = tokio::time::timeout(Duration::from_secs(5), input.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(received, typed.as_bytes());
+        }
+        assert!(inputs.iter_mut().all(|input| input.try_recv().is_err()));
+        assert_eq!(state.console().lock().await.total_written(), 0);
+
+        let url = format!("ws://{}{}vm3", addr, SESSION_PATH);
+        match tokio_tungstenite::connect_async(url).await {
+            Err(tungstenite::Error::Http(resp)) => assert_eq!(resp.status(), StatusCode::NOT_FOUND),
+            r => panic!("unknown session was served: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_token() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&["--auth-token", "s3cret"]);
+        let state = State::new(tx, None, &config);
+        let (tx, _session_rx) = mpsc::channel(WRITE_BACKLOG);
+        state.register_session("vm1".into(), State::new(tx, None, &config));
+        let addr = serve(state);
+
+        // Without the token, known and unknown sessions can't be told apart.
+        for (id, token, status) in [
+            ("vm1", "", StatusCode::UNAUTHORIZED),
+            ("vm3", "", StatusCode::UNAUTHORIZED),
+            ("vm3", "?token=s3cret", StatusCode::NOT_FOUND),
+        ] {
+            let url = format!("ws://{}{}{}{}", addr, SESSION_PATH, id, token);
+            match tokio_tungstenite::connect_async(url).await {
+                Err(tungstenite::Error::Http(resp)) => assert_eq!(resp.status(), status),
+                r => panic!("session {} was served: {:?}", id, r.map(|_| ())),
+            }
+        }
+        let url = format!("ws://{}{}vm1?token=s3cret", addr, SESSION_PATH);
+        tokio_tungstenite::connect_async(url).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_input_endpoint() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&[
+            "--allow-input-from",
+            "10.0.0.0/8",
+            "--trusted-proxy",
+            "127.0.0.1",
+        ]);
+        let state = State::new(tx, None, &config);
+        let addr = serve(state.clone());
+        let client = hyper::Client::new();
+        let post = |client_ip: &str, input: &'static str| {
+            let req = Request::post(format!("http://{}/input", addr))
+                .header("x-forwarded-for", client_ip)
+                .body(hyper::Body::from(input))
+                .unwrap();
+            client.request(req)
+        };
+
+        let resp = post("192.168.1.1", "reboot\r").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = post("10.1.2.3", "uptime\r").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let input = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(input, b"uptime\r");
+        assert!(rx.try_recv().is_err());
+        // The input is written without attaching to the output.
+        assert_eq!(state.inner.lock().await.remote_count(), 0);
+        assert_eq!(state.drain.sessions(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_auth_token() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&["--auth-token", "s3cret", "--auth-token", "other"]);
+        let state = State::new(tx, None, &config);
+        state.console().lock().await.write_data(b"login: ");
+        let addr = serve(state);
+
+        for url in [
+            format!("ws://{}/ws", addr),
+            format!("ws://{}/ws?token=wrong", addr),
+            format!("ws://{}{}", addr, HEX_PATH),
+        ] {
+            match tokio_tungstenite::connect_async(&url).await {
+                Err(tungstenite::Error::Http(resp)) => {
+                    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+                    assert_eq!(resp.headers()[header::WWW_AUTHENTICATE], "Bearer");
+                }
+                r => panic!("client without a token was served: {:?}", r.map(|_| ())),
+            }
+        }
+
+        let mut req = format!("ws://{}/ws", addr).into_client_request().unwrap();
+        req.headers_mut()
+            .insert(header::AUTHORIZATION, "Bearer s3cret".parse().unwrap());
+        let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+        assert_eq!(next_binary(&mut ws).await, b"login: ");
+        let url = format!("ws://{}/ws?token=other", addr);
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        assert_eq!(next_binary(&mut ws).await, b"login: ");
+
+        // Input without attaching needs a token as well.
+        let client = hyper::Client::new();
+        let req = Request::post(format!("http://{}/input", addr))
+            .body(hyper::Body::from("reboot\r"))
+            .unwrap();
+        let resp = client.request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = Request::post(format!("http://{}/input", addr))
+            .header(header::AUTHORIZATION, "Bearer s3cret")
+            .body(hyper::Body::from("uptime\r"))
+            .unwrap();
+        let resp = client.request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let input = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(input, b"uptime\r");
+
+        // So do the other endpoints, the drain last as it stops the server.
+        let routes = [
+            ("GET", "/buffer"),
+            ("GET", "/log"),
+            ("GET", "/metrics"),
+            ("GET", "/pty"),
+            ("GET", "/capabilities"),
+            ("POST", "/clear"),
+            ("POST", "/drain"),
+        ];
+        for (method, path) in routes {
+            let req = Request::builder()
+                .method(method)
+                .uri(format!("http://{}{}?token=wrong", addr, path))
+                .body(hyper::Body::empty())
+                .unwrap();
+            let resp = client.request(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{}", path);
+            let req = Request::builder()
+                .method(method)
+                .uri(format!("http://{}{}?token=other", addr, path))
+                .body(hyper::Body::empty())
+                .unwrap();
+            let resp = client.request(req).await.unwrap();
+            assert_ne!(resp.status(), StatusCode::UNAUTHORIZED, "{}", path);
+        }
+        // The page itself and the probes stay public.
+        for path in ["/", "/terminal.js", "/healthz"] {
+            let resp = client
+                .get(format!("http://{}{}", addr, path).parse().unwrap())
+                .await
+                .unwrap();
+            assert_ne!(resp.status(), StatusCode::UNAUTHORIZED, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clear_endpoint() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&[
+            "--allow-input-from",
+            "10.0.0.0/8",
+            "--trusted-proxy",
+            "127.0.0.1",
+        ]);
+        let state = State::new(tx, None, &config);
+        state.console().lock().await.write_data(b"secret\r\n");
+        let addr = serve(state.clone());
+        let mut ws = connect_forwarded(addr, "10.1.2.3").await;
+        assert_eq!(next_binary(&mut ws).await, b"secret\r\n");
+        let client = hyper::Client::new();
+        let post = |client_ip: &str| {
+            let req = Request::post(format!("http://{}/clear", addr))
+                .header("x-forwarded-for", client_ip)
+                .body(hyper::Body::empty())
+                .unwrap();
+            client.request(req)
+        };
+
+        // Read only clients can't clear the history.
+        let resp = post("192.168.1.1").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(state.console().lock().await.snapshot(), b"secret\r\n");
+
+        let resp = post("10.1.2.3").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(next_binary(&mut ws).await, cloud_console::CLEAR_SEQUENCE);
+        state.console().lock().await.write_data(b"$ ");
+        assert_eq!(next_binary(&mut ws).await, b"$ ");
+        // New clients only receive the output since.
+        let mut ws = connect_forwarded(addr, "10.1.2.3").await;
+        assert_eq!(next_binary(&mut ws).await, b"$ ");
+    }
+
+    #[tokio::test]
+    async fn test_macro_expansion() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&[
+            "--macro",
+            "uptime=uptime\\r",
+            "--allow-input-from",
+            "10.0.0.0/8",
+            "--trusted-proxy",
+            "127.0.0.1",
+        ]);
+        let addr = serve(State::new(tx, None, &config));
+
+        // Clients which can't send input can't trigger macros either.
+        let mut ro = connect_forwarded(addr, "192.168.1.1").await;
+        ro.send(tungstenite::Message::Text(
+            r#"{"type":"macro","name":"uptime"}"#.into(),
+        ))
+        .await
+        .unwrap();
+        let mut ws = connect_forwarded(addr, "10.1.2.3").await;
+        for name in ["unknown", "uptime"] {
+            let msg = format!(r#"{{"type":"macro","name":"{}"}}"#, name);
+            ws.send(tungstenite::Message::Text(msg)).await.unwrap();
+        }
+        let input = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(input, b"uptime\r");
+    }
+
+    #[tokio::test]
+    async fn test_read_only_route() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        state.console().lock().await.write_data(b"$ ");
+        let addr = serve(state);
+
+        // Observers receive the output, but none of their input reaches the pty.
+        let url = format!("ws://{}{}", addr, READ_ONLY_PATH);
+        let (mut ro, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        assert_eq!(next_binary(&mut ro).await, b"$ ");
+        ro.send(tungstenite::Message::Binary(b"reboot\r".to_vec()))
+            .await
+            .unwrap();
+        ro.send(tungstenite::Message::Text("reboot\r".into()))
+            .await
+            .unwrap();
+        ro.send(tungstenite::Message::Text(r#"{"type":"eof"}"#.into()))
+            .await
+            .unwrap();
+        // Frames are handled in order, once the capture is answered the input was handled too.
+        for msg in [r#"{"type":"marker"}"#, r#"{"type":"capture"}"#] {
+            ro.send(tungstenite::Message::Text(msg.into()))
+                .await
+                .unwrap();
+        }
+        next_text(&mut ro).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        ws.send(tungstenite::Message::Text("uptime\r".into()))
+            .await
+            .unwrap();
+        let input = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(input, b"uptime\r");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_eof_control_message() {
+        use std::io::{Read, Write};
+        use std::os::unix::io::AsRawFd;
+
+        let (mut master, mut slave) = openpty();
+        // Use an uncommon EOF character, to check it is taken from the pty.
+        // SAFETY: termios is a plain C struct and outlives the calls.
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            assert_eq!(libc::tcgetattr(slave.as_raw_fd(), &mut termios), 0);
+            termios.c_cc[libc::VEOF] = 0x01;
+            assert_eq!(
+                libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios),
+                0
+            );
+        }
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&[
+            "--allow-input-from",
+            "10.0.0.0/8",
+            "--trusted-proxy",
+            "127.0.0.1",
+        ]);
+        let state = State::new(tx, Some(slave.try_clone().unwrap()), &config);
+        let addr = serve(state);
+
+        // Clients which can't send input can't end the input either.
+        let mut ro = connect_forwarded(addr, "192.168.1.1").await;
+        ro.send(tungstenite::Message::Text(r#"{"type":"eof"}"#.into()))
+            .await
+            .unwrap();
+        let mut ws = connect_forwarded(addr, "10.1.2.3").await;
+        ws.send(tungstenite::Message::Text(r#"{"type":"eof"}"#.into()))
+            .await
+            .unwrap();
+        let eof = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(eof, [0x01]);
+
+        // Written to the pty, the program reading it sees the end of its input.
+        master.write_all(&eof).unwrap();
+        let read = tokio::task::spawn_blocking(move || slave.read(&mut [0; 16]).unwrap());
+        let read = tokio::time::timeout(Duration::from_secs(5), read).await;
+        assert_eq!(read.unwrap().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_idle_pty_released() {
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+
+        let (mut master, slave) = openpty();
+        let path = std::fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd())).unwrap();
+        // The amount of handles this process has open to the pty.
+        let open_handles = || {
+            std::fs::read_dir("/proc/self/fd")
+                .unwrap()
+                .filter_map(|fd| std::fs::read_link(fd.unwrap().path()).ok())
+                .filter(|target| *target == path)
+                .count()
+        };
+        let config = ServerConfig::parse_from([
+            "cloud-console",
+            path.to_str().unwrap(),
+            "127.0.0.1",
+            "0",
+            "--idle-release",
+            "1",
+            "--pty-reader",
+            "poll",
+        ]);
+        let (tx, rx) = mpsc::channel(WRITE_BACKLOG);
+        let mut state = State::new(tx, None, &config);
+        state.pty_loops = Some(Arc::new(PtyLoops::new(rx)));
+        state.acquire_pty().await.unwrap();
+        assert!(state.pty().is_some());
+        let acquired = open_handles();
+        tokio::spawn(release_idle_pty(state.clone(), Duration::from_secs(1)));
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while state.pty().is_some() {
+            assert!(std::time::Instant::now() < deadline, "pty was not released");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // Only the handle of the test is left.
+        assert_eq!(open_handles(), 1);
+        assert!(acquired > 1);
+        // Whether clients can write is decided once the pty is opened again.
+        state.pty_writable.store(false, Ordering::Relaxed);
+
+        // A client connecting acquires the pty again, and receives its output.
+        let addr = serve(state.clone());
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        assert!(state.pty().is_some());
+        assert_eq!(open_handles(), acquired);
+        master.write_all(b"awake\n").unwrap();
+        assert!(next_binary(&mut ws).await.starts_with(b"awake"));
+        ws.send(tungstenite::Message::Text("ls".into()))
+            .await
+            .unwrap();
+        let input = tokio::task::spawn_blocking(move || {
+            use std::io::Read;
+
+            // The echo of the output written above comes first.
+            let mut input = Vec::new();
+            let mut buf = [0; 64];
+            while !input.ends_with(b"ls") {
+                let n = master.read(&mut buf).unwrap();
+                input.extend_from_slice(&buf[..n]);
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(5), input)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_capture_since_marker() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        let console = state.console();
+        console.lock().await.write_data(b"$ ");
+        let addr = serve(state);
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        // Without a marker there is nothing to capture.
+        for msg in [
+            r#"{"type":"capture"}"#,
+            r#"{"type":"marker"}"#,
+            r#"{"type":"capture"}"#,
+        ] {
+            ws.send(tungstenite::Message::Text(msg.into()))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            next_text(&mut ws).await,
+            r#"{"type":"capture","output":"","truncated":false}"#
+        );
+        console
+            .lock()
+            .await
+            .write_data(b"uptime\r\n 12:00:00 up 1 day\r\n$ ");
+        ws.send(tungstenite::Message::Text(r#"{"type":"capture"}"#.into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_text(&mut ws).await,
+            r#"{"type":"capture","output":"uptime\r\n 12:00:00 up 1 day\r\n$ ","truncated":false}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hangup_policy() {
+        use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+        let (master, slave) = openpty();
+        let mut command = std::process::Command::new("sleep");
+        command.arg("60").stdin(slave);
+        // SAFETY: setsid and ioctl are async signal safe. The new session takes the pty as its
+        // controlling terminal, with the program as foreground process group.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = command.spawn().unwrap();
+
+        for policy in ["never", "disconnect"] {
+            let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+            let pty = master.try_clone().unwrap();
+            let config = test_config(&["--hangup", policy]);
+            let state = State::new(tx, Some(pty), &config);
+            let addr = serve(state.clone());
+            let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+                .await
+                .unwrap();
+            drop(ws);
+            while state.drain.sessions() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            if policy == "never" {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert!(child.try_wait().unwrap().is_none());
+                continue;
+            }
+            let exited = async {
+                loop {
+                    if let Some(status) = child.try_wait().unwrap() {
+                        return status;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            let status = tokio::time::timeout(Duration::from_secs(5), exited).await;
+            assert_eq!(status.unwrap().signal(), Some(libc::SIGHUP));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_only_fallback() {
+        // A directory can be opened for reading, but not for writing.
+        let dir = std::env::temp_dir();
+        assert!(open_pty(&dir, false).await.is_err());
+        let (_reader, writer) = open_pty(&dir, true).await.unwrap();
+        assert!(writer.is_none());
+
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--read-only-fallback"]));
+        state.pty_writable.store(false, Ordering::Relaxed);
+        state.console().lock().await.write_data(b"login: ");
+        let addr = serve(state);
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        let replay = next_binary(&mut ws).await;
+        assert!(replay.ends_with(b"login: "));
+        ws.send(tungstenite::Message::Text("root\r".into()))
+            .await
+            .unwrap();
+        ws.send(tungstenite::Message::Text(r#"{"type":"eof"}"#.into()))
+            .await
+            .unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(200), rx.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_input_frame() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--max-input-frame", "8"]));
+        state.console().lock().await.write_data(b"$ ");
+        let addr = serve(state);
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        assert_eq!(next_binary(&mut ws).await, b"$ ");
+        ws.send(tungstenite::Message::Binary(b"ls\r".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), b"ls\r");
+
+        ws.send(tungstenite::Message::Binary(b"rm -rf /\r".to_vec()))
+            .await
+            .unwrap();
+        let frame = loop {
+            match tokio::time::timeout(Duration::from_secs(5), ws.next()).await {
+                Ok(Some(Ok(tungstenite::Message::Close(frame)))) => break frame.unwrap(),
+                Ok(Some(Ok(_))) => continue,
+                r => panic!("client was not disconnected: {:?}", r),
+            }
+        };
+        assert_eq!(
+            frame.code,
+            tungstenite::protocol::frame::coding::CloseCode::Size
+        );
+        assert_eq!(frame.reason, INPUT_TOO_LARGE);
+        assert!(tokio::time::timeout(Duration::from_millis(200), rx.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pty_reopen() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+
+        // A directory can be opened, but reading from it fails.
+        let dir = std::env::temp_dir().join(format!("cloud-console-dir-{}", std::process::id()));
+        let path =
+            std::env::temp_dir().join(format!("cloud-console-reopen-{}", std::process::id()));
+        let _ = std::fs::create_dir(&dir);
+        let _ = std::fs::remove_file(&path);
+        std::os::unix::fs::symlink(&dir, &path).unwrap();
+        let config = ServerConfig::parse_from([
+            "cloud-console",
+            path.to_str().unwrap(),
+            "127.0.0.1",
+            "0",
+            "--read-only-fallback",
+            "--pty-reopen-attempts",
+            "5",
+        ]);
+        let (tx, rx) = mpsc::channel(WRITE_BACKLOG);
+        let mut state = State::new(tx, None, &config);
+        state.pty_loops = Some(Arc::new(PtyLoops::new(rx)));
+        state.console().lock().await.write_data(b"before\r\n");
+        state.acquire_pty().await.unwrap();
+        let addr = serve(state.clone());
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        assert_eq!(next_binary(&mut ws).await, b"before\r\n");
+
+        // The pty is reopened once it is back, without disconnecting the client.
+        let (mut master, slave) = openpty();
+        let pts = std::fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::os::unix::fs::symlink(&pts, &path).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !state.pty_writable.load(Ordering::Relaxed) {
+            assert!(std::time::Instant::now() < deadline, "pty was not reopened");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+        std::io::Write::write_all(&mut master, b"after\n").unwrap();
+        assert!(next_binary(&mut ws).await.starts_with(b"after"));
+
+        // Input is written to the reopened pty, and new clients still receive the history.
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        let replay = next_binary(&mut ws).await;
+        assert!(replay.starts_with(b"before\r\n"));
+        ws.send(tungstenite::Message::Binary(b"ls".to_vec()))
+            .await
+            .unwrap();
+        // The master also reads the echo of the output written to it.
+        let input = tokio::task::spawn_blocking(move || {
+            let mut input = Vec::new();
+            let mut buf = [0; 64];
+            while !input.ends_with(b"ls") {
+                let n = master.read(&mut buf).unwrap();
+                input.extend_from_slice(&buf[..n]);
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(5), input)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_commands() {
+        let path = std::env::temp_dir().join(format!("cloud-console-audit-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&["--trusted-proxy", "127.0.0.1"]);
+        let mut state = State::new(tx, None, &config);
+        state.audit = Some(AuditLog::open(&path, false, Arc::new(TokioClock)).unwrap());
+        let addr = serve(state);
+
+        let mut c1 = connect_forwarded(addr, "10.0.0.1").await;
+        let mut c2 = connect_forwarded(addr, "10.0.0.2").await;
+        c2.send(tungstenite::Message::Text("ls -".into()))
+            .await
+            .unwrap();
+        c2.send(tungstenite::Message::Binary(b"lx\x7fa\r".to_vec()))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            rx.recv().await.unwrap();
+        }
+        c1.close(None).await.unwrap();
+        c2.close(None).await.unwrap();
+
+        let records = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let log = std::fs::read_to_string(&path).unwrap();
+                let records: Vec<serde_json::Value> = log
+                    .lines()
+                    .map(|line| serde_json::from_str(line).unwrap())
+                    .collect();
+                if records.len() == 5 {
+                    return records;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let commands: Vec<_> = records
+            .iter()
+            .filter(|record| record["event"] == "command")
+            .collect();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0]["command"], "ls -la");
+        assert_eq!(commands[0]["client_ip"], "10.0.0.2");
+        let start = records
+            .iter()
+            .find(|record| record["event"] == "session_start" && record["client_ip"] == "10.0.0.2")
+            .unwrap();
+        assert_eq!(commands[0]["client"], start["client"]);
+        assert_eq!(
+            records
+                .iter()
+                .map(|r| r["seq"].as_u64().unwrap())
+                .collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_correlation_cookie_injection() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&["--correlation-cookie", "cc-id"]);
+        let addr = serve(State::new(tx, None, &config));
+
+        // An id which would add attributes to the cookie is replaced by a generated one.
+        let mut req = format!("ws://{}/ws", addr).into_client_request().unwrap();
+        let injected = "a;Domain=example.org;Max-Age=31536000";
+        req.headers_mut()
+            .insert("x-correlation-id", injected.parse().unwrap());
+        let (_ws, resp) = tokio_tungstenite::connect_async(req).await.unwrap();
+        let cookie = resp.headers()[header::SET_COOKIE].to_str().unwrap();
+        let (id, attributes) = cookie
+            .strip_prefix("cc-id=")
+            .unwrap()
+            .split_once(';')
+            .unwrap();
+        assert!(Uuid::parse_str(id).is_ok());
+        assert_eq!(attributes, " Path=/; SameSite=Strict");
+    }
+
+    #[tokio::test]
+    async fn test_correlation_id() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let path =
+            std::env::temp_dir().join(format!("cloud-console-correlation-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&["--correlation-cookie", "cc-id"]);
+        let mut state = State::new(tx, None, &config);
+        state.audit = Some(AuditLog::open(&path, false, Arc::new(TokioClock)).unwrap());
+        let addr = serve(state);
+
+        // A supplied id is used and returned.
+        let mut req = format!("ws://{}/ws", addr).into_client_request().unwrap();
+        req.headers_mut()
+            .insert("x-correlation-id", "req-42".parse().unwrap());
+        let (mut supplied, resp) = tokio_tungstenite::connect_async(req).await.unwrap();
+        assert_eq!(resp.headers()["x-correlation-id"], "req-42");
+        assert_eq!(
+            resp.headers()[header::SET_COOKIE],
+            "cc-id=req-42; Path=/; SameSite=Strict"
+        );
+        // Otherwise an id is generated.
+        let (mut generated, resp) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        let id = resp.headers()["x-correlation-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(Uuid::parse_str(&id).is_ok());
+        supplied.close(None).await.unwrap();
+        generated.close(None).await.unwrap();
+
+        let records = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let log = std::fs::read_to_string(&path).unwrap_or_default();
+                let records: Vec<serde_json::Value> = log
+                    .lines()
+                    .map(|line| serde_json::from_str(line).unwrap())
+                    .collect();
+                if records.len() == 4 {
+                    return records;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for id in ["req-42", &id] {
+            let events: Vec<_> = records
+                .iter()
+                .filter(|record| record["correlation_id"] == id)
+                .map(|record| record["event"].as_str().unwrap())
+                .collect();
+            assert_eq!(events, ["session_start", "session_end"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_paced_to_bandwidth() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        let history: Vec<u8> = (0..40)
+            .flat_map(|i| format!("{:099}\n", i).into_bytes())
+            .collect();
+        state.console().lock().await.write_data(&history);
+        let addr = serve(state);
+
+        // Without a bandwidth, the entire history is sent right away.
+        let url = format!("ws://{}/ws", addr);
+        let (mut fast, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let mut replay = Vec::new();
+        while replay.len() < history.len() {
+            replay.extend(next_binary(&mut fast).await);
+        }
+        assert_eq!(replay, history);
+
+        // A slow client receives what can be sent in the replay time, in chunks.
+        let start = std::time::Instant::now();
+        let url = format!("ws://{}/ws?bandwidth=1000", addr);
+        let (mut slow, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let mut replay = Vec::new();
+        let mut frames = 0;
+        while replay.len() < 2000 {
+            replay.extend(next_binary(&mut slow).await);
+            frames += 1;
+        }
+        assert_eq!(replay, history[history.len() - 2000..]);
+        assert_eq!(frames, 20);
+        assert!(start.elapsed() >= REPLAY_TIME - REPLAY_INTERVAL * 2);
+    }
+
+    #[tokio::test]
+    async fn test_attach_concurrency() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--attach-concurrency", "1"]));
+        let history: Vec<u8> = (0..4)
+            .flat_map(|i| format!("{:049}\n", i).into_bytes())
+            .collect();
+        state.console().lock().await.write_data(&history);
+        let addr = serve(state);
+
+        // A slow client takes its turn, sent in two chunks.
+        let url = format!("ws://{}/ws?bandwidth=1000", addr);
+        let (mut slow, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert_eq!(next_binary(&mut slow).await, history[..100]);
+
+        // The next client waits until the slow client received the entire history.
+        let url = format!("ws://{}/ws", addr);
+        let waiting = tokio::spawn(async move {
+            let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            next_binary(&mut ws).await;
+            std::time::Instant::now()
+        });
+        assert_eq!(next_binary(&mut slow).await, history[100..]);
+        let slow_done = std::time::Instant::now();
+        assert!(waiting.await.unwrap() > slow_done);
+
+        // A storm of clients is served one by one.
+        let clients = (0..8).map(|_| {
+            let url = format!("ws://{}/ws", addr);
+            tokio::spawn(async move {
+                let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+                next_binary(&mut ws).await
+            })
+        });
+        for client in clients.collect::<Vec<_>>() {
+            assert_eq!(client.await.unwrap(), history);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_route() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&["--replay-route", "/ws/lite=100"]);
+        let state = State::new(tx, None, &config);
+        let history: Vec<u8> = (0..40)
+            .flat_map(|i| format!("{:099}\n", i).into_bytes())
+            .collect();
+        state.console().lock().await.write_data(&history);
+        let addr = serve(state);
+
+        // The regular route replays the entire history.
+        let url = format!("ws://{}/ws", addr);
+        let (mut full, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let mut replay = Vec::new();
+        while replay.len() < history.len() {
+            replay.extend(next_binary(&mut full).await);
+        }
+        assert_eq!(replay, history);
+
+        // The extra route only replays the configured amount, followed by new output.
+        let url = format!("ws://{}/ws/lite", addr);
+        let (mut lite, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert_eq!(next_binary(&mut lite).await, history[history.len() - 100..]);
+        let url = format!("ws://{}/ws/other", addr);
+        assert!(tokio_tungstenite::connect_async(&url).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tail_first_replay() {
+        use tungstenite::client::IntoClientRequest;
+
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--tail-first-replay", "250"]));
+        let history: Vec<u8> = (0..40)
+            .flat_map(|i| format!("{:099}\n", i).into_bytes())
+            .collect();
+        state.console().lock().await.write_data(&history);
+        let addr = serve(state);
+
+        let url = format!("ws://{}/ws", addr);
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            TAIL_FIRST_PROTOCOL.parse().unwrap(),
+        );
+        let (mut ws, resp) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(
+            resp.headers()["Sec-WebSocket-Protocol"],
+            TAIL_FIRST_PROTOCOL
+        );
+        // The newest complete lines which fit in the tail come first.
+        let (older, newest) = history.split_at(history.len() - 200);
+        assert_eq!(next_binary(&mut ws).await, newest);
+        assert!(next_binary(&mut ws).await.ends_with(older));
+
+        // Clients which don't negotiate the subprotocol get the regular replay.
+        let (mut ws, resp) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert!(resp.headers().get("Sec-WebSocket-Protocol").is_none());
+        assert_eq!(next_binary(&mut ws).await, history);
+    }
+
+    #[tokio::test]
+    async fn test_history_modes() {
+        // Lines which don't line up with the size of the history, so the oldest retained line is
+        // incomplete.
+        let output: Vec<u8> = (0..CONSOLE_BUFFER / 99 + 50)
+            .flat_map(|i| format!("{:097}\r\n", i).into_bytes())
+            .collect();
+        let cut = output.len() - CONSOLE_BUFFER;
+        let replayed_with = |args: &[&str]| {
+            let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+            let state = State::new(tx, None, &test_config(args));
+            let output = output.clone();
+            async move {
+                let mut console = state.inner.lock().await;
+                console.write_data(&output);
+                let (tx, mut rx) = mpsc::channel(10);
+                console.attach_channel(tx).await;
+                let mut replay = Vec::new();
+                while let Ok(data) = rx.try_recv() {
+                    replay.extend_from_slice(&data);
+                }
+                (replay, console.screen().map(|screen| screen.lines()))
+            }
+        };
+        let replayed = |mode: &str| replayed_with(&["--history-mode", mode]);
+
+        let (replay, _) = replayed("bytes").await;
+        assert_eq!(replay, output[cut..]);
+
+        let (replay, _) = replayed("lines").await;
+        let start = cut + output[cut..].iter().position(|&b| b == b'\n').unwrap() + 1;
+        assert_eq!(replay, output[start..]);
+
+        let (replay, _) = replayed_with(&["--history-mode", "lines", "--history-lines", "3"]).await;
+        assert_eq!(replay, output[output.len() - 3 * 99..]);
+        let args = [
+            "--history-mode",
+            "lines",
+            "--history-lines",
+            "3",
+            "--history-line-max",
+            "10",
+        ];
+        let (replay, _) = replayed_with(&args).await;
+        assert_eq!(
+            replay,
+            [&output[output.len() - 99..][..10], b"\n"]
+                .concat()
+                .repeat(3)
+        );
+
+        // Only the current screen is replayed.
+        let (replay, lines) = replayed("screen").await;
+        assert!(replay.len() < DEFAULT_COLS as usize * DEFAULT_ROWS as usize * 2);
+        let mut client = cloud_console::Screen::new(DEFAULT_COLS, DEFAULT_ROWS);
+        client.feed(&replay);
+        assert_eq!(Some(client.lines()), lines);
+    }
+
+    #[tokio::test]
+    async fn test_thread_pty_reader() {
+        use std::io::Write;
+
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--pty-reader", "thread"]));
+        let (master, mut slave) = openpty();
+        tokio::spawn(forward_pty_output(
+            ThreadReader::spawn(master, state.config.read_buffer as usize),
+            state.clone(),
+        ));
+
+        slave.write_all(b"booting").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.inner.lock().await.snapshot() != b"booting" {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(get_status(&state, "/readyz").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_collapse_flushes_when_idle() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let clock = Arc::new(clock::MockClock::new(UNIX_EPOCH));
+        let state = State::with_clock(tx, None, &test_config(&["--collapse-repeats", "2"]), clock);
+        let (mut pty, reader) = tokio::io::duplex(64);
+        tokio::spawn(forward_pty_output(reader, state.clone()));
+
+        pty.write_all(b"retrying\nretrying\n").await.unwrap();
+        let settle = || async {
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+        };
+        settle().await;
+        assert_eq!(state.inner.lock().await.snapshot(), b"retrying\n");
+
+        // The timer has a resolution of a millisecond, so stay clear of the exact deadline.
+        tokio::time::advance(COLLAPSE_IDLE - Duration::from_millis(5)).await;
+        settle().await;
+        assert_eq!(state.inner.lock().await.snapshot(), b"retrying\n");
+
+        tokio::time::advance(Duration::from_millis(10)).await;
+        settle().await;
+        assert_eq!(
+            state.inner.lock().await.snapshot(),
+            b"retrying\n(repeated 2 times)\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_collapse_repeated_output() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--collapse-repeats", "3"]));
+        let (mut pty, reader) = tokio::io::duplex(64);
+        tokio::spawn(forward_pty_output(reader, state.clone()));
+
+        for _ in 0..5 {
+            pty.write_all(b"connection refused\r\n").await.unwrap();
+        }
+        // The run never ends, so the marker is written once the pty is idle.
+        let expected = b"connection refused\r\n(repeated 5 times)\r\n";
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.inner.lock().await.snapshot() != expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    /// Wait for the next binary frame on a websocket which is not empty, like the replay of an
+    /// empty history.
+    async fn next_binary<S>(ws: &mut S) -> Vec<u8>
+    where
+        S: futures::Stream<Item = Result<tungstenite::Message, tungstenite::Error>> + Unpin,
+    {
+        loop {
+            match tokio::time::timeout(Duration::from_secs(5), ws.next()).await {
+                Ok(Some(Ok(tungstenite::Message::Binary(b)))) if !b.is_empty() => return b,
+                Ok(Some(Ok(_))) => continue,
+                r => panic!("websocket did not produce a binary frame: {:?}", r),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_title_updates() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--title-updates"]));
+        let addr = serve(state.clone());
+        let url = format!("ws://{}/ws", addr);
+
+        let (mut c1, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        // The history is sent after the client is subscribed to control messages.
+        while !matches!(c1.next().await, Some(Ok(tungstenite::Message::Binary(_)))) {}
+
+        let (mut pty, reader) = tokio::io::duplex(64);
+        tokio::spawn(forward_pty_output(reader, state.clone()));
+        pty.write_all(b"\x1b]0;root@vm: /et").await.unwrap();
+        pty.write_all(b"c\x07# ").await.unwrap();
+        let title = r#"{"type":"title","title":"root@vm: /etc"}"#;
+        assert_eq!(next_text(&mut c1).await, title);
+
+        // Clients connecting later receive the current title right away.
+        let (mut c2, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert_eq!(next_text(&mut c2).await, title);
+    }
+
+    #[tokio::test]
+    async fn test_status_line() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&["--status-line", "--name", "vm-1"]);
+        let state = State::new(tx, None, &config);
+        state.console().lock().await.write_data(b"$ ");
+        let addr = serve(state.clone());
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        assert_eq!(next_binary(&mut ws).await, b"$ ");
+
+        // The status line is drawn once the size of the terminal is known, and moves along when
+        // it changes.
+        for rows in [40, 30] {
+            let resize = format!(r#"{{"type":"resize","cols":120,"rows":{}}}"#, rows);
+            ws.send(tungstenite::Message::Text(resize)).await.unwrap();
+            let draw = format!("\x1b7\x1b[{};1H\x1b[2K\x1b[7m vm-1\x1b[0m\x1b8", rows);
+            assert_eq!(next_binary(&mut ws).await, draw.as_bytes());
+        }
+
+        // Full-screen programs are left alone, the status line returns once they exit.
+        state.console().lock().await.write_data(b"\x1b[?1049h");
+        assert_eq!(next_binary(&mut ws).await, b"\x1b[?1049h");
+        state.console().lock().await.write_data(b"\x1b[?1049l");
+        assert_eq!(
+            next_binary(&mut ws).await,
+            b"\x1b[?1049l\x1b7\x1b[30;1H\x1b[2K\x1b[7m vm-1\x1b[0m\x1b8"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hexdump_view() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&["--detect-binary"]);
+        let state = State::new(tx, None, &config);
+        let addr = serve(state.clone());
+
+        let (mut text, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        let (mut hex, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws/hex", addr))
+            .await
+            .unwrap();
+        for ws in [&mut text, &mut hex] {
+            while !matches!(ws.next().await, Some(Ok(tungstenite::Message::Binary(_)))) {}
+        }
+
+        let (mut pty, reader) = tokio::io::duplex(1024);
+        tokio::spawn(forward_pty_output(reader, state.clone()));
+        let binary: Vec<u8> = (0..=255).collect();
+        pty.write_all(&binary).await.unwrap();
+
+        // Regular clients receive the output as is, and are advised to switch views, in any
+        // order.
+        let advice = r#"{"type":"binary_output","binary":true}"#;
+        let (mut advised, mut output) = (None, None);
+        while advised.is_none() || output.is_none() {
+            match tokio::time::timeout(Duration::from_secs(5), text.next()).await {
+                Ok(Some(Ok(tungstenite::Message::Text(t)))) => advised = Some(t),
+                Ok(Some(Ok(tungstenite::Message::Binary(b)))) if !b.is_empty() => output = Some(b),
+                Ok(Some(Ok(_))) => continue,
+                r => panic!("websocket did not produce the output: {:?}", r),
+            }
+        }
+        assert_eq!(advised.unwrap(), advice);
+        assert_eq!(output.unwrap(), binary);
+
+        let dump = String::from_utf8(next_binary(&mut hex).await).unwrap();
+        let lines: Vec<&str> = dump.split_terminator("\r\n").collect();
+        assert_eq!(lines.len(), 16);
+        assert_eq!(
+            lines[0],
+            "00000000  00 01 02 03 04 05 06 07  08 09 0a 0b 0c 0d 0e 0f  |................|"
+        );
+        assert_eq!(
+            lines[4],
+            "00000040  40 41 42 43 44 45 46 47  48 49 4a 4b 4c 4d 4e 4f  |@ABCDEFGHIJKLMNO|"
+        );
+        assert_eq!(
+            lines[15],
+            "000000f0  f0 f1 f2 f3 f4 f5 f6 f7  f8 f9 fa fb fc fd fe ff  |................|"
+        );
+
+        // Clients connecting later are advised right away.
+        let (mut late, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        assert_eq!(next_text(&mut late).await, advice);
+    }
+
+    #[tokio::test]
+    async fn test_local_echo_to_sender() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--local-echo", "sender"]));
+        let addr = serve(state.clone());
+        let url = format!("ws://{}/ws", addr);
+
+        let (mut c1, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        c1.send(tungstenite::Message::Text("ls\r".into()))
+            .await
+            .unwrap();
+
+        assert_eq!(rx.recv().await.unwrap(), b"ls\r");
+        assert_eq!(next_binary(&mut c1).await, b"ls\r\n");
+
+        // No echo while the console asks for a password.
+        let (mut pty, reader) = tokio::io::duplex(64);
+        tokio::spawn(forward_pty_output(reader, state.clone()));
+        pty.write_all(b"\r\nPassword: ").await.unwrap();
+        assert_eq!(next_binary(&mut c1).await, b"\r\nPassword: ");
+        c1.send(tungstenite::Message::Text("secret\r".into()))
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), b"secret\r");
+        assert_eq!(next_binary(&mut c1).await, b"\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_mouse_round_trip() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--local-echo", "sender"]));
+        let addr = serve(state.clone());
+        let url = format!("ws://{}/ws", addr);
+        let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+
+        // The program enables mouse tracking with SGR encoding.
+        let (mut pty, reader) = tokio::io::duplex(64);
+        tokio::spawn(forward_pty_output(reader, state.clone()));
+        pty.write_all(b"\x1b[?1000h\x1b[?1006h").await.unwrap();
+        assert_eq!(next_binary(&mut ws).await, b"\x1b[?1000h\x1b[?1006h");
+
+        // Clicks reach the pty as is, also in the X10 encoding which isn't valid UTF-8.
+        let sgr = "\x1b[<0;12;5M\x1b[<0;12;5m";
+        ws.send(tungstenite::Message::Text(sgr.into()))
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), sgr.as_bytes());
+        let x10 = b"\x1b[M \xff%".to_vec();
+        ws.send(tungstenite::Message::Binary(x10.clone()))
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), x10);
+
+        // The reports are not echoed.
+        ws.send(tungstenite::Message::Text("q".into()))
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), b"q");
+        assert_eq!(next_binary(&mut ws).await, b"q");
+    }
+
+    #[tokio::test]
+    async fn test_capabilities() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(
+            tx,
+            None,
+            &test_config(&["--resize-policy", "last", "--local-echo", "all"]),
+        );
+
+        let resp = local_app(state)
+            .oneshot(Request::get("/capabilities").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let caps: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(caps["protocol_version"], 1);
+        assert_eq!(caps["resize"]["policy"], "last");
+        assert_eq!(caps["local_echo"], "all");
+        assert_eq!(caps["read_only"], true);
+        assert_eq!(caps["buffer_size"], CONSOLE_BUFFER);
+    }
+
+    #[tokio::test]
+    async fn test_embed_origin() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        let resp = local_app(state)
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp
+            .headers()
+            .get(header::CONTENT_SECURITY_POLICY)
+            .is_none());
+
+        let config = test_config(&[
+            "--embed-origin",
+            "https://dashboard.example.com/",
+            "--embed-origin",
+            "http://10.0.0.1:8080",
+        ]);
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &config);
+        let resp = local_app(state.clone())
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.headers()[header::CONTENT_SECURITY_POLICY],
+            "frame-ancestors 'self' https://dashboard.example.com http://10.0.0.1:8080"
+        );
+
+        // The bridge in the frontend accepts messages from these origins only.
+        let resp = local_app(state)
+            .oneshot(Request::get("/capabilities").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let caps: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            caps["embed_origins"],
+            serde_json::json!(["https://dashboard.example.com", "http://10.0.0.1:8080"])
+        );
+
+        for origin in ["dashboard.example.com", "ftp://host", "https://host/path"] {
+            let args = [
+                "cloud-console",
+                "/dev/null",
+                "127.0.0.1",
+                "0",
+                "--embed-origin",
+                origin,
+            ];
+            assert!(ServerConfig::try_parse_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        use clap::error::ErrorKind;
+
+        let config = ServerConfig::try_parse_from([
+            "cloud-console",
+            "/dev/pts/3",
+            "::1",
+            "8080",
+            "/var/log/console.log",
+        ])
+        .unwrap();
+        assert_eq!(config.pty(), std::path::Path::new("/dev/pts/3"));
+        assert_eq!(config.bind(), "[::1]:8080".parse().unwrap());
+        assert_eq!(
+            config.log_file().unwrap(),
+            std::path::Path::new("/var/log/console.log")
+        );
+        assert!(config.positional_args());
+
+        // The named form is equivalent.
+        let config = ServerConfig::try_parse_from([
+            "cloud-console",
+            "--pty",
+            "/dev/pts/3",
+            "--bind",
+            "[::1]:8080",
+            "--log-file",
+            "/var/log/console.log",
+        ])
+        .unwrap();
+        assert_eq!(config.pty(), std::path::Path::new("/dev/pts/3"));
+        assert_eq!(config.bind(), "[::1]:8080".parse().unwrap());
+        assert_eq!(
+            config.log_file().unwrap(),
+            std::path::Path::new("/var/log/console.log")
+        );
+        assert!(!config.positional_args());
+        let args = [
+            "cloud-console",
+            "--pty",
+            "/dev/pts/3",
+            "--bind",
+            "[::1]:8080",
+        ];
+        let config = ServerConfig::try_parse_from(args).unwrap();
+        assert_eq!(config.log_file(), None);
+        // The same argument can't be given in both forms.
+        let args = [
+            "cloud-console",
+            "--pty",
+            "/dev/pts/3",
+            "/dev/pts/4",
+            "::1",
+            "8080",
+        ];
+        let e = ServerConfig::try_parse_from(args).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ArgumentConflict);
+        let args = ["cloud-console", "--pty", "/dev/pts/3", "--log-timestamps"];
+        let e = ServerConfig::try_parse_from(args).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::MissingRequiredArgument);
+
+        // Invalid values are reported as such, rather than failing later on.
+        for args in [
+            ["cloud-console", "/dev/pts/3", "localhost", "8080"],
+            ["cloud-console", "/dev/pts/3", "127.0.0.1", "65536"],
+        ] {
+            let e = ServerConfig::try_parse_from(args).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::ValueValidation);
+        }
+        let args = [
+            "cloud-console",
+            "--pty",
+            "/dev/pts/3",
+            "--bind",
+            "127.0.0.1",
+        ];
+        let e = ServerConfig::try_parse_from(args).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ValueValidation);
+        let config = test_config(&["--read-buffer", "4096"]);
+        assert_eq!(config.read_buffer, 4096);
+        for size in ["0", "63", "1048577"] {
+            let args = [
+                "cloud-console",
+                "/dev/pts/3",
+                "::1",
+                "8080",
+                "--read-buffer",
+                size,
+            ];
+            let e = ServerConfig::try_parse_from(args).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::ValueValidation);
+        }
+        assert_eq!(test_config(&[]).buffer_size, CONSOLE_BUFFER);
+        assert_eq!(
+            test_config(&["--buffer-size", "1048576"]).buffer_size,
+            1 << 20
+        );
+        for size in ["0", "1023", "large"] {
+            let args = [
+                "cloud-console",
+                "/dev/pts/3",
+                "::1",
+                "8080",
+                "--buffer-size",
+                size,
+            ];
+            let e = ServerConfig::try_parse_from(args).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::ValueValidation);
+        }
+        // Plain TCP clients can't present a token, so they would bypass it.
+        for token in ["--auth-token", "--auth-token-file"] {
+            let args = [
+                "cloud-console",
+                "/dev/pts/3",
+                "::1",
+                "8080",
+                "--tcp-bind",
+                "127.0.0.1:2323",
+                token,
+                "s3cret",
+            ];
+            let e = ServerConfig::try_parse_from(args).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::ArgumentConflict);
+        }
+        // A rotated log file can't be compressed.
+        let args = [
+            "cloud-console",
+            "/dev/pts/3",
+            "::1",
+            "8080",
+            "/var/log/console.log",
+            "--log-max-size",
+            "1048576",
+            "--log-compress",
+            "gzip",
+        ];
+        let e = ServerConfig::try_parse_from(args).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ArgumentConflict);
+        // But it can be signed.
+        let config = test_config(&[
+            "--log-file",
+            "/var/log/console.log",
+            "--log-max-size",
+            "1048576",
+            "--log-signing-key",
+            "/etc/cloud-console/key",
+        ]);
+        assert!(config.log_signing_key.is_some());
+        let e = ServerConfig::try_parse_from(["cloud-console", "/dev/pts/3"]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::MissingRequiredArgument);
+        let e = ServerConfig::try_parse_from(["cloud-console", "--version"]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::DisplayVersion);
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_reconnect() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let args = [
+            "--reconnect-delay",
+            "500",
+            "--reconnect-max-delay",
+            "8000",
+            "--reconnect-jitter",
+            "50",
+        ];
+        let state = State::new(tx, None, &test_config(&args));
+
+        let resp = local_app(state)
+            .oneshot(Request::get("/capabilities").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let caps: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            caps["reconnect"],
+            serde_json::json!({
+                "base_delay_ms": 500,
+                "max_delay_ms": 8000,
+                "jitter_percent": 50,
+                "resume": false,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metrics_queue_fill() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        // A remote which never reads, so its queue backs up.
+        let (remote, _remote_rx) = mpsc::channel(10);
+        {
+            let mut console = state.inner.lock().await;
+            console.attach_channel(remote).await;
+            for _ in 0..3 {
+                console.write_data(b"output");
+            }
+        }
+
+        let resp = local_app(state)
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        // The history is sent as 2 messages, plus the 3 writes.
+        assert!(body.contains("cloud_console_remote_queue_fill{remote=\"0\"} 0.5\n"));
+        assert!(body.contains("cloud_console_remote_queued_messages{remote=\"0\"} 5\n"));
+        assert!(body.contains("cloud_console_dropped_messages_total 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_counters() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        let addr = serve(state.clone());
+        let fetch = || async {
+            let resp = local_app(state.clone())
+                .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+        let body = fetch().await;
+        for metric in [
+            "cloud_console_bytes_written_total 0\n",
+            "cloud_console_pty_input_bytes_total 0\n",
+            "cloud_console_dropped_messages_total 0\n",
+            "cloud_console_history_bytes 0\n",
+            "cloud_console_connected_clients 0\n",
+        ] {
+            assert!(body.contains(metric), "{} missing in {}", metric, body);
+        }
+
+        state.console().lock().await.write_data(b"$ ");
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        assert_eq!(next_binary(&mut ws).await, b"$ ");
+        ws.send(tungstenite::Message::Binary(b"ls\r".to_vec()))
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let body = fetch().await;
+        for metric in [
+            "cloud_console_bytes_written_total 2\n",
+            "cloud_console_pty_input_bytes_total 3\n",
+            "cloud_console_history_bytes 2\n",
+            "cloud_console_connected_clients 1\n",
+        ] {
+            assert!(body.contains(metric), "{} missing in {}", metric, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_wait() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--send-wait", "5000"]));
+        let (remote, mut remote_rx) = mpsc::channel(3);
+        state.console().lock().await.attach_channel(remote).await;
+        let (mut pty, reader) = tokio::io::duplex(64);
+        tokio::spawn(forward_pty_output(reader, state.clone()));
+
+        // The channel of the remote fills up, after which the output waits for the remote
+        // instead of being dropped.
+        for line in ["one\r\n", "two\r\n", "three\r\n"] {
+            pty.write_all(line.as_bytes()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let mut received = Vec::new();
+        while received != b"one\r\ntwo\r\nthree\r\n" {
+            let msg = tokio::time::timeout(Duration::from_secs(5), remote_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            received.extend_from_slice(&msg);
+        }
+        assert_eq!(state.console().lock().await.dropped_messages(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_buffer() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--connection-buffer", "4"]));
+        state.console().lock().await.write_data(b"$ ");
+        let addr = serve(state.clone());
+        let url = format!("ws://{}/ws", addr);
+        let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        next_binary(&mut ws).await;
+
+        let fill = state.console().lock().await.queue_fill();
+        assert_eq!(fill.len(), 1);
+        assert_eq!(fill[0].capacity, 4);
+
+        // The two messages of the replay must fit.
+        for size in ["0", "1"] {
+            let args = [
+                "cloud-console",
+                "/dev/null",
+                "127.0.0.1",
+                "0",
+                "--connection-buffer",
+                size,
+            ];
+            let e = ServerConfig::try_parse_from(args).unwrap_err();
+            assert_eq!(e.kind(), clap::error::ErrorKind::ValueValidation);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pty_total_written() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        {
+            let mut console = state.inner.lock().await;
+            console.write_data(b"hello");
+            console.write_data(&vec![b'x'; CONSOLE_BUFFER + 10]);
+        }
+
+        let resp = local_app(state.clone())
+            .oneshot(Request::get("/pty").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["path"], "/dev/null");
+        assert_eq!(info["total_written"], CONSOLE_BUFFER + 15);
+        assert_eq!(info["winsize"], serde_json::Value::Null);
+
+        let resp = local_app(state)
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(&format!(
+            "cloud_console_bytes_written_total {}\n",
+            CONSOLE_BUFFER + 15
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_log_download() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        assert_eq!(get_status(&state, "/log").await, StatusCode::NOT_FOUND);
+
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&["--recording-size", "1000000"]));
+        let output = vec![b'a'; CONSOLE_BUFFER + 10];
+        state.console().lock().await.write_data(&output);
+
+        let resp = local_app(state)
+            .oneshot(Request::get("/log").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, output);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_connect_event() {
+        let (hook_tx, mut hook_rx) = mpsc::channel(10);
+        let hook = Router::new().route(
+            "/hook",
+            axum::routing::post(|body: String| async move {
+                hook_tx.send(body).await.unwrap();
+            }),
+        );
+        let hook_server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(hook.into_make_service());
+        let hook_addr = hook_server.local_addr();
+        tokio::spawn(hook_server);
+
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let hook_url = format!("http://{}/hook", hook_addr);
+        let config = test_config(&[
+            "--name",
+            "vm1",
+            "--webhook-url",
+            &hook_url,
+            "--webhook-events",
+            "connect",
+        ]);
+        let addr = serve(State::new(tx, None, &config));
+        let (_c1, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+
+        let body = tokio::time::timeout(Duration::from_secs(5), hook_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["event"], "connect");
+        assert_eq!(payload["console"], "vm1");
+        assert_eq!(payload["client_ip"], "127.0.0.1");
+        assert!(humantime::parse_rfc3339(payload["timestamp"].as_str().unwrap()).is_ok());
+        assert!(payload.get("reason").is_none());
+    }
+
+    #[cfg(feature = "otlp")]
+    #[tokio::test]
+    async fn test_otlp_export() {
+        let (export_tx, mut export_rx) = mpsc::channel(10);
+        let collector = Router::new().route(
+            "/v1/:signal",
+            axum::routing::post(
+                |axum::extract::Path(signal): axum::extract::Path<String>, body: String| async move {
+                    export_tx.send((signal, body)).await.unwrap();
+                },
+            ),
+        );
+        let collector_server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(collector.into_make_service());
+        let collector_addr = collector_server.local_addr();
+        tokio::spawn(collector_server);
+        async fn next_export(
+            rx: &mut mpsc::Receiver<(String, String)>,
+        ) -> (String, serde_json::Value) {
+            let (signal, body) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            (signal, serde_json::from_str(&body).unwrap())
+        }
+
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let endpoint = format!("http://{}", collector_addr);
+        let config = test_config(&["--name", "vm1", "--otlp-endpoint", &endpoint]);
+        let state = State::new(tx, None, &config);
+        let addr = serve(state.clone());
+        let (mut c1, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        state.console().lock().await.write_data(b"some output");
+        c1.close(None).await.unwrap();
+
+        let (signal, traces) = next_export(&mut export_rx).await;
+        assert_eq!(signal, "traces");
+        let resource = &traces["resourceSpans"][0]["resource"]["attributes"];
+        assert!(resource.as_array().unwrap().contains(
+            &serde_json::json!({"key": "console.name", "value": {"stringValue": "vm1"}})
+        ));
+        let span = &traces["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["name"], "session");
+        assert_eq!(span["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(span["spanId"].as_str().unwrap().len(), 16);
+        assert_eq!(
+            span["attributes"][0],
+            serde_json::json!({"key": "client.address", "value": {"stringValue": "127.0.0.1"}})
+        );
+        let start: u128 = span["startTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        let end: u128 = span["endTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        assert!(start > 0 && start <= end);
+
+        state
+            .otlp
+            .as_ref()
+            .unwrap()
+            .export_metrics(&state.metrics().await);
+        let (signal, metrics) = next_export(&mut export_rx).await;
+        assert_eq!(signal, "metrics");
+        let metrics = &metrics["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "cloud_console_bytes_written_total");
+        assert_eq!(metrics[0]["sum"]["isMonotonic"], true);
+        assert_eq!(metrics[0]["sum"]["dataPoints"][0]["asInt"], "11");
+        let clients = metrics
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|metric| metric["name"] == "cloud_console_connected_clients")
+            .unwrap();
+        assert!(clients["gauge"]["dataPoints"][0]["asInt"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_buffer_etag() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        state.console().lock().await.write_data(b"some output");
+
+        let resp = local_app(state.clone())
+            .oneshot(Request::get("/buffer").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = resp.headers()[header::ETAG].clone();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "some output");
+
+        let cached_request = || {
+            Request::get("/buffer")
+                .header(header::IF_NONE_MATCH, etag.clone())
+                .body(Body::empty())
+                .unwrap()
+        };
+        let resp = local_app(state.clone())
+            .oneshot(cached_request())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[header::ETAG], etag);
+
+        state.console().lock().await.write_data(b"\r\nmore output");
+        let resp = local_app(state.clone())
+            .oneshot(cached_request())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_ne!(resp.headers()[header::ETAG], etag);
+    }
+
+    /// Clearing doesn't write anything, but polling clients still need to see the empty buffer.
+    #[tokio::test]
+    async fn test_buffer_etag_clear() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        state.console().lock().await.write_data(b"secret");
+        let resp = local_app(state.clone())
+            .oneshot(Request::get("/buffer").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let etag = resp.headers()[header::ETAG].clone();
+
+        state.console().lock().await.clear();
+        let resp = local_app(state.clone())
+            .oneshot(
+                Request::get("/buffer")
+                    .header(header::IF_NONE_MATCH, etag.clone())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_ne!(resp.headers()[header::ETAG], etag);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "");
+    }
+
+    #[tokio::test]
+    async fn test_buffer_compressed() {
+        let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        state.console().lock().await.write_data(&[b'a'; 1000]);
+
+        for encoding in ["gzip", "br"] {
+            let resp = local_app(state.clone())
+                .oneshot(
+                    Request::get("/buffer")
+                        .header(header::ACCEPT_ENCODING, encoding)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.headers()[header::CONTENT_ENCODING], encoding);
+        }
+    }
+
+    /// Connect to the websocket endpoint at `addr` as if from the given client address, through
+    /// a trusted proxy.
+    async fn connect_forwarded(
+        addr: SocketAddr,
+        client: &str,
+    ) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>
+    {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut req = format!("ws://{}/ws", addr).into_client_request().unwrap();
+        req.headers_mut()
+            .insert("x-forwarded-for", client.parse().unwrap());
+        tokio_tungstenite::connect_async(req).await.unwrap().0
+    }
+
+    /// Fetch the buffer with the given accepted encoding, returning the content encoding and the
+    /// size of the body.
+    async fn get_compressed_buffer(state: &State, encoding: &str) -> (Option<String>, usize) {
+        let resp = local_app(state.clone())
+            .oneshot(
+                Request::get("/buffer")
+                    .header(header::ACCEPT_ENCODING, encoding)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let content_encoding = resp
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|v| v.to_str().unwrap().to_string());
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (content_encoding, body.len())
+    }
+
+    #[tokio::test]
+    async fn test_compression_settings() {
+        // Output which compresses better when more effort is spent.
+        let words = [
+            "error",
+            "retry",
+            "connection",
+            "refused",
+            "host",
+            "timeout",
+            "ok",
+            "\r\n",
+        ];
+        let mut output = Vec::new();
+        let mut seed = 1u32;
+        for _ in 0..10000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            output.extend_from_slice(words[(seed >> 16) as usize % words.len()].as_bytes());
+            output.push(b' ');
+        }
+
+        let mut sizes = Vec::new();
+        for level in ["1", "9"] {
+            let (tx, _rx) = mpsc::channel(WRITE_BACKLOG);
+            let config = test_config(&["--compression", "gzip", "--compression-level", level]);
+            let state = State::new(tx, None, &config);
+            state.console().lock().await.write_data(&output);
+
+            let (encoding, size) = get_compressed_buffer(&state, "gzip").await;
+            assert_eq!(encoding.as_deref(), Some("gzip"));
+            sizes.push(size);
+            // Brotli is disabled.
+            assert_eq!(get_compressed_buffer(&state, "br").await.0, None);
+        }
+        assert!(sizes[1] < sizes[0], "sizes per level: {:?}", sizes);
+
+        assert!(test_config(&["--compression-level", "10"])
+            .compression()
+            .is_err());
+        assert!(
+            test_config(&["--compression", "br", "--compression-level", "10"])
+                .compression()
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_input_allowed_from_range() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&[
+            "--allow-input-from",
+            "10.0.0.0/8",
+            "--trusted-proxy",
+            "127.0.0.1",
+        ]);
+        let addr = serve(State::new(tx, None, &config));
+
+        let mut client = connect_forwarded(addr, "10.1.2.3").await;
+        client
+            .send(tungstenite::Message::Text("ls\r".into()))
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), b"ls\r");
+    }
+
+    #[tokio::test]
+    async fn test_input_denied_outside_range() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let config = test_config(&[
+            "--allow-input-from",
+            "10.0.0.0/8",
+            "--trusted-proxy",
+            "127.0.0.1",
+        ]);
+        let state = State::new(tx, None, &config);
+        let addr = serve(state.clone());
+
+        let mut client = connect_forwarded(addr, "192.168.1.1").await;
+        client
+            .send(tungstenite::Message::Text("ls\r".into()))
+            .await
+            .unwrap();
+        client
+            .send(tungstenite::Message::Text(
+                r#"{"type":"resize","cols":120,"rows":40}"#.into(),
+            ))
+            .await
+            .unwrap();
+        // The client still receives output. The history is sent while attaching, so once it is
+        // received the client is attached.
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(tungstenite::Message::Binary(_)))
+        ));
+        state.console().lock().await.write_data(b"output");
+        assert_eq!(next_binary(&mut client).await, b"output");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(state.sizes.lock().await.effective(), None);
+    }
+
+    #[tokio::test]
+    async fn test_drain_refuses_new_connections() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BACKLOG);
+        let state = State::new(tx, None, &test_config(&[]));
+        let addr = serve(state.clone());
+        let url = format!("ws://{}/ws", addr);
+        let (mut c1, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+
+        let resp = local_app(state.clone())
+            .oneshot(Request::post("/drain").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(
+            get_status(&state, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(get_status(&state, "/healthz").await, StatusCode::OK);
+        match tokio_tungstenite::connect_async(&url).await {
+            Err(tungstenite::Error::Http(resp)) => {
+                assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE)
+            }
+            r => panic!("connection was not refused: {:?}", r),
         }
+
+        // The existing session keeps working.
+        c1.send(tungstenite::Message::Text("ls\r".into()))
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), b"ls\r");
+        state.console().lock().await.write_data(b"output");
+        assert_eq!(next_binary(&mut c1).await, b"output");
+
+        // Draining finishes once the session ends.
+        let finished = tokio::spawn({
+            let drain = state.drain.clone();
+            async move { drain.finished(Duration::from_secs(60)).await }
+        });
+        c1.close(None).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), finished)
+            .await
+            .unwrap()
+            .unwrap();
     }
 }
@@ -0,0 +1,188 @@
+use cloud_console::QueueFill;
+
+use std::fmt::{self, Write};
+
+/// Content type of the Prometheus text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The kind of a metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A total which only increases.
+    Counter,
+    /// A value which can go up and down.
+    Gauge,
+}
+
+/// The value of a sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(u64),
+    Float(f64),
+}
+
+/// A single metric with all its samples.
+#[derive(Debug, Clone)]
+pub struct Metric {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub help: &'static str,
+    pub samples: Vec<Sample>,
+}
+
+/// A sample of a metric, optionally for a single remote.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub remote: Option<u64>,
+    pub value: Value,
+}
+
+/// Collects metrics, which can be rendered in the Prometheus text exposition format, or exported
+/// otherwise.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    metrics: Vec<Metric>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Add the total amount of bytes written to the console.
+    pub fn bytes_written(&mut self, total: u64) -> &mut Self {
+        self.metrics.push(Metric {
+            name: "cloud_console_bytes_written_total",
+            kind: Kind::Counter,
+            help: "Total amount of bytes of output written by the pty.",
+            samples: vec![Sample {
+                remote: None,
+                value: Value::Int(total),
+            }],
+        });
+        self
+    }
+
+    /// Add the total amount of bytes of input forwarded to the pty.
+    pub fn bytes_forwarded(&mut self, total: u64) -> &mut Self {
+        self.metrics.push(Metric {
+            name: "cloud_console_pty_input_bytes_total",
+            kind: Kind::Counter,
+            help: "Total amount of bytes of input forwarded to the pty.",
+            samples: vec![Sample {
+                remote: None,
+                value: Value::Int(total),
+            }],
+        });
+        self
+    }
+
+    /// Add the total amount of messages dropped for lagging remotes.
+    pub fn dropped_messages(&mut self, total: u64) -> &mut Self {
+        self.metrics.push(Metric {
+            name: "cloud_console_dropped_messages_total",
+            kind: Kind::Counter,
+            help: "Total amount of output messages dropped for remotes which could not keep up.",
+            samples: vec![Sample {
+                remote: None,
+                value: Value::Int(total),
+            }],
+        });
+        self
+    }
+
+    /// Add the amount of output retained in the history.
+    pub fn history_bytes(&mut self, len: usize) -> &mut Self {
+        self.metrics.push(Metric {
+            name: "cloud_console_history_bytes",
+            kind: Kind::Gauge,
+            help: "Amount of bytes of output retained in the history.",
+            samples: vec![Sample {
+                remote: None,
+                value: Value::Int(len as u64),
+            }],
+        });
+        self
+    }
+
+    /// Add the amount of connected clients.
+    pub fn connections(&mut self, count: usize) -> &mut Self {
+        self.metrics.push(Metric {
+            name: "cloud_console_connected_clients",
+            kind: Kind::Gauge,
+            help: "Amount of clients connected to the console.",
+            samples: vec![Sample {
+                remote: None,
+                value: Value::Int(count as u64),
+            }],
+        });
+        self
+    }
+
+    /// Add the fill level of the queues of the attached remotes.
+    pub fn queue_fill(&mut self, fill: &[QueueFill]) -> &mut Self {
+        self.metrics.push(Metric {
+            name: "cloud_console_remote_queue_fill",
+            kind: Kind::Gauge,
+            help: "Fraction of the queue of a remote which is in use, messages are dropped once full.",
+            samples: fill
+                .iter()
+                .map(|remote| Sample {
+                    remote: Some(remote.remote),
+                    value: Value::Float(remote.queued as f64 / remote.capacity as f64),
+                })
+                .collect(),
+        });
+        self.metrics.push(Metric {
+            name: "cloud_console_remote_queued_messages",
+            kind: Kind::Gauge,
+            help: "Amount of messages queued for a remote.",
+            samples: fill
+                .iter()
+                .map(|remote| Sample {
+                    remote: Some(remote.remote),
+                    value: Value::Int(remote.queued as u64),
+                })
+                .collect(),
+        });
+        self
+    }
+
+    /// The collected metrics.
+    pub fn iter(&self) -> impl Iterator<Item = &Metric> {
+        self.metrics.iter()
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for metric in self.iter() {
+            let kind = match metric.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+            let _ = writeln!(out, "# TYPE {} {}", metric.name, kind);
+            for sample in &metric.samples {
+                let _ = match sample.remote {
+                    Some(remote) => writeln!(
+                        out,
+                        "{}{{remote=\"{}\"}} {}",
+                        metric.name, remote, sample.value
+                    ),
+                    None => writeln!(out, "{} {}", metric.name, sample.value),
+                };
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{}", value),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use tokio::io::AsyncWrite;
+
+use std::{
+    fmt, io,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+
+/// A line ending to normalize output to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    /// A single line feed (`\n`).
+    Lf,
+    /// A carriage return followed by a line feed (`\r\n`).
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+        }
+    }
+}
+
+impl FromStr for LineEnding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lf" => Ok(LineEnding::Lf),
+            "crlf" => Ok(LineEnding::CrLf),
+            _ => Err(format!("unknown line ending {}, expected lf or crlf", s)),
+        }
+    }
+}
+
+impl fmt::Display for LineEnding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LineEnding::Lf => "lf",
+            LineEnding::CrLf => "crlf",
+        })
+    }
+}
+
+/// An [`AsyncWrite`] adapter which normalizes line endings. Both `\n` and `\r\n` are written as
+/// the configured [`LineEnding`], a carriage return which is not followed by a line feed is
+/// written as is. Line endings which span multiple writes are handled.
+#[derive(Debug)]
+pub struct NewlineWriter<W> {
+    inner: W,
+    ending: LineEnding,
+    /// The last byte written was a carriage return, which is held back until we know if it is
+    /// followed by a line feed.
+    pending_cr: bool,
+    /// Converted data which is not yet written to the inner writer.
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<W> NewlineWriter<W> {
+    /// Create a new NewlineWriter, writing data with normalized line endings to `inner`.
+    pub fn new(inner: W, ending: LineEnding) -> NewlineWriter<W> {
+        NewlineWriter {
+            inner,
+            ending,
+            pending_cr: false,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Consume the NewlineWriter, returning the inner writer. Data which is not yet flushed is
+    /// lost.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn convert(&mut self, data: &[u8]) {
+        for &b in data {
+            if self.pending_cr {
+                self.pending_cr = false;
+                if b == b'\n' {
+                    self.buf.extend_from_slice(self.ending.as_bytes());
+                    continue;
+                }
+                self.buf.push(b'\r');
+            }
+            match b {
+                b'\r' => self.pending_cr = true,
+                b'\n' => self.buf.extend_from_slice(self.ending.as_bytes()),
+                b => self.buf.push(b),
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> NewlineWriter<W> {
+    /// Write all converted data to the inner writer.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pos < self.buf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.buf[self.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pos += n;
+        }
+        self.buf.clear();
+        self.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for NewlineWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        this.convert(data);
+        // Start writing right away, the data is accepted regardless.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // No more data will follow, so a held back carriage return is not part of a line ending.
+        if this.pending_cr {
+            this.pending_cr = false;
+            this.buf.push(b'\r');
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncWriteExt;
+
+    async fn normalize(ending: LineEnding, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut w = NewlineWriter::new(Vec::new(), ending);
+        for chunk in chunks {
+            w.write_all(chunk).await.unwrap();
+        }
+        w.shutdown().await.unwrap();
+        w.into_inner()
+    }
+
+    #[tokio::test]
+    async fn test_normalize_to_lf() {
+        assert_eq!(
+            normalize(LineEnding::Lf, &[b"a\r\nb\nc\rd\r"]).await,
+            b"a\nb\nc\rd\r"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_normalize_to_crlf() {
+        assert_eq!(
+            normalize(LineEnding::CrLf, &[b"a\r\nb\nc\rd\r\r\n"]).await,
+            b"a\r\nb\r\nc\rd\r\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_normalize_across_chunks() {
+        assert_eq!(
+            normalize(
+                LineEnding::Lf,
+                &[b"line 1\r", b"\nline 2\r", b"", b"\n", b"\r"]
+            )
+            .await,
+            b"line 1\nline 2\n\r"
+        );
+        assert_eq!(
+            normalize(LineEnding::CrLf, &[b"line 1\r", b"\nline 2\n", b"\r", b"x"]).await,
+            b"line 1\r\nline 2\r\n\rx"
+        );
+    }
+
+    #[test]
+    fn test_parse_line_ending() {
+        assert_eq!("lf".parse::<LineEnding>(), Ok(LineEnding::Lf));
+        assert_eq!("crlf".parse::<LineEnding>(), Ok(LineEnding::CrLf));
+        assert!("cr".parse::<LineEnding>().is_err());
+    }
+}
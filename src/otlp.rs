@@ -0,0 +1,209 @@
+use axum::http::{header, Method, Request, Uri};
+use hyper::{client::HttpConnector, Body, Client};
+use serde_json::{json, Value as Json};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    clock::Clock,
+    metrics::{Kind, Metrics, Value},
+};
+
+/// Amount of exports which can be queued. Exports are dropped if the queue is full, so a slow
+/// collector does not affect the console.
+const OTLP_BACKLOG: usize = 64;
+/// Maximum time a single export can take.
+const OTLP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Name of the instrumentation scope of all exported data.
+const SCOPE: &str = "cloud-console";
+
+/// A request to be posted to the collector.
+#[derive(Debug)]
+struct Export {
+    /// Path of the signal, relative to the endpoint.
+    path: &'static str,
+    body: Json,
+}
+
+/// Exports metrics and spans for client sessions to an OpenTelemetry collector, using OTLP over
+/// HTTP with JSON encoding. Exports are posted in the background, exporting never blocks.
+#[derive(Debug, Clone)]
+pub struct Otlp {
+    tx: mpsc::Sender<Export>,
+    /// Attributes identifying this console.
+    resource: Arc<Json>,
+    /// Start of the time window of the counters.
+    start: SystemTime,
+    clock: Arc<dyn Clock>,
+    ids: RandomState,
+}
+
+impl Otlp {
+    /// Spawn a task posting exports for the console with the given name to `endpoint`, the base
+    /// url of an OTLP/HTTP receiver (e.g. `http://localhost:4318`). Timestamps are taken from
+    /// `clock`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub fn spawn(endpoint: &Uri, console: &str, clock: Arc<dyn Clock>) -> Otlp {
+        let base = endpoint.to_string().trim_end_matches('/').to_string();
+        let (tx, mut rx) = mpsc::channel::<Export>(OTLP_BACKLOG);
+        tokio::spawn(async move {
+            let client = Client::new();
+            while let Some(export) = rx.recv().await {
+                let url = format!("{}{}", base, export.path);
+                match tokio::time::timeout(OTLP_TIMEOUT, post(&client, &url, &export.body)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Could not export to OTLP collector {}", e),
+                    Err(_) => warn!("Export to OTLP collector timed out"),
+                }
+            }
+        });
+
+        Otlp {
+            tx,
+            resource: Arc::new(json!({
+                "attributes": [
+                    string_attribute("service.name", SCOPE),
+                    string_attribute("console.name", console),
+                ]
+            })),
+            start: clock.wall(),
+            clock,
+            ids: RandomState::new(),
+        }
+    }
+
+    /// Export the current value of all metrics. Counters are cumulative since the exporter was
+    /// started.
+    pub fn export_metrics(&self, metrics: &Metrics) {
+        let (start, now) = (nanos(self.start), nanos(self.clock.wall()));
+        let metrics: Vec<Json> = metrics
+            .iter()
+            .map(|metric| {
+                let points: Vec<Json> = metric
+                    .samples
+                    .iter()
+                    .map(|sample| {
+                        let mut point = json!({
+                            "attributes": sample.remote.map(|remote| {
+                                json!({"key": "remote", "value": {"intValue": remote.to_string()}})
+                            }).into_iter().collect::<Vec<_>>(),
+                            "startTimeUnixNano": start,
+                            "timeUnixNano": now,
+                        });
+                        match sample.value {
+                            // 64 bit integers are encoded as strings in JSON.
+                            Value::Int(value) => point["asInt"] = json!(value.to_string()),
+                            Value::Float(value) => point["asDouble"] = json!(value),
+                        }
+                        point
+                    })
+                    .collect();
+                let mut out = json!({"name": metric.name, "description": metric.help});
+                match metric.kind {
+                    // Cumulative aggregation temporality.
+                    Kind::Counter => {
+                        out["sum"] = json!({
+                            "aggregationTemporality": 2,
+                            "isMonotonic": true,
+                            "dataPoints": points,
+                        })
+                    }
+                    Kind::Gauge => out["gauge"] = json!({ "dataPoints": points }),
+                }
+                out
+            })
+            .collect();
+        self.export(
+            "/v1/metrics",
+            json!({
+                "resourceMetrics": [{
+                    "resource": *self.resource,
+                    "scopeMetrics": [{"scope": {"name": SCOPE}, "metrics": metrics}],
+                }]
+            }),
+        );
+    }
+
+    /// Export a span for the session of a client which connected at `start`, and ended now.
+    pub fn export_session(
+        &self,
+        client: u64,
+        addr: SocketAddr,
+        correlation_id: &str,
+        writable: bool,
+        start: SystemTime,
+    ) {
+        let trace = self.ids.hash_one((client, start, 0));
+        let trace_low = self.ids.hash_one((client, start, 1));
+        let span = json!({
+            "traceId": format!("{:016x}{:016x}", trace, trace_low),
+            "spanId": format!("{:016x}", self.ids.hash_one((client, start, 2))),
+            "name": "session",
+            // A span of a server handling a request.
+            "kind": 2,
+            "startTimeUnixNano": nanos(start),
+            "endTimeUnixNano": nanos(self.clock.wall()),
+            "attributes": [
+                string_attribute("client.address", &addr.ip().to_string()),
+                {"key": "client.id", "value": {"intValue": client.to_string()}},
+                string_attribute("session.correlation_id", correlation_id),
+                {"key": "session.writable", "value": {"boolValue": writable}},
+            ],
+        });
+        self.export(
+            "/v1/traces",
+            json!({
+                "resourceSpans": [{
+                    "resource": *self.resource,
+                    "scopeSpans": [{"scope": {"name": SCOPE}, "spans": [span]}],
+                }]
+            }),
+        );
+    }
+
+    fn export(&self, path: &'static str, body: Json) {
+        if self.tx.try_send(Export { path, body }).is_err() {
+            warn!("OTLP export queue is full, dropping export to {}", path);
+        }
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> Json {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+/// Nanoseconds since the unix epoch, encoded as a string like all 64 bit integers in OTLP/JSON.
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+        .to_string()
+}
+
+async fn post(
+    client: &Client<HttpConnector>,
+    url: &str,
+    body: &Json,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(body)?))?;
+    let resp = client.request(req).await?;
+    if !resp.status().is_success() {
+        return Err(format!("collector returned status {}", resp.status()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use clap::ValueEnum;
+
+use std::borrow::Cow;
+
+/// How NUL bytes in the pty output are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NulBytes {
+    /// Pass NUL bytes on as is.
+    Pass,
+    /// Remove NUL bytes from the output.
+    Strip,
+}
+
+impl NulBytes {
+    /// Apply the NUL byte handling to output of the pty.
+    pub fn apply(self, data: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            NulBytes::Strip if data.contains(&0) => {
+                Cow::Owned(data.iter().copied().filter(|&b| b != 0).collect())
+            }
+            _ => Cow::Borrowed(data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_nul_bytes() {
+        assert_eq!(
+            &*NulBytes::Strip.apply(b"\0\0login:\0 \x1b[0m\0"),
+            b"login: \x1b[0m"
+        );
+        assert_eq!(&*NulBytes::Strip.apply(b"plain"), b"plain");
+        assert!(matches!(NulBytes::Strip.apply(b"plain"), Cow::Borrowed(_)));
+        assert_eq!(&*NulBytes::Pass.apply(b"a\0b"), b"a\0b");
+    }
+}
@@ -0,0 +1,270 @@
+use clap::ValueEnum;
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, ReadBuf},
+    sync::mpsc,
+};
+
+use std::{
+    io::{self, Read},
+    os::unix::io::AsRawFd,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    thread,
+};
+
+/// Amount of chunks read by the reader thread which can be queued before the thread stops
+/// reading.
+const THREAD_BACKLOG: usize = 16;
+
+/// The EOF character used if the pty does not define one, ^D.
+pub const DEFAULT_EOF: u8 = 0x04;
+
+/// How the pty is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PtyReader {
+    /// Read the pty with tokio's async file I/O.
+    Async,
+    /// Read the pty with blocking reads on a dedicated thread.
+    Thread,
+    /// Read the pty with non-blocking reads whenever the runtime reports it as readable.
+    Poll,
+}
+
+/// When the foreground process group of the pty receives a hangup, like a real terminal which is
+/// disconnected would deliver. Programs which don't handle SIGHUP exit on it, including background
+/// jobs started from a shell which forwards the hangup to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HangupPolicy {
+    /// Never send a hangup, programs keep running as if a client was still connected.
+    Never,
+    /// Send a hangup when the server shuts down.
+    Shutdown,
+    /// Send a hangup when the last client disconnects, and when the server shuts down.
+    Disconnect,
+}
+
+/// An [`AsyncRead`] reading a file with blocking reads on a dedicated thread. Unlike tokio's file
+/// I/O, which runs every read as a separate blocking task, a single read is in flight at any time,
+/// which suits character devices with blocking read semantics better.
+#[derive(Debug)]
+pub struct ThreadReader {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ThreadReader {
+    /// Spawn a thread reading from `file`, up to `read_size` bytes at once. The thread stops once
+    /// the file reaches EOF, reading fails, or the ThreadReader is dropped and the next read
+    /// completes.
+    pub fn spawn(mut file: std::fs::File, read_size: usize) -> ThreadReader {
+        let (tx, rx) = mpsc::channel(THREAD_BACKLOG);
+        thread::Builder::new()
+            .name("pty-reader".into())
+            .spawn(move || {
+                let mut buffer = vec![0; read_size];
+                loop {
+                    let res = match file.read(&mut buffer) {
+                        Ok(0) => return,
+                        Ok(n) => Ok(buffer[..n].to_vec()),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => Err(e),
+                    };
+                    let failed = res.is_err();
+                    if tx.blocking_send(res).is_err() || failed {
+                        return;
+                    }
+                }
+            })
+            .expect("can spawn pty reader thread");
+
+        ThreadReader {
+            rx,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl AsyncRead for ThreadReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos == this.buf.len() {
+            match ready!(this.rx.poll_recv(cx)) {
+                Some(Ok(data)) => {
+                    this.buf = data;
+                    this.pos = 0;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                // The thread stopped, which only happens at EOF.
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = usize::min(out.remaining(), this.buf.len() - this.pos);
+        out.put_slice(&this.buf[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// An [`AsyncRead`] reading a file with non-blocking reads, once the runtime reports the file as
+/// readable. Unlike tokio's file I/O or a [`ThreadReader`], no read is in flight while waiting for
+/// data, so the file is closed as soon as the PollReader is dropped.
+#[derive(Debug)]
+pub struct PollReader {
+    fd: AsyncFd<std::fs::File>,
+}
+
+impl PollReader {
+    /// Create a new PollReader, which puts `file` in non-blocking mode.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub fn new(file: std::fs::File) -> io::Result<PollReader> {
+        let fd = file.as_raw_fd();
+        // SAFETY: fcntl with these commands only reads and sets the flags of the fd, which is
+        // owned by `file`.
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(PollReader {
+            fd: AsyncFd::new(file)?,
+        })
+    }
+}
+
+impl AsyncRead for PollReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let unfilled = out.initialize_unfilled();
+            match guard.try_io(|fd| fd.get_ref().read(unfilled)) {
+                Ok(Ok(n)) => {
+                    out.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                // The readiness was stale, wait until the file is readable again.
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+/// The character which signals end of file to the program reading the terminal referred to by
+/// `fd`. The line discipline only treats it as end of file in canonical mode, in which case
+/// writing it at the start of a line makes the next read of the program return no data.
+pub fn eof_char(fd: &impl AsRawFd) -> io::Result<u8> {
+    // SAFETY: termios is a plain C struct, for which all zeroes is a valid value.
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    // SAFETY: tcgetattr writes to the termios struct, which outlives the call.
+    if unsafe { libc::tcgetattr(fd.as_raw_fd(), &mut termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The character can be disabled, in which case there is no better option than the default.
+    Ok(match termios.c_cc[libc::VEOF] {
+        libc::_POSIX_VDISABLE => DEFAULT_EOF,
+        c => c,
+    })
+}
+
+/// Send SIGHUP to the foreground process group of the terminal referred to by `fd`. For the master
+/// side of a pty, this is the foreground process group of the slave side.
+pub fn hangup(fd: &impl AsRawFd) -> io::Result<()> {
+    // SAFETY: tcgetpgrp only reads the foreground process group of the fd.
+    let pgrp = unsafe { libc::tcgetpgrp(fd.as_raw_fd()) };
+    if pgrp < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: killpg has no memory safety requirements.
+    if unsafe { libc::killpg(pgrp, libc::SIGHUP) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncReadExt;
+
+    use std::{io::Write, os::unix::io::FromRawFd};
+
+    /// A pipe standing in for a device, returning the read and write side.
+    fn pipe() -> (std::fs::File, std::fs::File) {
+        let mut fds = [0; 2];
+        // SAFETY: the pointer is valid for 2 fds, and on success the returned fds are owned by
+        // us.
+        unsafe {
+            assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+            (
+                std::fs::File::from_raw_fd(fds[0]),
+                std::fs::File::from_raw_fd(fds[1]),
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn test_thread_reader() {
+        let (device, mut input) = pipe();
+        let mut reader = ThreadReader::spawn(device, 4096);
+
+        input.write_all(b"hello from the device").unwrap();
+        let mut buf = [0; 10];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello from");
+
+        input.write_all(b"\r\n").unwrap();
+        drop(input);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b" the device\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_thread_reader_read_size() {
+        let (device, mut input) = pipe();
+        input.write_all(&[b'x'; 100]).unwrap();
+        let mut reader = ThreadReader::spawn(device, 64);
+
+        // Every chunk read by the thread is at most the read size.
+        let mut buf = [0; 128];
+        assert_eq!(reader.read(&mut buf).await.unwrap(), 64);
+        assert_eq!(reader.read(&mut buf).await.unwrap(), 36);
+    }
+
+    #[tokio::test]
+    async fn test_poll_reader() {
+        let (device, mut input) = pipe();
+        let mut reader = PollReader::new(device).unwrap();
+
+        input.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // A pending read completes once data arrives.
+        let read = tokio::spawn(async move {
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+        tokio::task::yield_now().await;
+        input.write_all(b" device").unwrap();
+        drop(input);
+        assert_eq!(read.await.unwrap(), b" device");
+    }
+}
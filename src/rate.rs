@@ -0,0 +1,128 @@
+//! Pacing of the output sent to remotes.
+
+use tokio::time::Instant;
+
+use std::collections::VecDeque;
+
+/// A token bucket, allowing `rate` bytes per second on average, with bursts of up to `burst`
+/// bytes. The bucket starts out full.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Create a new TokenBucket. A rate or burst of 0 is treated as 1.
+    pub fn new(rate: u64, burst: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate.max(1),
+            burst: burst.max(1),
+            tokens: burst.max(1) as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take up to `max` tokens, returning the amount of tokens taken.
+    pub fn take(&mut self, max: usize) -> usize {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = f64::min(self.tokens + elapsed * self.rate as f64, self.burst as f64);
+        self.last = now;
+        let taken = usize::min(self.tokens as usize, max);
+        self.tokens -= taken as f64;
+        taken
+    }
+}
+
+/// Output which is held back from remotes until the [`TokenBucket`] allows it to be sent.
+#[derive(Debug)]
+pub(crate) struct Pacer {
+    pub(crate) bucket: TokenBucket,
+    backlog: VecDeque<u8>,
+    capacity: usize,
+    /// Amount of bytes dropped from the backlog since output was last sent.
+    skipped: u64,
+}
+
+impl Pacer {
+    /// Create a new Pacer holding back up to `capacity` bytes.
+    pub(crate) fn new(bucket: TokenBucket, capacity: usize) -> Pacer {
+        Pacer {
+            bucket,
+            backlog: VecDeque::new(),
+            capacity,
+            skipped: 0,
+        }
+    }
+
+    /// The amount of bytes which are held back.
+    pub(crate) fn len(&self) -> usize {
+        self.backlog.len()
+    }
+
+    /// Hold back data. Once the backlog is full, the oldest data is dropped.
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        self.backlog.extend(data);
+        let excess = self.backlog.len().saturating_sub(self.capacity);
+        self.backlog.drain(..excess);
+        self.skipped += excess as u64;
+    }
+
+    /// Take the output which can be sent now, if any. If output was dropped, it is preceded by a
+    /// marker, so the gap is visible.
+    pub(crate) fn take(&mut self) -> Option<Vec<u8>> {
+        let n = self.bucket.take(self.backlog.len());
+        self.take_n(n)
+    }
+
+    /// Take all held back output.
+    pub(crate) fn take_all(&mut self) -> Option<Vec<u8>> {
+        self.take_n(self.backlog.len())
+    }
+
+    fn take_n(&mut self, n: usize) -> Option<Vec<u8>> {
+        if n == 0 {
+            return None;
+        }
+        let mut out = Vec::with_capacity(n);
+        if self.skipped > 0 {
+            out.extend_from_slice(format!("\r\n[{} bytes skipped]\r\n", self.skipped).as_bytes());
+            self.skipped = 0;
+        }
+        out.extend(self.backlog.drain(..n));
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(100, 50);
+        assert_eq!(bucket.take(80), 50);
+        assert_eq!(bucket.take(80), 0);
+        tokio::time::advance(Duration::from_millis(200)).await;
+        assert_eq!(bucket.take(10), 10);
+        assert_eq!(bucket.take(80), 10);
+        // The bucket never holds more than the burst.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(bucket.take(80), 50);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pacer_gap_marker() {
+        let mut pacer = Pacer::new(TokenBucket::new(10, 4), 8);
+        pacer.push(b"0123456789ab");
+        assert_eq!(pacer.len(), 8);
+        assert_eq!(pacer.take().unwrap(), b"\r\n[4 bytes skipped]\r\n4567");
+        assert_eq!(pacer.take(), None);
+        assert_eq!(pacer.take_all().unwrap(), b"89ab");
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+
+/// An in-memory capture of console output, which grows up to a maximum size. Once the maximum
+/// size is reached, the oldest data is discarded to make room for new data.
+///
+/// Unlike the history buffer of the [`ConsoleMux`](crate::ConsoleMux), memory is only allocated
+/// as data is captured, so the maximum size can be much larger than the history without the cost
+/// being paid upfront.
+#[derive(Debug)]
+pub struct Recording {
+    data: VecDeque<u8>,
+    max_size: usize,
+}
+
+impl Recording {
+    /// Create a new, empty Recording which retains at most `max_size` bytes.
+    pub fn new(max_size: usize) -> Recording {
+        Recording {
+            data: VecDeque::new(),
+            max_size,
+        }
+    }
+
+    /// Append data to the recording, discarding the oldest data if the maximum size is exceeded.
+    pub fn push(&mut self, data: &[u8]) {
+        let data = if data.len() > self.max_size {
+            &data[data.len() - self.max_size..]
+        } else {
+            data
+        };
+
+        let overflow = (self.data.len() + data.len()).saturating_sub(self.max_size);
+        self.data.drain(..overflow);
+        self.data.extend(data);
+    }
+
+    /// The amount of bytes currently retained.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns true if nothing has been recorded yet.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The maximum amount of bytes retained.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Copy the retained data, oldest data first.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let (front, back) = self.data.as_slices();
+        let mut data = Vec::with_capacity(self.data.len());
+        data.extend_from_slice(front);
+        data.extend_from_slice(back);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording_grows_up_to_max_size() {
+        let mut rec = Recording::new(100);
+
+        rec.push(&[1; 60]);
+        assert_eq!(rec.len(), 60);
+
+        rec.push(&[2; 60]);
+        assert_eq!(rec.len(), 100);
+        let data = rec.to_vec();
+        assert_eq!(&data[..40], &[1; 40]);
+        assert_eq!(&data[40..], &[2; 60]);
+    }
+
+    #[test]
+    fn test_recording_oversized_push() {
+        let mut rec = Recording::new(100);
+
+        rec.push(&[1; 10]);
+        rec.push(&[2; 150]);
+        assert_eq!(rec.to_vec(), vec![2; 100]);
+    }
+}
@@ -0,0 +1,80 @@
+use std::str::FromStr;
+
+use crate::session::SESSION_PATH;
+
+/// Path of the websocket route serving the output as a hexdump.
+pub const HEX_PATH: &str = "/ws/hex";
+/// Path of the websocket route serving the console read only, for clients which only observe.
+pub const READ_ONLY_PATH: &str = "/ws/readonly";
+
+/// An extra websocket route, which replays at most `max` bytes of history to clients connecting to
+/// it. Denoted as `<path>=<bytes>`, e.g. `/ws/lite=4096`. The path must be below `/ws/`, so it
+/// can't clash with the other routes, and can't be the hexdump view on [`HEX_PATH`] or the read
+/// only view on [`READ_ONLY_PATH`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayRoute {
+    pub path: String,
+    pub max: usize,
+}
+
+impl FromStr for ReplayRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, max) = s
+            .split_once('=')
+            .ok_or_else(|| "expected <path>=<bytes>".to_string())?;
+        if !path.starts_with("/ws/") || path.len() == 4 {
+            return Err("the path must be below /ws/".into());
+        }
+        if path == HEX_PATH {
+            return Err(format!("{} is reserved for the hexdump view", HEX_PATH));
+        }
+        if path == READ_ONLY_PATH {
+            return Err(format!(
+                "{} is reserved for the read only view",
+                READ_ONLY_PATH
+            ));
+        }
+        if path.starts_with(SESSION_PATH) {
+            return Err(format!(
+                "paths below {} are reserved for sessions",
+                SESSION_PATH
+            ));
+        }
+        if path.contains([':', '*']) {
+            return Err("the path can't contain parameters".into());
+        }
+        let max = max
+            .parse()
+            .map_err(|e| format!("invalid amount of bytes: {}", e))?;
+        Ok(ReplayRoute {
+            path: path.to_string(),
+            max,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_replay_route() {
+        assert_eq!(
+            "/ws/lite=4096".parse(),
+            Ok(ReplayRoute {
+                path: "/ws/lite".into(),
+                max: 4096,
+            })
+        );
+        assert!("/ws/lite".parse::<ReplayRoute>().is_err());
+        assert!("/lite=4096".parse::<ReplayRoute>().is_err());
+        assert!("/ws/=4096".parse::<ReplayRoute>().is_err());
+        assert!("/ws/:id=4096".parse::<ReplayRoute>().is_err());
+        assert!("/ws/hex=4096".parse::<ReplayRoute>().is_err());
+        assert!("/ws/readonly=4096".parse::<ReplayRoute>().is_err());
+        assert!("/ws/session/vm2=4096".parse::<ReplayRoute>().is_err());
+        assert!("/ws/lite=-1".parse::<ReplayRoute>().is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+use std::{collections::BTreeMap, io, os::unix::io::AsRawFd};
+
+/// Dimensions of a terminal, in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct WinSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// Policy to decide which size is used for the pty, if multiple clients report a different size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizePolicy {
+    /// Use the smallest amount of columns and rows reported by any client, so no client has a
+    /// clipped view.
+    Smallest,
+    /// Use the size of the client which has been connected the longest.
+    Controller,
+    /// Use the size of the client which reported a size most recently.
+    Last,
+}
+
+/// Tracks the terminal size reported by every connected client, and derives the size to apply to
+/// the pty according to the configured [`ResizePolicy`].
+#[derive(Debug)]
+pub struct SizeTracker {
+    policy: ResizePolicy,
+    /// Reported size per client id, with a sequence number of the report. Client ids are handed
+    /// out incrementally, so the first entry is the client which has been connected the longest.
+    sizes: BTreeMap<u64, (WinSize, u64)>,
+    seq: u64,
+    /// The size and mismatch state which was last returned to the caller.
+    applied: Option<(WinSize, bool)>,
+}
+
+impl SizeTracker {
+    /// Create a new SizeTracker which does not know about any clients.
+    pub fn new(policy: ResizePolicy) -> SizeTracker {
+        SizeTracker {
+            policy,
+            sizes: BTreeMap::new(),
+            seq: 0,
+            applied: None,
+        }
+    }
+
+    /// Record a new size for a client. If this changes the size which should be applied to the
+    /// pty, or whether clients have mismatching sizes, the new state is returned.
+    pub fn update(&mut self, client: u64, size: WinSize) -> Option<(WinSize, bool)> {
+        self.seq += 1;
+        self.sizes.insert(client, (size, self.seq));
+        self.refresh()
+    }
+
+    /// Forget the size of a (disconnected) client. If this changes the size which should be
+    /// applied to the pty, or whether clients have mismatching sizes, the new state is returned.
+    pub fn remove(&mut self, client: u64) -> Option<(WinSize, bool)> {
+        self.sizes.remove(&client)?;
+        self.refresh()
+    }
+
+    /// The size which should currently be applied to the pty, if any client reported one.
+    pub fn effective(&self) -> Option<WinSize> {
+        let mut sizes = self.sizes.values();
+        match self.policy {
+            ResizePolicy::Smallest => sizes.map(|(size, _)| *size).reduce(|a, b| WinSize {
+                cols: a.cols.min(b.cols),
+                rows: a.rows.min(b.rows),
+            }),
+            ResizePolicy::Controller => sizes.next().map(|(size, _)| *size),
+            ResizePolicy::Last => sizes.max_by_key(|(_, seq)| *seq).map(|(size, _)| *size),
+        }
+    }
+
+    /// Whether the connected clients reported different sizes.
+    pub fn is_mismatched(&self) -> bool {
+        let mut sizes = self.sizes.values().map(|(size, _)| size);
+        match sizes.next() {
+            Some(first) => sizes.any(|size| size != first),
+            None => false,
+        }
+    }
+
+    fn refresh(&mut self) -> Option<(WinSize, bool)> {
+        // If the last client left we keep the pty at its current size.
+        let current = (self.effective()?, self.is_mismatched());
+        if self.applied == Some(current) {
+            return None;
+        }
+        self.applied = Some(current);
+        Some(current)
+    }
+}
+
+/// Set the window size of the terminal referred to by `fd`, which will notify the foreground
+/// process group with a SIGWINCH.
+pub fn set_winsize(fd: &impl AsRawFd, size: WinSize) -> io::Result<()> {
+    let ws = libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCSWINSZ only reads the winsize struct, which lives for the duration of the call.
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCSWINSZ, &ws) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMALL: WinSize = WinSize { cols: 80, rows: 25 };
+    const BIG: WinSize = WinSize {
+        cols: 200,
+        rows: 60,
+    };
+
+    #[test]
+    fn test_smallest_wins() {
+        let mut st = SizeTracker::new(ResizePolicy::Smallest);
+
+        assert_eq!(st.update(1, BIG), Some((BIG, false)));
+        assert_eq!(
+            st.update(
+                2,
+                WinSize {
+                    cols: 100,
+                    rows: 80
+                }
+            ),
+            Some((
+                WinSize {
+                    cols: 100,
+                    rows: 60
+                },
+                true
+            ))
+        );
+        assert_eq!(st.remove(2), Some((BIG, false)));
+    }
+
+    #[test]
+    fn test_controller_wins() {
+        let mut st = SizeTracker::new(ResizePolicy::Controller);
+
+        assert_eq!(st.update(1, BIG), Some((BIG, false)));
+        assert_eq!(st.update(2, SMALL), Some((BIG, true)));
+        // Nothing changes for the pty or the mismatch state.
+        assert_eq!(st.update(2, SMALL), None);
+        // Controller leaves, the next client takes over.
+        assert_eq!(st.remove(1), Some((SMALL, false)));
+    }
+
+    #[test]
+    fn test_last_resize_wins() {
+        let mut st = SizeTracker::new(ResizePolicy::Last);
+
+        assert_eq!(st.update(1, BIG), Some((BIG, false)));
+        assert_eq!(st.update(2, SMALL), Some((SMALL, true)));
+        assert_eq!(st.update(1, BIG), Some((BIG, true)));
+        assert_eq!(st.remove(1), Some((SMALL, false)));
+    }
+
+    #[test]
+    fn test_last_client_leaves() {
+        let mut st = SizeTracker::new(ResizePolicy::Smallest);
+
+        st.update(1, SMALL);
+        assert_eq!(st.remove(1), None);
+        assert_eq!(st.remove(1), None);
+        assert_eq!(st.effective(), None);
+        assert!(!st.is_mismatched());
+    }
+}
@@ -0,0 +1,258 @@
+//! A log file which is rotated once it reaches a maximum size, see `--log-max-size`.
+
+use cloud_console::signature::{signature_path, FileSigner};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWrite, AsyncWriteExt},
+};
+
+use std::{
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+
+type Rotation = Pin<Box<dyn Future<Output = io::Result<File>> + Send>>;
+
+enum FileState {
+    Writing(File),
+    /// The full file is being renamed, and a new file opened.
+    Rotating(Rotation),
+    /// Rotating failed, the file can't be written to anymore.
+    Failed,
+}
+
+/// Appends to the file at a path until it holds `max_size` bytes. The file is then renamed to
+/// `<path>.1`, earlier rotated files are renamed to the next number, keeping up to `max_files` of
+/// them, and writing continues in a new file at the path. Output is split at the limit, so every
+/// rotated file holds exactly `max_size` bytes. If the file is signed, it is signed once more
+/// before it is rotated, and the signature moves along with it.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    signer: Option<Arc<Mutex<FileSigner>>>,
+    /// Size of the current file.
+    size: u64,
+    state: FileState,
+}
+
+impl RotatingFile {
+    /// Open the file at `path` for appending, creating it if needed. Data already in the file
+    /// counts towards its size.
+    pub async fn open(path: &Path, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        let file = open_append(path).await?;
+        let size = file.metadata().await?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            max_files,
+            signer: None,
+            size,
+            state: FileState::Writing(file),
+        })
+    }
+
+    /// Sign every file before it is rotated with `signer`, which signs the file at the path. The
+    /// signer starts over once the file is rotated.
+    pub fn signed_by(mut self, signer: Arc<Mutex<FileSigner>>) -> RotatingFile {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Wait until the current file can be written to, starting a rotation if it is full.
+    fn poll_file(&mut self, cx: &mut Context<'_>, rotate: bool) -> Poll<io::Result<&mut File>> {
+        loop {
+            match &mut self.state {
+                FileState::Writing(_) if rotate && self.size >= self.max_size => {
+                    let FileState::Writing(file) =
+                        std::mem::replace(&mut self.state, FileState::Failed)
+                    else {
+                        unreachable!()
+                    };
+                    let (path, max_files) = (self.path.clone(), self.max_files);
+                    let rotation = rotate_file(file, path, max_files, self.signer.clone());
+                    self.state = FileState::Rotating(Box::pin(rotation));
+                }
+                FileState::Writing(_) => break,
+                FileState::Rotating(rotation) => {
+                    let result = ready!(rotation.as_mut().poll(cx));
+                    match result {
+                        Ok(file) => {
+                            self.size = 0;
+                            self.state = FileState::Writing(file);
+                        }
+                        Err(e) => {
+                            self.state = FileState::Failed;
+                            return Poll::Ready(Err(e));
+                        }
+                    }
+                }
+                FileState::Failed => {
+                    return Poll::Ready(Err(io::Error::other("rotating the log file failed")))
+                }
+            }
+        }
+        match &mut self.state {
+            FileState::Writing(file) => Poll::Ready(Ok(file)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl AsyncWrite for RotatingFile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let room = (this.max_size - this.size.min(this.max_size)) as usize;
+        let limit = match room {
+            // The file is rotated first.
+            0 => (this.max_size as usize).min(buf.len()),
+            room => room.min(buf.len()),
+        };
+        let file = ready!(this.poll_file(cx, true))?;
+        let n = ready!(Pin::new(file).poll_write(cx, &buf[..limit]))?;
+        this.size += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let file = ready!(self.get_mut().poll_file(cx, false))?;
+        Pin::new(file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let file = ready!(self.get_mut().poll_file(cx, false))?;
+        Pin::new(file).poll_shutdown(cx)
+    }
+}
+
+async fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(false)
+        .create(true)
+        .truncate(false)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// The path of the rotated file with the given number.
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    rotated.into()
+}
+
+/// Rename `from` to `to`, if it exists.
+async fn rename_existing(from: &Path, to: &Path) -> io::Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Finish writing `file`, shift the rotated files of `path` and their signatures, dropping the
+/// oldest, move the file to `<path>.1` and open a new file at `path`. With a signer, the file is
+/// signed before it is moved.
+async fn rotate_file(
+    mut file: File,
+    path: PathBuf,
+    max_files: usize,
+    signer: Option<Arc<Mutex<FileSigner>>>,
+) -> io::Result<File> {
+    file.flush().await?;
+    drop(file);
+    for n in (1..max_files).rev() {
+        let (from, to) = (rotated_path(&path, n), rotated_path(&path, n + 1));
+        rename_existing(&from, &to).await?;
+        rename_existing(&signature_path(&from), &signature_path(&to)).await?;
+    }
+    match signer {
+        Some(signer) => {
+            let path = path.clone();
+            // The signer stays locked until it starts over, so it never signs the new file with
+            // the digest of the rotated one.
+            tokio::task::spawn_blocking(move || {
+                let mut signer = signer.lock().unwrap();
+                signer.sign()?;
+                let rotated = rotated_path(&path, 1);
+                std::fs::rename(signature_path(&path), signature_path(&rotated))?;
+                std::fs::rename(&path, rotated)?;
+                signer.restart();
+                Ok::<_, io::Error>(())
+            })
+            .await
+            .map_err(io::Error::other)??;
+        }
+        None => tokio::fs::rename(&path, rotated_path(&path, 1)).await?,
+    }
+    open_append(&path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("cloud-console-rotate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("console.log");
+        std::fs::write(&path, b"0123").unwrap();
+
+        let mut file = RotatingFile::open(&path, 10, 2).await.unwrap();
+        // The existing data counts, so the first write is split at the limit.
+        file.write_all(b"abcdefghij").await.unwrap();
+        file.write_all(b"klmnopqrstuvwxyz0123456789").await.unwrap();
+        file.shutdown().await.unwrap();
+
+        // Only `max_files` rotated files are kept, "0123abcdef" was dropped.
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+        assert_eq!(
+            std::fs::read(rotated_path(&path, 1)).unwrap(),
+            b"qrstuvwxyz"
+        );
+        assert_eq!(
+            std::fs::read(rotated_path(&path, 2)).unwrap(),
+            b"ghijklmnop"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rotating_file_signed() {
+        use cloud_console::signature::{parse_signing_key, verify};
+
+        let dir = std::env::temp_dir().join(format!("cloud-console-signed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("console.log");
+        let key = parse_signing_key(&"04".repeat(32)).unwrap();
+        let public = key.verifying_key();
+        let signer = Arc::new(Mutex::new(FileSigner::new(key, &path)));
+
+        let file = RotatingFile::open(&path, 10, 2).await.unwrap();
+        let mut file = file.signed_by(signer.clone());
+        file.write_all(b"0123456789abcdefghij0123").await.unwrap();
+        file.shutdown().await.unwrap();
+        signer.lock().unwrap().sign().unwrap();
+
+        // Every segment has its own signature, covering all of it.
+        for segment in [rotated_path(&path, 2), rotated_path(&path, 1), path.clone()] {
+            let verified = verify(&segment, &public).unwrap();
+            assert_eq!(verified.unsigned, 0, "{}", segment.display());
+        }
+        assert_eq!(verify(&rotated_path(&path, 1), &public).unwrap().signed, 10);
+        assert_eq!(verify(&path, &public).unwrap().signed, 4);
+        std::fs::write(rotated_path(&path, 2), b"0123456788").unwrap();
+        assert!(verify(&rotated_path(&path, 2), &public).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
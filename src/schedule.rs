@@ -0,0 +1,92 @@
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A daily window of whole hours in UTC, denoted as `<start>-<end>`, e.g. `22-6` for the hours
+/// from 22:00 until 06:00. The window includes the start hour, but not the end hour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HourRange {
+    start: u8,
+    end: u8,
+}
+
+impl HourRange {
+    /// Check if the given time falls within the window.
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let hour = (secs / 3600 % 24) as u8;
+        if self.start <= self.end {
+            self.start <= hour && hour < self.end
+        } else {
+            // The window wraps around midnight.
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl FromStr for HourRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |hour: &str| {
+            hour.trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|&hour| hour < 24)
+                .ok_or_else(|| format!("invalid hour {}, must be between 0 and 23", hour))
+        };
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| "expected <start>-<end>".to_string())?;
+        Ok(HourRange {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl fmt::Display for HourRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn at(hour: u64, minute: u64) -> SystemTime {
+        // Some day, at the given time.
+        UNIX_EPOCH + Duration::from_secs(19_000 * 86_400 + hour * 3600 + minute * 60)
+    }
+
+    #[test]
+    fn test_hour_range_contains() {
+        let day: HourRange = "9-17".parse().unwrap();
+        assert!(day.contains(at(9, 0)));
+        assert!(day.contains(at(16, 59)));
+        assert!(!day.contains(at(17, 0)));
+        assert!(!day.contains(at(8, 59)));
+
+        let night: HourRange = "22-6".parse().unwrap();
+        assert!(night.contains(at(23, 30)));
+        assert!(night.contains(at(0, 0)));
+        assert!(night.contains(at(5, 59)));
+        assert!(!night.contains(at(6, 0)));
+        assert!(!night.contains(at(12, 0)));
+    }
+
+    #[test]
+    fn test_hour_range_parse_errors() {
+        assert!("22".parse::<HourRange>().is_err());
+        assert!("22-24".parse::<HourRange>().is_err());
+        assert!("a-6".parse::<HourRange>().is_err());
+    }
+}
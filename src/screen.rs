@@ -0,0 +1,419 @@
+//! A basic model of the terminal screen, to reconstruct the current screen for new remotes.
+//!
+//! Only the visible grid, the cursor position and the basic text attributes are modeled. Scroll
+//! regions, the alternate screen and other modes are not, so programs which rely on them might not
+//! be reconstructed correctly.
+
+use std::fmt::{self, Write};
+
+use vte::{Params, Parser, Perform};
+
+/// A color of a cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Color {
+    #[default]
+    Default,
+    /// One of the 256 indexed colors.
+    Indexed(u8),
+}
+
+/// Text attributes of a cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Attrs {
+    bold: bool,
+    underline: bool,
+    reverse: bool,
+    fg: Color,
+    bg: Color,
+}
+
+impl Attrs {
+    /// Append the SGR sequence selecting these attributes, starting from the defaults.
+    fn write_sgr(&self, out: &mut String) {
+        out.push_str("\x1b[0");
+        if self.bold {
+            out.push_str(";1");
+        }
+        if self.underline {
+            out.push_str(";4");
+        }
+        if self.reverse {
+            out.push_str(";7");
+        }
+        if let Color::Indexed(color) = self.fg {
+            let _ = write!(out, ";38;5;{}", color);
+        }
+        if let Color::Indexed(color) = self.bg {
+            let _ = write!(out, ";48;5;{}", color);
+        }
+        out.push('m');
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    ch: char,
+    attrs: Attrs,
+}
+
+impl Default for Cell {
+    fn default() -> Cell {
+        Cell {
+            ch: ' ',
+            attrs: Attrs::default(),
+        }
+    }
+}
+
+/// The state of the screen, updated by the parser.
+#[derive(Debug)]
+struct Grid {
+    cols: usize,
+    rows: usize,
+    cells: Vec<Vec<Cell>>,
+    row: usize,
+    col: usize,
+    /// The last column was written, the cursor moves to the next line on the next character.
+    wrap_pending: bool,
+    attrs: Attrs,
+}
+
+/// A model of the terminal screen, which is updated by feeding it the console output.
+pub struct Screen {
+    parser: Parser,
+    grid: Grid,
+}
+
+impl Screen {
+    /// Create a new, empty screen of the given size.
+    pub fn new(cols: u16, rows: u16) -> Screen {
+        let (cols, rows) = (usize::from(cols.max(1)), usize::from(rows.max(1)));
+        Screen {
+            parser: Parser::new(),
+            grid: Grid {
+                cols,
+                rows,
+                cells: vec![vec![Cell::default(); cols]; rows],
+                row: 0,
+                col: 0,
+                wrap_pending: false,
+                attrs: Attrs::default(),
+            },
+        }
+    }
+
+    /// Update the screen with console output.
+    pub fn feed(&mut self, data: &[u8]) {
+        for &b in data {
+            self.parser.advance(&mut self.grid, b);
+        }
+    }
+
+    /// Clear the screen, as if it was new.
+    pub fn reset(&mut self) {
+        *self = Screen::new(self.grid.cols as u16, self.grid.rows as u16);
+    }
+
+    /// Resize the screen. Content outside of the new size is lost.
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        let grid = &mut self.grid;
+        let (cols, rows) = (usize::from(cols.max(1)), usize::from(rows.max(1)));
+        // Keep the bottom of the screen, which is where the cursor usually is.
+        if rows < grid.rows {
+            let excess = (grid.rows - rows).min(grid.row);
+            grid.cells.drain(..excess);
+            grid.row -= excess;
+        }
+        grid.cells.resize(rows, vec![Cell::default(); cols]);
+        for row in &mut grid.cells {
+            row.resize(cols, Cell::default());
+        }
+        grid.cols = cols;
+        grid.rows = rows;
+        grid.row = grid.row.min(rows - 1);
+        grid.col = grid.col.min(cols - 1);
+        grid.wrap_pending = false;
+    }
+
+    /// The text of every row, without trailing spaces.
+    pub fn lines(&self) -> Vec<String> {
+        self.grid
+            .cells
+            .iter()
+            .map(|row| {
+                let line: String = row.iter().map(|cell| cell.ch).collect();
+                line.trim_end().to_string()
+            })
+            .collect()
+    }
+
+    /// The cursor position, as 0 based row and column.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.grid.row, self.grid.col)
+    }
+
+    /// Render escape sequences which draw the current screen on a terminal of the same size, and
+    /// restore the cursor position and the current attributes.
+    pub fn render(&self) -> Vec<u8> {
+        let grid = &self.grid;
+        let mut out = String::from("\x1b[0m\x1b[H\x1b[2J");
+        for (r, row) in grid.cells.iter().enumerate() {
+            let len = row
+                .iter()
+                .rposition(|cell| *cell != Cell::default())
+                .map_or(0, |last| last + 1);
+            if len == 0 {
+                continue;
+            }
+            let _ = write!(out, "\x1b[{}H", r + 1);
+            let mut attrs = Attrs::default();
+            for cell in &row[..len] {
+                if cell.attrs != attrs {
+                    cell.attrs.write_sgr(&mut out);
+                    attrs = cell.attrs;
+                }
+                out.push(cell.ch);
+            }
+            if attrs != Attrs::default() {
+                out.push_str("\x1b[0m");
+            }
+        }
+        let _ = write!(out, "\x1b[{};{}H", grid.row + 1, grid.col + 1);
+        grid.attrs.write_sgr(&mut out);
+        out.into_bytes()
+    }
+}
+
+impl fmt::Debug for Screen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Screen").field("grid", &self.grid).finish()
+    }
+}
+
+impl Grid {
+    fn line_feed(&mut self) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.cells.remove(0);
+            self.cells.push(vec![Cell::default(); self.cols]);
+        }
+    }
+
+    fn erase(&mut self, row: usize, cols: std::ops::Range<usize>) {
+        for cell in &mut self.cells[row][cols] {
+            *cell = Cell {
+                ch: ' ',
+                attrs: Attrs {
+                    bg: self.attrs.bg,
+                    ..Attrs::default()
+                },
+            };
+        }
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.row = row.min(self.rows - 1);
+        self.col = col.min(self.cols - 1);
+        self.wrap_pending = false;
+    }
+
+    fn sgr(&mut self, params: &Params) {
+        if params.is_empty() {
+            self.attrs = Attrs::default();
+        }
+        let mut params = params.iter().map(|p| p[0]);
+        while let Some(param) = params.next() {
+            match param {
+                0 => self.attrs = Attrs::default(),
+                1 => self.attrs.bold = true,
+                4 => self.attrs.underline = true,
+                7 => self.attrs.reverse = true,
+                22 => self.attrs.bold = false,
+                24 => self.attrs.underline = false,
+                27 => self.attrs.reverse = false,
+                30..=37 => self.attrs.fg = Color::Indexed((param - 30) as u8),
+                39 => self.attrs.fg = Color::Default,
+                40..=47 => self.attrs.bg = Color::Indexed((param - 40) as u8),
+                49 => self.attrs.bg = Color::Default,
+                90..=97 => self.attrs.fg = Color::Indexed((param - 90 + 8) as u8),
+                100..=107 => self.attrs.bg = Color::Indexed((param - 100 + 8) as u8),
+                38 | 48 => {
+                    // Only indexed colors are supported, true colors are dropped.
+                    let color = match params.next() {
+                        Some(5) => params.next().map(|c| Color::Indexed(c as u8)),
+                        Some(2) => {
+                            params.by_ref().take(3).for_each(drop);
+                            None
+                        }
+                        _ => None,
+                    };
+                    if let Some(color) = color {
+                        if param == 38 {
+                            self.attrs.fg = color;
+                        } else {
+                            self.attrs.bg = color;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Perform for Grid {
+    fn print(&mut self, ch: char) {
+        if self.wrap_pending {
+            self.col = 0;
+            self.line_feed();
+            self.wrap_pending = false;
+        }
+        self.cells[self.row][self.col] = Cell {
+            ch,
+            attrs: self.attrs,
+        };
+        if self.col + 1 < self.cols {
+            self.col += 1;
+        } else {
+            self.wrap_pending = true;
+        }
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            b'\n' | 0x0b | 0x0c => {
+                self.line_feed();
+                self.wrap_pending = false;
+            }
+            b'\r' => self.move_to(self.row, 0),
+            0x08 => self.move_to(self.row, self.col.saturating_sub(1)),
+            b'\t' => self.move_to(self.row, (self.col / 8 + 1) * 8),
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        // Private modes (e.g. `CSI ? 25 h`) are not modeled.
+        if ignore || !intermediates.is_empty() {
+            return;
+        }
+        let arg = |i: usize, default: usize| {
+            params
+                .iter()
+                .nth(i)
+                .map(|p| p[0] as usize)
+                .filter(|&p| p != 0)
+                .unwrap_or(default)
+        };
+        let (row, col) = (self.row, self.col);
+        match action {
+            'A' => self.move_to(row.saturating_sub(arg(0, 1)), col),
+            'B' => self.move_to(row + arg(0, 1), col),
+            'C' => self.move_to(row, col + arg(0, 1)),
+            'D' => self.move_to(row, col.saturating_sub(arg(0, 1))),
+            'E' => self.move_to(row + arg(0, 1), 0),
+            'F' => self.move_to(row.saturating_sub(arg(0, 1)), 0),
+            'G' => self.move_to(row, arg(0, 1) - 1),
+            'd' => self.move_to(arg(0, 1) - 1, col),
+            'H' | 'f' => self.move_to(arg(0, 1) - 1, arg(1, 1) - 1),
+            'J' => {
+                let (start, end) = match params.iter().next().map_or(0, |p| p[0]) {
+                    0 => {
+                        self.erase(row, col..self.cols);
+                        (row + 1, self.rows)
+                    }
+                    1 => {
+                        self.erase(row, 0..col + 1);
+                        (0, row)
+                    }
+                    _ => (0, self.rows),
+                };
+                for r in start..end {
+                    self.erase(r, 0..self.cols);
+                }
+            }
+            'K' => match params.iter().next().map_or(0, |p| p[0]) {
+                0 => self.erase(row, col..self.cols),
+                1 => self.erase(row, 0..col + 1),
+                _ => self.erase(row, 0..self.cols),
+            },
+            'X' => self.erase(row, col..(col + arg(0, 1)).min(self.cols)),
+            'm' => self.sgr(params),
+            _ => {}
+        }
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
+        if !intermediates.is_empty() {
+            return;
+        }
+        match byte {
+            // Full reset.
+            b'c' => {
+                *self = Screen::new(self.cols as u16, self.rows as u16).grid;
+            }
+            // Index and next line.
+            b'D' => self.line_feed(),
+            b'E' => {
+                self.line_feed();
+                self.move_to(self.row, 0);
+            }
+            // Reverse index.
+            b'M' => {
+                if self.row > 0 {
+                    self.row -= 1;
+                } else {
+                    self.cells.pop();
+                    self.cells.insert(0, vec![Cell::default(); self.cols]);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_screen_model() {
+        let mut screen = Screen::new(10, 3);
+        screen.feed(b"hello\r\nworld\x1b[1;3H\x1b[31mX\x1b[0m\r\n\x1b[3;1Hlast line!");
+        assert_eq!(screen.lines(), ["heXlo", "world", "last line!"]);
+        // The last column was written, but the cursor does not wrap yet.
+        assert_eq!(screen.cursor(), (2, 9));
+
+        screen.feed(b"\rscrolled\x1b[K\r\n");
+        assert_eq!(screen.lines(), ["world", "scrolled", ""]);
+        assert_eq!(screen.cursor(), (2, 0));
+
+        screen.feed(b"\x1b[2J\x1b[2;4Hmid");
+        assert_eq!(screen.lines(), ["", "   mid", ""]);
+    }
+
+    #[test]
+    fn test_screen_split_sequences() {
+        let mut screen = Screen::new(20, 2);
+        screen.feed(b"abc\x1b[");
+        screen.feed(b"1;");
+        screen.feed(b"2Hz \xe2\x9c");
+        screen.feed(b"\x93");
+        assert_eq!(screen.lines(), ["az \u{2713}", ""]);
+    }
+
+    #[test]
+    fn test_screen_render_reconstructs() {
+        let mut screen = Screen::new(20, 4);
+        screen.feed(b"$ ls\r\n\x1b[1;34mdir\x1b[0m  file\r\n\x1b[7mstatus\x1b[0m\x1b[2;8H\x1b[32m");
+
+        let mut reconstructed = Screen::new(20, 4);
+        reconstructed.feed(b"garbage which is cleared");
+        reconstructed.feed(&screen.render());
+        assert_eq!(reconstructed.lines(), screen.lines());
+        assert_eq!(reconstructed.cursor(), (1, 7));
+        assert_eq!(reconstructed.grid.cells, screen.grid.cells);
+        assert_eq!(reconstructed.grid.attrs, screen.grid.attrs);
+    }
+}
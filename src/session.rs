@@ -0,0 +1,83 @@
+use std::{path::PathBuf, str::FromStr};
+
+use crate::config::parse_buffer_size;
+
+/// Prefix of the websocket routes serving the sessions, followed by the id of the session.
+pub const SESSION_PATH: &str = "/ws/session/";
+
+/// Another pty served by the same server, with its own console and clients, on
+/// [`SESSION_PATH`] followed by its id. Denoted as `<id>=<path>[,<bytes>]`, e.g. `vm2=/dev/pts/4`.
+/// The id can only contain ASCII letters, digits, `-` and `_`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionPty {
+    pub id: String,
+    pub path: PathBuf,
+    /// Size of the history of the session, instead of `--buffer-size`.
+    pub buffer_size: Option<usize>,
+}
+
+impl FromStr for SessionPty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, path) = s
+            .split_once('=')
+            .ok_or_else(|| "expected <id>=<path>".to_string())?;
+        if id.is_empty() {
+            return Err("the id of a session can't be empty".into());
+        }
+        if !id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err("the id can only contain ASCII letters, digits, - and _".into());
+        }
+        let (path, buffer_size) = match path.rsplit_once(',') {
+            Some((path, size)) => {
+                let size = parse_buffer_size(size).map_err(|e| format!("invalid size: {}", e))?;
+                (path, Some(size))
+            }
+            None => (path, None),
+        };
+        if path.is_empty() {
+            return Err("the path of the pty can't be empty".into());
+        }
+        Ok(SessionPty {
+            id: id.to_string(),
+            path: path.into(),
+            buffer_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_session() {
+        assert_eq!(
+            "vm-2=/dev/pts/4".parse(),
+            Ok(SessionPty {
+                id: "vm-2".into(),
+                path: "/dev/pts/4".into(),
+                buffer_size: None,
+            })
+        );
+        assert_eq!(
+            "vm-2=/dev/pts/4,1048576".parse(),
+            Ok(SessionPty {
+                id: "vm-2".into(),
+                path: "/dev/pts/4".into(),
+                buffer_size: Some(1 << 20),
+            })
+        );
+        assert!("vm2=/dev/pts/4,".parse::<SessionPty>().is_err());
+        assert!("vm2=/dev/pts/4,512".parse::<SessionPty>().is_err());
+        assert!("vm2=,4096".parse::<SessionPty>().is_err());
+        assert!("/dev/pts/4".parse::<SessionPty>().is_err());
+        assert!("=/dev/pts/4".parse::<SessionPty>().is_err());
+        assert!("vm/2=/dev/pts/4".parse::<SessionPty>().is_err());
+        assert!("vm2=".parse::<SessionPty>().is_err());
+    }
+}
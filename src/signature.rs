@@ -0,0 +1,277 @@
+//! Detached Ed25519 signatures over files which are only appended to, like the log file of a
+//! console.
+//!
+//! The signature of a file is written to a file next to it, with `.sig` appended to its name. It
+//! covers the length and the SHA-256 digest of the file at the time it was signed, so output
+//! appended afterwards does not invalidate it, but any change to the signed part does.
+
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::{
+    fmt::{self, Write as _},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// Size of the reads when hashing a file.
+const READ_SIZE: usize = 64 << 10;
+
+/// The contents of a signature file.
+#[derive(Debug, Serialize, Deserialize)]
+struct SignatureFile {
+    /// Amount of bytes at the start of the file which are signed.
+    length: u64,
+    /// Hex encoded SHA-256 digest of the signed bytes.
+    sha256: String,
+    /// Hex encoded Ed25519 signature over [`signed_message`].
+    signature: String,
+}
+
+/// Signs a file which is only appended to. Every call to [`FileSigner::sign`] only hashes the
+/// data appended since the previous call.
+#[derive(Debug)]
+pub struct FileSigner {
+    key: SigningKey,
+    path: PathBuf,
+    hasher: Sha256,
+    /// Amount of bytes of the file which are hashed.
+    hashed: u64,
+}
+
+/// A successful verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verified {
+    /// Amount of bytes which are covered by the signature.
+    pub signed: u64,
+    /// Amount of bytes appended to the file after it was signed.
+    pub unsigned: u64,
+}
+
+/// Reasons a verification fails.
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The file or its signature can't be read.
+    Io(io::Error),
+    /// The signature file is not valid.
+    Malformed(String),
+    /// The file is shorter than the signed length.
+    Truncated { signed: u64, actual: u64 },
+    /// The signed part of the file changed.
+    Modified,
+    /// The signature was not made with the key.
+    BadSignature,
+}
+
+impl FileSigner {
+    /// Create a new FileSigner, signing the file at `path` with `key`.
+    pub fn new(key: SigningKey, path: &Path) -> FileSigner {
+        FileSigner {
+            key,
+            path: path.to_path_buf(),
+            hasher: Sha256::new(),
+            hashed: 0,
+        }
+    }
+
+    /// Start over with an empty file at the same path, e.g. once the signed file was rotated.
+    pub fn restart(&mut self) {
+        self.hasher = Sha256::new();
+        self.hashed = 0;
+    }
+
+    /// Sign the current contents of the file, replacing the previous signature. Returns the amount
+    /// of bytes signed.
+    pub fn sign(&mut self) -> io::Result<u64> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.hashed))?;
+        let mut buf = vec![0; READ_SIZE];
+        loop {
+            match file.read(&mut buf)? {
+                0 => break,
+                n => {
+                    self.hasher.update(&buf[..n]);
+                    self.hashed += n as u64;
+                }
+            }
+        }
+        let sha256 = hex(&self.hasher.clone().finalize());
+        let signature = self.key.sign(&signed_message(self.hashed, &sha256));
+        let sig = SignatureFile {
+            length: self.hashed,
+            sha256,
+            signature: hex(&signature.to_bytes()),
+        };
+        // Write the new signature next to the old one first, so a signature is always present.
+        let sig_path = signature_path(&self.path);
+        let tmp = sig_path.with_extension("sig.tmp");
+        // Serializing this type can't fail, there are no maps with non string keys.
+        std::fs::write(&tmp, serde_json::to_vec(&sig).unwrap())?;
+        std::fs::rename(&tmp, &sig_path)?;
+        Ok(self.hashed)
+    }
+}
+
+/// Verify the signature of the file at `path` with `key`.
+pub fn verify(path: &Path, key: &VerifyingKey) -> Result<Verified, VerifyError> {
+    let sig = std::fs::read(signature_path(path)).map_err(VerifyError::Io)?;
+    let sig: SignatureFile =
+        serde_json::from_slice(&sig).map_err(|e| VerifyError::Malformed(e.to_string()))?;
+    let signature = parse_hex::<64>(&sig.signature)
+        .map(|bytes| Signature::from_bytes(&bytes))
+        .ok_or_else(|| VerifyError::Malformed("invalid signature".into()))?;
+    key.verify(&signed_message(sig.length, &sig.sha256), &signature)
+        .map_err(|_| VerifyError::BadSignature)?;
+
+    let file = File::open(path).map_err(VerifyError::Io)?;
+    let actual = file.metadata().map_err(VerifyError::Io)?.len();
+    if actual < sig.length {
+        return Err(VerifyError::Truncated {
+            signed: sig.length,
+            actual,
+        });
+    }
+    let mut hasher = Sha256::new();
+    io::copy(&mut file.take(sig.length), &mut hasher).map_err(VerifyError::Io)?;
+    if hex(&hasher.finalize()) != sig.sha256 {
+        return Err(VerifyError::Modified);
+    }
+    Ok(Verified {
+        signed: sig.length,
+        unsigned: actual - sig.length,
+    })
+}
+
+/// The path of the signature of the file at `path`.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut sig = path.as_os_str().to_owned();
+    sig.push(".sig");
+    sig.into()
+}
+
+/// Parse a hex encoded Ed25519 signing key.
+pub fn parse_signing_key(key: &str) -> Option<SigningKey> {
+    parse_hex::<32>(key).map(|bytes| SigningKey::from_bytes(&bytes))
+}
+
+/// Parse a hex encoded Ed25519 public key.
+pub fn parse_verifying_key(key: &str) -> Option<VerifyingKey> {
+    parse_hex::<32>(key).and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+}
+
+/// Hex encode a public key.
+pub fn format_verifying_key(key: &VerifyingKey) -> String {
+    hex(key.as_bytes())
+}
+
+/// The message which is signed, binding the digest to the signed length.
+fn signed_message(length: u64, sha256: &str) -> Vec<u8> {
+    format!("cloud-console signature v1\n{}\n{}\n", length, sha256).into_bytes()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{:02x}", b);
+    }
+    out
+}
+
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.trim();
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut out = [0; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Io(e) => write!(f, "{}", e),
+            VerifyError::Malformed(e) => write!(f, "malformed signature file: {}", e),
+            VerifyError::Truncated { signed, actual } => write!(
+                f,
+                "file is truncated, {} bytes are signed but only {} remain",
+                signed, actual
+            ),
+            VerifyError::Modified => write!(f, "the signed part of the file was modified"),
+            VerifyError::BadSignature => write!(f, "the signature was not made with this key"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("cloud-console-{}-{}", name, std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let path = temp_file("signed", b"first output\r\n");
+        let key = parse_signing_key(&"01".repeat(32)).unwrap();
+        let public = key.verifying_key();
+        let mut signer = FileSigner::new(key, &path);
+        assert_eq!(signer.sign().unwrap(), 14);
+        let verified = verify(&path, &public).unwrap();
+        assert_eq!((verified.signed, verified.unsigned), (14, 0));
+
+        // Output appended after signing is not covered, until the file is signed again.
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"more output").unwrap();
+        assert_eq!(verify(&path, &public).unwrap().unsigned, 11);
+        assert_eq!(signer.sign().unwrap(), 25);
+        assert_eq!(verify(&path, &public).unwrap().signed, 25);
+
+        // Another key did not make the signature.
+        let other = parse_signing_key(&"02".repeat(32)).unwrap();
+        assert!(matches!(
+            verify(&path, &other.verifying_key()),
+            Err(VerifyError::BadSignature)
+        ));
+        let _ = std::fs::remove_file(signature_path(&path));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_verify_tampered() {
+        let path = temp_file("tampered", b"rm -rf /tmp/x\r\n");
+        let key = parse_signing_key(&"03".repeat(32)).unwrap();
+        let public = parse_verifying_key(&format_verifying_key(&key.verifying_key())).unwrap();
+        FileSigner::new(key, &path).sign().unwrap();
+
+        std::fs::write(&path, b"rm -rf /tmp/y\r\n").unwrap();
+        assert!(matches!(verify(&path, &public), Err(VerifyError::Modified)));
+        std::fs::write(&path, b"rm").unwrap();
+        assert!(matches!(
+            verify(&path, &public),
+            Err(VerifyError::Truncated {
+                signed: 15,
+                actual: 2
+            })
+        ));
+        let _ = std::fs::remove_file(signature_path(&path));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,214 @@
+//! A status line the server renders on the bottom row of the terminal of a client, showing the
+//! state of the console next to the output of the program.
+//!
+//! The status line is drawn over whatever the program wrote to the last row, with the cursor
+//! position and attributes saved and restored around it, and is only redrawn when the state it
+//! shows changes. Output of the program can overwrite or scroll it until the next change. It is
+//! not drawn while the alternate screen is active, since full-screen programs use every row.
+
+use crate::{control::ServerMessage, resize::WinSize};
+
+const ESC: u8 = 0x1b;
+
+/// Parameters of the private modes which switch to the alternate screen.
+const ALT_SCREEN_MODES: [u32; 3] = [47, 1047, 1049];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParseState {
+    Ground,
+    /// Received ESC.
+    Escape,
+    /// Received CSI.
+    Csi,
+    /// Parsing the parameters of a private mode sequence, with the alternate screen mode seen so
+    /// far and the current parameter.
+    Private {
+        alt: bool,
+        param: u32,
+    },
+    /// Inside a CSI sequence which does not set a private mode.
+    Ignore,
+}
+
+/// Keeps the status line of one client, producing the sequences to draw it whenever its state
+/// changes.
+#[derive(Debug)]
+pub struct StatusLine {
+    name: String,
+    size: Option<WinSize>,
+    read_only: bool,
+    binary: bool,
+    alt_screen: bool,
+    state: ParseState,
+    /// The text which is drawn, if any.
+    drawn: Option<String>,
+}
+
+impl StatusLine {
+    /// Create a new StatusLine for the console with the given name. Nothing is drawn until the
+    /// size of the terminal is known.
+    pub fn new(name: String) -> StatusLine {
+        StatusLine {
+            name,
+            size: None,
+            read_only: false,
+            binary: false,
+            alt_screen: false,
+            state: ParseState::Ground,
+            drawn: None,
+        }
+    }
+
+    /// Set the size of the terminal. Returns the sequences to draw the status line, if it
+    /// changed.
+    pub fn set_size(&mut self, size: WinSize) -> Option<Vec<u8>> {
+        if self.size != Some(size) {
+            self.size = Some(size);
+            // Redraw on the new bottom row, even if the text is the same.
+            self.drawn = None;
+        }
+        self.draw()
+    }
+
+    /// Set whether the input of the client is discarded.
+    pub fn set_read_only(&mut self, read_only: bool) -> Option<Vec<u8>> {
+        self.read_only = read_only;
+        self.draw()
+    }
+
+    /// Set whether the console output is mostly binary data.
+    pub fn set_binary(&mut self, binary: bool) -> Option<Vec<u8>> {
+        self.binary = binary;
+        self.draw()
+    }
+
+    /// Apply a control message sent to all clients, which might change the state shown.
+    pub fn update(&mut self, event: &ServerMessage) -> Option<Vec<u8>> {
+        match *event {
+            ServerMessage::Winsize { cols, rows, .. } => self.set_size(WinSize { cols, rows }),
+            ServerMessage::BinaryOutput { binary } => self.set_binary(binary),
+            _ => None,
+        }
+    }
+
+    /// Feed a chunk of console output sent to the client, to track whether the alternate screen
+    /// is active. Returns the sequences to draw the status line once the alternate screen is left.
+    pub fn feed(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let was_alt = self.alt_screen;
+        for &b in data {
+            self.state = match (self.state, b) {
+                (_, ESC) => ParseState::Escape,
+                (ParseState::Escape, b'[') => ParseState::Csi,
+                (ParseState::Csi, b'?') => ParseState::Private {
+                    alt: false,
+                    param: 0,
+                },
+                (ParseState::Private { alt, param }, b'0'..=b'9') => ParseState::Private {
+                    alt,
+                    param: param.saturating_mul(10).saturating_add((b - b'0') as u32),
+                },
+                (ParseState::Private { alt, param }, b';') => ParseState::Private {
+                    alt: alt || ALT_SCREEN_MODES.contains(&param),
+                    param: 0,
+                },
+                (ParseState::Private { alt, param }, b'h' | b'l') => {
+                    if alt || ALT_SCREEN_MODES.contains(&param) {
+                        self.alt_screen = b == b'h';
+                    }
+                    ParseState::Ground
+                }
+                (
+                    ParseState::Csi | ParseState::Private { .. } | ParseState::Ignore,
+                    0x40..=0x7e,
+                ) => ParseState::Ground,
+                (ParseState::Csi | ParseState::Private { .. } | ParseState::Ignore, _) => {
+                    ParseState::Ignore
+                }
+                _ => ParseState::Ground,
+            };
+        }
+        if was_alt && !self.alt_screen {
+            // The main screen is restored as it was, redraw in case the state changed meanwhile.
+            self.drawn = None;
+            return self.draw();
+        }
+        None
+    }
+
+    /// The sequences to draw the status line, if it can be drawn and is not drawn already.
+    fn draw(&mut self) -> Option<Vec<u8>> {
+        let size = self.size.filter(|size| size.rows > 1 && size.cols > 0)?;
+        if self.alt_screen {
+            return None;
+        }
+        let mut text = format!(" {}", self.name);
+        if self.read_only {
+            text.push_str(" | read only");
+        }
+        if self.binary {
+            text.push_str(" | binary output");
+        }
+        // Writing the last column would wrap on some terminals.
+        let text: String = text
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(size.cols as usize - 1)
+            .collect();
+        if self.drawn.as_ref() == Some(&text) {
+            return None;
+        }
+        // Save the cursor, draw the text in reverse video on the cleared bottom row, and restore
+        // the cursor with its attributes.
+        let out = format!(
+            "\x1b7\x1b[{};1H\x1b[2K\x1b[7m{}\x1b[0m\x1b8",
+            size.rows, text
+        );
+        self.drawn = Some(text);
+        Some(out.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_line() {
+        let mut status = StatusLine::new("vm-1".into());
+        // Nothing is drawn until the size is known.
+        assert_eq!(status.set_read_only(true), None);
+        let size = WinSize { cols: 80, rows: 24 };
+        assert_eq!(
+            status.set_size(size).unwrap(),
+            b"\x1b7\x1b[24;1H\x1b[2K\x1b[7m vm-1 | read only\x1b[0m\x1b8"
+        );
+        // Only changes are drawn.
+        assert_eq!(status.set_size(size), None);
+        assert_eq!(status.feed(b"ls\r\n\x1b[?25l"), None);
+        assert_eq!(
+            status.set_read_only(false).unwrap(),
+            b"\x1b7\x1b[24;1H\x1b[2K\x1b[7m vm-1\x1b[0m\x1b8"
+        );
+
+        // Full-screen programs are left alone, the status line is drawn once they exit.
+        assert_eq!(status.feed(b"\x1b[?1049h\x1b[H"), None);
+        let event = ServerMessage::BinaryOutput { binary: true };
+        assert_eq!(status.update(&event), None);
+        assert_eq!(status.feed(b"\x1b[?10"), None);
+        assert_eq!(
+            status.feed(b"49l$ ").unwrap(),
+            b"\x1b7\x1b[24;1H\x1b[2K\x1b[7m vm-1 | binary output\x1b[0m\x1b8"
+        );
+
+        // The text is cut off before the last column.
+        let event = ServerMessage::Winsize {
+            cols: 8,
+            rows: 10,
+            mismatch: false,
+        };
+        assert_eq!(
+            status.update(&event).unwrap(),
+            b"\x1b7\x1b[10;1H\x1b[2K\x1b[7m vm-1 |\x1b[0m\x1b8"
+        );
+    }
+}
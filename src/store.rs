@@ -0,0 +1,349 @@
+//! Storage for the history of a [`ConsoleMux`](crate::ConsoleMux).
+
+use std::collections::VecDeque;
+
+/// Storage for the history of a console, retaining the most recent output up to a fixed capacity.
+pub trait HistoryStore {
+    /// Append data to the history. Once the capacity is exceeded, the oldest data is evicted.
+    fn append(&mut self, data: &[u8]);
+
+    /// The retained history, oldest data first, as two slices which form the history when joined.
+    /// Until the store is filled for the first time, the history may be padded with zeroes at
+    /// the start, up to the capacity.
+    fn snapshot(&self) -> (&[u8], &[u8]);
+
+    /// The amount of retained history, not counting padding.
+    fn len(&self) -> usize;
+
+    /// The maximum amount of history which can be retained.
+    fn capacity(&self) -> usize;
+
+    /// Discard all retained history, leaving the store as if it was new.
+    fn clear(&mut self);
+
+    /// Whether the store does not retain any history.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A [`HistoryStore`] keeping the history in a fixed size in-memory array of H bytes.
+#[derive(Debug, Clone)]
+pub struct RingBuffer<const H: usize> {
+    pub(crate) data: [u8; H],
+    pub(crate) head: usize,
+    /// The buffer has been filled at least once, so it no longer contains padding.
+    filled: bool,
+}
+
+impl<const H: usize> RingBuffer<H> {
+    /// Create a new, empty RingBuffer.
+    pub fn new() -> RingBuffer<H> {
+        RingBuffer {
+            data: [0; H],
+            head: 0,
+            filled: false,
+        }
+    }
+}
+
+impl<const H: usize> Default for RingBuffer<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const H: usize> HistoryStore for RingBuffer<H> {
+    fn append(&mut self, data: &[u8]) {
+        // Only keep the data we can actually write to the buffer.
+        let sized_data = if data.len() > H {
+            &data[data.len() - H..]
+        } else {
+            data
+        };
+
+        // Write data to buffer from head -> end of buffer
+        let remainder = H - self.head;
+        let to_write = usize::min(remainder, sized_data.len());
+        self.data[self.head..self.head + to_write].copy_from_slice(&sized_data[..to_write]);
+        // Now write remainder of data to start of buffer -> head
+        if sized_data.len() > remainder {
+            self.data[..sized_data.len() - remainder].copy_from_slice(&sized_data[to_write..]);
+        }
+
+        // Update head
+        if self.head + sized_data.len() >= H {
+            self.filled = true;
+        }
+        self.head = (self.head + sized_data.len()) % H;
+    }
+
+    fn snapshot(&self) -> (&[u8], &[u8]) {
+        (&self.data[self.head..], &self.data[..self.head])
+    }
+
+    fn len(&self) -> usize {
+        if self.filled {
+            H
+        } else {
+            self.head
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        H
+    }
+
+    fn clear(&mut self) {
+        // Overwrite the data, since cleared history should not linger in memory.
+        self.data.fill(0);
+        self.head = 0;
+        self.filled = false;
+    }
+}
+
+/// A [`HistoryStore`] like [`RingBuffer`], of which the size is chosen at runtime and can be
+/// changed later on. The memory is allocated as the history grows.
+#[derive(Debug, Clone)]
+pub struct DynRingBuffer {
+    data: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl DynRingBuffer {
+    /// Create a new, empty DynRingBuffer retaining up to `capacity` bytes.
+    pub fn new(capacity: usize) -> DynRingBuffer {
+        DynRingBuffer {
+            data: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Change the amount of bytes retained. When shrinking, the oldest data which no longer fits
+    /// is evicted.
+    pub fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+        self.data.shrink_to(capacity);
+    }
+
+    /// Evict the oldest data beyond the capacity.
+    fn evict(&mut self) {
+        let excess = self.data.len().saturating_sub(self.capacity);
+        self.data.drain(..excess);
+    }
+}
+
+impl HistoryStore for DynRingBuffer {
+    fn append(&mut self, data: &[u8]) {
+        // Only keep the data which can be retained.
+        self.data
+            .extend(&data[data.len().saturating_sub(self.capacity)..]);
+        self.evict();
+    }
+
+    fn snapshot(&self) -> (&[u8], &[u8]) {
+        self.data.as_slices()
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+    }
+}
+
+/// A [`HistoryStore`] like [`RingBuffer`], which only retains complete lines. Once the oldest data
+/// is evicted, the history starts after the first newline in the remaining data, so it never
+/// starts halfway a line. A single line which does not fit is retained partially.
+#[derive(Debug, Clone, Default)]
+pub struct LineRing<const H: usize> {
+    ring: RingBuffer<H>,
+}
+
+impl<const H: usize> LineRing<H> {
+    /// Create a new, empty LineRing.
+    pub fn new() -> LineRing<H> {
+        LineRing {
+            ring: RingBuffer::new(),
+        }
+    }
+
+    /// The amount of bytes at the start of the ring which are part of an evicted line.
+    fn partial_line(&self) -> usize {
+        match self.ring.filled {
+            true => first_line_len(self.ring.snapshot()),
+            false => 0,
+        }
+    }
+}
+
+impl<const H: usize> HistoryStore for LineRing<H> {
+    fn append(&mut self, data: &[u8]) {
+        self.ring.append(data);
+    }
+
+    fn snapshot(&self) -> (&[u8], &[u8]) {
+        skip_start(self.ring.snapshot(), self.partial_line())
+    }
+
+    fn len(&self) -> usize {
+        self.ring.len() - self.partial_line()
+    }
+
+    fn capacity(&self) -> usize {
+        H
+    }
+
+    fn clear(&mut self) {
+        self.ring.clear();
+    }
+}
+
+/// A [`HistoryStore`] like [`LineRing`], of which the size is chosen at runtime, like a
+/// [`DynRingBuffer`].
+#[derive(Debug, Clone)]
+pub struct DynLineRing {
+    ring: DynRingBuffer,
+}
+
+impl DynLineRing {
+    /// Create a new, empty DynLineRing retaining up to `capacity` bytes.
+    pub fn new(capacity: usize) -> DynLineRing {
+        DynLineRing {
+            ring: DynRingBuffer::new(capacity),
+        }
+    }
+
+    /// Change the amount of bytes retained, like [`DynRingBuffer::resize`].
+    pub fn resize(&mut self, capacity: usize) {
+        self.ring.resize(capacity);
+    }
+
+    /// The amount of bytes at the start of the ring which are part of an evicted line. Once the
+    /// ring is full, the oldest data might have been evicted.
+    fn partial_line(&self) -> usize {
+        match self.ring.len() == self.ring.capacity() {
+            true => first_line_len(self.ring.snapshot()),
+            false => 0,
+        }
+    }
+}
+
+impl HistoryStore for DynLineRing {
+    fn append(&mut self, data: &[u8]) {
+        self.ring.append(data);
+    }
+
+    fn snapshot(&self) -> (&[u8], &[u8]) {
+        skip_start(self.ring.snapshot(), self.partial_line())
+    }
+
+    fn len(&self) -> usize {
+        self.ring.len() - self.partial_line()
+    }
+
+    fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+
+    fn clear(&mut self) {
+        self.ring.clear();
+    }
+}
+
+/// The length of the first line in the concatenation of `first` and `second`, including its
+/// newline, or 0 if there is no newline.
+fn first_line_len((first, second): (&[u8], &[u8])) -> usize {
+    first
+        .iter()
+        .chain(second)
+        .position(|&b| b == b'\n')
+        .map_or(0, |i| i + 1)
+}
+
+/// Skip the first `skip` bytes of the concatenation of `first` and `second`.
+fn skip_start<'a>((first, second): (&'a [u8], &'a [u8]), skip: usize) -> (&'a [u8], &'a [u8]) {
+    match skip.checked_sub(first.len()) {
+        Some(skip) => (&second[skip..], &[]),
+        None => (&first[skip..], second),
+    }
+}
+
+/// A [`HistoryStore`] retaining the last `max_lines` complete lines of output, followed by the
+/// current incomplete line. Lines longer than `max_line_len` bytes are truncated, so the amount of
+/// scrollback is predictable regardless of the length of the lines.
+#[derive(Debug, Clone)]
+pub struct LineBuffer {
+    data: VecDeque<u8>,
+    /// The length of every complete line in `data`, including its newline.
+    lines: VecDeque<usize>,
+    /// The length of the incomplete line at the end of `data`.
+    partial: usize,
+    max_lines: usize,
+    max_line_len: usize,
+}
+
+impl LineBuffer {
+    /// Create a new, empty LineBuffer.
+    pub fn new(max_lines: usize, max_line_len: usize) -> LineBuffer {
+        LineBuffer {
+            data: VecDeque::new(),
+            lines: VecDeque::new(),
+            partial: 0,
+            max_lines,
+            max_line_len,
+        }
+    }
+}
+
+impl HistoryStore for LineBuffer {
+    fn append(&mut self, data: &[u8]) {
+        for chunk in data.split_inclusive(|&b| b == b'\n') {
+            let (text, newline) = match chunk.split_last() {
+                Some((b'\n', text)) => (text, true),
+                _ => (chunk, false),
+            };
+            // The part of a line beyond the maximum length is dropped, the newline is kept.
+            let room = self.max_line_len.saturating_sub(self.partial);
+            let text = &text[..usize::min(text.len(), room)];
+            self.data.extend(text);
+            self.partial += text.len();
+            if !newline {
+                continue;
+            }
+            self.data.push_back(b'\n');
+            self.lines.push_back(self.partial + 1);
+            self.partial = 0;
+            if self.lines.len() > self.max_lines {
+                // There is always an oldest line if there are more lines than allowed.
+                let evicted = self.lines.pop_front().unwrap();
+                self.data.drain(..evicted);
+            }
+        }
+    }
+
+    fn snapshot(&self) -> (&[u8], &[u8]) {
+        self.data.as_slices()
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.max_lines * (self.max_line_len + 1) + self.max_line_len
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+        self.lines.clear();
+        self.partial = 0;
+    }
+}
@@ -0,0 +1,122 @@
+use tokio::io::AsyncWrite;
+
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use crate::clock::Clock;
+
+/// An [`AsyncWrite`] adapter which prefixes every line with the time its first byte was written,
+/// as an RFC 3339 timestamp followed by a space, e.g. for a log file which is reviewed later. A
+/// line which spans multiple writes is prefixed once.
+#[derive(Debug)]
+pub struct TimestampingWriter<W> {
+    inner: W,
+    clock: Arc<dyn Clock>,
+    /// The next byte written starts a new line.
+    line_start: bool,
+    /// Prefixed data which is not yet written to the inner writer.
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<W> TimestampingWriter<W> {
+    /// Create a new TimestampingWriter, writing data with timestamped lines to `inner`.
+    pub fn new(inner: W, clock: Arc<dyn Clock>) -> TimestampingWriter<W> {
+        TimestampingWriter {
+            inner,
+            clock,
+            line_start: true,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn prefix(&mut self, data: &[u8]) {
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if self.line_start {
+                let timestamp = humantime::format_rfc3339_millis(self.clock.wall());
+                self.buf
+                    .extend_from_slice(format!("{} ", timestamp).as_bytes());
+            }
+            self.buf.extend_from_slice(line);
+            self.line_start = line.ends_with(b"\n");
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> TimestampingWriter<W> {
+    /// Write all prefixed data to the inner writer.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pos < self.buf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.buf[self.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pos += n;
+        }
+        self.buf.clear();
+        self.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for TimestampingWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        this.prefix(data);
+        // Start writing right away, the data is accepted regardless.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+    use tokio::io::AsyncWriteExt;
+
+    use crate::clock::MockClock;
+
+    #[tokio::test]
+    async fn test_timestamping_writer() {
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(1000)));
+        let mut writer = TimestampingWriter::new(Vec::new(), clock);
+        writer.write_all(b"$ ls\r\nfile").await.unwrap();
+        // The rest of a line isn't prefixed again, the next line has the time it started.
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        writer.write_all(b".txt\r\n").await.unwrap();
+        writer.write_all(b"$ ").await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert_eq!(
+            writer.inner,
+            b"1970-01-01T00:16:40.000Z $ ls\r\n\
+              1970-01-01T00:16:40.000Z file.txt\r\n\
+              1970-01-01T00:16:41.500Z $ "
+        );
+    }
+}
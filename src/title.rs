@@ -0,0 +1,123 @@
+//! Extract title updates from OSC 0 and OSC 2 sequences in the console output.
+
+/// Longest title which is extracted, longer titles are ignored.
+const MAX_TITLE_LEN: usize = 1024;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParseState {
+    Ground,
+    /// Received ESC.
+    Escape,
+    /// Received the start of an OSC sequence, parsing the command number.
+    Command(u32),
+    /// Parsing the title of an OSC 0 or OSC 2 sequence.
+    Title,
+    /// Received ESC while parsing the title, which should be the start of ST.
+    TitleEscape,
+    /// Inside an OSC sequence which is not a title update, or an oversized one.
+    Ignore,
+    /// Received ESC in an ignored OSC sequence.
+    IgnoreEscape,
+}
+
+/// Stateful parser, finding title updates in console output. Sequences can be split over
+/// multiple chunks of output.
+#[derive(Debug)]
+pub struct TitleParser {
+    state: ParseState,
+    title: Vec<u8>,
+}
+
+impl TitleParser {
+    /// Create a new TitleParser, for output which starts outside of any escape sequence.
+    pub fn new() -> TitleParser {
+        TitleParser {
+            state: ParseState::Ground,
+            title: Vec::new(),
+        }
+    }
+
+    /// Feed a chunk of console output. Returns the last title set in the chunk, if any.
+    pub fn feed(&mut self, data: &[u8]) -> Option<String> {
+        let mut title = None;
+        for &b in data {
+            // An ESC in a string which does not start ST aborts the string, and starts a new
+            // sequence.
+            if matches!(
+                self.state,
+                ParseState::TitleEscape | ParseState::IgnoreEscape
+            ) && b != b'\\'
+            {
+                self.state = ParseState::Escape;
+            }
+            self.state = match (self.state, b) {
+                (ParseState::Escape, b']') => ParseState::Command(0),
+                (ParseState::Command(cmd), b'0'..=b'9') => {
+                    ParseState::Command(cmd.saturating_mul(10).saturating_add((b - b'0') as u32))
+                }
+                (ParseState::Command(0 | 2), b';') => {
+                    self.title.clear();
+                    ParseState::Title
+                }
+                (ParseState::Title, BEL) | (ParseState::TitleEscape, _) => {
+                    title = Some(String::from_utf8_lossy(&self.title).into_owned());
+                    ParseState::Ground
+                }
+                (ParseState::Title, ESC) => ParseState::TitleEscape,
+                (ParseState::Title, b) if self.title.len() < MAX_TITLE_LEN => {
+                    self.title.push(b);
+                    ParseState::Title
+                }
+                (ParseState::IgnoreEscape, _) => ParseState::Ground,
+                (ParseState::Command(_) | ParseState::Ignore, BEL) => ParseState::Ground,
+                (ParseState::Command(_) | ParseState::Ignore, ESC) => ParseState::IgnoreEscape,
+                (ParseState::Command(_) | ParseState::Title | ParseState::Ignore, _) => {
+                    ParseState::Ignore
+                }
+                (_, ESC) => ParseState::Escape,
+                _ => ParseState::Ground,
+            };
+        }
+        title
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_title_bel_and_st() {
+        let mut parser = TitleParser::new();
+        assert_eq!(
+            parser.feed(b"\x1b]0;user@host: ~\x07$ "),
+            Some("user@host: ~".into())
+        );
+        assert_eq!(parser.feed(b"\x1b]2;vim\x1b\\"), Some("vim".into()));
+        // Other OSC sequences, e.g. setting the icon name, are ignored.
+        assert_eq!(parser.feed(b"\x1b]1;icon\x07\x1b]8;;http://x\x07"), None);
+        assert_eq!(parser.feed(b"plain \x1b[31mtext"), None);
+    }
+
+    #[test]
+    fn test_title_split_sequence() {
+        let mut parser = TitleParser::new();
+        assert_eq!(parser.feed(b"output\x1b"), None);
+        assert_eq!(parser.feed(b"]"), None);
+        assert_eq!(parser.feed(b"2;my ti"), None);
+        assert_eq!(parser.feed(b"tle\x1b"), None);
+        assert_eq!(parser.feed(b"\\more output"), Some("my title".into()));
+    }
+
+    #[test]
+    fn test_title_last_wins() {
+        let mut parser = TitleParser::new();
+        assert_eq!(
+            parser.feed(b"\x1b]0;first\x07\x1b]0;second\x07"),
+            Some("second".into())
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use axum::http::{header, Method, Request, Uri};
+use clap::ValueEnum;
+use hyper::{client::HttpConnector, Body, Client};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::clock::Clock;
+
+/// Amount of webhook calls which can be queued. Events are dropped if the queue is full, so a slow
+/// webhook receiver does not affect the console.
+const WEBHOOK_BACKLOG: usize = 64;
+/// Maximum time a single webhook call can take.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Lifecycle events of a client connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LifecycleEvent {
+    /// A client connected.
+    Connect,
+    /// A client closed the connection.
+    Disconnect,
+    /// The server dropped the connection to a client, e.g. because it could not be written to.
+    Dropped,
+}
+
+/// The JSON payload posted to the webhook.
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookPayload {
+    pub event: LifecycleEvent,
+    pub console: String,
+    pub client_ip: IpAddr,
+    /// Correlation id of the connection of the client.
+    pub correlation_id: String,
+    /// RFC 3339 formatted time at which the event happened.
+    pub timestamp: String,
+    /// Extra information on why the event happened, if available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// An outbound webhook which is notified of client lifecycle events. Calls are made in the
+/// background, notifying the webhook never blocks.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    tx: mpsc::Sender<WebhookPayload>,
+    events: Arc<[LifecycleEvent]>,
+    console: Arc<str>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Webhook {
+    /// Spawn a task posting the given events for the console with the given name to `url`. Event
+    /// timestamps are taken from `clock`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic when executed outside the scope of a [`tokio::runtime::Runtime`]
+    pub fn spawn(
+        url: Uri,
+        events: &[LifecycleEvent],
+        console: &str,
+        clock: Arc<dyn Clock>,
+    ) -> Webhook {
+        let (tx, mut rx) = mpsc::channel::<WebhookPayload>(WEBHOOK_BACKLOG);
+        tokio::spawn(async move {
+            let client = Client::new();
+            while let Some(payload) = rx.recv().await {
+                match tokio::time::timeout(WEBHOOK_TIMEOUT, post(&client, &url, &payload)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Could not call webhook {}", e),
+                    Err(_) => warn!("Webhook call timed out"),
+                }
+            }
+        });
+
+        Webhook {
+            tx,
+            events: events.into(),
+            console: console.into(),
+            clock,
+        }
+    }
+
+    /// Notify the webhook of an event for the client at the given address and with the given
+    /// correlation id, if the webhook is interested in the event.
+    pub fn notify(
+        &self,
+        event: LifecycleEvent,
+        client: SocketAddr,
+        correlation_id: &str,
+        reason: Option<String>,
+    ) {
+        if !self.events.contains(&event) {
+            return;
+        }
+        let payload = WebhookPayload {
+            event,
+            console: self.console.to_string(),
+            client_ip: client.ip(),
+            correlation_id: correlation_id.to_string(),
+            timestamp: humantime::format_rfc3339_millis(self.clock.wall()).to_string(),
+            reason,
+        };
+        if self.tx.try_send(payload).is_err() {
+            warn!("Webhook queue is full, dropping {:?} event", event);
+        }
+    }
+}
+
+async fn post(
+    client: &Client<HttpConnector>,
+    url: &Uri,
+    payload: &WebhookPayload,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(payload)?))?;
+    let resp = client.request(req).await?;
+    if !resp.status().is_success() {
+        return Err(format!("webhook returned status {}", resp.status()).into());
+    }
+    Ok(())
+}